@@ -66,6 +66,18 @@ pub fn tokenizers() -> Vec<TokenizerInfo> {
             hf_model: "microsoft/phi-1_5",
             model_ids: "",
         },
+        TokenizerInfo {
+            name: "qwen2",
+            description: "Qwen1.5 and Qwen2; with <|im_start|> and <|im_end|> for chat",
+            hf_model: "Qwen/Qwen2-7B-Instruct",
+            model_ids: "qwen1.5",
+        },
+        TokenizerInfo {
+            name: "gemma",
+            description: "Gemma; gated on HuggingFace, set HF_TOKEN",
+            hf_model: "google/gemma-7b",
+            model_ids: "",
+        },
         TokenizerInfo {
             name: "gpt2",
             description: "GPT-2",
@@ -140,6 +152,8 @@ pub fn find_tokenizer(mut name: &str) -> Result<ByteTokenizer> {
             Some(s) => args.revision = s,
             None => {}
         }
+        // needed for gated models, like Gemma
+        args.auth_token = std::env::var("HF_TOKEN").ok();
         Tokenizer::from_pretrained(name2, Some(args))
     };
 
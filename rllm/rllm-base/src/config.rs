@@ -1,9 +1,10 @@
 // based on https://github.com/vllm-project/vllm/blob/b9fe4616f98b77b4b9458bce203aa6544cb31ef2/vllm/config.py
 
-use crate::ModelExec;
+use crate::{grammar::Grammar, seq::Token, ModelExec, PreemptionMode};
 use aicirt::{bail_user, valid_module_or_tag};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct RllmConfig<ME: ModelExec> {
@@ -26,6 +27,9 @@ pub struct ModelMeta {
 pub struct ParallelConfig {
     pub pipeline_parallel_size: usize,
     pub tensor_parallel_size: usize,
+    /// Each batch is split into this many parts, which go through the pipeline
+    /// stages one after another, so that the stages run at the same time.
+    pub num_micro_batches: usize,
 }
 
 impl ParallelConfig {
@@ -33,6 +37,7 @@ impl ParallelConfig {
         Self {
             pipeline_parallel_size: 1,
             tensor_parallel_size: 1,
+            num_micro_batches: 1,
         }
     }
 }
@@ -40,13 +45,113 @@ impl ParallelConfig {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SchedulerConfig {
     /// Maximum number of tokens to be processed in a single iteration (passed through FFN).
+    /// Both prompt and generation steps are limited; a single group may go over it.
     pub max_num_batched_tokens: usize,
     /// Maximum number of KV entries to be processed in a single iteration.
     pub max_num_kv_tokens: usize,
     /// Maximum number of sequences to be processed in a single iteration.
     pub max_num_seqs: usize,
+    /// Maximum number of prompt tokens (prefill) in a single iteration;
+    /// if None, only max_num_batched_tokens applies.
+    pub max_num_prefill_tokens: Option<usize>,
+    /// Maximum number of decoding sequences in a single iteration; new prompts are not
+    /// admitted while this many sequences decode. If None, only max_num_seqs applies.
+    pub max_num_decode_seqs: Option<usize>,
     /// Maximum length of a sequence (including prompt and generated text).
     pub max_model_len: usize,
+    /// Maximum number of KV entries kept for sessions between requests.
+    /// The same limit applies to the KV kept for `continuable` requests.
+    pub max_session_kv_tokens: usize,
+    /// If set, the KV kept for a `continuable` request is freed when it's not
+    /// continued within this many seconds.
+    pub kept_kv_idle_secs: Option<f32>,
+    /// If set, the KV of sessions idle for this many seconds is written to
+    /// `session_spill_dir` and freed, until the next request of the session;
+    /// spilled sessions don't count towards max_session_kv_tokens.
+    pub session_spill_secs: Option<f32>,
+    pub session_spill_dir: String,
+    /// The least recently used spilled sessions are dropped when their files
+    /// take more than this.
+    pub max_session_spill_bytes: usize,
+    /// If set, when the engine runs out of work and the longest run of consecutive
+    /// free GPU blocks is less than this fraction of the free blocks, the used
+    /// blocks are moved to the start of the cache (see ModelExec::compact_kv()).
+    pub compact_kv_free_run: Option<f32>,
+    /// Order of admission to the batch, and of preemption (in reverse).
+    pub policy: SchedulerPolicy,
+    /// If set, prompts are prefilled in chunks of at most this many tokens,
+    /// batched together with decoding sequences; otherwise prompts run in their own steps.
+    pub prefill_chunk_size: Option<usize>,
+    /// How to preempt sequence groups when KV blocks run out. If None, groups
+    /// with at least `min_swap_tokens` computed tokens are swapped, and smaller
+    /// ones are recomputed. Groups with classifier-free guidance are always recomputed.
+    pub preemption_mode: Option<PreemptionMode>,
+    pub min_swap_tokens: usize,
+    /// When the CPU swap space is full, drop the blocks of swapped-out groups that
+    /// are resumed last and hold fewer blocks (they are recomputed instead) to make
+    /// room for a newly preempted group; otherwise, the new group is recomputed.
+    pub evict_swapped: bool,
+    /// Effective priority of a group grows by one for every this many seconds
+    /// since its arrival, so that low priority groups are not starved.
+    pub priority_aging_secs: Option<f32>,
+    /// Groups that arrived more than this many seconds ago are scheduled before all others.
+    pub max_queue_secs: Option<f32>,
+    /// Groups that haven't started this many seconds after arrival are admitted even
+    /// if it takes preempting others, or else finished with FinishReason::QueueTimeout.
+    pub max_waiting_time: Option<f32>,
+    /// While the first waiting group doesn't fit in the KV cache, groups holding more
+    /// than this fraction of GPU blocks are preempted, so that one long request
+    /// can't keep all others waiting.
+    pub max_group_block_share: Option<f32>,
+    /// New requests are rejected (EngineError::QueueFull) when this many are already waiting.
+    pub max_queued_requests: Option<usize>,
+    /// Likewise, for the total number of prompt tokens of waiting requests.
+    pub max_queued_prompt_tokens: Option<usize>,
+    /// Classes for SchedulerPolicy::WeightedQos.
+    pub qos_classes: Vec<QosClass>,
+    /// Throughput mode: if set, waiting prompts are held for up to this many milliseconds
+    /// after the oldest of them arrived, so that they are prefilled in larger batches.
+    pub batch_window_ms: Option<u64>,
+    /// The window closes early when this many requests are waiting.
+    pub batch_window_requests: usize,
+    /// Fraction of max_num_batched_tokens that prompts can't use while sequences are decoding,
+    /// so that the time to the next token stays bounded when many prompts arrive.
+    pub decode_reserve: f32,
+    /// Fraction of the GPU blocks that stays free when prompts are admitted or
+    /// groups swapped in.
+    pub block_watermark: f32,
+    /// Free GPU blocks kept, when a prompt is admitted, for each sequence on the GPU
+    /// (including the ones admitted in the same step) to grow, so that admitting
+    /// the prompt doesn't force preemption in the next steps.
+    pub reserved_blocks_per_seq: usize,
+    /// Let the backend prepare the batch of the next step while the forward pass
+    /// of the current one runs, assuming all sequences keep decoding
+    /// (see ModelExec::prepare_next_run()).
+    pub pipeline_steps: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerPolicy {
+    /// First come, first served; SamplingParams.priority is ignored.
+    Fcfs,
+    /// Higher SamplingParams.priority first, then by arrival time.
+    Priority,
+    /// Like Priority, but the prompt token budget of a step is split evenly
+    /// between tenants (SamplingParams.tenant_id) with waiting requests.
+    FairShare,
+    /// Earliest SamplingParams.deadline first; requests without a deadline
+    /// go after these, ordered like with Priority.
+    Deadline,
+    /// Like FairShare, but the budget is split between QoS classes
+    /// (SamplingParams.qos_class) in proportion to their weights.
+    WeightedQos,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QosClass {
+    pub name: String,
+    /// Relative share of the step token budget; requests without a class have weight 1.
+    pub weight: usize,
 }
 
 pub const SAMPLING_EPS: f32 = 1e-5;
@@ -58,6 +163,18 @@ pub enum EarlyStopping {
     Never,
 }
 
+/// What to return instead of sampled tokens for `SamplingParams::hidden_states`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HiddenStates {
+    /// Final-layer hidden states of all prompt tokens.
+    All,
+    /// Mean of the final-layer hidden states over the prompt.
+    Mean,
+    /// Final-layer hidden state of the last prompt token.
+    Last,
+}
+
 /// Sampling parameters for text generation.
 ///
 /// Overall, we follow the sampling parameters from the OpenAI text completion
@@ -107,19 +224,92 @@ pub struct SamplingParams {
     /// List of strings that stop the generation when they are generated.
     pub stop: Vec<String>,
 
+    /// List of token ids that stop the generation when they are generated.
+    pub stop_token_ids: Vec<Token>,
+
     /// Whether to ignore the EOS token and continue generating tokens after the EOS token is generated.
     pub ignore_eos: bool,
 
     /// Maximum number of tokens to generate per output sequence.
     pub max_tokens: usize,
 
+    /// Maximum number of tokens to generate, summed over all sequences of the request
+    /// (see `best_of`).
+    pub max_total_tokens: Option<usize>,
+
     /// Number of log probabilities to return per output token.
     pub logprobs: Option<i32>,
+
+    /// GBNF grammar (llama.cpp-style) to constrain the generation with.
+    pub grammar: Option<String>,
+
+    /// Trim the last prompt token and only allow first generated tokens that extend it.
+    pub token_healing: bool,
+
+    /// Tokens the generation starts with; they are prefilled together with
+    /// the prompt, and count towards max_tokens.
+    pub forced_tokens: Vec<Token>,
+
+    /// Maximum wall-clock time (in seconds) to spend on the request, counting from its arrival.
+    pub max_time: Option<f32>,
+
+    /// Time (in seconds, from arrival) by which the request should complete.
+    /// With SchedulerPolicy::Deadline, the earliest deadline is scheduled first.
+    pub deadline: Option<f32>,
+
+    /// Include the prompt at the front of the output.
+    pub echo: bool,
+
+    /// Scheduling priority; higher goes first (see SchedulerPolicy). Default is 0.
+    pub priority: i32,
+
+    /// Tenant (user) the request is accounted to, with SchedulerPolicy::FairShare.
+    pub tenant_id: Option<String>,
+
+    /// QoS class (see SchedulerConfig.qos_classes), with SchedulerPolicy::WeightedQos.
+    pub qos_class: Option<String>,
+
+    /// Whether to leave special tokens out of the output text.
+    pub skip_special_tokens: bool,
+
+    /// Whether to keep the matched stop string at the end of the output text.
+    pub include_stop_str_in_output: bool,
+
+    /// Keep the KV cache when max_tokens is reached, so that the request can be continued.
+    /// The KV is freed anyway when idle for too long, or when other requests need the blocks.
+    pub continuable: bool,
+
+    /// Requests with the same session id reuse the KV cache of the previous
+    /// request in the session, for the common prefix of the tokens.
+    pub session_id: Option<String>,
+
+    /// Request this one continues from (eg., after a tool call); the session
+    /// (and thus the KV cache) of the parent is inherited.
+    pub parent_request_id: Option<String>,
+
+    /// Negative prompt for classifier-free guidance.
+    pub negative_prompt: Option<String>,
+
+    /// Logits are computed as `pos + guidance_scale * (pos - neg)`, where `neg`
+    /// are the logits given the negative prompt. Default is 1.0.
+    pub guidance_scale: f32,
+
+    /// Only run the prompt through the model and return its final-layer hidden
+    /// states, without generating any tokens.
+    pub hidden_states: Option<HiddenStates>,
+
+    /// LoRA adapter to generate with, by the name the server loaded it under;
+    /// None for the base model.
+    pub adapter: Option<String>,
+
+    // grammar, as parsed by verify_args()
+    #[serde(skip)]
+    parsed_grammar: Option<Arc<Grammar>>,
 }
 
 impl SamplingParams {
     pub fn default() -> Self {
-        let r = Self {
+        let mut r = Self {
             controller: None,
             controller_arg: String::new(),
             aici_fuel: None,
@@ -134,17 +324,45 @@ impl SamplingParams {
             length_penalty: 1.0,
             early_stopping: EarlyStopping::False,
             stop: Vec::new(),
+            stop_token_ids: Vec::new(),
             ignore_eos: false,
             max_tokens: 16,
+            max_total_tokens: None,
             logprobs: None,
+            grammar: None,
+            token_healing: false,
+            forced_tokens: Vec::new(),
+            max_time: None,
+            deadline: None,
+            echo: false,
+            priority: 0,
+            tenant_id: None,
+            qos_class: None,
+            skip_special_tokens: false,
+            include_stop_str_in_output: false,
+            continuable: false,
+            session_id: None,
+            parent_request_id: None,
+            negative_prompt: None,
+            guidance_scale: 1.0,
+            hidden_states: None,
+            adapter: None,
+            parsed_grammar: None,
         };
         r.verify_args().unwrap();
         r
     }
 
-    /// Verifies the arguments of the sampling parameters.
-    pub fn verify_args(&self) -> Result<()> {
+    /// Verifies the arguments of the sampling parameters, and parses the grammar.
+    pub fn verify_args(&mut self) -> Result<()> {
         self._verify_args()?;
+        self.parsed_grammar = match self.grammar.as_ref() {
+            Some(grammar) => match Grammar::from_gbnf(grammar) {
+                Ok(g) => Some(Arc::new(g)),
+                Err(e) => bail_user!("invalid grammar: {}", e),
+            },
+            None => None,
+        };
         if self.use_beam_search {
             self._verify_beam_search()?;
         } else {
@@ -156,6 +374,16 @@ impl SamplingParams {
         Ok(())
     }
 
+    /// The grammar to constrain the generation with; only parsed here if
+    /// verify_args() wasn't called.
+    pub fn get_grammar(&self) -> Result<Option<Arc<Grammar>>> {
+        match (self.parsed_grammar.as_ref(), self.grammar.as_ref()) {
+            (Some(g), _) => Ok(Some(g.clone())),
+            (None, Some(grammar)) => Ok(Some(Arc::new(Grammar::from_gbnf(grammar)?))),
+            (None, None) => Ok(None),
+        }
+    }
+
     fn _verify_args(&self) -> Result<()> {
         if let Some(mod_id) = self.controller.as_ref() {
             if !valid_module_or_tag(mod_id) && !mod_id.starts_with("gh:") {
@@ -206,11 +434,82 @@ impl SamplingParams {
         if self.max_tokens < 1 {
             bail_user!("max_tokens must be at least 1, got {}.", self.max_tokens);
         }
+        if let Some(max_total_tokens) = self.max_total_tokens {
+            if max_total_tokens < 1 {
+                bail_user!(
+                    "max_total_tokens must be at least 1, got {}.",
+                    max_total_tokens
+                );
+            }
+        }
         if let Some(logprobs) = self.logprobs {
             if logprobs < 0 {
                 bail_user!("logprobs must be non-negative, got {}.", logprobs);
             }
         }
+        if self.grammar.is_some() {
+            if self.controller.is_some() {
+                bail_user!("grammar can't be used together with a controller.");
+            }
+        }
+        if let Some(max_time) = self.max_time {
+            if !(max_time > 0.0) {
+                bail_user!("max_time must be positive, got {}.", max_time);
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if !(deadline > 0.0) {
+                bail_user!("deadline must be positive, got {}.", deadline);
+            }
+        }
+        if self.session_id.is_some() && self.controller.is_some() {
+            bail_user!("session_id can't be used together with a controller.");
+        }
+        if self.negative_prompt.is_some() {
+            if self.controller.is_some() || self.best_of > 1 {
+                bail_user!(
+                    "negative_prompt can't be used together with a controller or best_of > 1."
+                );
+            }
+            if self.guidance_scale < 0.0 {
+                bail_user!(
+                    "guidance_scale must be non-negative, got {}.",
+                    self.guidance_scale
+                );
+            }
+        }
+        if self.continuable && self.controller.is_some() {
+            bail_user!("continuable can't be used together with a controller.");
+        }
+        if self.forced_tokens.len() > 0 {
+            if self.controller.is_some() || self.token_healing {
+                bail_user!(
+                    "forced_tokens can't be used together with a controller or token_healing."
+                );
+            }
+            if self.forced_tokens.len() >= self.max_tokens {
+                bail_user!(
+                    "forced_tokens ({}) must be shorter than max_tokens ({}).",
+                    self.forced_tokens.len(),
+                    self.max_tokens
+                );
+            }
+        }
+        if self.hidden_states.is_some()
+            && (self.controller.is_some()
+                || self.best_of > 1
+                || self.negative_prompt.is_some()
+                || self.forced_tokens.len() > 0
+                || self.token_healing)
+        {
+            bail_user!(
+                "hidden_states can't be used together with a controller, best_of > 1, \
+                negative_prompt, forced_tokens or token_healing."
+            );
+        }
+        if self.token_healing && (self.controller.is_some() || self.grammar.is_some()) {
+            bail_user!("token_healing can't be used together with a controller or grammar.");
+        }
         Ok(())
     }
 
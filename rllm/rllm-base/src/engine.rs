@@ -1,15 +1,25 @@
 use crate::{
-    config::{ParallelConfig, RllmConfig, SamplingParams, SchedulerConfig},
+    config::{
+        HiddenStates, QosClass, RllmConfig, SamplingParams, SchedulerConfig, SchedulerPolicy,
+    },
+    fim::FimTokens,
+    grammar::GrammarMatcher,
     iface::AiciRtIface,
+    migration::ExportedRequest,
     seq::{
-        FinishReason, RequestOutput, SchedulingPhase, SeqOutput, Sequence, SequenceGroup, Token,
-        TokenUsage,
+        prune_draft, EmbeddingSpan, FinishReason, PromptEmbeddings, RequestOutput, SchedulingPhase,
+        SeqOutput, Sequence, SequenceGroup, StopCriterion, StopMatch, Token, TokenUsage,
     },
+    session::SessionCache,
     util::get_setting,
-    AiciBias as _, HashMap, LoaderArgs, LogitsProcessor, ModelExec, Scheduler, SchedulerOutputs,
-    SequenceManager, TBlockSpaceManager as _,
+    AiciBias as _, BlockPoolStats, HashMap, HashSet, LoaderArgs, LogitsProcessor, Logprobs,
+    ModelExec, Scheduler, SchedulerHooks, SchedulerOutputs, SchedulerState, SchedulingPolicy,
+    SeqId, SequenceManager, StepMetrics, TBlockSpaceManager as _,
+};
+use aici_abi::{
+    toktrie::{Recognizer, SpecialToken, TokTrie},
+    SimpleVob, Splice,
 };
-use aici_abi::{toktrie::TokTrie, Splice};
 use aicirt::{
     api::{AiciMidOp, AiciMidProcessReq, ModuleInstId, SequenceResult},
     with_timer, TimerRef, TimerSet,
@@ -17,10 +27,16 @@ use aicirt::{
 use anyhow::{bail, Error as E, Result};
 use hf_hub::{
     api::sync::{Api, ApiRepo},
-    RepoType,
+    Cache, CacheRepo, RepoType,
 };
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, ops::Deref, path::PathBuf, sync::Arc, time::Instant};
+use std::{
+    fmt::Display,
+    ops::Deref,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokenizers::Tokenizer;
 
 #[derive(Clone)]
@@ -43,10 +59,71 @@ pub struct AddRequest {
     pub sampling_params: SamplingParams,
     pub expected: Option<ExpectedGeneration>,
     pub init_result: Option<SequenceResult>,
+    pub prompt_embeddings: Option<PromptEmbeddings>,
+    /// Placeholder spans of the prompt, with their embeddings.
+    pub embedding_spans: Vec<EmbeddingSpan>,
+    /// Opaque client data, returned in every RequestOutput.
+    pub metadata: Option<serde_json::Value>,
+    pub stop_criterion: Option<StopCriterion>,
+}
+
+/// Summary of a single engine step.
+#[derive(Debug, Default)]
+pub struct StepOutcome {
+    pub outputs: Vec<RequestOutput>,
+    pub num_scheduled_groups: usize,
+    pub num_preempted_groups: usize,
+    pub num_swapped_in_blocks: usize,
+    pub num_swapped_out_blocks: usize,
+    /// Blocks moved to compact the KV cache; see SchedulerConfig::compact_kv_free_run.
+    pub num_compacted_blocks: usize,
+    /// Number of tokens passed through the model.
+    pub num_batched_tokens: usize,
+    pub forward_time: Duration,
+    pub sample_time: Duration,
+}
+
+/// Errors of the engine that callers may want to handle specifically.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineError {
+    /// The waiting queue is at capacity (see SchedulerConfig); retry later.
+    QueueFull {
+        num_requests: usize,
+        num_prompt_tokens: usize,
+    },
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::QueueFull {
+                num_requests,
+                num_prompt_tokens,
+            } => write!(
+                f,
+                "request queue is full ({} requests, {} prompt tokens waiting)",
+                num_requests, num_prompt_tokens
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+/// A request to be tokenized and queued by `add_requests()`.
+pub struct RequestSpec {
+    pub request_id: String,
+    pub prompt: String,
+    pub sampling_params: SamplingParams,
+    pub metadata: Option<serde_json::Value>,
 }
 
 pub enum Repo {
+    /// Files are downloaded on first use, and cached (in ~/.cache/huggingface/hub,
+    /// or under $HF_HOME).
     Api(ApiRepo),
+    /// Offline mode: files previously downloaded by Api.
+    Cache(CacheRepo, String),
     Local(String),
 }
 
@@ -55,15 +132,15 @@ impl Repo {
         match &args.local_weights {
             Some(path) => Ok(Repo::Local(path.to_owned() + "/")),
             None => {
-                let api = Api::new()?;
                 let model_id = args.model_id.clone();
                 let revision = args.revision.clone().unwrap_or("main".to_string());
-                let api = api.repo(hf_hub::Repo::with_revision(
-                    model_id,
-                    RepoType::Model,
-                    revision,
-                ));
-                Ok(Repo::Api(api))
+                let repo = hf_hub::Repo::with_revision(model_id, RepoType::Model, revision);
+                if args.offline {
+                    let name = format!("{}@{}", repo.url(), repo.revision());
+                    Ok(Repo::Cache(Cache::default().repo(repo), name))
+                } else {
+                    Ok(Repo::Api(Api::new()?.repo(repo)))
+                }
             }
         }
     }
@@ -71,7 +148,7 @@ impl Repo {
     #[allow(dead_code)]
     pub fn is_local(&self) -> bool {
         match self {
-            Repo::Api(_) => false,
+            Repo::Api(_) | Repo::Cache(..) => false,
             Repo::Local(_) => true,
         }
     }
@@ -79,6 +156,10 @@ impl Repo {
     pub fn get(&self, filename: &str) -> Result<PathBuf> {
         match self {
             Repo::Api(api) => api.get(filename).map_err(E::msg),
+            Repo::Cache(cache, name) => match cache.get(filename) {
+                Some(p) => Ok(p),
+                None => bail!("file {filename} of {name} isn't in the HuggingFace cache (offline)"),
+            },
             Repo::Local(path) => {
                 let p: PathBuf = (path.to_owned() + filename).into();
                 if p.exists() {
@@ -100,6 +181,7 @@ impl Display for Repo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Repo::Api(api) => write!(f, "{}", api.url("")),
+            Repo::Cache(_, name) => write!(f, "{}", name),
             Repo::Local(path) => write!(f, "{}", path),
         }
     }
@@ -118,6 +200,12 @@ impl Stats {
     }
 }
 
+/// Called after each sampled token, with logits after biases and masks are applied.
+pub type TokenCallback = Box<dyn FnMut(&SeqId, Token, &Logprobs) + Send>;
+
+/// Called after each scheduling step, before the model is run.
+pub type StepCallback = Box<dyn FnMut(&StepMetrics) + Send>;
+
 pub struct RllmEngine<ME: ModelExec> {
     pub config: Arc<RllmConfig<ME>>,
     pub tokenizer: Arc<Tokenizer>,
@@ -131,6 +219,13 @@ pub struct RllmEngine<ME: ModelExec> {
     pub eos_token_id: Token,
     pub space_token_id: Token,
     pub num_errors: usize,
+    // only return per-step deltas for unfinished requests
+    stream_deltas: bool,
+    pub fim: Option<FimTokens>,
+    special_tokens: Arc<HashSet<Token>>,
+    token_callback: Option<TokenCallback>,
+    step_callback: Option<StepCallback>,
+    sessions: SessionCache,
 
     pub timers: TimerSet,
 
@@ -168,12 +263,47 @@ impl<ME: ModelExec> RllmEngine<ME> {
         let rllm_config = RllmConfig {
             model: model_config,
             meta: model_meta,
-            parallel: ParallelConfig::single(),
+            parallel: ME::parallel_config(model_args),
             scheduler: SchedulerConfig {
                 max_num_batched_tokens: model_len,
                 max_num_kv_tokens: model_len * 10,
                 max_num_seqs: 100,
+                max_num_prefill_tokens: None,
+                max_num_decode_seqs: None,
                 max_model_len: model_len,
+                max_session_kv_tokens: model_len * 4,
+                kept_kv_idle_secs: Some(300.0),
+                session_spill_secs: None,
+                session_spill_dir: std::env::temp_dir()
+                    .join("rllm-sessions")
+                    .to_string_lossy()
+                    .to_string(),
+                max_session_spill_bytes: 16 << 30,
+                compact_kv_free_run: None,
+                policy: SchedulerPolicy::Priority,
+                prefill_chunk_size: Some(512),
+                preemption_mode: None,
+                min_swap_tokens: 1024,
+                evict_swapped: false,
+                priority_aging_secs: Some(10.0),
+                max_queue_secs: Some(120.0),
+                max_waiting_time: Some(300.0),
+                max_group_block_share: Some(0.5),
+                max_queued_requests: None,
+                max_queued_prompt_tokens: None,
+                qos_classes: [("realtime", 8), ("interactive", 4), ("batch", 1)]
+                    .iter()
+                    .map(|(name, weight)| QosClass {
+                        name: name.to_string(),
+                        weight: *weight,
+                    })
+                    .collect(),
+                batch_window_ms: None,
+                batch_window_requests: 32,
+                decode_reserve: 0.0,
+                block_watermark: 0.01,
+                reserved_blocks_per_seq: 1,
+                pipeline_steps: false,
             },
             aici,
         };
@@ -211,6 +341,21 @@ impl<ME: ModelExec> RllmEngine<ME> {
             None => {}
         }
 
+        let fim = FimTokens::from_tokenizer(&tokenizer);
+        let special_tokens = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter_map(|(id, tok)| if tok.special { Some(id) } else { None })
+            .collect::<HashSet<_>>();
+        if let Some(fim) = fim.as_ref() {
+            log::info!("infilling supported: {}", fim.family);
+        }
+
+        let sessions = SessionCache::new(
+            rllm_config.scheduler.max_session_kv_tokens,
+            rllm_config.scheduler.max_session_spill_bytes,
+        );
+
         Ok(RllmEngine {
             config: rllm_config,
             tokenizer: Arc::new(tokenizer),
@@ -221,6 +366,12 @@ impl<ME: ModelExec> RllmEngine<ME> {
             step_no: 0,
             req_id_cnt: 0,
             num_errors: 0,
+            stream_deltas: false,
+            fim,
+            special_tokens: Arc::new(special_tokens),
+            token_callback: None,
+            step_callback: None,
+            sessions,
             eos_token_id,
             space_token_id,
             alt: args.alt,
@@ -260,14 +411,299 @@ impl<ME: ModelExec> RllmEngine<ME> {
         format!("_{}", self.req_id_cnt)
     }
 
-    pub fn abort_request(&mut self, request_id: &str) {
+    /// Abort the request, freeing its KV cache right away.
+    /// Returns the final output, or None if the request is not running.
+    pub fn abort_request(&mut self, request_id: &str) -> Option<RequestOutput> {
+        let mut sg = self.scheduler.abort_seq_group(request_id)?;
+        Some(self.req_output(&mut sg, true))
+    }
+
+    /// Stop scheduling the request until resume_request() is called.
+    /// Sampler and controller state are kept.
+    pub fn pause_request(&mut self, request_id: &str) -> Result<()> {
+        if !self.scheduler.pause_seq_group(request_id) {
+            bail!("request {} not found", request_id);
+        }
+        Ok(())
+    }
+
+    pub fn resume_request(&mut self, request_id: &str) -> Result<()> {
+        if !self.scheduler.resume_seq_group(request_id) {
+            bail!("request {} is not paused", request_id);
+        }
+        Ok(())
+    }
+
+    /// Continue a `continuable` request that finished with MaxTokensReached,
+    /// generating up to `extra_tokens` more without recomputing its KV cache.
+    /// Its outputs follow, even though a final output was already returned.
+    pub fn continue_request(&mut self, request_id: &str, extra_tokens: usize) -> Result<()> {
+        if !self.scheduler.continue_seq_group(request_id, extra_tokens) {
+            bail!("request {} can't be continued", request_id);
+        }
+        Ok(())
+    }
+
+    /// Free the KV cache kept for a `continuable` request.
+    pub fn release_request(&mut self, request_id: &str) -> Result<()> {
+        if !self.scheduler.release_seq_group(request_id) {
+            bail!("request {} has no kept KV cache", request_id);
+        }
+        Ok(())
+    }
+
+    /// Free the KV cache kept for the session.
+    pub fn close_session(&mut self, session_id: &str) -> Result<()> {
+        if !self.sessions.remove(self.seq_mgr.deref(), session_id) {
+            bail!("session {} not found", session_id);
+        }
+        Ok(())
+    }
+
+    /// Take the request out of the engine and serialize it (tokens, sampling parameters,
+    /// sampler state and KV cache), so that import_request() of another engine running
+    /// the same model continues it. Sequences without KV on the GPU are recomputed there.
+    pub fn export_request(&mut self, request_id: &str) -> Result<Vec<u8>> {
+        let (mut req, seq_ids) = self.with_seq_group(request_id, |sg| {
+            ExportedRequest::from_group(&self.model_id, sg)
+        })?;
+        let mut kv = Vec::with_capacity(seq_ids.len());
+        for (seq, seq_id) in req.seqs.iter_mut().zip(seq_ids) {
+            if seq.kv_len == 0 || seq.kv_len >= seq.tokens.len() {
+                seq.kv_len = 0;
+                kv.push(Vec::new());
+                continue;
+            }
+            match self.tmodel.export_kv(seq_id, seq.kv_len) {
+                Ok(data) => kv.push(data),
+                Err(e) => {
+                    log::warn!("request {request_id}: KV not exported ({e}); it's recomputed");
+                    seq.kv_len = 0;
+                    kv.push(Vec::new());
+                }
+            }
+        }
+        let data = req.encode(kv)?;
         self.scheduler.abort_seq_group(request_id);
+        Ok(data)
+    }
+
+    /// Queue a request serialized by export_request(); its outputs continue
+    /// where the exporting engine stopped.
+    pub fn import_request(&mut self, data: &[u8]) -> Result<()> {
+        let (req, kv) = ExportedRequest::decode(data)?;
+        if req.model_id != self.model_id {
+            bail!(
+                "request {} was exported by model {}, not {}",
+                req.request_id,
+                req.model_id,
+                self.model_id
+            );
+        }
+        if self.with_seq_group(&req.request_id, |_| Ok(())).is_ok() {
+            bail!("request {} already exists", req.request_id);
+        }
+
+        let params = &req.sampling_params;
+        let mut seqs = Vec::with_capacity(req.seqs.len());
+        for exp in req.seqs.iter() {
+            let mut seq = exp.to_sequence(self.seq_mgr.new_sequence());
+            if params.skip_special_tokens {
+                seq.skip_special = Some(self.special_tokens.clone());
+            }
+            seq.include_stop_str = params.include_stop_str_in_output;
+            seq.keep_kv = params.continuable;
+            seq.adapter = params.adapter.clone();
+            if let Some(grammar) = params.get_grammar()? {
+                let mut grm = GrammarMatcher::new(grammar);
+                for t in &exp.tokens[exp.prompt_len..] {
+                    if self.tok_trie.append_token(&mut grm, *t).is_err() {
+                        bail!("request {}: output not allowed by grammar", req.request_id);
+                    }
+                }
+                seq.grammar = Some(grm);
+            }
+            seqs.push(seq);
+        }
+
+        // all sequences of a group run together, so either all get their KV, or none
+        let mut imported = Vec::new();
+        for ((seq, exp), data) in seqs.iter().zip(req.seqs.iter()).zip(kv) {
+            if exp.kv_len == 0 {
+                break;
+            }
+            match self.tmodel.import_kv(seq.seq_id, exp.kv_len, data) {
+                Ok(true) => imported.push(seq.seq_id),
+                Ok(false) => {
+                    log::warn!(
+                        "request {}: not enough free blocks for its KV; it's recomputed",
+                        req.request_id
+                    );
+                    break;
+                }
+                Err(e) => {
+                    for seq in seqs.iter() {
+                        self.seq_mgr.delete(seq.seq_id);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        let on_gpu = imported.len() == seqs.len();
+        if on_gpu {
+            for (seq, exp) in seqs.iter_mut().zip(req.seqs.iter()) {
+                seq.num_kv_computed = exp.kv_len;
+                seq.sched_phase = SchedulingPhase::Running;
+            }
+        } else {
+            for seq_id in imported {
+                self.seq_mgr.delete(seq_id);
+            }
+        }
+
+        let mut sg = SequenceGroup {
+            request_id: req.request_id,
+            prompt: req.prompt,
+            seqs,
+            arrival_time: Instant::now(),
+            priority: req.priority,
+            logits_processor: LogitsProcessor::new(&req.sampling_params),
+            max_index: req.max_index,
+            usage: req.usage,
+            metadata: req.metadata,
+            lineage: req.lineage,
+            stop_criterion: None,
+            scheduled_time: None,
+            first_token_time: None,
+            sampling_params: req.sampling_params,
+        };
+        sg.restore_sampler_state(&req.sampler);
+        self.sessions.add_request(
+            &sg.request_id,
+            sg.sampling_params.session_id.clone(),
+            sg.lineage.clone(),
+        );
+        if on_gpu {
+            self.scheduler.add_running_seq_group(sg);
+        } else {
+            self.scheduler.add_seq_group(sg);
+        }
+        Ok(())
+    }
+
+    pub fn abort_all(&mut self) -> Vec<RequestOutput> {
+        self.scheduler
+            .abort_all()
+            .iter_mut()
+            .map(|sg| self.req_output(sg, true))
+            .collect()
+    }
+
+    fn with_seq_group<T>(
+        &self,
+        request_id: &str,
+        f: impl FnOnce(&mut SequenceGroup) -> Result<T>,
+    ) -> Result<T> {
+        let mut f = Some(f);
+        let mut res = None;
+        self.scheduler.for_each_sg(|sg| {
+            if sg.request_id == request_id {
+                if let Some(f) = f.take() {
+                    res = Some(f(sg));
+                }
+            }
+        });
+        match res {
+            Some(r) => r,
+            None => bail!("request {} not found", request_id),
+        }
+    }
+
+    /// Fork sequence `seq_id` of request `request_id`; returns the id of the new sequence.
+    /// The new sequence shares the full KV cache blocks with the original.
+    /// Sequences with a controller can't be forked here.
+    pub fn fork(&mut self, request_id: &str, seq_id: SeqId) -> Result<SeqId> {
+        self.with_seq_group(request_id, |sg| sg.fork_seq(self.seq_mgr.deref(), seq_id))
+    }
+
+    /// Replace tokens of the sequence from position `at` onwards with `tokens`,
+    /// rolling back its KV cache to `at`.
+    pub fn splice(
+        &mut self,
+        request_id: &str,
+        seq_id: SeqId,
+        at: usize,
+        tokens: &[Token],
+    ) -> Result<()> {
+        self.with_seq_group(request_id, |sg| {
+            let seq = sg.get_seq_mut(seq_id)?;
+            if seq.is_finished() {
+                bail!("can't splice finished sequence {}", seq_id);
+            }
+            if seq.has_aici || seq.grammar.is_some() {
+                bail!(
+                    "can't splice sequence {} with controller or grammar",
+                    seq_id
+                );
+            }
+            if at > seq.get_verified_len() {
+                bail!(
+                    "splice position {} past the end of sequence {} ({} tokens)",
+                    at,
+                    seq_id,
+                    seq.get_verified_len()
+                );
+            }
+            seq.accept_draft(self.seq_mgr.deref(), &[]);
+            seq.splice(self.seq_mgr.deref(), at, tokens);
+            Ok(())
+        })
+    }
+
+    /// Append a fixed run of `tokens` to a running sequence.
+    /// The tokens are not sampled; they are all prefilled in the next step,
+    /// after which generation continues as usual.
+    pub fn fast_forward(
+        &mut self,
+        request_id: &str,
+        seq_id: SeqId,
+        tokens: &[Token],
+    ) -> Result<()> {
+        self.with_seq_group(request_id, |sg| {
+            let seq = sg.get_seq_mut(seq_id)?;
+            if seq.is_finished() {
+                bail!("can't fast-forward finished sequence {}", seq_id);
+            }
+            if seq.has_aici {
+                bail!("can't fast-forward sequence {} with controller", seq_id);
+            }
+            if let Some(grm) = seq.grammar.as_ref() {
+                let mut grm = grm.clone();
+                for t in tokens {
+                    if self.tok_trie.append_token(&mut grm, *t).is_err() {
+                        bail!(
+                            "token {} not allowed by grammar",
+                            self.tok_trie.token_dbg(*t)
+                        );
+                    }
+                }
+                seq.grammar = Some(grm);
+            }
+            seq.accept_draft(self.seq_mgr.deref(), &[]);
+            seq.append_tokens(tokens);
+            Ok(())
+        })
     }
 
     pub fn num_pending_requests(&self) -> usize {
         self.scheduler.get_num_unfinished_seq_groups()
     }
 
+    /// See Scheduler::batch_window_left().
+    pub fn batch_window_left(&self) -> Option<Duration> {
+        self.scheduler.batch_window_left()
+    }
+
     pub fn tokenize(&self, text: &str, add_special_tokens: bool) -> Result<Vec<Token>> {
         let tokens = self
             .tokenizer
@@ -277,12 +713,219 @@ impl<ME: ModelExec> RllmEngine<ME> {
     }
 
     pub fn queue_request(&mut self, req: AddRequest) -> Result<()> {
+        self.check_queue_capacity(1, req.prompt.len())?;
+        let sg = self.build_seq_group(req)?;
+        self.enqueue_seq_group(sg);
+        Ok(())
+    }
+
+    /// Tokenize all prompts (in parallel) and queue the requests.
+    /// Either all requests are queued, or none are (when an error is returned).
+    pub fn add_requests(&mut self, reqs: Vec<RequestSpec>) -> Result<()> {
+        let prompts = reqs.iter().map(|r| r.prompt.as_str()).collect::<Vec<_>>();
+        let encodings = self
+            .tokenizer
+            .encode_batch(prompts, true)
+            .map_err(anyhow::Error::msg)?;
+        self.check_queue_capacity(
+            encodings.len(),
+            encodings.iter().map(|e| e.get_ids().len()).sum(),
+        )?;
+
+        let mut groups = Vec::with_capacity(reqs.len());
+        for (req, enc) in reqs.into_iter().zip(encodings) {
+            let res = self.build_seq_group(AddRequest {
+                request_id: req.request_id,
+                prompt: enc.get_ids().to_vec(),
+                sampling_params: req.sampling_params,
+                expected: None,
+                init_result: None,
+                prompt_embeddings: None,
+                embedding_spans: Vec::new(),
+                metadata: req.metadata,
+                stop_criterion: None,
+            });
+            match res {
+                Ok(sg) => groups.push(sg),
+                Err(e) => {
+                    for seq in groups.iter().flat_map(|sg| sg.seqs.iter()) {
+                        self.seq_mgr.delete(seq.seq_id);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        for sg in groups {
+            self.enqueue_seq_group(sg);
+        }
+        Ok(())
+    }
+
+    /// Fail with EngineError::QueueFull if queueing the requests would go over
+    /// the limits of SchedulerConfig.
+    fn check_queue_capacity(&self, num_requests: usize, num_prompt_tokens: usize) -> Result<()> {
+        let cfg = &self.config.scheduler;
+        let (queued_requests, queued_tokens) = self.scheduler.waiting_load();
+        let over = |limit: Option<usize>, total: usize| limit.map_or(false, |l| total > l);
+        if over(cfg.max_queued_requests, queued_requests + num_requests)
+            || over(
+                cfg.max_queued_prompt_tokens,
+                queued_tokens + num_prompt_tokens,
+            )
+        {
+            return Err(EngineError::QueueFull {
+                num_requests: queued_requests,
+                num_prompt_tokens: queued_tokens,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn check_embeddings(
+        &self,
+        emb: &PromptEmbeddings,
+        start: usize,
+        prompt_len: usize,
+    ) -> Result<()> {
+        match self.tmodel.prompt_embedding_size() {
+            Some(hidden_size) if hidden_size == emb.hidden_size => {}
+            Some(hidden_size) => bail!(
+                "prompt embeddings have hidden size {}, model expects {}",
+                emb.hidden_size,
+                hidden_size
+            ),
+            None => bail!("prompt embeddings not supported by this model"),
+        }
+        if emb.data.len() % emb.hidden_size != 0 || start + emb.num_tokens() > prompt_len {
+            bail!(
+                "prompt embeddings don't match the prompt ({} tokens)",
+                prompt_len
+            );
+        }
+        Ok(())
+    }
+
+    fn build_seq_group(&mut self, mut req: AddRequest) -> Result<SequenceGroup> {
+        if let Some(class) = req.sampling_params.qos_class.as_ref() {
+            let classes = &self.config.scheduler.qos_classes;
+            if !classes.iter().any(|c| &c.name == class) {
+                bail!(
+                    "unknown QoS class {}; expecting one of: {}",
+                    class,
+                    classes
+                        .iter()
+                        .map(|c| c.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+        }
+        if let Some(adapter) = req.sampling_params.adapter.as_ref() {
+            let adapters = self.tmodel.lora_adapters();
+            if !adapters.contains(adapter) {
+                bail!(
+                    "unknown LoRA adapter {}; expecting one of: {}",
+                    adapter,
+                    adapters.join(", ")
+                );
+            }
+        }
+
+        let mut lineage = Vec::new();
+        if let Some(parent) = req.sampling_params.parent_request_id.as_ref() {
+            match self.sessions.child_of(parent) {
+                Some((session_id, parent_lineage)) => {
+                    if req.sampling_params.session_id.is_none()
+                        && req.sampling_params.controller.is_none()
+                    {
+                        req.sampling_params.session_id = session_id;
+                    }
+                    lineage = parent_lineage;
+                }
+                None => bail!("unknown parent request {}", parent),
+            }
+        }
+
+        let mut healing_prefix = None;
+        if req.sampling_params.token_healing && req.prompt.len() > 1 {
+            let last = req.prompt.pop().unwrap();
+            healing_prefix = Some(self.tok_trie.token(last).to_vec());
+        }
+
         let mut seq = Sequence::new(self.seq_mgr.new_sequence(), &req.prompt);
+        seq.healing_prefix = healing_prefix;
+        if req.sampling_params.skip_special_tokens {
+            seq.skip_special = Some(self.special_tokens.clone());
+        }
+        seq.include_stop_str = req.sampling_params.include_stop_str_in_output;
+        seq.keep_kv = req.sampling_params.continuable;
+        seq.adapter = req.sampling_params.adapter.clone();
+        if let Some(fim) = self.fim.as_ref() {
+            if req.prompt.contains(&fim.middle) {
+                seq.strip_tokens = fim.sentinels();
+                if let Some(end) = fim.end {
+                    req.sampling_params.stop_token_ids.push(end);
+                }
+            }
+        }
+        if req.sampling_params.echo {
+            seq.echo = true;
+            seq.output_ptr = 0;
+        }
         match req.init_result {
             Some(r) => seq.aici_logs.push(r.clone()),
             None => {}
         }
         seq.expected = req.expected;
+        if let Some(emb) = req.prompt_embeddings {
+            self.check_embeddings(&emb, 0, req.prompt.len())?;
+            seq.prompt_embeddings = Some(emb);
+        }
+        let mut spans = req.embedding_spans;
+        spans.sort_by_key(|s| s.start);
+        for (idx, span) in spans.iter().enumerate() {
+            self.check_embeddings(&span.embeddings, span.start, req.prompt.len())?;
+            if idx > 0 && spans[idx - 1].end() > span.start {
+                bail!("embedding spans overlap at position {}", span.start);
+            }
+        }
+        seq.embedding_spans = spans;
+        if let Some(grammar) = req.sampling_params.get_grammar()? {
+            seq.grammar = Some(GrammarMatcher::new(grammar));
+        }
+        if req.sampling_params.forced_tokens.len() > 0 {
+            let forced = &req.sampling_params.forced_tokens;
+            if let Some(grm) = seq.grammar.as_mut() {
+                for t in forced {
+                    if self.tok_trie.append_token(grm, *t).is_err() {
+                        bail!(
+                            "forced token {} not allowed by grammar",
+                            self.tok_trie.token_dbg(*t)
+                        );
+                    }
+                }
+            }
+            // generated, but not sampled: they are prefilled with the prompt
+            seq.append_tokens(forced);
+        }
+
+        let guidance_seq = match req.sampling_params.negative_prompt.as_ref() {
+            Some(negative_prompt) => {
+                let tokens = self.tokenize(negative_prompt, true)?;
+                if tokens.is_empty() {
+                    bail!("negative_prompt is empty");
+                }
+                let mut gseq = Sequence::new(self.seq_mgr.new_sequence(), &tokens);
+                gseq.append_tokens(&req.sampling_params.forced_tokens);
+                gseq.index = 1;
+                gseq.guidance = true;
+                gseq.adapter = req.sampling_params.adapter.clone();
+                Some(gseq)
+            }
+            None => None,
+        };
 
         let logits_processor = LogitsProcessor::new(&req.sampling_params);
         let prompt = self
@@ -290,20 +933,63 @@ impl<ME: ModelExec> RllmEngine<ME> {
             .decode(&req.prompt, false)
             .map_err(anyhow::Error::msg)?;
 
-        let sg = SequenceGroup {
+        let mut sg = SequenceGroup {
             request_id: req.request_id,
             prompt,
             seqs: vec![seq],
-            sampling_params: req.sampling_params,
             arrival_time: Instant::now(),
+            priority: req.sampling_params.priority,
             logits_processor,
             max_index: 0,
             usage: TokenUsage::default(),
+            metadata: req.metadata,
+            lineage,
+            stop_criterion: req.stop_criterion,
+            scheduled_time: None,
+            first_token_time: None,
+            sampling_params: req.sampling_params,
         };
+        if let Some(gseq) = guidance_seq {
+            sg.seqs.push(gseq);
+            sg.max_index = 1;
+        }
 
-        self.scheduler.add_seq_group(sg);
+        Ok(sg)
+    }
 
-        Ok(())
+    fn enqueue_seq_group(&mut self, mut sg: SequenceGroup) {
+        // only queued requests can be parents
+        self.sessions.add_request(
+            &sg.request_id,
+            sg.sampling_params.session_id.clone(),
+            sg.lineage.clone(),
+        );
+        if let Some(session_id) = sg.sampling_params.session_id.clone() {
+            // the negative prompt of guidance always needs a prefill, embeddings
+            // are not captured by comparing tokens, and hidden states are needed
+            // for the whole prompt
+            let len = if sg.has_guidance()
+                || sg.sampling_params.hidden_states.is_some()
+                || !sg.seqs[0].embedding_overrides().is_empty()
+            {
+                0
+            } else if self
+                .sessions
+                .unspill(&mut self.tmodel, self.seq_mgr.deref(), &session_id)
+            {
+                self.sessions.prefix_len(&session_id, &sg.seqs[0])
+            } else {
+                0
+            };
+            if len > 0 {
+                // it waits like any other prompt, holding the blocks of the prefix
+                log::debug!("session {}: reusing {} tokens", session_id, len);
+                self.sessions
+                    .restore(self.seq_mgr.deref(), &session_id, &mut sg.seqs[0], len);
+            }
+        }
+
+        self.scheduler.add_seq_group(sg);
     }
 
     pub fn add_expected_generation(
@@ -321,6 +1007,10 @@ impl<ME: ModelExec> RllmEngine<ME> {
             },
             expected: Some(exp_gen),
             init_result: None,
+            prompt_embeddings: None,
+            embedding_spans: Vec::new(),
+            metadata: None,
+            stop_criterion: None,
         })
     }
 
@@ -330,13 +1020,60 @@ impl<ME: ModelExec> RllmEngine<ME> {
         prompt: &str,
         sampling_params: SamplingParams,
     ) -> Result<()> {
-        let tokens = self.tokenize(prompt, true)?;
+        self.add_request_text(request_id, prompt, true, sampling_params)
+    }
+
+    /// Tokenize `prompt` with the model tokenizer and queue it.
+    /// `add_special_tokens` controls whether BOS (and other tokenizer-specific
+    /// special tokens) are added around the prompt.
+    pub fn add_request_text(
+        &mut self,
+        request_id: String,
+        prompt: &str,
+        add_special_tokens: bool,
+        sampling_params: SamplingParams,
+    ) -> Result<()> {
+        let tokens = self.tokenize(prompt, add_special_tokens)?;
+        self.queue_request(AddRequest {
+            request_id,
+            prompt: tokens,
+            sampling_params,
+            expected: None,
+            init_result: None,
+            prompt_embeddings: None,
+            embedding_spans: Vec::new(),
+            metadata: None,
+            stop_criterion: None,
+        })
+    }
+
+    /// Queue a request generating text to go between `prefix` and `suffix`.
+    pub fn add_infill_request(
+        &mut self,
+        request_id: String,
+        prefix: &str,
+        suffix: &str,
+        sampling_params: SamplingParams,
+    ) -> Result<()> {
+        let fim = match self.fim.as_ref() {
+            Some(fim) => fim,
+            None => bail!("model {} doesn't support infilling", self.model_id),
+        };
+        let tokens = fim.build_prompt(
+            &self.tokenize("", true)?,
+            &self.tokenize(prefix, false)?,
+            &self.tokenize(suffix, false)?,
+        );
         self.queue_request(AddRequest {
             request_id,
             prompt: tokens,
             sampling_params,
             expected: None,
             init_result: None,
+            prompt_embeddings: None,
+            embedding_spans: Vec::new(),
+            metadata: None,
+            stop_criterion: None,
         })
     }
 
@@ -472,10 +1209,12 @@ impl<ME: ModelExec> RllmEngine<ME> {
     fn dropped_outputs(&mut self, sched_out: &mut SchedulerOutputs) -> Vec<RequestOutput> {
         let mut res = Vec::new();
 
-        sched_out
-            .dropped_seq_groups
-            .iter_mut()
-            .for_each(|sg| res.push(self.req_output(sg, true)));
+        for mut sg in sched_out.dropped_seq_groups.drain(..) {
+            res.push(self.req_output(&mut sg, true));
+            if sg.is_continuable() {
+                self.scheduler.keep_finished(sg);
+            }
+        }
 
         res
     }
@@ -485,19 +1224,169 @@ impl<ME: ModelExec> RllmEngine<ME> {
         Ok(self.dropped_outputs(sched_out))
     }
 
+    /// For best_of>1 requests, fork the prompt sequence once its prefill is done,
+    /// so that each fork samples independently from the same logits.
+    fn fork_parallel_samples(
+        &self,
+        sg: &mut SequenceGroup,
+        seq_id_mapping: &mut HashMap<usize, usize>,
+    ) {
+        let num_seqs = sg.sampling_params.best_of;
+        if num_seqs <= 1 || sg.seqs.len() != 1 || sg.sampling_params.controller.is_some() {
+            return;
+        }
+        let seq = &sg.seqs[0];
+        if seq.sched_phase != SchedulingPhase::Running
+            || seq.num_pending_tokens() > 0
+            || seq.get_gen_len() > 0
+            || seq.expected.is_some()
+        {
+            return;
+        }
+        for _ in 1..num_seqs {
+            let new_id = self.seq_mgr.new_sequence();
+            let mut copy = sg.seqs[0].fork_as(self.seq_mgr.deref(), new_id, sg.max_index + 1);
+            log::debug!("forked: {:?} -> {:?}", sg.seqs[0].seq_id, copy.seq_id);
+            sg.max_index += 1;
+            copy.logits_processor = Some(sg.logits_processor.fork());
+            seq_id_mapping.insert(new_id.to_num(), sg.seqs[0].seq_id.to_num());
+            sg.seqs.push(copy);
+        }
+    }
+
+    /// Walk down the tree of draft tokens of a sequence (see Sequence::draft_parents),
+    /// sampling from the logits of each node, for as long as a child of the node
+    /// matches the sampled token; returns the indices of the accepted draft tokens,
+    /// and the last sampled token with its logits.
+    fn verify_draft(
+        &mut self,
+        processor: &mut LogitsProcessor,
+        params: &SamplingParams,
+        seq_id: SeqId,
+        sidx: usize,
+        draft: &[Token],
+        parents: &[usize],
+    ) -> Result<(Vec<usize>, Token, ME::Tensor)> {
+        let mut all_logits = self.tmodel.get_draft_logits(sidx);
+        assert!(all_logits.len() == draft.len() + 1);
+        let mut path = Vec::new();
+        // the last verified token is node 0, draft token idx is node idx + 1
+        let mut node = 0;
+        loop {
+            let logits = &all_logits[node];
+            let token = with_timer!(
+                self.tim_logit_sample,
+                self.tmodel.sample(processor, logits)?
+            );
+            let ends = (!params.ignore_eos && token == self.eos_token_id)
+                || params.stop_token_ids.contains(&token);
+            let child = (0..draft.len()).find(|&idx| parents[idx] == node && draft[idx] == token);
+            let idx = match child {
+                Some(idx) if !ends => idx,
+                _ => return Ok((path, token, all_logits.swap_remove(node))),
+            };
+            if let Some(cb) = self.token_callback.as_mut() {
+                let logprobs = Logprobs::from_logits(
+                    &ME::tensor_to_vec1(logits),
+                    token,
+                    params.logprobs.unwrap_or(0) as usize,
+                );
+                cb(&seq_id, token, &logprobs);
+            }
+            path.push(idx);
+            node = idx + 1;
+        }
+    }
+
     fn sample(&mut self, sched_out: &mut SchedulerOutputs) -> Result<Vec<RequestOutput>> {
-        let (aici_bias, seq_id_mapping) =
+        let (aici_bias, mut seq_id_mapping) =
             with_timer!(self.tim_aici_bias, self.aici_bias(sched_out)?);
 
         for sg in sched_out.next_seq_groups.iter_mut() {
+            self.fork_parallel_samples(sg, &mut seq_id_mapping);
+
+            let guidance_logits = sg
+                .seqs
+                .iter()
+                .find(|s| s.guidance && s.sched_phase == SchedulingPhase::Running)
+                .map(|s| self.tmodel.get_logits(s.seq_id.to_num()));
+            let mut guidance_splice = None;
+            // Medusa heads draft tokens for plain single-sequence requests
+            let can_draft = self.tmodel.num_draft_tokens() > 0 && sg.seqs.len() == 1;
+
             for seq in sg.seqs.iter_mut() {
-                if seq.sched_phase != SchedulingPhase::Running {
+                if seq.sched_phase != SchedulingPhase::Running || seq.guidance {
+                    continue;
+                }
+                if let Some(mode) = sg.sampling_params.hidden_states {
+                    // prefill-only; collect hidden states instead of sampling
+                    match self.tmodel.get_hidden_states(seq.seq_id.to_num()) {
+                        Some((hidden_size, data)) => {
+                            seq.hidden_states.extend_from_slice(&data);
+                            if seq.num_pending_tokens() == 0 {
+                                let states = std::mem::take(&mut seq.hidden_states);
+                                seq.hidden_output =
+                                    Some(pool_hidden_states(mode, hidden_size, states));
+                                self.scheduler.finish_seq(seq, FinishReason::HiddenStates);
+                            }
+                        }
+                        None => {
+                            log::warn!("{}: model didn't return hidden states", sg.request_id);
+                            self.scheduler.finish_seq(seq, FinishReason::Failed);
+                        }
+                    }
+                    continue;
+                }
+                if seq.num_pending_tokens() > 0 {
+                    // only a chunk of the prompt was computed
                     continue;
                 }
 
                 let sidx = seq.seq_id.to_num();
                 let sidx = seq_id_mapping.get(&sidx).unwrap_or(&sidx);
                 let mut logits = self.tmodel.get_logits(*sidx);
+                if let Some(negative) = guidance_logits.as_ref() {
+                    self.tmodel.apply_guidance(
+                        &mut logits,
+                        negative,
+                        sg.sampling_params.guidance_scale,
+                    );
+                }
+
+                // draft tokens matching the tokens sampled before them are accepted
+                let mut accepted = Vec::new();
+                // node of the draft tree whose logits the token is sampled from
+                let mut draft_node = 0;
+                let mut draft_sampled = None;
+                if seq.num_draft > 0 {
+                    let draft = seq.get_tokens()[seq.get_verified_len()..].to_vec();
+                    let parents = seq.draft_parents.clone();
+                    let seq_id = seq.seq_id;
+                    let processor = seq
+                        .logits_processor
+                        .as_mut()
+                        .unwrap_or(&mut sg.logits_processor);
+                    let (path, token, token_logits) = self.verify_draft(
+                        processor,
+                        &sg.sampling_params,
+                        seq_id,
+                        *sidx,
+                        &draft,
+                        &parents,
+                    )?;
+                    log::trace!(
+                        "sample *{}: accepted {}/{} draft tokens",
+                        seq.seq_id,
+                        path.len(),
+                        draft.len()
+                    );
+                    seq.accept_draft(self.seq_mgr.deref(), &path);
+                    sg.usage.gen_tokens += path.len();
+                    accepted = path.iter().map(|&idx| draft[idx]).collect();
+                    draft_node = path.last().map_or(0, |idx| idx + 1);
+                    draft_sampled = Some(token);
+                    logits = token_logits;
+                }
 
                 let mut info = "";
                 let mut sampled = None;
@@ -522,18 +1411,46 @@ impl<ME: ModelExec> RllmEngine<ME> {
                             None => {}
                         }
 
+                        if let Some(grm) = seq.grammar.as_mut() {
+                            let mut allowed = self.tok_trie.alloc_token_set();
+                            self.tok_trie.compute_bias(grm, &mut allowed);
+                            self.tmodel.apply_token_mask(&mut logits, &allowed);
+                        }
+
+                        if let Some(prefix) = seq.healing_prefix.as_ref() {
+                            if seq.get_gen_len() == 0 {
+                                let allowed = healing_token_set(&self.tok_trie, prefix);
+                                self.tmodel.apply_token_mask(&mut logits, &allowed);
+                            }
+                        }
+
                         let next_token = if seq.expected.is_some() {
                             let logits = ME::tensor_to_vec1(&logits);
                             self.check_expected(logits, &sg.request_id, seq)
+                        } else if let Some(token) = draft_sampled {
+                            token
                         } else {
+                            let processor = seq
+                                .logits_processor
+                                .as_mut()
+                                .unwrap_or(&mut sg.logits_processor);
                             with_timer!(
                                 self.tim_logit_sample,
-                                self.tmodel.sample(&mut sg.logits_processor, &logits)?
+                                self.tmodel.sample(processor, &logits)?
                             )
                         };
 
                         sampled = Some(next_token);
 
+                        if let Some(cb) = self.token_callback.as_mut() {
+                            let logprobs = Logprobs::from_logits(
+                                &ME::tensor_to_vec1(&logits),
+                                next_token,
+                                sg.sampling_params.logprobs.unwrap_or(0) as usize,
+                            );
+                            cb(&seq.seq_id, next_token, &logprobs);
+                        }
+
                         let splices = seq
                             .aici_sampling
                             .as_ref()
@@ -588,8 +1505,31 @@ impl<ME: ModelExec> RllmEngine<ME> {
                     splice.backtrack as usize,
                     &splice.ff_tokens,
                 );
+                if guidance_logits.is_some() {
+                    guidance_splice = Some((splice.backtrack as usize, splice.ff_tokens.clone()));
+                }
 
-                let has_eos = splice.ff_tokens.contains(&self.eos_token_id);
+                // the accepted draft tokens are new as well
+                let new_tokens = [&accepted[..], &splice.ff_tokens[..]].concat();
+                let has_eos = new_tokens.contains(&self.eos_token_id);
+                let stop_token = new_tokens
+                    .iter()
+                    .find(|t| sg.sampling_params.stop_token_ids.contains(t))
+                    .cloned();
+                let stop_string = seq.find_stop_string(
+                    &self.tok_trie,
+                    &sg.sampling_params.stop,
+                    new_tokens.len(),
+                );
+
+                let grammar_ok = match seq.grammar.as_mut() {
+                    Some(grm) => splice
+                        .ff_tokens
+                        .iter()
+                        .filter(|t| **t != self.eos_token_id)
+                        .all(|t| self.tok_trie.append_token(grm, *t).is_ok()),
+                    None => true,
+                };
 
                 if seq.has_aici {
                     seq.mid_op.as_mut().unwrap().tokens = splice.ff_tokens;
@@ -597,13 +1537,76 @@ impl<ME: ModelExec> RllmEngine<ME> {
                     seq.mid_op.as_mut().unwrap().sampled = sampled;
                 }
 
-                if !sg.sampling_params.ignore_eos && has_eos {
-                    self.scheduler.finish_seq(seq, FinishReason::FoundEos);
+                let reason = if !grammar_ok {
+                    Some(FinishReason::Failed)
+                } else if has_eos && finishes_on_eos(&sg.sampling_params, seq.grammar.as_ref()) {
+                    Some(FinishReason::FoundEos)
+                } else if let Some(t) = stop_token {
+                    seq.stop_match = Some(StopMatch::Token(t));
+                    Some(FinishReason::StopTokenHit)
+                } else if let Some(s) = stop_string {
+                    seq.stop_match = Some(StopMatch::String(s));
+                    Some(FinishReason::StopStringHit)
+                } else if sg.stop_criterion.as_ref().map_or(false, |f| f(seq)) {
+                    Some(FinishReason::CustomStop)
                 } else if seq.get_gen_len() >= sg.sampling_params.max_tokens {
-                    self.scheduler
-                        .finish_seq(seq, FinishReason::MaxTokensReached);
+                    Some(FinishReason::MaxTokensReached)
+                } else if seq.get_len() >= self.config.scheduler.max_model_len {
+                    Some(FinishReason::ContextLengthExceeded)
+                } else {
+                    None
+                };
+
+                if let Some(reason) = reason {
+                    if let Some(session_id) = sg.sampling_params.session_id.as_ref() {
+                        if reason != FinishReason::Failed && seq.index == 0 {
+                            self.sessions.save(self.seq_mgr.deref(), session_id, seq);
+                        }
+                    }
+                    self.scheduler.finish_seq(seq, reason);
+                } else if can_draft
+                    && !seq.has_aici
+                    && seq.grammar.is_none()
+                    && seq.expected.is_none()
+                {
+                    let (draft, parents) = self.tmodel.get_draft(*sidx, draft_node);
+                    // the tokens along any branch and the token after them have to fit
+                    // in max_tokens and max_total_tokens (the sequence is the only one
+                    // of the group), and all of them have to fit in the context
+                    let max_gen = sg.sampling_params.max_tokens - seq.get_gen_len() - 1;
+                    let max_total = sg
+                        .sampling_params
+                        .max_total_tokens
+                        .map_or(usize::MAX, |max| max.saturating_sub(seq.get_gen_len() + 1));
+                    let max_ctx = self.config.scheduler.max_model_len - seq.get_len();
+                    let (draft, parents) =
+                        prune_draft(&draft, &parents, std::cmp::min(max_gen, max_total), max_ctx);
+                    seq.append_draft(&draft, &parents);
+                }
+            }
+
+            // the negative prompt follows the tokens generated for the main sequence
+            if let Some((backtrack, tokens)) = guidance_splice {
+                let main_phase = sg.seqs[0].sched_phase;
+                let gseq = sg.seqs.iter_mut().find(|s| s.guidance).unwrap();
+                gseq.splice_tokens(self.seq_mgr.deref(), backtrack, &tokens);
+                if let SchedulingPhase::Finished(reason) = main_phase {
+                    self.scheduler.finish_seq(gseq, reason);
+                }
+            }
+
+            if let Some(max_total_tokens) = sg.sampling_params.max_total_tokens {
+                if sg.total_gen_len() >= max_total_tokens {
+                    for seq in sg.seqs.iter_mut() {
+                        self.scheduler
+                            .finish_seq(seq, FinishReason::MaxTokensReached);
+                    }
                 }
             }
+
+            if sg.first_token_time.is_none() && sg.seqs.iter().any(|s| s.get_gen_len() > 0) {
+                sg.first_token_time = Some(Instant::now());
+            }
         }
 
         let mut outputs = self.dropped_outputs(sched_out);
@@ -611,39 +1614,88 @@ impl<ME: ModelExec> RllmEngine<ME> {
             sched_out
                 .next_seq_groups
                 .iter_mut()
-                .map(|sg| self.req_output(sg, false)),
+                .map(|sg| self.req_output(sg, false))
+                .filter(|outp| {
+                    !self.stream_deltas || !outp.seq_outputs.iter().all(|so| so.is_empty_delta())
+                }),
         );
 
         Ok(outputs)
     }
 
+    /// Register (or with None, remove) a callback invoked after every sampled token.
+    /// Computing log-probabilities copies the logits, so this slows down sampling.
+    pub fn set_token_callback(&mut self, callback: Option<TokenCallback>) {
+        self.token_callback = callback;
+    }
+
+    /// Register (or with None, remove) a callback invoked with metrics of every step.
+    pub fn set_step_callback(&mut self, callback: Option<StepCallback>) {
+        self.step_callback = callback;
+    }
+
+    /// Register (or with None, remove) hooks notified of preemption and swapping.
+    pub fn set_scheduler_hooks(&mut self, hooks: Option<Box<dyn SchedulerHooks>>) {
+        self.scheduler.set_hooks(hooks);
+    }
+
+    /// Replace the ordering, admission and preemption policy of the scheduler.
+    pub fn set_scheduling_policy(&mut self, policy: Box<dyn SchedulingPolicy>) {
+        self.scheduler.set_policy(policy);
+    }
+
+    /// In streaming mode, `step()` only returns requests that made progress,
+    /// and only the final output carries the full `output_tokens`;
+    /// otherwise clients should use `new_output_tokens` and `new_text`.
+    pub fn set_stream_deltas(&mut self, stream_deltas: bool) {
+        self.stream_deltas = stream_deltas;
+    }
+
     fn req_output(&self, sg: &mut SequenceGroup, is_final: bool) -> RequestOutput {
+        let full_output = is_final || !self.stream_deltas;
         RequestOutput {
             request_id: sg.request_id.clone(),
             seq_outputs: sg
                 .seqs
                 .iter_mut()
-                .map(|seq| seq.gen_output(&self.tok_trie))
+                .filter(|seq| !seq.guidance)
+                .map(|seq| seq.gen_output(&self.tok_trie, full_output))
                 .collect(),
             usage: sg.usage.clone(),
+            timing: sg.timing(),
             is_final,
+            metadata: sg.metadata.clone(),
+            lineage: sg.lineage.clone(),
         }
     }
 
-    fn run_model(&mut self, sched_out: &mut SchedulerOutputs) -> Result<Vec<RequestOutput>> {
+    fn run_model(
+        &mut self,
+        sched_out: &mut SchedulerOutputs,
+        outcome: &mut StepOutcome,
+    ) -> Result<Vec<RequestOutput>> {
         if sched_out.is_empty() {
             log::debug!("no seqs to run");
             return Ok(self.empty_outputs(sched_out)?);
         }
 
+        let t0 = Instant::now();
         self.tmodel.run(
             self.tok_trie.vocab_size(),
             &self.tim_model_fwd,
             self.step_no,
             sched_out,
         )?;
+        outcome.forward_time = t0.elapsed();
 
+        if self.config.scheduler.pipeline_steps {
+            // the forward pass is still running on the device
+            self.tmodel.prepare_next_run(sched_out);
+        }
+
+        let t0 = Instant::now();
         let r = with_timer!(self.tim_sample, { self.sample(sched_out) });
+        outcome.sample_time = t0.elapsed();
 
         self.tmodel.finalize_run()?;
 
@@ -724,7 +1776,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
         }
     }
 
-    pub fn step(&mut self) -> Result<Vec<RequestOutput>> {
+    pub fn step(&mut self) -> Result<StepOutcome> {
         let r = with_timer!(self.tim_step, self.step_inner());
 
         if self.step_no % 20 == 0 {
@@ -735,11 +1787,21 @@ impl<ME: ModelExec> RllmEngine<ME> {
         r
     }
 
-    fn step_inner(&mut self) -> Result<Vec<RequestOutput>> {
+    fn step_inner(&mut self) -> Result<StepOutcome> {
         self.step_no += 1;
 
+        if let Some(secs) = self.config.scheduler.session_spill_secs {
+            let dir = PathBuf::from(&self.config.scheduler.session_spill_dir);
+            self.sessions.spill_idle(
+                &mut self.tmodel,
+                self.seq_mgr.deref(),
+                Duration::from_secs_f32(secs),
+                &dir,
+            );
+        }
+
         self.scheduler.for_each_waiting_sg(|sg| {
-            if sg.only_seq().get_len() == 0 {
+            if sg.seqs[0].get_len() == 0 {
                 // this happens when we fork right away, and there is no start token
                 // for the current model
                 sg.seqs[0].append_tokens(&[self.space_token_id]);
@@ -747,6 +1809,15 @@ impl<ME: ModelExec> RllmEngine<ME> {
         });
 
         let mut sched_out = with_timer!(self.tim_schedule, self.scheduler.schedule());
+        for sg in sched_out.next_seq_groups.iter_mut() {
+            if sg.scheduled_time.is_none() {
+                sg.scheduled_time = Some(Instant::now());
+            }
+        }
+        sched_out.metrics.step_no = self.step_no;
+        if let Some(cb) = self.step_callback.as_mut() {
+            cb(&sched_out.metrics);
+        }
 
         with_timer!(self.tim_aici_mid, self.aici_mid(&mut sched_out)?);
 
@@ -755,16 +1826,40 @@ impl<ME: ModelExec> RllmEngine<ME> {
             sched_out.next_seq_groups.len(),
             sched_out.dropped_seq_groups.len()
         );
-        let outputs = with_timer!(self.tim_run_model, self.run_model(&mut sched_out));
+        let mut outcome = StepOutcome {
+            num_scheduled_groups: sched_out.next_seq_groups.len(),
+            num_preempted_groups: sched_out.num_preempted,
+            num_swapped_in_blocks: sched_out.blocks_to_swap_in.len(),
+            num_swapped_out_blocks: sched_out.blocks_to_swap_out.len(),
+            num_batched_tokens: sched_out.num_batched_tokens,
+            ..StepOutcome::default()
+        };
+        let outputs = with_timer!(
+            self.tim_run_model,
+            self.run_model(&mut sched_out, &mut outcome)
+        );
         // we run step_finished() regardless if model failed
         self.scheduler.step_finished(sched_out);
 
         let outputs = outputs?;
-        if outputs.is_empty() {
+        if let Some(frac) = self.config.scheduler.compact_kv_free_run {
+            if !self.scheduler.has_unfinished_seqs() {
+                outcome.num_compacted_blocks = self.tmodel.compact_kv(frac);
+                if outcome.num_compacted_blocks > 0 {
+                    log::debug!(
+                        "compacted KV cache: {} blocks moved",
+                        outcome.num_compacted_blocks
+                    );
+                }
+            }
+        }
+        // in streaming mode, steps where nothing was generated have no outputs
+        if outputs.is_empty() && !self.stream_deltas {
             assert!(!self.scheduler.has_unfinished_seqs());
         }
 
-        Ok(outputs)
+        outcome.outputs = outputs;
+        Ok(outcome)
     }
 
     fn decode_seq(&self, tokens: &Vec<Token>) -> Result<String> {
@@ -783,11 +1878,13 @@ impl<ME: ModelExec> RllmEngine<ME> {
         let t0 = Instant::now();
 
         while self.scheduler.has_unfinished_seqs() {
-            let outp = self.step()?;
+            let outp = self.step()?.outputs;
             if !outp.is_empty() {
                 assert!(outp.len() == 1);
                 assert!(outp[0].seq_outputs.len() == 1);
-                outputs = outp[0].seq_outputs[0].output_tokens.clone();
+                if outp[0].is_final || !self.stream_deltas {
+                    outputs = outp[0].seq_outputs[0].output_tokens.clone();
+                }
             }
         }
 
@@ -802,6 +1899,28 @@ impl<ME: ModelExec> RllmEngine<ME> {
         Ok(self.decode_seq(&outputs)?)
     }
 
+    pub fn scheduler_state(&self) -> SchedulerState {
+        self.scheduler.debug_state()
+    }
+
+    pub fn block_pool_stats(&self) -> BlockPoolStats {
+        self.scheduler.block_pool_stats()
+    }
+
+    /// Keep the KV of `prompt` (typically a system prompt) in the prefix cache
+    /// once computed, instead of evicting it when the cache is full.
+    /// Returns the number of pinned blocks.
+    pub fn pin_prefix(&mut self, prompt: &str) -> Result<usize> {
+        let tokens = self.tokenize(prompt, true)?;
+        Ok(self.scheduler.block_manager.pin_prefix(&tokens, true))
+    }
+
+    /// Undo pin_prefix(); the blocks become subject to LRU eviction.
+    pub fn unpin_prefix(&mut self, prompt: &str) -> Result<usize> {
+        let tokens = self.tokenize(prompt, true)?;
+        Ok(self.scheduler.block_manager.pin_prefix(&tokens, false))
+    }
+
     pub fn get_stats(&self) -> Stats {
         Stats {
             free_gpu_blocks: self.scheduler.block_manager.get_num_free_gpu_blocks(),
@@ -809,3 +1928,108 @@ impl<ME: ModelExec> RllmEngine<ME> {
         }
     }
 }
+
+/// Whether sampling EOS finishes a sequence; with a grammar, EOS is only allowed once
+/// the grammar is complete, and then it always does (/run sets ignore_eos).
+fn finishes_on_eos(params: &SamplingParams, grammar: Option<&GrammarMatcher>) -> bool {
+    !params.ignore_eos || grammar.map_or(false, |g| g.can_end())
+}
+
+/// Matches bytes starting with the given prefix.
+struct PrefixMatcher<'a> {
+    prefix: &'a [u8],
+    // number of bytes pushed so far, with the trie walk
+    lens: Vec<usize>,
+}
+
+impl Recognizer for PrefixMatcher<'_> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.lens.truncate(self.lens.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        let len = self.lens.pop().unwrap();
+        self.lens.clear();
+        self.lens.push(len);
+    }
+
+    fn special_allowed(&mut self, _tok: SpecialToken) -> bool {
+        false
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.lens.len() == 1);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let len = *self.lens.last().unwrap();
+        if len < self.prefix.len() && self.prefix[len] != byte {
+            false
+        } else {
+            self.lens.push(len + 1);
+            true
+        }
+    }
+}
+
+/// Tokens that start with `prefix` (the bytes of the prompt token trimmed by token healing);
+/// only the part of the trie under the prefix is walked.
+fn healing_token_set(tok_trie: &TokTrie, prefix: &[u8]) -> SimpleVob {
+    let mut allowed = tok_trie.alloc_token_set();
+    let mut matcher = PrefixMatcher {
+        prefix,
+        lens: vec![0],
+    };
+    tok_trie.compute_bias(&mut matcher, &mut allowed);
+    // tokens on the way down to the prefix are shorter than it
+    for len in 1..prefix.len() {
+        if let Some(t) = tok_trie.token_id(&prefix[..len]) {
+            allowed.disallow_token(t);
+        }
+    }
+    allowed
+}
+
+/// Reduce row-major `[num_tokens, hidden_size]` hidden states as requested.
+fn pool_hidden_states(mode: HiddenStates, hidden_size: usize, states: Vec<f32>) -> Vec<f32> {
+    let num_tokens = states.len() / hidden_size;
+    match mode {
+        HiddenStates::All => states,
+        HiddenStates::Last => states[(num_tokens - 1) * hidden_size..].to_vec(),
+        HiddenStates::Mean => {
+            let mut r = vec![0.0; hidden_size];
+            for row in states.chunks_exact(hidden_size) {
+                for (acc, v) in r.iter_mut().zip(row) {
+                    *acc += v;
+                }
+            }
+            r.iter_mut().for_each(|v| *v /= num_tokens as f32);
+            r
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::Grammar;
+
+    #[test]
+    fn grammar_eos_with_ignore_eos() {
+        let grammar = Grammar::from_gbnf("root ::= \"ab\"").unwrap();
+        let mut grm = GrammarMatcher::new(Arc::new(grammar));
+        let mut params = SamplingParams::default();
+        params.ignore_eos = true;
+
+        assert!(!finishes_on_eos(&params, None));
+        assert!(!finishes_on_eos(&params, Some(&grm)));
+        for b in b"ab" {
+            assert!(grm.try_push_byte(*b));
+            grm.collapse();
+        }
+        assert!(finishes_on_eos(&params, Some(&grm)));
+
+        params.ignore_eos = false;
+        assert!(finishes_on_eos(&params, None));
+    }
+}
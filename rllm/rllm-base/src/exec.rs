@@ -1,12 +1,14 @@
 use std::{fmt::Display, sync::Arc};
 
+use aici_abi::SimpleVob;
 use aicirt::TimerRef;
-use anyhow::Result;
+use anyhow::{bail, Result};
+use serde::Serialize;
 
 use crate::{
-    config::{ModelMeta, RllmConfig},
+    config::{ModelMeta, ParallelConfig, RllmConfig},
     scheduler::SchedulerOutputs,
-    seq::{Sequence, SequenceGroup},
+    seq::{Sequence, SequenceGroup, Token},
     HashMap, LoaderArgs, LogitsProcessor, RllmEngine,
 };
 
@@ -40,6 +42,11 @@ pub trait SequenceManager {
     fn copy(&self, src: SeqId, dst: SeqId, length: usize);
     fn trim(&self, seq: SeqId, length: usize);
     fn delete(&self, seq: SeqId);
+    /// Number of leading tokens out of `length` that a fork can share with its
+    /// original; the KV of the rest is recomputed for the fork.
+    fn shareable_len(&self, length: usize) -> usize {
+        length
+    }
 }
 
 pub trait ModelExec: Sized {
@@ -57,6 +64,10 @@ pub trait ModelExec: Sized {
         model_args: &mut Self::ModelLoaderArgs,
     ) -> Result<(ModelMeta, Self::ModelConfig)>;
     fn verify_args(args: &RllmConfig<Self>) -> Result<()>;
+    /// How the model is split across devices.
+    fn parallel_config(_model_args: &Self::ModelLoaderArgs) -> ParallelConfig {
+        ParallelConfig::single()
+    }
     fn load_rllm_engine(
         args: LoaderArgs,
         model_args: Self::ModelLoaderArgs,
@@ -72,6 +83,31 @@ pub trait ModelExec: Sized {
         sched_out: &mut SchedulerOutputs,
     ) -> Result<()>;
     fn get_logits(&self, seq_id: usize) -> Self::Tensor;
+
+    /// Number of tokens drafted per step for speculative decoding (by Medusa heads);
+    /// 0 if the model doesn't draft.
+    fn num_draft_tokens(&self) -> usize {
+        0
+    }
+
+    /// Logits of the last token of `seq_id` before its draft tokens, and of each draft
+    /// token, computed in the last run; the last ones are get_logits().
+    fn get_draft_logits(&self, seq_id: usize) -> Vec<Self::Tensor> {
+        vec![self.get_logits(seq_id)]
+    }
+
+    /// Tree of tokens drafted to follow the one sampled from get_draft_logits()[`row`]:
+    /// the tokens, and their parents (see Sequence::draft_parents).
+    fn get_draft(&self, _seq_id: usize, _row: usize) -> (Vec<Token>, Vec<usize>) {
+        (Vec::new(), Vec::new())
+    }
+
+    /// Called after run(), before sampling its results; the backend can use the time
+    /// the forward pass takes to prepare the batch of the next step, assuming every
+    /// sequence of `sched_out` generates one token. The next run() has to check
+    /// that its SchedulerOutputs match.
+    fn prepare_next_run(&mut self, _sched_out: &SchedulerOutputs) {}
+
     fn finalize_run(&mut self) -> Result<()>;
 
     fn empty_bias(&self, vocab_size: usize) -> Self::AiciBias;
@@ -79,17 +115,136 @@ pub trait ModelExec: Sized {
         -> Self::AiciBias;
 
     fn sample(&self, processor: &mut LogitsProcessor, logits: &Self::Tensor) -> Result<u32>;
+
+    /// Set logits of tokens not in `allowed` to -inf.
+    fn apply_token_mask(&self, logits: &mut Self::Tensor, allowed: &SimpleVob);
+
+    /// Classifier-free guidance: set `logits` to `logits + scale * (logits - negative)`.
+    fn apply_guidance(&self, logits: &mut Self::Tensor, negative: &Self::Tensor, scale: f32);
+
+    /// Hidden size of the model, if it accepts prompt embeddings in place of tokens.
+    fn prompt_embedding_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Names of the LoRA adapters requests can select (SamplingParams.adapter).
+    fn lora_adapters(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Serialize the KV of the first `len` tokens of `seq_id`, which keeps its blocks.
+    fn export_kv(&mut self, _seq_id: SeqId, _len: usize) -> Result<Vec<u8>> {
+        bail!("exporting KV cache is not supported")
+    }
+
+    /// Allocate blocks for `seq_id` and fill them with KV from export_kv(),
+    /// possibly of another process; false if there are not enough free blocks.
+    fn import_kv(&mut self, _seq_id: SeqId, _len: usize, _data: &[u8]) -> Result<bool> {
+        bail!("exporting KV cache is not supported")
+    }
+
+    /// Move the used KV blocks to the start of the cache, if the longest run of
+    /// free blocks is less than `min_free_run` of them; returns the number of blocks moved.
+    /// Only called when no sequence is running.
+    fn compact_kv(&mut self, _min_free_run: f32) -> usize {
+        0
+    }
+
+    /// Final-layer hidden states of the tokens of `seq_id` computed in the last run,
+    /// as (hidden_size, row-major [num_tokens, hidden_size] data).
+    /// Only available for sequences of groups that requested them.
+    fn get_hidden_states(&self, _seq_id: usize) -> Option<(usize, Vec<f32>)> {
+        None
+    }
+}
+
+/// Occupancy of the KV cache block pools; see TBlockSpaceManager::get_pool_stats().
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct BlockPoolStats {
+    pub num_gpu_blocks: usize,
+    pub num_free_gpu_blocks: usize,
+    pub num_cpu_blocks: usize,
+    pub num_free_cpu_blocks: usize,
+    /// Most GPU blocks in use at any point so far.
+    pub max_used_gpu_blocks: usize,
+    pub max_used_cpu_blocks: usize,
+    /// Fraction of the token slots of used GPU blocks that hold no token
+    /// (the unfilled tails of last blocks of sequences).
+    pub gpu_fragmentation: f32,
+    /// Free GPU blocks whose contents are kept for prefix caching, including pinned ones.
+    pub num_cached_blocks: usize,
+    pub num_pinned_blocks: usize,
+    /// Cached blocks reused for other contents so far.
+    pub num_evicted_blocks: usize,
+    /// Blocks (on GPU or CPU) held by each sequence group, by request id;
+    /// filled in by the Scheduler.
+    pub blocks_per_group: HashMap<String, usize>,
 }
 
 pub trait TBlockSpaceManager<ME: ModelExec> {
     fn can_allocate(&self, _seq_group: &SequenceGroup) -> bool;
+
+    /// Number of GPU blocks allocate() needs for the group, not counting
+    /// the ones it may find in the prefix cache.
+    fn get_num_required_blocks(&self, _seq_group: &SequenceGroup) -> usize {
+        0
+    }
+
+    /// Allocate blocks for the prompts of the group; a prefix found in the cache
+    /// is marked as computed.
     fn allocate(&mut self, seq_group: &mut SequenceGroup);
 
+    /// Whether a prefix of the prompts, not in the cache yet, is being computed for
+    /// another group; the group then waits to find it in the cache.
+    fn is_prefix_pending(&self, _seq_group: &SequenceGroup) -> bool {
+        false
+    }
+
     fn can_append_slot(&self, _seq_group: &SequenceGroup) -> bool;
     fn append_slots(&mut self, _seq: &mut Sequence, _outputs: &mut SchedulerOutputs);
     fn get_num_free_gpu_blocks(&self) -> usize;
     fn get_num_free_cpu_blocks(&self) -> usize;
 
+    /// Total number of GPU blocks; 0 if the backend doesn't manage blocks.
+    fn get_num_gpu_blocks(&self) -> usize {
+        0
+    }
+
+    /// Total number of CPU (swap) blocks; 0 if the backend doesn't manage blocks.
+    fn get_num_cpu_blocks(&self) -> usize {
+        0
+    }
+
+    /// Current occupancy of the block pools; backends that don't track
+    /// high-water marks report current usage instead.
+    fn get_pool_stats(&self) -> BlockPoolStats {
+        let num_gpu_blocks = self.get_num_gpu_blocks();
+        let num_cpu_blocks = self.get_num_cpu_blocks();
+        let num_free_gpu_blocks = self.get_num_free_gpu_blocks();
+        let num_free_cpu_blocks = self.get_num_free_cpu_blocks();
+        BlockPoolStats {
+            num_gpu_blocks,
+            num_free_gpu_blocks,
+            num_cpu_blocks,
+            num_free_cpu_blocks,
+            max_used_gpu_blocks: num_gpu_blocks.saturating_sub(num_free_gpu_blocks),
+            max_used_cpu_blocks: num_cpu_blocks.saturating_sub(num_free_cpu_blocks),
+            ..BlockPoolStats::default()
+        }
+    }
+
+    /// Keep (or stop keeping) the KV of the full blocks of the prefix `tokens`
+    /// in the prefix cache, even when no sequence uses them.
+    /// Returns the number of blocks affected; 0 without prefix caching.
+    fn pin_prefix(&mut self, _tokens: &[Token], _pinned: bool) -> usize {
+        0
+    }
+
+    /// Number of blocks (on GPU or CPU) held by the sequences of the group.
+    fn get_num_blocks(&self, _seq_group: &SequenceGroup) -> usize {
+        0
+    }
+
     fn can_swap_in(&self, _seq_group: &SequenceGroup) -> bool {
         false
     }
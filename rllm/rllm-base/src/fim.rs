@@ -0,0 +1,140 @@
+// Fill-in-the-middle (infilling) prompts, for models trained with FIM sentinel tokens.
+
+use crate::seq::Token;
+use tokenizers::Tokenizer;
+
+struct FimFamily {
+    name: &'static str,
+    prefix: &'static str,
+    suffix: &'static str,
+    middle: &'static str,
+    end: Option<&'static str>,
+}
+
+const FIM_FAMILIES: &[FimFamily] = &[
+    FimFamily {
+        name: "codellama",
+        prefix: "▁<PRE>",
+        suffix: "▁<SUF>",
+        middle: "▁<MID>",
+        end: Some("▁<EOT>"),
+    },
+    FimFamily {
+        name: "starcoder",
+        prefix: "<fim_prefix>",
+        suffix: "<fim_suffix>",
+        middle: "<fim_middle>",
+        end: None,
+    },
+    FimFamily {
+        name: "deepseek-coder",
+        prefix: "<｜fim▁begin｜>",
+        suffix: "<｜fim▁hole｜>",
+        middle: "<｜fim▁end｜>",
+        end: None,
+    },
+];
+
+/// Sentinel tokens of the model; the infilled text is generated after `middle`.
+#[derive(Debug, Clone)]
+pub struct FimTokens {
+    pub family: &'static str,
+    pub prefix: Token,
+    pub suffix: Token,
+    pub middle: Token,
+    /// End of the infilled text, when it's not the EOS token.
+    pub end: Option<Token>,
+}
+
+impl FimTokens {
+    /// Find the sentinel tokens in the vocabulary; None if the model doesn't support infilling.
+    pub fn from_tokenizer(tokenizer: &Tokenizer) -> Option<Self> {
+        FIM_FAMILIES.iter().find_map(|f| {
+            let end = match f.end {
+                Some(e) => Some(tokenizer.token_to_id(e)?),
+                None => None,
+            };
+            Some(FimTokens {
+                family: f.name,
+                prefix: tokenizer.token_to_id(f.prefix)?,
+                suffix: tokenizer.token_to_id(f.suffix)?,
+                middle: tokenizer.token_to_id(f.middle)?,
+                end,
+            })
+        })
+    }
+
+    /// Assemble prefix-suffix-middle prompt; `start` is typically the BOS token, if any.
+    pub fn build_prompt(&self, start: &[Token], prefix: &[Token], suffix: &[Token]) -> Vec<Token> {
+        let mut r = start.to_vec();
+        r.push(self.prefix);
+        r.extend_from_slice(prefix);
+        r.push(self.suffix);
+        r.extend_from_slice(suffix);
+        r.push(self.middle);
+        r
+    }
+
+    pub fn sentinels(&self) -> Vec<Token> {
+        let mut r = vec![self.prefix, self.suffix, self.middle];
+        r.extend(self.end);
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::models::wordlevel::WordLevel;
+
+    fn tokenizer(words: &[&str]) -> Tokenizer {
+        let vocab = words
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (w.to_string(), i as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+        Tokenizer::new(model)
+    }
+
+    #[test]
+    fn families() {
+        let tok = tokenizer(&["<unk>", "<fim_prefix>", "<fim_middle>", "<fim_suffix>"]);
+        let fim = FimTokens::from_tokenizer(&tok).unwrap();
+        assert_eq!(fim.family, "starcoder");
+        assert_eq!((fim.prefix, fim.suffix, fim.middle), (1, 3, 2));
+        assert_eq!(fim.end, None);
+        assert_eq!(fim.sentinels(), vec![1, 3, 2]);
+
+        let tok = tokenizer(&["<unk>", "▁<PRE>", "▁<SUF>", "▁<MID>", "▁<EOT>"]);
+        let fim = FimTokens::from_tokenizer(&tok).unwrap();
+        assert_eq!(fim.family, "codellama");
+        assert_eq!(fim.sentinels(), vec![1, 2, 3, 4]);
+
+        // all the sentinels are needed
+        let tok = tokenizer(&["<unk>", "▁<PRE>", "▁<SUF>", "▁<MID>"]);
+        assert!(FimTokens::from_tokenizer(&tok).is_none());
+        assert!(FimTokens::from_tokenizer(&tokenizer(&["<unk>"])).is_none());
+    }
+
+    #[test]
+    fn prompt() {
+        let fim = FimTokens {
+            family: "test",
+            prefix: 100,
+            suffix: 101,
+            middle: 102,
+            end: Some(103),
+        };
+        assert_eq!(
+            fim.build_prompt(&[1], &[10, 11], &[20]),
+            vec![1, 100, 10, 11, 101, 20, 102]
+        );
+        assert_eq!(fim.build_prompt(&[], &[], &[]), vec![100, 101, 102]);
+        assert_eq!(fim.sentinels(), vec![100, 101, 102, 103]);
+    }
+}
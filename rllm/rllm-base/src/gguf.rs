@@ -0,0 +1,917 @@
+// GGUF (llama.cpp checkpoint format) reader
+// see https://github.com/ggerganov/ggml/blob/master/docs/gguf.md
+//
+// Quantized tensors are dequantized when the model is loaded, and stored in the model
+// dtype; this saves disk and download, but not GPU memory (a Q4_K model takes as much
+// as the f16 one).
+
+use crate::{HashMap, LoaderArgs, Repo};
+use anyhow::{bail, ensure, Result};
+use half::{bf16, f16};
+use memmap2::Mmap;
+use serde_json::{json, Value};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: usize = 32;
+const QK_K: usize = 256;
+const SUPPORTED_TYPES: &str = "F32, F16, BF16, Q4_0, Q4_1, Q8_0, Q2_K, Q3_K, Q4_K, Q5_K and Q6_K";
+/// Pre-tokenizer split of Qwen2 (unlike GPT-2, digits are split one by one).
+const QWEN2_SPLIT_REGEX: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+#[derive(Debug, Clone)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Str(String),
+    Array(Vec<GgufValue>),
+}
+
+impl GgufValue {
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            GgufValue::U8(v) => Some(v as usize),
+            GgufValue::U16(v) => Some(v as usize),
+            GgufValue::U32(v) => Some(v as usize),
+            GgufValue::U64(v) => Some(v as usize),
+            GgufValue::I8(v) => v.try_into().ok(),
+            GgufValue::I16(v) => v.try_into().ok(),
+            GgufValue::I32(v) => v.try_into().ok(),
+            GgufValue::I64(v) => v.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            GgufValue::F32(v) => Some(v),
+            GgufValue::F64(v) => Some(v as f32),
+            _ => self.as_usize().map(|v| v as f32),
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            GgufValue::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+/// Element types of ggml tensors; only the ones we can dequantize are listed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgmlType {
+    F32,
+    F16,
+    BF16,
+    Q4_0,
+    Q4_1,
+    Q8_0,
+    Q2K,
+    Q3K,
+    Q4K,
+    Q5K,
+    Q6K,
+}
+
+impl GgmlType {
+    fn from_u32(v: u32) -> Result<Self> {
+        Ok(match v {
+            0 => GgmlType::F32,
+            1 => GgmlType::F16,
+            2 => GgmlType::Q4_0,
+            3 => GgmlType::Q4_1,
+            8 => GgmlType::Q8_0,
+            10 => GgmlType::Q2K,
+            11 => GgmlType::Q3K,
+            12 => GgmlType::Q4K,
+            13 => GgmlType::Q5K,
+            14 => GgmlType::Q6K,
+            30 => GgmlType::BF16,
+            _ => bail!(
+                "ggml tensor type {} is not supported (only {SUPPORTED_TYPES} are)",
+                ggml_type_name(v)
+            ),
+        })
+    }
+
+    /// Number of elements and bytes in one block.
+    fn block_size(&self) -> (usize, usize) {
+        match self {
+            GgmlType::F32 => (1, 4),
+            GgmlType::F16 | GgmlType::BF16 => (1, 2),
+            GgmlType::Q4_0 => (32, 2 + 16),
+            GgmlType::Q4_1 => (32, 2 + 2 + 16),
+            GgmlType::Q8_0 => (32, 2 + 32),
+            GgmlType::Q2K => (QK_K, QK_K / 16 + QK_K / 4 + 2 + 2),
+            GgmlType::Q3K => (QK_K, QK_K / 8 + QK_K / 4 + 12 + 2),
+            GgmlType::Q4K => (QK_K, 2 + 2 + 12 + QK_K / 2),
+            GgmlType::Q5K => (QK_K, 2 + 2 + 12 + QK_K / 8 + QK_K / 2),
+            GgmlType::Q6K => (QK_K, QK_K / 2 + QK_K / 4 + QK_K / 16 + 2),
+        }
+    }
+}
+
+/// Names of the ggml types we can't load, for error messages.
+fn ggml_type_name(v: u32) -> String {
+    let name = match v {
+        6 => "Q5_0",
+        7 => "Q5_1",
+        9 => "Q8_1",
+        15 => "Q8_K",
+        16 => "IQ2_XXS",
+        17 => "IQ2_XS",
+        18 => "IQ3_XXS",
+        19 => "IQ1_S",
+        20 => "IQ4_NL",
+        21 => "IQ3_S",
+        22 => "IQ2_S",
+        23 => "IQ4_XS",
+        24 => "I8",
+        25 => "I16",
+        26 => "I32",
+        27 => "I64",
+        28 => "F64",
+        29 => "IQ1_M",
+        _ => return format!("{v}"),
+    };
+    name.to_string()
+}
+
+#[derive(Debug, Clone)]
+pub struct GgufTensor {
+    pub name: String,
+    /// Torch order, i.e., outermost dimension first (reverse of what's in the file).
+    pub shape: Vec<usize>,
+    pub dtype: GgmlType,
+    offset: usize,
+}
+
+impl GgufTensor {
+    pub fn numel(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    fn byte_size(&self) -> usize {
+        let (elts, bytes) = self.dtype.block_size();
+        self.numel() / elts * bytes
+    }
+}
+
+pub struct GgufFile {
+    mmap: Mmap,
+    data_offset: usize,
+    pub metadata: HashMap<String, GgufValue>,
+    pub tensors: Vec<GgufTensor>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        ensure!(
+            self.pos + n <= self.data.len(),
+            "unexpected end of GGUF header"
+        );
+        let r = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(r)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u64()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+
+    fn value(&mut self, tp: u32) -> Result<GgufValue> {
+        Ok(match tp {
+            0 => GgufValue::U8(self.bytes(1)?[0]),
+            1 => GgufValue::I8(self.bytes(1)?[0] as i8),
+            2 => GgufValue::U16(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap())),
+            3 => GgufValue::I16(i16::from_le_bytes(self.bytes(2)?.try_into().unwrap())),
+            4 => GgufValue::U32(self.u32()?),
+            5 => GgufValue::I32(self.u32()? as i32),
+            6 => GgufValue::F32(f32::from_bits(self.u32()?)),
+            7 => GgufValue::Bool(self.bytes(1)?[0] != 0),
+            8 => GgufValue::Str(self.string()?),
+            9 => {
+                let elt_tp = self.u32()?;
+                let len = self.u64()? as usize;
+                let mut arr = Vec::with_capacity(std::cmp::min(len, 1 << 20));
+                for _ in 0..len {
+                    arr.push(self.value(elt_tp)?);
+                }
+                GgufValue::Array(arr)
+            }
+            10 => GgufValue::U64(self.u64()?),
+            11 => GgufValue::I64(self.u64()? as i64),
+            12 => GgufValue::F64(f64::from_bits(self.u64()?)),
+            _ => bail!("invalid GGUF value type {tp}"),
+        })
+    }
+}
+
+impl GgufFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let fp = std::fs::File::open(path)?;
+        let mmap = unsafe { memmap2::MmapOptions::new().map(&fp)? };
+
+        let mut r = Reader {
+            data: &mmap,
+            pos: 0,
+        };
+        ensure!(r.bytes(4)? == GGUF_MAGIC, "{path:?} is not a GGUF file");
+        let version = r.u32()?;
+        ensure!(
+            version == 2 || version == 3,
+            "unsupported GGUF version {version}"
+        );
+        let num_tensors = r.u64()? as usize;
+        let num_kv = r.u64()? as usize;
+
+        let mut metadata = HashMap::default();
+        for _ in 0..num_kv {
+            let key = r.string()?;
+            let tp = r.u32()?;
+            let val = r.value(tp)?;
+            metadata.insert(key, val);
+        }
+
+        let mut tensors = Vec::with_capacity(num_tensors);
+        for _ in 0..num_tensors {
+            let name = r.string()?;
+            let n_dims = r.u32()? as usize;
+            let mut shape = (0..n_dims)
+                .map(|_| r.u64().map(|d| d as usize))
+                .collect::<Result<Vec<_>>>()?;
+            shape.reverse();
+            let dtype = GgmlType::from_u32(r.u32()?);
+            let offset = r.u64()? as usize;
+            let dtype = match dtype {
+                Ok(t) => t,
+                Err(e) => bail!("tensor {name}: {e}"),
+            };
+            tensors.push(GgufTensor {
+                name,
+                shape,
+                dtype,
+                offset,
+            });
+        }
+
+        let alignment = metadata
+            .get("general.alignment")
+            .and_then(|v| v.as_usize())
+            .unwrap_or(DEFAULT_ALIGNMENT);
+        let data_offset = r.pos.div_ceil(alignment) * alignment;
+
+        let file = GgufFile {
+            mmap,
+            data_offset,
+            metadata,
+            tensors,
+        };
+        for t in &file.tensors {
+            let (elts, _) = t.dtype.block_size();
+            ensure!(
+                t.numel() % elts == 0,
+                "tensor {}: {} elements, not a multiple of {elts}",
+                t.name,
+                t.numel()
+            );
+            ensure!(
+                file.data_offset + t.offset + t.byte_size() <= file.mmap.len(),
+                "tensor {} is outside of the file",
+                t.name
+            );
+        }
+        Ok(file)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.get(key)
+    }
+
+    pub fn get_str(&self, key: &str) -> Result<&str> {
+        match self.get(key).and_then(|v| v.as_str()) {
+            Some(v) => Ok(v),
+            None => bail!("GGUF: missing string {key}"),
+        }
+    }
+
+    pub fn get_usize(&self, key: &str) -> Result<usize> {
+        match self.get(key).and_then(|v| v.as_usize()) {
+            Some(v) => Ok(v),
+            None => bail!("GGUF: missing integer {key}"),
+        }
+    }
+
+    pub fn get_f32(&self, key: &str) -> Result<f32> {
+        match self.get(key).and_then(|v| v.as_f32()) {
+            Some(v) => Ok(v),
+            None => bail!("GGUF: missing number {key}"),
+        }
+    }
+
+    /// Model architecture, eg. "llama"; model hyper-parameters are prefixed with it.
+    pub fn architecture(&self) -> Result<&str> {
+        self.get_str("general.architecture")
+    }
+
+    /// Look up "{architecture}.{key}", eg. arch_key("rope.freq_base").
+    pub fn arch_key(&self, key: &str) -> Result<String> {
+        Ok(format!("{}.{}", self.architecture()?, key))
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&GgufTensor> {
+        self.tensors.iter().find(|t| t.name == name)
+    }
+
+    /// Dequantize the tensor to f32, on the host.
+    pub fn read_f32(&self, t: &GgufTensor) -> Vec<f32> {
+        let start = self.data_offset + t.offset;
+        let data = &self.mmap[start..start + t.byte_size()];
+        let (elts, bytes) = t.dtype.block_size();
+        let mut res = Vec::with_capacity(t.numel());
+        for block in data.chunks_exact(bytes) {
+            match t.dtype {
+                GgmlType::F32 => res.push(f32::from_le_bytes(block.try_into().unwrap())),
+                GgmlType::F16 => res.push(read_f16(block)),
+                GgmlType::BF16 => res.push(bf16::from_le_bytes([block[0], block[1]]).to_f32()),
+                GgmlType::Q4_0 => dequant_q4_0(block, &mut res),
+                GgmlType::Q4_1 => dequant_q4_1(block, &mut res),
+                GgmlType::Q8_0 => dequant_q8_0(block, &mut res),
+                GgmlType::Q2K => dequant_q2_k(block, &mut res),
+                GgmlType::Q3K => dequant_q3_k(block, &mut res),
+                GgmlType::Q4K => dequant_q4_k(block, &mut res),
+                GgmlType::Q5K => dequant_q5_k(block, &mut res),
+                GgmlType::Q6K => dequant_q6_k(block, &mut res),
+            }
+        }
+        assert!(res.len() == data.len() / bytes * elts);
+        res
+    }
+
+    fn token_list(&self) -> Result<Vec<String>> {
+        let tokens = self
+            .get("tokenizer.ggml.tokens")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&[]);
+        ensure!(!tokens.is_empty(), "GGUF: no tokenizer.ggml.tokens");
+        Ok(tokens
+            .iter()
+            .map(|t| t.as_str().unwrap_or("").to_string())
+            .collect())
+    }
+
+    fn numeric_list(&self, key: &str, len: usize) -> Vec<f32> {
+        match self.get(key).and_then(|v| v.as_array()) {
+            Some(arr) => arr.iter().map(|v| v.as_f32().unwrap_or(0.0)).collect(),
+            None => vec![0.0; len],
+        }
+    }
+
+    /// Build HuggingFace tokenizer.json from the vocabulary stored in the header.
+    /// SentencePiece ("llama") and byte-level BPE ("gpt2") vocabularies are supported.
+    pub fn tokenizer_json(&self) -> Result<Value> {
+        let model = self.get_str("tokenizer.ggml.model")?;
+        let tokens = self.token_list()?;
+        let types = self.numeric_list("tokenizer.ggml.token_type", tokens.len());
+
+        let vocab: serde_json::Map<String, Value> = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, t)| (t.clone(), json!(id)))
+            .collect();
+
+        // token types: 1 - normal, 2 - unknown, 3 - control, 4 - user defined, 6 - byte
+        let added_tokens: Vec<Value> = tokens
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| matches!(types.get(*id), Some(t) if *t == 3.0 || *t == 4.0))
+            .map(|(id, t)| {
+                json!({
+                    "id": id,
+                    "content": t,
+                    "single_word": false,
+                    "lstrip": false,
+                    "rstrip": false,
+                    "normalized": false,
+                    "special": types[id] == 3.0,
+                })
+            })
+            .collect();
+
+        let unk = match self.get("tokenizer.ggml.unknown_token_id") {
+            Some(v) => v.as_usize().and_then(|id| tokens.get(id)).cloned(),
+            None => None,
+        };
+
+        let res = match model {
+            "llama" => {
+                let scores = self.numeric_list("tokenizer.ggml.scores", tokens.len());
+                let merges = spm_merges(&tokens, &scores);
+                // Gemma vocabularies don't add a space in front of the text
+                let add_space_prefix = self
+                    .get("tokenizer.ggml.add_space_prefix")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(true);
+                let mut normalizers = vec![
+                    json!({ "type": "Replace", "pattern": { "String": " " }, "content": "\u{2581}" }),
+                ];
+                let mut decoders = vec![
+                    json!({ "type": "Replace", "pattern": { "String": "\u{2581}" }, "content": " " }),
+                    json!({ "type": "ByteFallback" }),
+                    json!({ "type": "Fuse" }),
+                ];
+                if add_space_prefix {
+                    normalizers.insert(0, json!({ "type": "Prepend", "prepend": "\u{2581}" }));
+                    let strip = json!({ "type": "Strip", "content": " ", "start": 1, "stop": 0 });
+                    decoders.push(strip);
+                }
+                json!({
+                    "version": "1.0",
+                    "truncation": null,
+                    "padding": null,
+                    "added_tokens": added_tokens,
+                    "normalizer": { "type": "Sequence", "normalizers": normalizers },
+                    "pre_tokenizer": null,
+                    "post_processor": null,
+                    "decoder": { "type": "Sequence", "decoders": decoders },
+                    "model": {
+                        "type": "BPE",
+                        "dropout": null,
+                        "unk_token": unk.unwrap_or("<unk>".to_string()),
+                        "continuing_subword_prefix": null,
+                        "end_of_word_suffix": null,
+                        "fuse_unk": true,
+                        "byte_fallback": true,
+                        "vocab": vocab,
+                        "merges": merges,
+                    }
+                })
+            }
+            "gpt2" => {
+                let merges: Vec<&str> = self
+                    .get("tokenizer.ggml.merges")
+                    .and_then(|v| v.as_array())
+                    .unwrap_or(&[])
+                    .iter()
+                    .filter_map(|m| m.as_str())
+                    .collect();
+                ensure!(!merges.is_empty(), "GGUF: no tokenizer.ggml.merges");
+                let byte_level = json!({
+                    "type": "ByteLevel",
+                    "add_prefix_space": false,
+                    "trim_offsets": true,
+                    "use_regex": true,
+                });
+                let pre_tokenizer = match self.get_str("tokenizer.ggml.pre") {
+                    Ok("qwen2") => json!({
+                        "type": "Sequence",
+                        "pretokenizers": [
+                            {
+                                "type": "Split",
+                                "pattern": { "Regex": QWEN2_SPLIT_REGEX },
+                                "behavior": "Isolated",
+                                "invert": false,
+                            },
+                            {
+                                "type": "ByteLevel",
+                                "add_prefix_space": false,
+                                "trim_offsets": false,
+                                "use_regex": false,
+                            },
+                        ]
+                    }),
+                    _ => byte_level.clone(),
+                };
+                json!({
+                    "version": "1.0",
+                    "truncation": null,
+                    "padding": null,
+                    "added_tokens": added_tokens,
+                    "normalizer": null,
+                    "pre_tokenizer": pre_tokenizer,
+                    "post_processor": null,
+                    "decoder": byte_level,
+                    "model": {
+                        "type": "BPE",
+                        "dropout": null,
+                        "unk_token": unk,
+                        "continuing_subword_prefix": null,
+                        "end_of_word_suffix": null,
+                        "fuse_unk": false,
+                        "byte_fallback": false,
+                        "vocab": vocab,
+                        "merges": merges,
+                    }
+                })
+            }
+            _ => bail!("GGUF: unsupported tokenizer model {model}"),
+        };
+        Ok(res)
+    }
+}
+
+/// SentencePiece vocabularies only have scores; BPE merges are all the splits
+/// of a token into two other tokens, ranked by the score of the merged token.
+fn spm_merges(tokens: &[String], scores: &[f32]) -> Vec<String> {
+    let ids: HashMap<&str, usize> = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, t)| (t.as_str(), id))
+        .collect();
+    let mut merges = Vec::new();
+    for (id, t) in tokens.iter().enumerate() {
+        for (split, _) in t.char_indices().skip(1) {
+            let (l, r) = t.split_at(split);
+            if let (Some(&lid), Some(&rid)) = (ids.get(l), ids.get(r)) {
+                merges.push((scores.get(id).cloned().unwrap_or(0.0), lid, rid));
+            }
+        }
+    }
+    merges.sort_by(|a, b| b.0.total_cmp(&a.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+    merges
+        .into_iter()
+        .map(|(_, l, r)| format!("{} {}", tokens[l], tokens[r]))
+        .collect()
+}
+
+fn read_f16(b: &[u8]) -> f32 {
+    f16::from_le_bytes([b[0], b[1]]).to_f32()
+}
+
+fn dequant_q4_0(block: &[u8], res: &mut Vec<f32>) {
+    let d = read_f16(block);
+    let qs = &block[2..18];
+    res.extend(qs.iter().map(|q| ((q & 0xf) as f32 - 8.0) * d));
+    res.extend(qs.iter().map(|q| ((q >> 4) as f32 - 8.0) * d));
+}
+
+fn dequant_q4_1(block: &[u8], res: &mut Vec<f32>) {
+    let d = read_f16(block);
+    let m = read_f16(&block[2..]);
+    let qs = &block[4..20];
+    res.extend(qs.iter().map(|q| (q & 0xf) as f32 * d + m));
+    res.extend(qs.iter().map(|q| (q >> 4) as f32 * d + m));
+}
+
+fn dequant_q8_0(block: &[u8], res: &mut Vec<f32>) {
+    let d = read_f16(block);
+    res.extend(block[2..34].iter().map(|q| *q as i8 as f32 * d));
+}
+
+fn dequant_q2_k(block: &[u8], res: &mut Vec<f32>) {
+    let scales = &block[0..QK_K / 16];
+    let qs = &block[QK_K / 16..QK_K / 16 + QK_K / 4];
+    let d = read_f16(&block[QK_K / 16 + QK_K / 4..]);
+    let dmin = read_f16(&block[QK_K / 16 + QK_K / 4 + 2..]);
+    // 16 sub-blocks of 16, with 4-bit scales and mins; 32 bytes of 2-bit values per 128
+    for (is, sc) in scales.iter().enumerate() {
+        let q = &qs[is / 8 * 32 + is % 2 * 16..][..16];
+        let shift = is % 8 / 2 * 2;
+        let (dl, ml) = (d * (sc & 0xf) as f32, dmin * (sc >> 4) as f32);
+        res.extend(q.iter().map(|q| dl * ((q >> shift) & 3) as f32 - ml));
+    }
+}
+
+fn dequant_q3_k(block: &[u8], res: &mut Vec<f32>) {
+    let hmask = &block[0..QK_K / 8];
+    let qs = &block[QK_K / 8..QK_K / 8 + QK_K / 4];
+    let scales = &block[QK_K / 8 + QK_K / 4..QK_K / 8 + QK_K / 4 + 12];
+    let d = read_f16(&block[QK_K / 8 + QK_K / 4 + 12..]);
+    // 16 sub-blocks of 16, like Q2_K, with 6-bit signed scales, and the third bit
+    // of the values (set means no -4) in hmask
+    for is in 0..QK_K / 16 {
+        let low = if is < 8 {
+            scales[is] & 0xf
+        } else {
+            scales[is - 8] >> 4
+        };
+        let high = (scales[8 + is % 4] >> (is / 4 * 2)) & 3;
+        let dl = d * ((low | (high << 4)) as i32 - 32) as f32;
+        let off = is % 2 * 16;
+        let q = &qs[is / 8 * 32 + off..][..16];
+        let hm = &hmask[off..off + 16];
+        let (shift, m) = (is % 8 / 2 * 2, 1u8 << (is / 2));
+        res.extend(q.iter().zip(hm).map(|(q, h)| {
+            let q = ((q >> shift) & 3) as i32 - if h & m != 0 { 0 } else { 4 };
+            dl * q as f32
+        }));
+    }
+}
+
+/// 6-bit scale and min of sub-block j of a Q4_K/Q5_K block.
+fn scale_min_k4(j: usize, q: &[u8]) -> (f32, f32) {
+    if j < 4 {
+        ((q[j] & 63) as f32, (q[j + 4] & 63) as f32)
+    } else {
+        let sc = (q[j + 4] & 0xf) | ((q[j - 4] >> 6) << 4);
+        let m = (q[j + 4] >> 4) | ((q[j] >> 6) << 4);
+        (sc as f32, m as f32)
+    }
+}
+
+fn dequant_q4_k(block: &[u8], res: &mut Vec<f32>) {
+    let d = read_f16(block);
+    let dmin = read_f16(&block[2..]);
+    let scales = &block[4..16];
+    let qs = &block[16..16 + QK_K / 2];
+    for (j, q) in qs.chunks_exact(32).enumerate() {
+        let (sc1, m1) = scale_min_k4(2 * j, scales);
+        let (sc2, m2) = scale_min_k4(2 * j + 1, scales);
+        res.extend(q.iter().map(|q| d * sc1 * (q & 0xf) as f32 - dmin * m1));
+        res.extend(q.iter().map(|q| d * sc2 * (q >> 4) as f32 - dmin * m2));
+    }
+}
+
+fn dequant_q5_k(block: &[u8], res: &mut Vec<f32>) {
+    let d = read_f16(block);
+    let dmin = read_f16(&block[2..]);
+    let scales = &block[4..16];
+    let qh = &block[16..16 + QK_K / 8];
+    let qs = &block[16 + QK_K / 8..16 + QK_K / 8 + QK_K / 2];
+    for (j, q) in qs.chunks_exact(32).enumerate() {
+        let (sc1, m1) = scale_min_k4(2 * j, scales);
+        let (sc2, m2) = scale_min_k4(2 * j + 1, scales);
+        let (u1, u2) = (1u8 << (2 * j), 2u8 << (2 * j));
+        for (l, q) in q.iter().enumerate() {
+            let h = if qh[l] & u1 != 0 { 16.0 } else { 0.0 };
+            res.push(d * sc1 * ((q & 0xf) as f32 + h) - dmin * m1);
+        }
+        for (l, q) in q.iter().enumerate() {
+            let h = if qh[l] & u2 != 0 { 16.0 } else { 0.0 };
+            res.push(d * sc2 * ((q >> 4) as f32 + h) - dmin * m2);
+        }
+    }
+}
+
+fn dequant_q6_k(block: &[u8], res: &mut Vec<f32>) {
+    let ql = &block[0..QK_K / 2];
+    let qh = &block[QK_K / 2..QK_K / 2 + QK_K / 4];
+    let scales = &block[QK_K / 2 + QK_K / 4..QK_K / 2 + QK_K / 4 + QK_K / 16];
+    let d = read_f16(&block[QK_K / 2 + QK_K / 4 + QK_K / 16..]);
+    for n in 0..QK_K / 128 {
+        let ql = &ql[n * 64..];
+        let qh = &qh[n * 32..];
+        let sc = &scales[n * 8..];
+        let mut y = [0f32; 128];
+        for l in 0..32 {
+            let is = l / 16;
+            let q1 = ((ql[l] & 0xf) | ((qh[l] & 3) << 4)) as i32 - 32;
+            let q2 = ((ql[l + 32] & 0xf) | (((qh[l] >> 2) & 3) << 4)) as i32 - 32;
+            let q3 = ((ql[l] >> 4) | (((qh[l] >> 4) & 3) << 4)) as i32 - 32;
+            let q4 = ((ql[l + 32] >> 4) | (((qh[l] >> 6) & 3) << 4)) as i32 - 32;
+            y[l] = d * (sc[is] as i8) as f32 * q1 as f32;
+            y[l + 32] = d * (sc[is + 2] as i8) as f32 * q2 as f32;
+            y[l + 64] = d * (sc[is + 4] as i8) as f32 * q3 as f32;
+            y[l + 96] = d * (sc[is + 6] as i8) as f32 * q4 as f32;
+        }
+        res.extend_from_slice(&y);
+    }
+}
+
+pub fn is_gguf(args: &LoaderArgs) -> bool {
+    args.file.as_ref().map_or(false, |f| f.ends_with(".gguf"))
+}
+
+lazy_static::lazy_static! {
+    // the tokenizer, config and weights are all read from the same file
+    static ref OPENED: Mutex<Option<(PathBuf, Arc<GgufFile>)>> = Mutex::new(None);
+}
+
+fn gguf_path(args: &LoaderArgs) -> Result<PathBuf> {
+    let repo = Repo::from(args)?;
+    repo.get(args.file.as_ref().unwrap())
+}
+
+fn open_cached(path: &Path) -> Result<Arc<GgufFile>> {
+    let mut opened = OPENED.lock().unwrap();
+    if let Some((p, gguf)) = opened.as_ref() {
+        if p == path {
+            return Ok(gguf.clone());
+        }
+    }
+    let gguf = Arc::new(GgufFile::open(path)?);
+    *opened = Some((path.to_path_buf(), gguf.clone()));
+    Ok(gguf)
+}
+
+/// Open the .gguf file of the model; the header is only parsed the first time.
+pub fn open_gguf(args: &LoaderArgs) -> Result<Arc<GgufFile>> {
+    open_cached(&gguf_path(args)?)
+}
+
+/// Unmap the file opened by open_gguf(), once the weights are loaded.
+pub fn close_gguf() {
+    *OPENED.lock().unwrap() = None;
+}
+
+/// Write tokenizer.json built from the GGUF header next to the .gguf file,
+/// and return its path (to be used as --tokenizer).
+pub fn extract_tokenizer(args: &LoaderArgs) -> Result<String> {
+    let path = gguf_path(args)?;
+    let gguf = open_cached(&path)?;
+    let json = gguf.tokenizer_json()?;
+    let out: PathBuf = path.with_extension("tokenizer.json");
+    std::fs::write(&out, serde_json::to_vec(&json)?)?;
+    let out = std::fs::canonicalize(&out)?;
+    Ok(out.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn f16_bytes(x: f32) -> [u8; 2] {
+        f16::from_f32(x).to_le_bytes()
+    }
+
+    fn dequant(f: fn(&[u8], &mut Vec<f32>), block: &[u8]) -> Vec<f32> {
+        let mut res = Vec::new();
+        f(block, &mut res);
+        res
+    }
+
+    #[test]
+    fn q4_0() {
+        let mut block = f16_bytes(0.5).to_vec();
+        block.extend([0x9a; 16]);
+        let res = dequant(dequant_q4_0, &block);
+        assert_eq!(res.len(), 32);
+        // low nibbles first
+        assert!(res[..16].iter().all(|v| *v == 1.0));
+        assert!(res[16..].iter().all(|v| *v == 0.5));
+    }
+
+    #[test]
+    fn q4_1() {
+        let mut block = f16_bytes(2.0).to_vec();
+        block.extend(f16_bytes(-1.0));
+        block.extend([0x31; 16]);
+        let res = dequant(dequant_q4_1, &block);
+        assert_eq!(res.len(), 32);
+        assert!(res[..16].iter().all(|v| *v == 1.0));
+        assert!(res[16..].iter().all(|v| *v == 5.0));
+    }
+
+    #[test]
+    fn q8_0() {
+        let mut block = f16_bytes(0.25).to_vec();
+        block.extend((0..32).map(|i| (i as i8 - 16) as u8));
+        let res = dequant(dequant_q8_0, &block);
+        let expected = (0..32).map(|i| (i - 16) as f32 * 0.25).collect::<Vec<_>>();
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn q2_k() {
+        // scale 2 and min 1, except for the second sub-block
+        let mut block = [0x12u8; QK_K / 16].to_vec();
+        block[1] = 0x01;
+        block.extend([0b11_10_01_00; QK_K / 4]);
+        block.extend(f16_bytes(1.0));
+        block.extend(f16_bytes(0.5));
+        assert_eq!(block.len(), GgmlType::Q2K.block_size().1);
+        let res = dequant(dequant_q2_k, &block);
+        assert_eq!(res.len(), QK_K);
+        assert!(res[..16].iter().all(|v| *v == -0.5));
+        assert!(res[16..32].iter().all(|v| *v == 0.0));
+        assert_eq!(res[32], 1.5);
+        assert_eq!(res[96], 5.5);
+        assert_eq!(res[128], -0.5);
+    }
+
+    #[test]
+    fn q3_k() {
+        let mut hmask = [0xffu8; QK_K / 8];
+        hmask[0] = 0xfe;
+        let mut qs = [0u8; QK_K / 4];
+        qs[0] = 0b0111;
+        qs[32] = 2;
+        // scales are 33 - 32, except 35 - 32 for the first sub-block
+        let mut scales = [0x11u8; 12];
+        scales[0] = 0x13;
+        scales[8..].fill(0xaa);
+        let mut block = [&hmask[..], &qs[..], &scales[..]].concat();
+        block.extend(f16_bytes(0.5));
+        assert_eq!(block.len(), GgmlType::Q3K.block_size().1);
+        let res = dequant(dequant_q3_k, &block);
+        assert_eq!(res.len(), QK_K);
+        // the missing high bit subtracts 4
+        assert_eq!(res[0], -1.5);
+        assert_eq!(res[1], 0.0);
+        assert_eq!(res[32], 0.5);
+        assert_eq!(res[128], 1.0);
+    }
+
+    #[test]
+    fn unsupported_type() {
+        let err = GgmlType::from_u32(6).unwrap_err().to_string();
+        assert!(err.contains("Q5_0") && err.contains(SUPPORTED_TYPES));
+    }
+
+    #[test]
+    fn k_scales() {
+        let mut q = [0u8; 12];
+        q[0] = 0xc0 | 5;
+        q[4] = 0x40 | 7;
+        q[8] = 0x21;
+        assert_eq!(scale_min_k4(0, &q), (5.0, 7.0));
+        // the upper 2 bits of sub-blocks 4.. come from the ones of 0..
+        assert_eq!(scale_min_k4(4, &q), (49.0, 18.0));
+    }
+
+    #[test]
+    fn q4_k() {
+        let mut block = f16_bytes(1.0).to_vec();
+        block.extend(f16_bytes(0.5));
+        // scales 2 for sub-blocks 0..4 and 3 for 4..8, mins 1
+        block.extend([2, 2, 2, 2, 1, 1, 1, 1, 0x13, 0x13, 0x13, 0x13]);
+        block.extend([0x21; QK_K / 2]);
+        assert_eq!(block.len(), GgmlType::Q4K.block_size().1);
+        let res = dequant(dequant_q4_k, &block);
+        assert_eq!(res.len(), QK_K);
+        assert_eq!(res[0], 1.5);
+        assert_eq!(res[32], 3.5);
+        assert_eq!(res[128], 2.5);
+        assert_eq!(res[160], 5.5);
+    }
+
+    #[test]
+    fn q5_k() {
+        let mut block = f16_bytes(1.0).to_vec();
+        block.extend(f16_bytes(0.0));
+        block.extend([1, 1, 1, 1, 0, 0, 0, 0, 0x01, 0x01, 0x01, 0x01]);
+        // the 5th bit of the first sub-block, and of the first value of the second one
+        let mut qh = [1u8; QK_K / 8];
+        qh[0] |= 2;
+        block.extend(qh);
+        block.extend([0; QK_K / 2]);
+        assert_eq!(block.len(), GgmlType::Q5K.block_size().1);
+        let res = dequant(dequant_q5_k, &block);
+        assert_eq!(res.len(), QK_K);
+        assert!(res[..32].iter().all(|v| *v == 16.0));
+        assert_eq!(res[32], 16.0);
+        assert!(res[33..].iter().all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn q6_k() {
+        let mut ql = [0u8; QK_K / 2];
+        ql[0] = 5;
+        let mut qh = [0u8; QK_K / 4];
+        qh[0] = 0b1000_0000;
+        let mut scales = [1u8; QK_K / 16];
+        scales[8] = -1i8 as u8;
+        let mut block = [&ql[..], &qh[..], &scales[..]].concat();
+        block.extend(f16_bytes(0.5));
+        assert_eq!(block.len(), GgmlType::Q6K.block_size().1);
+        let res = dequant(dequant_q6_k, &block);
+        assert_eq!(res.len(), QK_K);
+        assert_eq!(res[0], -13.5);
+        assert_eq!(res[1], -16.0);
+        assert_eq!(res[64], -16.0);
+        assert_eq!(res[96], 0.0);
+        // negative scale of the second half
+        assert_eq!(res[128], 16.0);
+    }
+}
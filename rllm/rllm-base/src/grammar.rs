@@ -0,0 +1,660 @@
+// GBNF grammars, as used by llama.cpp: https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md
+
+use crate::HashMap;
+use aici_abi::toktrie::{Recognizer, SpecialToken};
+use anyhow::{bail, Result};
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+enum Elem {
+    /// Matches a single character in (or, if negated, not in) one of the ranges.
+    Char {
+        ranges: Vec<(u32, u32)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Elem {
+    fn single(c: char) -> Self {
+        Elem::Char {
+            ranges: vec![(c as u32, c as u32)],
+            negated: false,
+        }
+    }
+
+    fn matches(&self, c: u32) -> bool {
+        match self {
+            Elem::Char { ranges, negated } => {
+                ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi) != *negated
+            }
+            Elem::Rule(_) => false,
+        }
+    }
+}
+
+type Alt = Vec<Elem>;
+
+#[derive(Debug)]
+pub struct Grammar {
+    rules: Vec<Vec<Alt>>,
+    names: Vec<String>,
+    root: usize,
+}
+
+struct GbnfParser {
+    src: Vec<char>,
+    pos: usize,
+    rules: Vec<Option<Vec<Alt>>>,
+    names: Vec<String>,
+    name_to_id: HashMap<String, usize>,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+impl GbnfParser {
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            }
+            None => bail!("unexpected end of grammar"),
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for c in s.chars() {
+            if self.peek() != Some(c) {
+                bail!("expecting '{}' at {}", s, self.pos);
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c == ' ' || c == '\t' || (newlines && (c == '\n' || c == '\r')) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            bail!("expecting rule name at {}", self.pos);
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.name_to_id.get(name) {
+            return *id;
+        }
+        let id = self.new_rule(name.to_string());
+        self.name_to_id.insert(name.to_string(), id);
+        id
+    }
+
+    fn new_rule(&mut self, name: String) -> usize {
+        self.rules.push(None);
+        self.names.push(name);
+        self.rules.len() - 1
+    }
+
+    fn synthetic_rule(&mut self, parent: usize, alts: Vec<Alt>) -> usize {
+        let name = format!("{}_{}", self.names[parent], self.rules.len());
+        let id = self.new_rule(name);
+        self.rules[id] = Some(alts);
+        id
+    }
+
+    fn parse_hex(&mut self, len: usize) -> Result<char> {
+        let mut v = 0;
+        for _ in 0..len {
+            let c = self.next()?;
+            match c.to_digit(16) {
+                Some(d) => v = v * 16 + d,
+                None => bail!("invalid hex digit '{}' at {}", c, self.pos),
+            }
+        }
+        match char::from_u32(v) {
+            Some(c) => Ok(c),
+            None => bail!("invalid code point {:#x}", v),
+        }
+    }
+
+    fn parse_char(&mut self) -> Result<char> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let c = self.next()?;
+        match c {
+            'n' => Ok('\n'),
+            'r' => Ok('\r'),
+            't' => Ok('\t'),
+            'x' => self.parse_hex(2),
+            'u' => self.parse_hex(4),
+            'U' => self.parse_hex(8),
+            '\\' | '"' | '[' | ']' | '-' | '^' => Ok(c),
+            _ => bail!("unknown escape '\\{}' at {}", c, self.pos),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<Elem> {
+        self.expect("[")?;
+        let negated = if self.peek() == Some('^') {
+            self.pos += 1;
+            true
+        } else {
+            false
+        };
+        let mut ranges = Vec::new();
+        while self.peek() != Some(']') {
+            let lo = self.parse_char()? as u32;
+            if self.peek() == Some('-') && self.src.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                let hi = self.parse_char()? as u32;
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        self.expect("]")?;
+        Ok(Elem::Char { ranges, negated })
+    }
+
+    fn parse_sequence(&mut self, rule: usize, nested: bool) -> Result<Alt> {
+        let mut seq = Vec::new();
+        let mut last_start = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    while self.peek() != Some('"') {
+                        seq.push(Elem::single(self.parse_char()?));
+                    }
+                    self.pos += 1;
+                }
+                '[' => {
+                    last_start = seq.len();
+                    seq.push(self.parse_class()?);
+                }
+                '.' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    seq.push(Elem::Char {
+                        ranges: vec![],
+                        negated: true,
+                    });
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alts = self.parse_alternatives(rule, true)?;
+                    self.expect(")")?;
+                    last_start = seq.len();
+                    seq.push(Elem::Rule(self.synthetic_rule(rule, alts)));
+                }
+                '*' | '+' | '?' => {
+                    self.pos += 1;
+                    if last_start == seq.len() {
+                        bail!("expecting preceding item to '{}' at {}", c, self.pos);
+                    }
+                    let sub = seq.split_off(last_start);
+                    // R ::= sub R | ""  or  R ::= sub R | sub  or  R ::= sub | ""
+                    let id = self.synthetic_rule(rule, vec![]);
+                    let mut rec = sub.clone();
+                    rec.push(Elem::Rule(id));
+                    let alts = match c {
+                        '*' => vec![rec, vec![]],
+                        '+' => vec![rec, sub],
+                        _ => vec![sub, vec![]],
+                    };
+                    self.rules[id] = Some(alts);
+                    seq.push(Elem::Rule(id));
+                }
+                c if is_name_char(c) => {
+                    let name = self.parse_name()?;
+                    last_start = seq.len();
+                    seq.push(Elem::Rule(self.rule_id(&name)));
+                }
+                _ => break,
+            }
+            self.skip_space(nested);
+        }
+        Ok(seq)
+    }
+
+    fn parse_alternatives(&mut self, rule: usize, nested: bool) -> Result<Vec<Alt>> {
+        let mut alts = vec![self.parse_sequence(rule, nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alts.push(self.parse_sequence(rule, nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_rule(&mut self) -> Result<()> {
+        let name = self.parse_name()?;
+        let id = self.rule_id(&name);
+        self.skip_space(false);
+        self.expect("::=")?;
+        self.skip_space(true);
+        let alts = self.parse_alternatives(id, false)?;
+        if self.rules[id].is_some() {
+            bail!("rule '{}' defined twice", name);
+        }
+        self.rules[id] = Some(alts);
+        self.skip_space(false);
+        match self.peek() {
+            None | Some('\n') | Some('\r') => Ok(()),
+            Some(c) => bail!("unexpected '{}' at {} in rule '{}'", c, self.pos, name),
+        }
+    }
+}
+
+impl Grammar {
+    pub fn from_gbnf(src: &str) -> Result<Self> {
+        let mut parser = GbnfParser {
+            src: src.chars().collect(),
+            pos: 0,
+            rules: Vec::new(),
+            names: Vec::new(),
+            name_to_id: HashMap::default(),
+        };
+
+        loop {
+            parser.skip_space(true);
+            if parser.peek().is_none() {
+                break;
+            }
+            parser.parse_rule()?;
+        }
+
+        let root = match parser.name_to_id.get("root") {
+            Some(id) => *id,
+            None => bail!("grammar has no 'root' rule"),
+        };
+        let mut rules = Vec::new();
+        for (id, alts) in parser.rules.into_iter().enumerate() {
+            match alts {
+                Some(alts) => rules.push(alts),
+                None => bail!("undefined rule '{}'", parser.names[id]),
+            }
+        }
+
+        let grm = Grammar {
+            rules,
+            names: parser.names,
+            root,
+        };
+        grm.check_left_recursion()?;
+        Ok(grm)
+    }
+
+    fn check_left_recursion(&self) -> Result<()> {
+        let mut nullable = vec![false; self.rules.len()];
+        loop {
+            let mut changed = false;
+            for (id, alts) in self.rules.iter().enumerate() {
+                if !nullable[id]
+                    && alts.iter().any(|alt| {
+                        alt.iter().all(|e| match e {
+                            Elem::Rule(r) => nullable[*r],
+                            Elem::Char { .. } => false,
+                        })
+                    })
+                {
+                    nullable[id] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // rules that can be expanded at the left edge of a given rule
+        let left_edges = self
+            .rules
+            .iter()
+            .map(|alts| {
+                let mut r = Vec::new();
+                for alt in alts {
+                    for e in alt {
+                        match e {
+                            Elem::Rule(id) => {
+                                r.push(*id);
+                                if !nullable[*id] {
+                                    break;
+                                }
+                            }
+                            Elem::Char { .. } => break,
+                        }
+                    }
+                }
+                r
+            })
+            .collect::<Vec<_>>();
+
+        // 0 - unvisited, 1 - on stack, 2 - done
+        let mut marks = vec![0u8; self.rules.len()];
+        fn visit(id: usize, edges: &[Vec<usize>], marks: &mut [u8]) -> Option<usize> {
+            match marks[id] {
+                1 => return Some(id),
+                2 => return None,
+                _ => {}
+            }
+            marks[id] = 1;
+            for e in &edges[id] {
+                if let Some(r) = visit(*e, edges, marks) {
+                    return Some(r);
+                }
+            }
+            marks[id] = 2;
+            None
+        }
+        for id in 0..self.rules.len() {
+            if let Some(r) = visit(id, &left_edges, &mut marks) {
+                bail!(
+                    "left recursion in rule '{}' is not supported",
+                    self.names[r]
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn elem_at(&self, pos: &Pos) -> Option<&Elem> {
+        self.rules[pos.rule][pos.alt].get(pos.idx)
+    }
+
+    /// Expand rule references at the top of the stack, until each resulting stack
+    /// is either empty (grammar accepted) or has a character element on top.
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>) {
+        while let Some(top) = stack.last().copied() {
+            match self.elem_at(&top) {
+                None => {
+                    stack.pop();
+                }
+                Some(Elem::Char { .. }) => break,
+                Some(Elem::Rule(r)) => {
+                    stack.pop();
+                    if top.idx + 1 < self.rules[top.rule][top.alt].len() {
+                        stack.push(Pos {
+                            idx: top.idx + 1,
+                            ..top
+                        });
+                    }
+                    for alt in 0..self.rules[*r].len() {
+                        let mut s = stack.clone();
+                        s.push(Pos {
+                            rule: *r,
+                            alt,
+                            idx: 0,
+                        });
+                        self.expand(s, out);
+                    }
+                    return;
+                }
+            }
+        }
+        if !out.contains(&stack) {
+            out.push(stack);
+        }
+    }
+
+    fn initial_stacks(&self) -> Vec<Stack> {
+        let mut out = Vec::new();
+        for alt in 0..self.rules[self.root].len() {
+            let pos = Pos {
+                rule: self.root,
+                alt,
+                idx: 0,
+            };
+            self.expand(vec![pos], &mut out);
+        }
+        out
+    }
+
+    fn advance(&self, stacks: &[Stack], c: u32) -> Vec<Stack> {
+        let mut out = Vec::new();
+        for stack in stacks {
+            if let Some(top) = stack.last() {
+                if self.elem_at(top).unwrap().matches(c) {
+                    let mut s = stack.clone();
+                    s.pop();
+                    s.push(Pos {
+                        idx: top.idx + 1,
+                        ..*top
+                    });
+                    self.expand(s, &mut out);
+                }
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pos {
+    rule: usize,
+    alt: usize,
+    idx: usize,
+}
+
+type Stack = Vec<Pos>;
+
+#[derive(Clone)]
+struct MatchState {
+    stacks: Arc<Vec<Stack>>,
+    // bytes of an incomplete UTF-8 character
+    utf8: Vec<u8>,
+}
+
+/// Byte-level recognizer for a GBNF grammar, to be used with `TokTrie::compute_bias()`.
+#[derive(Clone)]
+pub struct GrammarMatcher {
+    grammar: Arc<Grammar>,
+    states: Vec<MatchState>,
+}
+
+fn utf8_len(first: u8) -> usize {
+    match first {
+        0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 0,
+    }
+}
+
+impl GrammarMatcher {
+    pub fn new(grammar: Arc<Grammar>) -> Self {
+        let stacks = grammar.initial_stacks();
+        Self {
+            grammar,
+            states: vec![MatchState {
+                stacks: Arc::new(stacks),
+                utf8: Vec::new(),
+            }],
+        }
+    }
+
+    /// True if the characters so far are a complete match of the grammar
+    /// (which may still allow more of them).
+    pub fn can_end(&self) -> bool {
+        let st = self.states.last().unwrap();
+        st.utf8.is_empty() && st.stacks.iter().any(|s| s.is_empty())
+    }
+
+    /// True if the grammar doesn't allow any more characters.
+    pub fn is_finished(&self) -> bool {
+        let st = self.states.last().unwrap();
+        st.utf8.is_empty() && st.stacks.iter().all(|s| s.is_empty())
+    }
+}
+
+impl Recognizer for GrammarMatcher {
+    fn pop_bytes(&mut self, num: usize) {
+        self.states.truncate(self.states.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        let final_state = self.states.pop().unwrap();
+        self.states.clear();
+        self.states.push(final_state);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => self.can_end(),
+            _ => false,
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.states.len() == 1);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let st = self.states.last().unwrap();
+        let mut utf8 = st.utf8.clone();
+        utf8.push(byte);
+
+        let need = utf8_len(utf8[0]);
+        if need == 0 || (utf8.len() > 1 && byte & 0b1100_0000 != 0b1000_0000) {
+            return false;
+        }
+        if utf8.len() < need {
+            let stacks = st.stacks.clone();
+            self.states.push(MatchState { stacks, utf8 });
+            return true;
+        }
+
+        let c = match std::str::from_utf8(&utf8) {
+            Ok(s) => s.chars().next().unwrap() as u32,
+            Err(_) => return false,
+        };
+        let stacks = self.grammar.advance(&st.stacks, c);
+        if stacks.is_empty() {
+            false
+        } else {
+            self.states.push(MatchState {
+                stacks: Arc::new(stacks),
+                utf8: Vec::new(),
+            });
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(src: &str) -> GrammarMatcher {
+        GrammarMatcher::new(Arc::new(Grammar::from_gbnf(src).unwrap()))
+    }
+
+    fn push_str(grm: &mut GrammarMatcher, s: &str) -> bool {
+        s.bytes().all(|b| grm.try_push_byte(b))
+    }
+
+    fn accepts(src: &str, s: &str) -> bool {
+        let mut grm = matcher(src);
+        push_str(&mut grm, s) && grm.can_end()
+    }
+
+    #[test]
+    fn parse() {
+        let src =
+            "# numbers\nroot ::= \"n=\" num (\",\" num)*\nnum ::= [0-9]+ | \"-\" [1-9] [0-9]?\n";
+        assert!(accepts(src, "n=1"));
+        assert!(accepts(src, "n=12,-3,-45"));
+        assert!(!accepts(src, "n="));
+        assert!(!accepts(src, "n=-0"));
+        assert!(!accepts(src, "n=1,"));
+        assert!(accepts("root ::= \"\\x41\\n\" .", "A\n?"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = |src: &str| Grammar::from_gbnf(src).unwrap_err().to_string();
+        assert!(err("foo ::= \"a\"").contains("no 'root' rule"));
+        assert!(err("root ::= foo").contains("undefined rule 'foo'"));
+        assert!(err("root ::= \"a\"\nroot ::= \"b\"").contains("defined twice"));
+        assert!(err("root ::= * \"a\"").contains("expecting preceding item"));
+        assert!(err("root ::= \"\\q\"").contains("unknown escape"));
+    }
+
+    #[test]
+    fn left_recursion() {
+        let err = |src: &str| Grammar::from_gbnf(src).unwrap_err().to_string();
+        assert!(err("root ::= root \"a\" | \"a\"").contains("left recursion"));
+        assert!(err("root ::= x \"a\"\nx ::= root \"b\" | \"c\"").contains("left recursion"));
+        // rules that can match nothing don't stop the recursion
+        assert!(err("root ::= x root \"a\" | \"b\"\nx ::= \"c\"?").contains("left recursion"));
+        assert!(Grammar::from_gbnf("root ::= \"a\" root | \"a\"").is_ok());
+    }
+
+    #[test]
+    fn utf8_ranges() {
+        let src = "root ::= [α-ω]+";
+        assert!(accepts(src, "αβω"));
+        assert!(!accepts(src, "a"));
+        assert!(!accepts(src, "Ω"));
+
+        // a partial character isn't a complete match
+        let mut grm = matcher(src);
+        assert!(grm.try_push_byte("α".as_bytes()[0]));
+        assert!(!grm.can_end());
+        assert!(grm.try_push_byte("α".as_bytes()[1]));
+        assert!(grm.can_end());
+        // invalid continuation byte
+        assert!(grm.try_push_byte(0xCE));
+        assert!(!grm.try_push_byte(b'a'));
+
+        assert!(accepts("root ::= [^a]", "é"));
+        assert!(accepts("root ::= [^a]", "😀"));
+        assert!(!accepts("root ::= [^a]", "a"));
+        assert!(accepts("root ::= \"\\u00e9\"", "é"));
+    }
+
+    #[test]
+    fn special_allowed() {
+        let mut grm = matcher("root ::= \"ab\" \"c\"?");
+        assert!(!grm.special_allowed(SpecialToken::EndOfSentence));
+        assert!(push_str(&mut grm, "ab"));
+        grm.collapse();
+        assert!(grm.special_allowed(SpecialToken::EndOfSentence));
+        assert!(!grm.is_finished());
+        assert!(!grm.special_allowed(SpecialToken::BeginningOfSentence));
+        assert!(push_str(&mut grm, "c"));
+        assert!(grm.special_allowed(SpecialToken::EndOfSentence));
+        assert!(grm.is_finished());
+        grm.pop_bytes(1);
+        assert!(!grm.is_finished());
+    }
+}
@@ -1,3 +1,5 @@
+pub mod fim;
+pub mod grammar;
 pub mod seq;
 
 // vllm modules
@@ -5,19 +7,24 @@ pub mod config;
 mod engine;
 mod exec;
 mod expected;
+pub mod gguf;
 pub mod iface;
 mod logits;
+mod migration;
 mod scheduler;
+mod session;
 pub mod server;
+pub mod sim;
 pub mod util;
 
 use config::AiciConfig;
 pub use engine::*;
 pub use exec::*;
-pub use logits::LogitsProcessor;
+pub use logits::{LogitsProcessor, LogitsProcessorState, Logprobs};
 pub use scheduler::*;
 use std::sync::atomic::AtomicBool;
 
+pub use aici_abi::SimpleVob;
 pub use aicirt::HashMap;
 pub use aicirt::HashSet;
 
@@ -27,6 +34,8 @@ pub struct LoaderArgs {
     pub revision: Option<String>,
     pub file: Option<String>,
     pub local_weights: Option<String>,
+    /// Only use files already in the HuggingFace cache.
+    pub offline: bool,
     pub alt: usize,
     pub aici: AiciConfig,
 }
@@ -38,6 +47,7 @@ impl Default for LoaderArgs {
             model_id: "NousResearch/Llama-2-7b-hf".to_string(),
             revision: None,
             local_weights: None,
+            offline: std::env::var("HF_HUB_OFFLINE").map_or(false, |v| v == "1"),
             file: None,
             aici: AiciConfig::default(),
             alt: 0,
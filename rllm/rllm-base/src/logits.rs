@@ -1,10 +1,25 @@
 // based on https://github.com/huggingface/candle/blob/main/candle-transformers/src/generation/mod.rs
 
 use crate::config::{SamplingParams, SAMPLING_EPS};
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 
 pub struct LogitsProcessor {
-    pub rng: rand::rngs::StdRng,
+    // re-seeded from (seed, num_samples) on every sample, so that the whole
+    // state is captured by LogitsProcessorState
+    rng: rand::rngs::StdRng,
+    seed: u64,
+    num_samples: u64,
+    pub temperature: Option<f32>,
+    pub top_p: f32,
+}
+
+/// Sampler state; a processor restored from it draws the same samples
+/// as the original would, regardless of swapping or preemption in between.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogitsProcessorState {
+    pub seed: u64,
+    pub num_samples: u64,
     pub temperature: Option<f32>,
     pub top_p: f32,
 }
@@ -17,14 +32,54 @@ impl LogitsProcessor {
             Some(sampling_params.temperature)
         };
 
-        Self {
-            rng: rand::rngs::StdRng::from_entropy(),
-            // seed_from_u64(42),
+        Self::from_state(&LogitsProcessorState {
+            seed: rand::random(),
+            // seed: 42,
+            num_samples: 0,
             temperature,
             top_p: sampling_params.top_p,
+        })
+    }
+
+    pub fn from_state(state: &LogitsProcessorState) -> Self {
+        Self {
+            rng: rand::rngs::StdRng::seed_from_u64(state.seed),
+            seed: state.seed,
+            num_samples: state.num_samples,
+            temperature: state.temperature,
+            top_p: state.top_p,
+        }
+    }
+
+    pub fn state(&self) -> LogitsProcessorState {
+        LogitsProcessorState {
+            seed: self.seed,
+            num_samples: self.num_samples,
+            temperature: self.temperature,
+            top_p: self.top_p,
         }
     }
 
+    /// RNG to be used for drawing the next sample.
+    pub fn sample_rng(&mut self) -> &mut rand::rngs::StdRng {
+        self.rng = rand::rngs::StdRng::seed_from_u64(
+            self.seed ^ self.num_samples.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+        );
+        self.num_samples += 1;
+        &mut self.rng
+    }
+
+    /// Create a processor with the same settings, but an independent RNG stream.
+    pub fn fork(&mut self) -> Self {
+        let seed = self.sample_rng().gen();
+        Self::from_state(&LogitsProcessorState {
+            seed,
+            num_samples: 0,
+            temperature: self.temperature,
+            top_p: self.top_p,
+        })
+    }
+
     pub fn set_temperature(&mut self, temperature: f32) {
         if temperature < SAMPLING_EPS {
             self.temperature = None;
@@ -33,3 +88,32 @@ impl LogitsProcessor {
         }
     }
 }
+
+/// Log-probability of a sampled token, with the most likely alternatives.
+#[derive(Debug, Clone)]
+pub struct Logprobs {
+    pub logprob: f32,
+    /// Up to SamplingParams.logprobs most likely tokens, most likely first.
+    pub top: Vec<(u32, f32)>,
+}
+
+impl Logprobs {
+    pub fn from_logits(logits: &[f32], token: u32, num_top: usize) -> Self {
+        let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+        let mut top = Vec::new();
+        if num_top > 0 {
+            let mut idxs = (0..logits.len()).collect::<Vec<_>>();
+            idxs.sort_by(|&a, &b| logits[b].total_cmp(&logits[a]));
+            top = idxs
+                .iter()
+                .take(num_top)
+                .map(|&i| (i as u32, logits[i] - log_sum))
+                .collect();
+        }
+        Self {
+            logprob: logits[token as usize] - log_sum,
+            top,
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use crate::{
+    config::SamplingParams,
+    seq::{SamplerState, SchedulingPhase, Sequence, SequenceGroup, Token, TokenUsage},
+    SeqId,
+};
+use anyhow::{bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 8] = b"RLLMREQ1";
+
+/// A request taken out of an engine by RllmEngine::export_request().
+///
+/// Encoded as MAGIC, the length of the JSON header (u64, little-endian),
+/// the header, and then the KV blobs of the sequences (see ModelExec::export_kv()),
+/// kv_size bytes each.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ExportedRequest {
+    pub model_id: String,
+    pub request_id: String,
+    pub prompt: String,
+    pub sampling_params: SamplingParams,
+    pub priority: i32,
+    pub usage: TokenUsage,
+    pub metadata: Option<serde_json::Value>,
+    pub lineage: Vec<String>,
+    pub max_index: usize,
+    pub sampler: SamplerState,
+    pub seqs: Vec<ExportedSeq>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ExportedSeq {
+    pub index: usize,
+    pub tokens: Vec<Token>,
+    pub prompt_len: usize,
+    pub output_ptr: usize,
+    pub output_pending: Vec<u8>,
+    pub healing_prefix: Option<Vec<u8>>,
+    pub echo: bool,
+    pub strip_tokens: Vec<Token>,
+    pub guidance: bool,
+    /// Number of tokens with KV in the blob; 0 if they are recomputed.
+    pub kv_len: usize,
+    pub kv_size: usize,
+}
+
+impl ExportedRequest {
+    /// State of the unfinished sequences of the group, and their ids; KV is
+    /// exported for the ones on the GPU, up to the tokens computed so far.
+    pub fn from_group(model_id: &str, sg: &SequenceGroup) -> Result<(Self, Vec<SeqId>)> {
+        let params = &sg.sampling_params;
+        if params.controller.is_some() {
+            bail!("requests with an AICI controller can't be exported");
+        }
+        if params.hidden_states.is_some() || sg.stop_criterion.is_some() {
+            bail!("requests with hidden states or a stop criterion can't be exported");
+        }
+        let mut seqs = Vec::new();
+        let mut seq_ids = Vec::new();
+        for seq in sg.seqs.iter().filter(|seq| !seq.is_finished()) {
+            if seq.expected.is_some() || !seq.embedding_overrides().is_empty() {
+                bail!("requests with expected output or embeddings can't be exported");
+            }
+            let on_gpu = seq.sched_phase == SchedulingPhase::Running;
+            // unverified draft tokens are left out
+            let len = seq.get_verified_len();
+            seqs.push(ExportedSeq {
+                index: seq.index,
+                tokens: seq.get_tokens()[..len].to_vec(),
+                prompt_len: seq.prompt_len,
+                output_ptr: seq.output_ptr,
+                output_pending: seq.output_pending.clone(),
+                healing_prefix: seq.healing_prefix.clone(),
+                echo: seq.echo,
+                strip_tokens: seq.strip_tokens.clone(),
+                guidance: seq.guidance,
+                kv_len: if on_gpu {
+                    std::cmp::min(seq.num_kv_computed, len)
+                } else {
+                    0
+                },
+                kv_size: 0,
+            });
+            seq_ids.push(seq.seq_id);
+        }
+        if seqs.is_empty() {
+            bail!("request {} is finished", sg.request_id);
+        }
+        let req = ExportedRequest {
+            model_id: model_id.to_string(),
+            request_id: sg.request_id.clone(),
+            prompt: sg.prompt.clone(),
+            sampling_params: sg.sampling_params.clone(),
+            priority: sg.priority,
+            usage: sg.usage.clone(),
+            metadata: sg.metadata.clone(),
+            lineage: sg.lineage.clone(),
+            max_index: sg.max_index,
+            sampler: sg.sampler_state(),
+            seqs,
+        };
+        Ok((req, seq_ids))
+    }
+
+    pub fn encode(mut self, kv: Vec<Vec<u8>>) -> Result<Vec<u8>> {
+        assert!(kv.len() == self.seqs.len());
+        for (seq, data) in self.seqs.iter_mut().zip(kv.iter()) {
+            seq.kv_size = data.len();
+        }
+        let header = serde_json::to_vec(&self)?;
+        let mut r = Vec::with_capacity(MAGIC.len() + 8 + header.len());
+        r.extend_from_slice(MAGIC);
+        r.extend_from_slice(&(header.len() as u64).to_le_bytes());
+        r.extend_from_slice(&header);
+        for data in kv {
+            r.extend_from_slice(&data);
+        }
+        Ok(r)
+    }
+
+    /// Inverse of encode(); the KV blobs are slices of `data`.
+    pub fn decode(data: &[u8]) -> Result<(Self, Vec<&[u8]>)> {
+        ensure!(
+            data.len() >= MAGIC.len() + 8 && data.starts_with(MAGIC),
+            "not an exported request"
+        );
+        let mut ptr = MAGIC.len();
+        let header_len = u64::from_le_bytes(data[ptr..ptr + 8].try_into().unwrap()) as usize;
+        ptr += 8;
+        ensure!(
+            data.len() - ptr >= header_len,
+            "exported request is truncated"
+        );
+        let req: ExportedRequest = serde_json::from_slice(&data[ptr..ptr + header_len])?;
+        ptr += header_len;
+        let mut kv = Vec::with_capacity(req.seqs.len());
+        for seq in req.seqs.iter() {
+            ensure!(
+                data.len() - ptr >= seq.kv_size,
+                "exported request is truncated"
+            );
+            kv.push(&data[ptr..ptr + seq.kv_size]);
+            ptr += seq.kv_size;
+        }
+        ensure!(ptr == data.len(), "trailing data after exported request");
+        Ok((req, kv))
+    }
+}
+
+impl ExportedSeq {
+    /// Restore the exported state on a new sequence; settings derived from
+    /// the sampling parameters are left to the caller.
+    pub fn to_sequence(&self, seq_id: SeqId) -> Sequence {
+        let mut seq = Sequence::new(seq_id, &self.tokens);
+        seq.index = self.index;
+        seq.prompt_len = self.prompt_len;
+        seq.output_ptr = self.output_ptr;
+        seq.output_pending = self.output_pending.clone();
+        // decoded from the tokens with the next output
+        seq.output_text = None;
+        seq.healing_prefix = self.healing_prefix.clone();
+        seq.echo = self.echo;
+        seq.strip_tokens = self.strip_tokens.clone();
+        seq.guidance = self.guidance;
+        seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LogitsProcessorState;
+
+    fn exported_seq(index: usize, tokens: Vec<Token>, kv_len: usize) -> ExportedSeq {
+        ExportedSeq {
+            index,
+            prompt_len: 2,
+            output_ptr: 2,
+            output_pending: vec![0xc3],
+            healing_prefix: None,
+            echo: false,
+            strip_tokens: vec![],
+            guidance: false,
+            kv_len,
+            kv_size: 0,
+            tokens,
+        }
+    }
+
+    fn exported_request() -> ExportedRequest {
+        let sampler = LogitsProcessorState {
+            seed: 42,
+            num_samples: 3,
+            temperature: Some(0.5),
+            top_p: 0.9,
+        };
+        ExportedRequest {
+            model_id: "model".to_string(),
+            request_id: "req".to_string(),
+            prompt: "hi".to_string(),
+            sampling_params: SamplingParams::default(),
+            priority: 1,
+            usage: TokenUsage::default(),
+            metadata: None,
+            lineage: vec!["parent".to_string()],
+            max_index: 1,
+            sampler: SamplerState {
+                group: sampler,
+                seqs: vec![],
+            },
+            seqs: vec![
+                exported_seq(0, vec![1, 2, 3, 4], 3),
+                exported_seq(1, vec![1, 2, 5], 0),
+            ],
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let data = exported_request()
+            .encode(vec![vec![7, 8, 9], vec![]])
+            .unwrap();
+        let (req, kv) = ExportedRequest::decode(&data).unwrap();
+        assert_eq!(req.request_id, "req");
+        assert_eq!(req.lineage, vec!["parent".to_string()]);
+        assert_eq!(req.sampler.group.num_samples, 3);
+        assert_eq!(req.seqs.len(), 2);
+        assert_eq!(req.seqs[0].tokens, vec![1, 2, 3, 4]);
+        assert_eq!(req.seqs[0].kv_size, 3);
+        assert_eq!(req.seqs[1].kv_size, 0);
+        assert_eq!(kv, vec![&[7u8, 8, 9][..], &[]]);
+
+        let seq = req.seqs[0].to_sequence(SeqId(5));
+        assert_eq!(seq.get_tokens(), &[1, 2, 3, 4]);
+        assert_eq!(seq.prompt_len, 2);
+        assert_eq!(seq.output_pending, vec![0xc3]);
+        assert_eq!(seq.num_kv_computed, 0);
+    }
+
+    #[test]
+    fn decode_errors() {
+        let data = exported_request()
+            .encode(vec![vec![7, 8, 9], vec![]])
+            .unwrap();
+        let err = |data: &[u8]| ExportedRequest::decode(data).err().unwrap().to_string();
+        assert!(err(&data[..data.len() - 1]).contains("truncated"));
+        assert!(err(&data[..20]).contains("truncated"));
+        assert!(err(&[data.as_slice(), &[0]].concat()).contains("trailing data"));
+        assert!(err(b"RLLMREQ0\0\0\0\0\0\0\0\0").contains("not an exported request"));
+        assert!(err(b"RLLM").contains("not an exported request"));
+    }
+}
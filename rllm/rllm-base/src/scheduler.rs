@@ -1,19 +1,21 @@
 use crate::{
-    config::RllmConfig,
+    config::{RllmConfig, SchedulerConfig, SchedulerPolicy},
     seq::{FinishReason, SchedulingPhase, Sequence, SequenceGroup},
     util::limit_str,
-    HashMap, ModelExec, SequenceManager, TBlockSpaceManager,
+    BlockPoolStats, HashMap, ModelExec, SequenceManager, TBlockSpaceManager,
 };
 use aicirt::api::SequenceResult;
+use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
     ops::Deref,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
     vec::Vec,
 };
 
 /// Preemption modes.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PreemptionMode {
     /// Swap out the blocks of the preempted sequences to CPU memory
     /// and swap them back in when the sequences are resumed.
@@ -25,16 +27,49 @@ pub enum PreemptionMode {
     Recompute,
 }
 
+/// Summary of the decisions of a single scheduling step.
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct StepMetrics {
+    /// Set by the engine.
+    pub step_no: usize,
+    pub num_seq_groups: usize,
+    /// Tokens of prompts (or recomputed sequences) in this step.
+    pub num_prompt_tokens: usize,
+    /// Sequences generating a token in this step.
+    pub num_decode_tokens: usize,
+    pub num_preempted: usize,
+    /// Prompt tokens whose KV was found in the prefix cache.
+    pub num_cached_prompt_tokens: usize,
+    pub num_swapped_in_blocks: usize,
+    pub num_swapped_out_blocks: usize,
+    pub num_free_gpu_blocks: usize,
+    pub num_free_cpu_blocks: usize,
+    pub num_gpu_blocks: usize,
+    pub num_cpu_blocks: usize,
+    /// High-water marks of block usage so far.
+    pub max_used_gpu_blocks: usize,
+    pub max_used_cpu_blocks: usize,
+    /// See BlockPoolStats::gpu_fragmentation.
+    pub gpu_fragmentation: f32,
+    /// Prefix cache blocks evicted so far.
+    pub num_evicted_blocks: usize,
+}
+
 /// Scheduler outputs.
 pub struct SchedulerOutputs {
     pub prompt_run: bool,
     pub num_batched_tokens: usize,
+    pub num_preempted: usize,
+    /// Swapped-in groups only run in the next step, so these copies can overlap
+    /// with the forward pass of this step.
     pub blocks_to_swap_in: HashMap<usize, usize>,
     pub blocks_to_swap_out: HashMap<usize, usize>,
     pub blocks_to_copy: HashMap<usize, Vec<usize>>,
 
     pub next_seq_groups: Vec<SequenceGroup>,
     pub dropped_seq_groups: Vec<SequenceGroup>,
+
+    pub metrics: StepMetrics,
 }
 
 impl SchedulerOutputs {
@@ -42,24 +77,31 @@ impl SchedulerOutputs {
         SchedulerOutputs {
             prompt_run: false,
             num_batched_tokens: 0,
+            num_preempted: 0,
             blocks_to_swap_in: HashMap::default(),
             blocks_to_swap_out: HashMap::default(),
             blocks_to_copy: HashMap::default(),
             dropped_seq_groups: Vec::new(),
             next_seq_groups: Vec::new(),
+            metrics: StepMetrics::default(),
         }
     }
     fn validate(&self) {
         assert!(self.blocks_to_swap_in.is_empty() || self.blocks_to_swap_out.is_empty());
-        // swapping not impl yet
-        assert!(self.blocks_to_swap_in.is_empty());
-        assert!(self.blocks_to_swap_out.is_empty());
 
         self.dropped_seq_groups.iter().for_each(|sg| {
             assert!(sg.is_finished());
         });
         self.next_seq_groups.iter().for_each(|sg| {
             assert!(!sg.is_finished());
+            // all sequences of a group are scheduled together
+            assert!(
+                sg.seqs
+                    .iter()
+                    .all(|seq| seq.is_finished() || seq.sched_phase == SchedulingPhase::Running),
+                "seq_group {} is only partially scheduled",
+                sg.request_id
+            );
         });
     }
     pub fn is_empty(&self) -> bool {
@@ -78,9 +120,152 @@ impl SchedulerOutputs {
     }
 }
 
+/// Snapshot of a sequence group, see Scheduler::debug_state().
+#[derive(Debug, Clone, Serialize)]
+pub struct SeqGroupState {
+    pub request_id: String,
+    pub priority: i32,
+    /// Phase of each sequence.
+    pub phases: Vec<String>,
+    /// Length of the longest sequence, including prompt.
+    pub num_tokens: usize,
+    pub num_kv_computed: usize,
+    pub num_blocks: usize,
+    /// Seconds since arrival.
+    pub age: f64,
+}
+
+/// What the scheduler did in the last step.
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct StepDecisions {
+    pub scheduled: Vec<String>,
+    pub preempted: Vec<String>,
+    pub swapped_in: Vec<String>,
+    pub dropped: Vec<String>,
+    pub num_batched_tokens: usize,
+    /// The first waiting group that couldn't be admitted, and why.
+    pub waiting_blocked: Option<(String, String)>,
+}
+
+/// Serializable snapshot of the scheduler, for debugging.
+#[derive(Debug, Clone, Serialize)]
+pub struct SchedulerState {
+    pub waiting: Vec<SeqGroupState>,
+    pub on_gpu: Vec<SeqGroupState>,
+    pub swapped: Vec<SeqGroupState>,
+    pub paused: Vec<SeqGroupState>,
+    pub num_free_gpu_blocks: usize,
+    pub num_free_cpu_blocks: usize,
+    pub last_step: StepDecisions,
+}
+
+/// Scheduling decisions that don't involve KV block accounting;
+/// the Scheduler still checks that admitted groups fit in the cache and the batch.
+/// See Scheduler::set_policy().
+pub trait SchedulingPolicy: Send {
+    /// Sort the queue, so that the group to run first is at the end,
+    /// and the preferred preemption victim is at the front.
+    fn order(&self, config: &SchedulerConfig, seq_groups: &mut Vec<SequenceGroup>, now: Instant);
+
+    /// Whether a waiting group can be admitted in this step; if not, it keeps waiting.
+    fn admit(&self, _config: &SchedulerConfig, _seq_group: &SequenceGroup, _now: Instant) -> bool {
+        true
+    }
+
+    /// Index of the group to preempt when KV blocks run out, among groups
+    /// on the GPU as sorted by order().
+    fn choose_victim(&self, _config: &SchedulerConfig, _running: &[SequenceGroup]) -> usize {
+        0
+    }
+}
+
+/// Notified of scheduler actions on sequence groups, so that state kept outside
+/// of the scheduler (controllers, session caches, metrics) can follow them.
+/// See Scheduler::set_hooks().
+pub trait SchedulerHooks: Send {
+    /// The group was preempted; called after on_swap_out() when it was swapped out,
+    /// otherwise its KV cache is already dropped, to be recomputed.
+    fn on_preempt(&mut self, _seq_group: &SequenceGroup, _mode: PreemptionMode) {}
+
+    /// The KV cache of the group is being moved to CPU memory.
+    fn on_swap_out(&mut self, _seq_group: &SequenceGroup) {}
+
+    /// The KV cache of the group is being moved back to the GPU.
+    fn on_swap_in(&mut self, _seq_group: &SequenceGroup) {}
+
+    /// A preempted group is scheduled again (after on_swap_in() if it was swapped).
+    fn on_resume(&mut self, _seq_group: &SequenceGroup) {}
+}
+
+/// Implements SchedulerConfig::policy.
+pub struct DefaultPolicy;
+
+impl SchedulingPolicy for DefaultPolicy {
+    fn order(&self, config: &SchedulerConfig, seq_groups: &mut Vec<SequenceGroup>, now: Instant) {
+        // note that we take elements first from the end of the queue (Vec::pop())
+        seq_groups.sort_by(|a, b| {
+            let by_arrival = b.arrival_time.cmp(&a.arrival_time);
+            let by_priority = || {
+                effective_priority(config, a, now)
+                    .cmp(&effective_priority(config, b, now))
+                    .then(by_arrival)
+            };
+            match config.policy {
+                SchedulerPolicy::Fcfs => by_arrival,
+                SchedulerPolicy::Priority
+                | SchedulerPolicy::FairShare
+                | SchedulerPolicy::WeightedQos => by_priority(),
+                SchedulerPolicy::Deadline => match (deadline(a), deadline(b)) {
+                    (Some(da), Some(db)) => db.cmp(&da).then(by_arrival),
+                    (Some(_), None) => std::cmp::Ordering::Greater,
+                    (None, Some(_)) => std::cmp::Ordering::Less,
+                    (None, None) => by_priority(),
+                },
+            }
+        });
+    }
+}
+
+/// Priority of the group, raised with the time it has been waiting (aging).
+pub fn effective_priority(
+    config: &SchedulerConfig,
+    seq_group: &SequenceGroup,
+    now: Instant,
+) -> i64 {
+    if is_overdue(config, seq_group, now) {
+        return i64::MAX;
+    }
+    let waited = now
+        .saturating_duration_since(seq_group.arrival_time)
+        .as_secs_f32();
+    let boost = match config.priority_aging_secs {
+        Some(secs) => (waited / secs) as i64,
+        None => 0,
+    };
+    seq_group.priority as i64 + boost
+}
+
+fn deadline(seq_group: &SequenceGroup) -> Option<Instant> {
+    let secs = seq_group.sampling_params.deadline?;
+    Some(seq_group.arrival_time + Duration::from_secs_f32(secs))
+}
+
+/// Whether the group waited longer than max_queue_secs and has to go first.
+pub fn is_overdue(config: &SchedulerConfig, seq_group: &SequenceGroup, now: Instant) -> bool {
+    match config.max_queue_secs {
+        Some(max) => {
+            now.saturating_duration_since(seq_group.arrival_time)
+                .as_secs_f32()
+                > max
+        }
+        None => false,
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 enum Queue {
-    /// These have no KV cache stored anywhere. Each sequence group has only 1 sequence.
+    /// These have no KV cache stored anywhere. Each sequence group has only 1 sequence,
+    /// or 2 with classifier-free guidance (the second one for the negative prompt).
     Waiting,
 
     /// These currently sit on GPU but are not scheduled to run next. The ones to run next are in SchedulerOutputs.
@@ -88,9 +273,12 @@ enum Queue {
 
     /// These are swapped out to CPU memory.
     Swapped,
+
+    /// These were paused by the user and are not scheduled until resumed.
+    Paused,
 }
 
-const NUM_QUEUES: usize = Queue::Swapped as usize + 1;
+const NUM_QUEUES: usize = Queue::Paused as usize + 1;
 
 /// Scheduler.
 pub struct Scheduler<ME: ModelExec> {
@@ -101,6 +289,14 @@ pub struct Scheduler<ME: ModelExec> {
     seq_mgr: Arc<ME::SequenceManager>,
 
     queues: Mutex<Vec<Vec<SequenceGroup>>>,
+    // finished groups that can still be continued, with the time they finished;
+    // the oldest first
+    kept: Vec<(Instant, SequenceGroup)>,
+    last_step: StepDecisions,
+    policy: Box<dyn SchedulingPolicy>,
+    hooks: Option<Box<dyn SchedulerHooks>>,
+    // virtual time of simulations (see sim::simulate()); None for the wall clock
+    clock: Option<Instant>,
 }
 
 impl<ME: ModelExec> Scheduler<ME> {
@@ -154,10 +350,8 @@ impl<ME: ModelExec> Scheduler<ME> {
         block_manager: ME::BlockSpaceManager,
         config: Arc<RllmConfig<ME>>,
     ) -> Self {
-        let prompt_limit = std::cmp::min(
-            config.scheduler.max_model_len,
-            config.scheduler.max_num_batched_tokens,
-        );
+        // longer prompts than max_num_batched_tokens are prefilled in chunks
+        let prompt_limit = config.scheduler.max_model_len;
         Self {
             config,
             seq_mgr,
@@ -165,9 +359,38 @@ impl<ME: ModelExec> Scheduler<ME> {
             block_manager,
             freed_seq_ids: RefCell::new(Vec::new()),
             queues: Mutex::new((0..NUM_QUEUES).map(|_| Vec::new()).collect()),
+            kept: Vec::new(),
+            last_step: StepDecisions::default(),
+            policy: Box::new(DefaultPolicy),
+            hooks: None,
+            clock: None,
         }
     }
 
+    /// Current time, as seen by the scheduler.
+    pub fn now(&self) -> Instant {
+        self.clock.unwrap_or_else(Instant::now)
+    }
+
+    /// Switch to a virtual clock, stopped at the current time; it only moves
+    /// with advance_clock().
+    pub(crate) fn stop_clock(&mut self) {
+        self.clock = Some(self.now());
+    }
+
+    pub(crate) fn advance_clock(&mut self, d: Duration) {
+        self.clock = Some(self.now() + d);
+    }
+
+    /// Replace the policy derived from SchedulerConfig::policy.
+    pub fn set_policy(&mut self, policy: Box<dyn SchedulingPolicy>) {
+        self.policy = policy;
+    }
+
+    pub fn set_hooks(&mut self, hooks: Option<Box<dyn SchedulerHooks>>) {
+        self.hooks = hooks;
+    }
+
     pub(crate) fn get_freed_seq_ids(&self) -> Vec<usize> {
         self.freed_seq_ids.borrow_mut().drain(..).collect()
     }
@@ -182,20 +405,157 @@ impl<ME: ModelExec> Scheduler<ME> {
         self.q_push(Queue::Waiting, seq_group);
     }
 
-    pub fn abort_seq_group(&mut self, request_id: &str) {
-        self.for_each_sg(|seq_group| {
-            if seq_group.request_id == request_id {
-                self.set_phase(seq_group, SchedulingPhase::Finished(FinishReason::Aborted));
+    /// Add a group whose sequences are past their prompts (e.g., imported with
+    /// their KV cache); it skips the waiting queue.
+    pub(crate) fn add_running_seq_group(&mut self, seq_group: SequenceGroup) {
+        log::debug!(
+            "add_running_seq_group: {}; {} tokens computed",
+            seq_group.request_id,
+            seq_group.seqs[0].num_kv_computed
+        );
+        self.q_push(Queue::OnGpu, seq_group);
+    }
+
+    /// Finish the group as aborted (freeing its blocks) and remove it from the queues.
+    pub fn abort_seq_group(&mut self, request_id: &str) -> Option<SequenceGroup> {
+        if self.release_seq_group(request_id) {
+            // it already returned its final output
+            return None;
+        }
+        let mut queues = self.queues.lock().unwrap();
+        for q in queues.iter_mut() {
+            if let Some(idx) = q.iter().position(|sg| sg.request_id == request_id) {
+                let mut seq_group = q.remove(idx);
+                self.set_phase(
+                    &mut seq_group,
+                    SchedulingPhase::Finished(FinishReason::Aborted),
+                );
+                return Some(seq_group);
             }
+        }
+        None
+    }
+
+    pub fn abort_all(&mut self) -> Vec<SequenceGroup> {
+        for (_, seq_group) in std::mem::take(&mut self.kept) {
+            self.release_seq_group_kv(seq_group);
+        }
+        let mut queues = self.queues.lock().unwrap();
+        let mut res = Vec::new();
+        for q in queues.iter_mut() {
+            for mut seq_group in q.drain(..) {
+                self.set_phase(
+                    &mut seq_group,
+                    SchedulingPhase::Finished(FinishReason::Aborted),
+                );
+                res.push(seq_group);
+            }
+        }
+        res
+    }
+
+    /// Take the group out of scheduling until resume_seq_group().
+    /// Single-sequence groups on GPU drop their KV cache (it's recomputed on resume);
+    /// the KV cache of other groups on GPU is swapped out (or dropped) in the next
+    /// step, as if they were preempted.
+    pub fn pause_seq_group(&mut self, request_id: &str) -> bool {
+        for q in [Queue::Waiting, Queue::OnGpu, Queue::Swapped] {
+            let seq_group = self.q_with(q, |q| {
+                q.iter()
+                    .position(|sg| sg.request_id == request_id)
+                    .map(|idx| q.remove(idx))
+            });
+            if let Some(mut seq_group) = seq_group {
+                if matches!(q, Queue::OnGpu)
+                    && seq_group.seqs.len() == 1
+                    && !seq_group.is_finished()
+                {
+                    self.set_phase(&mut seq_group, SchedulingPhase::Waiting);
+                }
+                log::debug!("paused seq_group {} from {:?}", request_id, q);
+                self.q_push(Queue::Paused, seq_group);
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn resume_seq_group(&mut self, request_id: &str) -> bool {
+        let seq_group = self.q_with(Queue::Paused, |q| {
+            q.iter()
+                .position(|sg| sg.request_id == request_id)
+                .map(|idx| q.remove(idx))
         });
+        match seq_group {
+            Some(seq_group) => {
+                let q = if seq_group
+                    .seqs
+                    .iter()
+                    .any(|s| s.sched_phase == SchedulingPhase::Swapped)
+                {
+                    Queue::Swapped
+                } else if seq_group
+                    .seqs
+                    .iter()
+                    .all(|s| s.sched_phase == SchedulingPhase::Waiting)
+                {
+                    Queue::Waiting
+                } else {
+                    Queue::OnGpu
+                };
+                log::debug!("resumed seq_group {} to {:?}", request_id, q);
+                self.q_push(q, seq_group);
+                true
+            }
+            None => false,
+        }
     }
 
     pub fn has_unfinished_seqs(&self) -> bool {
         self.get_num_unfinished_seq_groups() > 0
     }
 
+    /// Number of groups that still need scheduling; doesn't include paused groups.
     pub fn get_num_unfinished_seq_groups(&self) -> usize {
-        self.queues.lock().unwrap().iter().map(|q| q.len()).sum()
+        self.queues.lock().unwrap()[..Queue::Paused as usize]
+            .iter()
+            .map(|q| q.len())
+            .sum()
+    }
+
+    /// Number of waiting groups, and the total number of their tokens.
+    pub fn waiting_load(&self) -> (usize, usize) {
+        self.q_with(Queue::Waiting, |q| {
+            let num_tokens = q
+                .iter()
+                .flat_map(|sg| sg.seqs.iter())
+                .map(|seq| seq.get_len())
+                .sum();
+            (q.len(), num_tokens)
+        })
+    }
+
+    /// How much longer waiting prompts are held back to form a larger batch
+    /// (see SchedulerConfig::batch_window_ms); None if they can be admitted now.
+    pub fn batch_window_left(&self) -> Option<Duration> {
+        let sched = &self.config.scheduler;
+        let window = Duration::from_millis(sched.batch_window_ms?);
+        let left = self.q_with(Queue::Waiting, |q| {
+            if q.is_empty() || q.len() >= sched.batch_window_requests {
+                return None;
+            }
+            let oldest = q.iter().map(|sg| sg.arrival_time).min().unwrap();
+            window.checked_sub(self.now().saturating_duration_since(oldest))
+        })?;
+        if left.is_zero() {
+            None
+        } else {
+            Some(left)
+        }
+    }
+
+    pub fn get_num_paused_seq_groups(&self) -> usize {
+        self.q_len(Queue::Paused)
     }
 
     fn drop_finished(outputs: &mut SchedulerOutputs, q: &mut Vec<SequenceGroup>) {
@@ -219,6 +579,7 @@ impl<ME: ModelExec> Scheduler<ME> {
     }
 
     fn step_drop_finished(&mut self, outputs: &mut SchedulerOutputs) {
+        let now = self.now();
         self.for_each_sg(|sg| {
             if sg.sampling_params.controller.is_some() {
                 let fuel = sg.usage.fuel_tokens();
@@ -231,11 +592,23 @@ impl<ME: ModelExec> Scheduler<ME> {
                     self.set_phase(sg, SchedulingPhase::Finished(FinishReason::AiciOutOfFuel));
                 }
             }
+            if let Some(max_time) = sg.sampling_params.max_time {
+                let elapsed = now.saturating_duration_since(sg.arrival_time);
+                if !sg.is_finished() && elapsed.as_secs_f32() > max_time {
+                    log::warn!("seq_group {} timed out", sg.request_id);
+                    self.set_phase(sg, SchedulingPhase::Finished(FinishReason::TimedOut));
+                }
+            }
         });
 
         self.q_for_each(Queue::Waiting, |seq_group| {
-            assert!(seq_group.seqs.len() == 1);
-            let num_prompt_tokens = seq_group.get_seqs(None)[0].get_len();
+            // more than one sequence only with classifier-free guidance
+            let num_prompt_tokens = seq_group
+                .seqs
+                .iter()
+                .map(|seq| seq.get_len())
+                .max()
+                .unwrap();
             if num_prompt_tokens > self.prompt_limit {
                 log::warn!(
                     "Sequence group {} has a prompt that is too long ({} > {})",
@@ -243,7 +616,10 @@ impl<ME: ModelExec> Scheduler<ME> {
                     num_prompt_tokens,
                     self.prompt_limit
                 );
-                self.set_phase(seq_group, SchedulingPhase::Finished(FinishReason::Failed));
+                self.set_phase(
+                    seq_group,
+                    SchedulingPhase::Finished(FinishReason::ContextLengthExceeded),
+                );
             }
         });
 
@@ -260,77 +636,465 @@ impl<ME: ModelExec> Scheduler<ME> {
 
     fn step_prompts(&mut self, outputs: &mut SchedulerOutputs) {
         log::trace!("step_start_waiting ({} seqs)", self.q_len(Queue::Waiting));
-        self.sort_by_priority(Queue::Waiting);
+        self.sort_queue(Queue::Waiting);
+
+        let max_tokens = self.config.scheduler.max_num_batched_tokens;
+        let num_decoding = self.q_with(Queue::OnGpu, |q| Self::num_decoding(q))
+            + Self::num_decoding(&outputs.next_seq_groups);
+        let max_prompt_tokens = self.max_prompt_tokens(num_decoding);
+        let max_decode_seqs = self
+            .config
+            .scheduler
+            .max_num_decode_seqs
+            .unwrap_or(usize::MAX);
+        // sequences decoding once the admitted prompts are prefilled
+        let mut num_decoders = num_decoding;
+        // prompt tokens already in the batch (chunks of running sequences)
+        let mut num_prompt_tokens_batched = outputs
+            .num_batched_tokens
+            .saturating_sub(Self::num_decoding(&outputs.next_seq_groups));
+        // with FairShare and WeightedQos, the budget is split between tenants or classes
+        let shares = self.prompt_token_shares(max_tokens);
+        let mut limit_shares = shares.is_some();
+        let mut share_tokens: HashMap<String, usize> = HashMap::default();
+        let mut deferred = Vec::new();
+        // groups preempted to make room; they wait until the next step
+        let mut preempted = Vec::new();
+
+        let max_chunk = self
+            .config
+            .scheduler
+            .prefill_chunk_size
+            .unwrap_or(usize::MAX);
+        let mut num_curr_seqs = self.max_num_running_seq(Queue::OnGpu)
+            + outputs
+                .next_seq_groups
+                .iter()
+                .map(|sg| sg.get_max_num_running_seqs())
+                .sum::<usize>();
+        loop {
+            let mut blocked = false;
+            while let Some(mut seq_group) = self.q_pop(Queue::Waiting) {
+                let key = self.share_key(&seq_group);
+                let share_used = *share_tokens.get(&key).unwrap_or(&0);
+                let share = match (&shares, limit_shares) {
+                    // only keys with waiting groups at the start of the step have shares
+                    (Some(shares), true) => shares.get(&key).copied().unwrap_or(usize::MAX),
+                    _ => usize::MAX,
+                };
+                let now = self.now();
+                if !self.policy.admit(&self.config.scheduler, &seq_group, now) {
+                    deferred.push(seq_group);
+                    continue;
+                }
+                let overdue = self.is_overdue(&seq_group, now);
+                if share_used >= share && !overdue {
+                    // used up its share; give the others a chance
+                    deferred.push(seq_group);
+                    continue;
+                }
+                if !overdue && self.block_manager.is_prefix_pending(&seq_group) {
+                    // shares a prompt prefix with a group being prefilled;
+                    // it will be in the prefix cache shortly
+                    deferred.push(seq_group);
+                    continue;
+                }
 
-        let mut num_curr_seqs = self.max_num_running_seq(Queue::OnGpu);
-        while let Some(mut seq_group) = self.q_pop(Queue::Waiting) {
-            let num_prompt_tokens = seq_group.only_seq().get_len();
-            let num_new_seqs = seq_group.get_max_num_running_seqs();
+                // a prefix restored from a session is already computed
+                let num_prompt_tokens = seq_group
+                    .seqs
+                    .iter()
+                    .map(|seq| seq.num_pending_tokens())
+                    .sum::<usize>();
+                let num_new_seqs = seq_group.get_max_num_running_seqs();
 
-            log::trace!(
-                "seq_group {} has {} prompt tokens and {} new seqs",
-                seq_group.request_id,
-                num_prompt_tokens,
-                num_new_seqs
-            );
+                log::trace!(
+                    "seq_group {} has {} prompt tokens and {} new seqs",
+                    seq_group.request_id,
+                    num_prompt_tokens,
+                    num_new_seqs
+                );
 
-            // Check allocation and batch token limits
-            if !self.block_manager.can_allocate(&seq_group)
-                || outputs.num_batched_tokens + num_prompt_tokens
-                    > self.config.scheduler.max_num_batched_tokens
-                || num_curr_seqs + num_new_seqs > self.config.scheduler.max_num_seqs
+                let budget = max_prompt_tokens.saturating_sub(num_prompt_tokens_batched);
+                // a group that can't be chunked may go over its share
+                let chunk_budget = *[budget, share - share_used, max_chunk]
+                    .iter()
+                    .min()
+                    .unwrap();
+                let chunked =
+                    num_prompt_tokens > chunk_budget && budget > 0 && Self::can_chunk(&seq_group);
+                let num_step_tokens = if chunked {
+                    chunk_budget
+                } else {
+                    num_prompt_tokens
+                };
+
+                while !self.can_admit(&seq_group, num_curr_seqs + num_new_seqs)
+                    && self.release_oldest_kept()
+                {}
+
+                let expired = self.waited_too_long(&seq_group, now);
+                if expired {
+                    let num_waiting = self.q_len(Queue::Waiting);
+                    self.preempt_for(&seq_group, &mut num_curr_seqs, outputs);
+                    self.q_with(Queue::Waiting, |q| preempted.extend(q.drain(num_waiting..)));
+                }
+
+                // Check allocation and batch token limits; a group that waited
+                // too long may go over the token budget
+                let can_admit = self.can_admit(&seq_group, num_curr_seqs + num_new_seqs);
+                if !can_admit
+                    || (num_step_tokens > budget && !expired)
+                    || (num_decoders + num_new_seqs > max_decode_seqs && !expired)
+                    || num_curr_seqs + num_new_seqs > self.config.scheduler.max_num_seqs
+                {
+                    if expired {
+                        log::warn!(
+                            "seq_group {} waited too long and can't be admitted",
+                            seq_group.request_id
+                        );
+                        self.set_phase(
+                            &mut seq_group,
+                            SchedulingPhase::Finished(FinishReason::QueueTimeout),
+                        );
+                        outputs.dropped_seq_groups.push(seq_group);
+                        continue;
+                    }
+                    if limit_shares && share_used > 0 && !overdue {
+                        // this share already got some tokens; let the others try
+                        deferred.push(seq_group);
+                        continue;
+                    }
+                    let reason = if !can_admit {
+                        "not enough free blocks"
+                    } else if num_step_tokens > budget {
+                        "over max_num_batched_tokens"
+                    } else if num_decoders + num_new_seqs > max_decode_seqs {
+                        "over max_num_decode_seqs"
+                    } else {
+                        "over max_num_seqs"
+                    };
+                    self.last_step.waiting_blocked =
+                        Some((seq_group.request_id.clone(), reason.to_string()));
+                    self.q_push(Queue::Waiting, seq_group); // Put back the sequence group
+                    blocked = true;
+                    break;
+                }
+
+                if chunked {
+                    log::debug!(
+                        "seq_group {}: prefilling {} of {} prompt tokens",
+                        seq_group.request_id,
+                        num_step_tokens,
+                        num_prompt_tokens
+                    );
+                }
+                seq_group.seqs[0].prefill_chunk =
+                    if chunked { Some(num_step_tokens) } else { None };
+
+                if seq_group.scheduled_time.is_some() {
+                    // it was preempted (or paused) and is recomputed now
+                    if let Some(hooks) = self.hooks.as_mut() {
+                        hooks.on_resume(&seq_group);
+                    }
+                }
+                if seq_group.seqs[0].num_kv_computed > 0 {
+                    // restored from a session, holding the blocks of the prefix
+                    self.set_phase(&mut seq_group, SchedulingPhase::Running);
+                    self._append_slots(&mut seq_group, outputs);
+                } else {
+                    self._allocate(&mut seq_group);
+                }
+                // the block manager may have found a prefix in its cache
+                let num_cached = seq_group
+                    .seqs
+                    .iter()
+                    .map(|seq| seq.num_kv_computed)
+                    .sum::<usize>();
+                let num_step_tokens = if num_cached > 0 {
+                    outputs.metrics.num_cached_prompt_tokens += num_cached;
+                    seq_group
+                        .seqs
+                        .iter()
+                        .map(|seq| seq.step_positions().len())
+                        .sum()
+                } else {
+                    num_step_tokens
+                };
+                outputs.next_seq_groups.push(seq_group);
+                outputs.num_batched_tokens += num_step_tokens;
+                num_prompt_tokens_batched += num_step_tokens;
+                *share_tokens.entry(key).or_insert(0) += num_step_tokens;
+                num_curr_seqs += num_new_seqs;
+                num_decoders += num_new_seqs;
+            }
+
+            if limit_shares
+                && !blocked
+                && deferred.len() > 0
+                && num_prompt_tokens_batched < max_prompt_tokens
             {
-                self.q_push(Queue::Waiting, seq_group); // Put back the sequence group
-                break;
+                // the others didn't use all of their shares; lend the rest
+                limit_shares = false;
+                deferred.reverse();
+                self.q_with(Queue::Waiting, |q| q.append(&mut deferred));
+                continue;
             }
+            break;
+        }
 
-            self._allocate(&mut seq_group);
-            outputs.next_seq_groups.push(seq_group);
-            outputs.num_batched_tokens += num_prompt_tokens;
-            num_curr_seqs += num_new_seqs;
+        if deferred.len() > 0 {
+            self.q_with(Queue::Waiting, |q| q.append(&mut deferred));
+        }
+        if preempted.len() > 0 {
+            self.q_with(Queue::Waiting, |q| q.append(&mut preempted));
         }
     }
 
-    fn sort_by_priority(&self, q: Queue) {
+    /// Whether the group hasn't started within max_waiting_time.
+    fn waited_too_long(&self, seq_group: &SequenceGroup, now: Instant) -> bool {
+        match self.config.scheduler.max_waiting_time {
+            Some(max) => {
+                seq_group.scheduled_time.is_none()
+                    && now
+                        .saturating_duration_since(seq_group.arrival_time)
+                        .as_secs_f32()
+                        > max
+            }
+            None => false,
+        }
+    }
+
+    /// Preempt groups on the GPU (not scheduled in this step), as chosen by the policy,
+    /// until there is room for `seq_group`. Groups to be recomputed (and swapped groups
+    /// evicted for the ones swapped out) are pushed onto Waiting.
+    fn preempt_for(
+        &mut self,
+        seq_group: &SequenceGroup,
+        num_curr_seqs: &mut usize,
+        outputs: &mut SchedulerOutputs,
+    ) {
+        let num_new_seqs = seq_group.get_max_num_running_seqs();
+        let max_num_seqs = self.config.scheduler.max_num_seqs;
+        self.sort_queue(Queue::OnGpu);
+        while !self.block_manager.can_allocate(seq_group)
+            || *num_curr_seqs + num_new_seqs > max_num_seqs
+        {
+            let victim = self.q_with(Queue::OnGpu, |q| {
+                if q.is_empty() {
+                    None
+                } else {
+                    let idx = self.policy.choose_victim(&self.config.scheduler, q);
+                    Some(q.remove(idx))
+                }
+            });
+            match victim {
+                Some(victim) => {
+                    *num_curr_seqs =
+                        num_curr_seqs.saturating_sub(victim.get_max_num_running_seqs());
+                    self._preempt(victim, outputs);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Prompt token budget of each tenant (or QoS class) that has waiting groups,
+    /// proportional to its weight; None if the policy doesn't split the budget.
+    fn prompt_token_shares(&self, max_tokens: usize) -> Option<HashMap<String, usize>> {
+        match self.config.scheduler.policy {
+            SchedulerPolicy::FairShare | SchedulerPolicy::WeightedQos => {}
+            _ => return None,
+        }
+        let mut weights: HashMap<String, usize> = HashMap::default();
+        self.q_for_each(Queue::Waiting, |sg| {
+            let key = self.share_key(sg);
+            let weight = self.share_weight(&key);
+            weights.insert(key, weight);
+        });
+        let total = std::cmp::max(1, weights.values().sum::<usize>());
+        Some(
+            weights
+                .into_iter()
+                .map(|(key, w)| (key, std::cmp::max(1, max_tokens * w / total)))
+                .collect(),
+        )
+    }
+
+    fn share_key(&self, seq_group: &SequenceGroup) -> String {
+        let params = &seq_group.sampling_params;
+        let key = match self.config.scheduler.policy {
+            SchedulerPolicy::WeightedQos => params.qos_class.as_deref(),
+            _ => params.tenant_id.as_deref(),
+        };
+        key.unwrap_or("").to_string()
+    }
+
+    fn share_weight(&self, key: &str) -> usize {
+        match self.config.scheduler.policy {
+            SchedulerPolicy::WeightedQos => self
+                .config
+                .scheduler
+                .qos_classes
+                .iter()
+                .find(|c| c.name == key)
+                .map_or(1, |c| c.weight),
+            _ => 1,
+        }
+    }
+
+    /// Only groups with a single sequence and no controller are prefilled in chunks
+    /// (controllers expect to see the logits after each forward pass).
+    fn can_chunk(seq_group: &SequenceGroup) -> bool {
+        // draft tokens are verified together
+        seq_group.seqs.len() == 1
+            && seq_group.sampling_params.controller.is_none()
+            && seq_group.seqs[0].num_draft == 0
+    }
+
+    /// Number of running sequences that generate a single token (possibly after
+    /// draft tokens) in this step.
+    fn num_decoding(seq_groups: &[SequenceGroup]) -> usize {
+        seq_groups
+            .iter()
+            .map(|sg| {
+                sg.get_seqs(Some(SchedulingPhase::Running))
+                    .iter()
+                    .filter(|seq| seq.num_pending_tokens() <= 1 + seq.num_draft)
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Tokens of the step budget that prompts can't use while `num_decoding`
+    /// sequences are decoding (see SchedulerConfig::decode_reserve).
+    fn decode_reserve(&self, num_decoding: usize) -> usize {
+        if num_decoding == 0 {
+            return 0;
+        }
+        let sched = &self.config.scheduler;
+        let reserve =
+            (sched.decode_reserve.clamp(0.0, 1.0) * sched.max_num_batched_tokens as f32) as usize;
+        std::cmp::max(reserve, num_decoding)
+    }
+
+    /// Prompt tokens allowed in a step while `num_decoding` sequences are decoding.
+    fn max_prompt_tokens(&self, num_decoding: usize) -> usize {
+        let sched = &self.config.scheduler;
+        let max_tokens = sched
+            .max_num_batched_tokens
+            .saturating_sub(self.decode_reserve(num_decoding));
+        std::cmp::min(
+            max_tokens,
+            sched.max_num_prefill_tokens.unwrap_or(usize::MAX),
+        )
+    }
+
+    /// Limit the number of prompt tokens computed in this step for sequences
+    /// with many pending tokens, so that decoding sequences are not starved.
+    fn set_prefill_chunks(&self) {
+        let num_decoding = self.q_with(Queue::OnGpu, |q| Self::num_decoding(q));
+        let max_chunk = self
+            .config
+            .scheduler
+            .prefill_chunk_size
+            .unwrap_or(usize::MAX);
+        let mut budget = self.max_prompt_tokens(num_decoding);
+        self.q_with(Queue::OnGpu, |seq_groups| {
+            // highest priority is at the end
+            for sg in seq_groups.iter_mut().rev() {
+                let can_chunk = Self::can_chunk(sg);
+                for seq in sg.seqs.iter_mut() {
+                    seq.prefill_chunk = None;
+                    let pending = seq.num_pending_tokens();
+                    if seq.sched_phase != SchedulingPhase::Running || pending <= 1 + seq.num_draft {
+                        continue;
+                    }
+                    // always make some progress
+                    let chunk =
+                        std::cmp::max(1, *[pending, budget, max_chunk].iter().min().unwrap());
+                    budget = budget.saturating_sub(chunk);
+                    if chunk < pending && can_chunk {
+                        seq.prefill_chunk = Some(chunk);
+                    }
+                }
+            }
+        });
+    }
+
+    fn sort_queue(&self, q: Queue) {
+        let now = self.now();
         self.q_with(q, |seq_groups| {
-            // note that we take elements first from the end of the queue (Vec::pop())
-            seq_groups.sort_by_key(|g| g.arrival_time);
-            seq_groups.reverse();
+            self.policy.order(&self.config.scheduler, seq_groups, now)
         });
     }
 
+    fn is_overdue(&self, seq_group: &SequenceGroup, now: Instant) -> bool {
+        is_overdue(&self.config.scheduler, seq_group, now)
+    }
+
     /// Move sequences from OnGpu queue to outputs.next_seq_groups or
     /// to Swapped/Waiting queues (preemption).
     fn step_generation(&mut self, outputs: &mut SchedulerOutputs) -> bool {
         let mut did_preempt = false;
-        self.sort_by_priority(Queue::OnGpu);
-
-        let mut suspended = Vec::new();
-
-        while let Some(mut seq_group) = self.q_pop(Queue::OnGpu) {
+        self.sort_queue(Queue::OnGpu);
+        self.set_prefill_chunks();
+
+        let max_tokens = self.config.scheduler.max_num_batched_tokens;
+        let max_decode_seqs = self
+            .config
+            .scheduler
+            .max_num_decode_seqs
+            .unwrap_or(usize::MAX);
+        let mut num_tokens = 0;
+        let mut num_decode_seqs = 0;
+        // suspended groups, and groups that don't fit in the token budget of this step
+        let mut not_scheduled = Vec::new();
+
+        'groups: while let Some(mut seq_group) = self.q_pop(Queue::OnGpu) {
             if seq_group.is_suspended() {
-                suspended.push(seq_group);
+                not_scheduled.push(seq_group);
+                continue;
+            }
+            let num_step_tokens = seq_group
+                .get_seqs(Some(SchedulingPhase::Running))
+                .iter()
+                .map(|seq| seq.step_positions().len())
+                .sum::<usize>();
+            let num_group_decode_seqs = Self::num_decoding(std::slice::from_ref(&seq_group));
+            if (num_tokens + num_step_tokens > max_tokens
+                || num_decode_seqs + num_group_decode_seqs > max_decode_seqs)
+                && num_tokens > 0
+            {
+                not_scheduled.push(seq_group);
                 continue;
             }
             while !self.block_manager.can_append_slot(&seq_group) {
+                if self.release_oldest_kept() {
+                    continue;
+                }
                 did_preempt = true;
                 if self.q_len(Queue::OnGpu) > 0 {
-                    // take the first group in queue (lowest priority)
-                    let victim_seq_group = self.q_with(Queue::OnGpu, |q| q.remove(0));
+                    let victim_seq_group = self.q_with(Queue::OnGpu, |q| {
+                        let idx = self.policy.choose_victim(&self.config.scheduler, q);
+                        q.remove(idx)
+                    });
                     self._preempt(victim_seq_group, outputs);
                 } else {
                     // preempt the current sequence group and stop
                     self._preempt(seq_group, outputs);
-                    return did_preempt;
+                    break 'groups;
                 }
             }
 
             self._append_slots(&mut seq_group, outputs);
             outputs.next_seq_groups.push(seq_group);
+            num_tokens += num_step_tokens;
+            num_decode_seqs += num_group_decode_seqs;
         }
 
-        if suspended.len() > 0 {
-            self.q_with(Queue::OnGpu, |q| q.append(&mut suspended));
+        if not_scheduled.len() > 0 {
+            self.q_with(Queue::OnGpu, |q| q.append(&mut not_scheduled));
         }
 
         return did_preempt;
@@ -355,36 +1119,138 @@ impl<ME: ModelExec> Scheduler<ME> {
     }
 
     fn _preempt(&mut self, mut seq_group: SequenceGroup, outputs: &mut SchedulerOutputs) {
-        let mode = if seq_group.get_max_num_running_seqs() == 1 {
+        let q = self.move_off_gpu(&mut seq_group, outputs);
+        self.q_push(q, seq_group);
+    }
+
+    /// Swap out the KV cache of a group on GPU, or drop it to be recomputed;
+    /// returns the queue the group goes to when it's to be scheduled again.
+    fn move_off_gpu(
+        &mut self,
+        seq_group: &mut SequenceGroup,
+        outputs: &mut SchedulerOutputs,
+    ) -> Queue {
+        let mode = self.preemption_mode(seq_group);
+        let mode = if mode == PreemptionMode::Swap
+            && !self.block_manager.can_swap_out(seq_group)
+            && !self.evict_swapped_for(seq_group)
+        {
+            log::warn!(
+                "not enough CPU swap space for seq_group {}; recomputing",
+                seq_group.request_id
+            );
             PreemptionMode::Recompute
         } else {
-            PreemptionMode::Swap
+            mode
         };
 
         log::debug!("preempting seq_group {} ({:?})", seq_group.request_id, mode);
+        outputs.num_preempted += 1;
+        self.last_step.preempted.push(seq_group.request_id.clone());
 
-        match mode {
+        let q = match mode {
             PreemptionMode::Swap => {
-                if !self.block_manager.can_swap_out(&seq_group) {
-                    panic!("Aborted due to the lack of CPU swap space. Please increase the swap space to avoid this error.");
-                }
-                let map = self.block_manager.swap_out(&mut seq_group);
+                let map = self.block_manager.swap_out(seq_group);
                 outputs.blocks_to_swap_out.extend(map);
-                self.q_push(Queue::Swapped, seq_group);
+                if let Some(hooks) = self.hooks.as_mut() {
+                    hooks.on_swap_out(seq_group);
+                }
+                Queue::Swapped
             }
             PreemptionMode::Recompute => {
-                self.set_phase(&mut seq_group, SchedulingPhase::Waiting);
-                self.q_push(Queue::Waiting, seq_group);
+                self.set_phase(seq_group, SchedulingPhase::Waiting);
+                Queue::Waiting
             }
+        };
+        if let Some(hooks) = self.hooks.as_mut() {
+            hooks.on_preempt(seq_group, mode);
         }
+        q
+    }
+
+    /// Move paused groups still on GPU off it (see pause_seq_group()).
+    fn step_paused_off_gpu(&mut self, outputs: &mut SchedulerOutputs) {
+        let paused = self.q_with(Queue::Paused, std::mem::take);
+        for mut seq_group in paused {
+            if !seq_group.is_finished()
+                && seq_group
+                    .seqs
+                    .iter()
+                    .any(|s| s.sched_phase == SchedulingPhase::Running)
+            {
+                self.move_off_gpu(&mut seq_group, outputs);
+            }
+            self.q_push(Queue::Paused, seq_group);
+        }
+    }
+
+    fn preemption_mode(&self, seq_group: &SequenceGroup) -> PreemptionMode {
+        let sched = &self.config.scheduler;
+        let mode = if seq_group.has_guidance() {
+            PreemptionMode::Recompute
+        } else if let Some(mode) = sched.preemption_mode {
+            mode
+        } else {
+            let num_kv_tokens = seq_group
+                .get_seqs(Some(SchedulingPhase::Running))
+                .iter()
+                .map(|seq| seq.num_kv_computed)
+                .sum::<usize>();
+            if num_kv_tokens >= sched.min_swap_tokens {
+                PreemptionMode::Swap
+            } else {
+                PreemptionMode::Recompute
+            }
+        };
+        mode
+    }
+
+    /// With evict_swapped, drop the CPU blocks of swapped-out groups (they are
+    /// recomputed when resumed) until `seq_group` can be swapped out. Only groups
+    /// resumed after the others, holding fewer blocks than `seq_group`, and not
+    /// swapped out in this step (their copies are pending) are evicted.
+    /// Returns whether `seq_group` can be swapped out now.
+    fn evict_swapped_for(&mut self, seq_group: &SequenceGroup) -> bool {
+        if !self.config.scheduler.evict_swapped {
+            return false;
+        }
+        let num_blocks = self.block_manager.get_num_blocks(seq_group);
+        self.sort_queue(Queue::Swapped);
+        let mut kept = Vec::new();
+        while !self.block_manager.can_swap_out(seq_group) {
+            let mut victim = match self.q_pop(Queue::Swapped) {
+                Some(sg) => sg,
+                None => break,
+            };
+            if self.last_step.preempted.contains(&victim.request_id)
+                || self.block_manager.get_num_blocks(&victim) >= num_blocks
+            {
+                kept.push(victim);
+                continue;
+            }
+            log::debug!(
+                "evicting swapped seq_group {} for {}",
+                victim.request_id,
+                seq_group.request_id
+            );
+            self.set_phase(&mut victim, SchedulingPhase::Waiting);
+            self.q_push(Queue::Waiting, victim);
+        }
+        self.q_with(Queue::Swapped, |q| q.extend(kept.into_iter().rev()));
+        self.block_manager.can_swap_out(seq_group)
     }
 
     fn step_swap_in(&mut self, outputs: &mut SchedulerOutputs) {
-        self.sort_by_priority(Queue::Swapped);
+        if !outputs.blocks_to_swap_out.is_empty() {
+            // paused groups were swapped out in this step
+            return;
+        }
+        self.sort_queue(Queue::Swapped);
 
         let mut num_curr_seqs = self.max_num_running_seq(Queue::OnGpu);
         while let Some(mut seq_group) = self.q_pop(Queue::Swapped) {
             let num_new_seqs = seq_group.get_max_num_running_seqs();
+            while !self.block_manager.can_swap_in(&seq_group) && self.release_oldest_kept() {}
             if !self.block_manager.can_swap_in(&seq_group)
                 || num_curr_seqs + num_new_seqs > self.config.scheduler.max_num_seqs
             {
@@ -392,6 +1258,11 @@ impl<ME: ModelExec> Scheduler<ME> {
                 break;
             }
             self._swap_in(&mut seq_group, outputs);
+            self.last_step.swapped_in.push(seq_group.request_id.clone());
+            if let Some(hooks) = self.hooks.as_mut() {
+                hooks.on_swap_in(&seq_group);
+                hooks.on_resume(&seq_group);
+            }
             self._append_slots(&mut seq_group, outputs);
             num_curr_seqs += num_new_seqs;
             self.q_push(Queue::OnGpu, seq_group);
@@ -411,39 +1282,232 @@ impl<ME: ModelExec> Scheduler<ME> {
     }
 
     pub fn schedule(&mut self) -> SchedulerOutputs {
+        self.last_step = StepDecisions::default();
         let mut outputs = SchedulerOutputs::new();
         self.step_drop_finished(&mut outputs);
+        self.step_release_kept();
+        self.step_paused_off_gpu(&mut outputs);
 
-        if self.q_len(Queue::Swapped) == 0 {
-            self.step_prompts(&mut outputs);
-        }
+        self.step_rebalance(&mut outputs);
 
-        if outputs.next_seq_groups.is_empty() {
-            let did_preempt = self.step_generation(&mut outputs);
-
-            // Swap in logic for swapped sequences
-            if !did_preempt {
-                self.step_swap_in(&mut outputs);
+        let hold_prompts = self.batch_window_left().is_some();
+        if self.config.scheduler.prefill_chunk_size.is_some() {
+            // decode first, then fill the rest of the batch with prompt chunks
+            let did_preempt = self.step_generation_and_swap_in(&mut outputs);
+            if !did_preempt && !hold_prompts && !self.swapped_blocks_prompts() {
+                self.step_prompts(&mut outputs);
+            }
+        } else {
+            if !hold_prompts && !self.swapped_blocks_prompts() {
+                self.step_prompts(&mut outputs);
             }
 
-            // Update num_batched_tokens based on the sequences in the RUNNING state
-            outputs.num_batched_tokens = self
-                .q_map(Queue::OnGpu, |sg| {
-                    sg.get_seqs(Some(SchedulingPhase::Running))
-                        .iter()
-                        .map(|seq| seq.get_len())
-                        .sum()
-                })
-                .iter()
-                .sum();
+            if outputs.next_seq_groups.is_empty() {
+                self.step_generation_and_swap_in(&mut outputs);
+            }
         }
 
         outputs.validate();
+
+        let ids = |sgs: &Vec<SequenceGroup>| sgs.iter().map(|sg| sg.request_id.clone()).collect();
+        self.last_step.scheduled = ids(&outputs.next_seq_groups);
+        self.last_step.dropped = ids(&outputs.dropped_seq_groups);
+        self.last_step.num_batched_tokens = outputs.num_batched_tokens;
+
+        outputs.metrics = self.step_metrics(&outputs);
+
         outputs
     }
 
+    /// Whether the prompts of the group fit in the free GPU blocks, leaving
+    /// SchedulerConfig::reserved_blocks_per_seq for each of the `num_seqs` sequences
+    /// on the GPU once it's admitted. A group admitted to an empty GPU needs no reserve.
+    fn can_admit(&self, seq_group: &SequenceGroup, num_seqs: usize) -> bool {
+        if !self.block_manager.can_allocate(seq_group) {
+            return false;
+        }
+        let per_seq = self.config.scheduler.reserved_blocks_per_seq;
+        if per_seq == 0
+            || num_seqs <= seq_group.get_max_num_running_seqs()
+            || self.block_manager.get_num_gpu_blocks() == 0
+        {
+            return true;
+        }
+        let required = self.block_manager.get_num_required_blocks(seq_group);
+        self.block_manager.get_num_free_gpu_blocks() >= required + per_seq * num_seqs
+    }
+
+    /// Maximum number of blocks a group can hold while others wait
+    /// (see SchedulerConfig::max_group_block_share).
+    fn group_block_cap(&self) -> Option<usize> {
+        let share = self.config.scheduler.max_group_block_share?;
+        let num_blocks = self.block_manager.get_num_gpu_blocks();
+        if num_blocks == 0 {
+            return None;
+        }
+        Some((share.clamp(0.0, 1.0) * num_blocks as f32) as usize)
+    }
+
+    /// Preempt the groups on the GPU holding more blocks than group_block_cap(),
+    /// largest first, until the first waiting group fits.
+    fn step_rebalance(&mut self, outputs: &mut SchedulerOutputs) {
+        let cap = match self.group_block_cap() {
+            Some(cap) => cap,
+            None => return,
+        };
+        self.sort_queue(Queue::Waiting);
+        let first = match self.q_pop(Queue::Waiting) {
+            Some(sg) => sg,
+            None => return,
+        };
+        while !self.block_manager.can_allocate(&first) {
+            let hog = self.q_with(Queue::OnGpu, |q| {
+                let (num_blocks, idx) = q
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, sg)| (self.block_manager.get_num_blocks(sg), idx))
+                    .max()?;
+                if num_blocks > cap {
+                    Some(q.remove(idx))
+                } else {
+                    None
+                }
+            });
+            match hog {
+                Some(hog) => {
+                    log::info!(
+                        "seq_group {} holds too many blocks while {} is waiting",
+                        hog.request_id,
+                        first.request_id
+                    );
+                    self._preempt(hog, outputs);
+                }
+                None => break,
+            }
+        }
+        // put it back in front of the queue
+        self.q_push(Queue::Waiting, first);
+    }
+
+    /// Swapped groups have to be swapped in before new prompts are admitted,
+    /// except for the ones preempted for holding too many blocks.
+    fn swapped_blocks_prompts(&self) -> bool {
+        let cap = self.group_block_cap();
+        self.q_with(Queue::Swapped, |q| {
+            q.iter().any(|sg| match cap {
+                Some(cap) => self.block_manager.get_num_blocks(sg) <= cap,
+                None => true,
+            })
+        })
+    }
+
+    fn step_metrics(&self, outputs: &SchedulerOutputs) -> StepMetrics {
+        let pool = self.block_manager.get_pool_stats();
+        let mut metrics = StepMetrics {
+            num_seq_groups: outputs.next_seq_groups.len(),
+            num_preempted: outputs.num_preempted,
+            num_cached_prompt_tokens: outputs.metrics.num_cached_prompt_tokens,
+            num_swapped_in_blocks: outputs.blocks_to_swap_in.len(),
+            num_swapped_out_blocks: outputs.blocks_to_swap_out.len(),
+            num_free_gpu_blocks: pool.num_free_gpu_blocks,
+            num_free_cpu_blocks: pool.num_free_cpu_blocks,
+            num_gpu_blocks: pool.num_gpu_blocks,
+            num_cpu_blocks: pool.num_cpu_blocks,
+            max_used_gpu_blocks: pool.max_used_gpu_blocks,
+            max_used_cpu_blocks: pool.max_used_cpu_blocks,
+            gpu_fragmentation: pool.gpu_fragmentation,
+            num_evicted_blocks: pool.num_evicted_blocks,
+            ..StepMetrics::default()
+        };
+        for sg in outputs.next_seq_groups.iter() {
+            for seq in sg.get_seqs(Some(SchedulingPhase::Running)) {
+                let positions = seq.step_positions();
+                // draft tokens are computed along with the token to generate from
+                if positions.len() == 1 + seq.num_draft && positions.end == seq.get_len() {
+                    metrics.num_decode_tokens += 1;
+                } else {
+                    metrics.num_prompt_tokens += positions.len();
+                }
+            }
+        }
+        metrics
+    }
+
+    /// Occupancy of the block pools, including the blocks held by each sequence group.
+    pub fn block_pool_stats(&self) -> BlockPoolStats {
+        let mut stats = self.block_manager.get_pool_stats();
+        for q in [Queue::Waiting, Queue::OnGpu, Queue::Swapped, Queue::Paused] {
+            for (id, n) in self.q_map(q, |sg| {
+                (sg.request_id.clone(), self.block_manager.get_num_blocks(sg))
+            }) {
+                stats.blocks_per_group.insert(id, n);
+            }
+        }
+        stats
+    }
+
+    /// Snapshot of the queues and of the decisions made in the last step.
+    pub fn debug_state(&self) -> SchedulerState {
+        let now = self.now();
+        let state = |q: Queue| {
+            self.q_map(q, |sg| SeqGroupState {
+                request_id: sg.request_id.clone(),
+                priority: sg.priority,
+                phases: sg
+                    .seqs
+                    .iter()
+                    .map(|seq| format!("{:?}", seq.sched_phase))
+                    .collect(),
+                num_tokens: sg.seqs.iter().map(|seq| seq.get_len()).max().unwrap_or(0),
+                num_kv_computed: sg
+                    .seqs
+                    .iter()
+                    .map(|seq| seq.num_kv_computed)
+                    .max()
+                    .unwrap_or(0),
+                num_blocks: self.block_manager.get_num_blocks(sg),
+                age: now.saturating_duration_since(sg.arrival_time).as_secs_f64(),
+            })
+        };
+        SchedulerState {
+            waiting: state(Queue::Waiting),
+            on_gpu: state(Queue::OnGpu),
+            swapped: state(Queue::Swapped),
+            paused: state(Queue::Paused),
+            num_free_gpu_blocks: self.block_manager.get_num_free_gpu_blocks(),
+            num_free_cpu_blocks: self.block_manager.get_num_free_cpu_blocks(),
+            last_step: self.last_step.clone(),
+        }
+    }
+
+    fn step_generation_and_swap_in(&mut self, outputs: &mut SchedulerOutputs) -> bool {
+        let did_preempt = self.step_generation(outputs);
+
+        // Swap in logic for swapped sequences
+        if !did_preempt {
+            self.step_swap_in(outputs);
+        }
+
+        // Update num_batched_tokens based on the sequences in the RUNNING state
+        outputs.num_batched_tokens = outputs
+            .next_seq_groups
+            .iter()
+            .map(|sg| {
+                sg.get_seqs(Some(SchedulingPhase::Running))
+                    .iter()
+                    .map(|seq| seq.step_positions().len())
+                    .sum::<usize>()
+            })
+            .sum();
+
+        did_preempt
+    }
+
     pub fn finish_seq(&self, seq: &mut Sequence, reason: FinishReason) {
         if seq.is_finished() {
+            if reason == FinishReason::Aborted && seq.has_kept_kv() {
+                self.release_kv(seq);
+            }
             return;
         }
         if reason != FinishReason::AiciStop && seq.has_aici {
@@ -453,10 +1517,111 @@ impl<ME: ModelExec> Scheduler<ME> {
             )))
         }
         seq.sched_phase = SchedulingPhase::Finished(reason);
+        if seq.has_kept_kv() {
+            return;
+        }
         self.freed_seq_ids.borrow_mut().push(seq.seq_id.to_num());
         self.seq_mgr.delete(seq.seq_id);
     }
 
+    fn release_kv(&self, seq: &mut Sequence) {
+        seq.keep_kv = false;
+        self.freed_seq_ids.borrow_mut().push(seq.seq_id.to_num());
+        self.seq_mgr.delete(seq.seq_id);
+    }
+
+    /// Hold on to a finished group, so it can be continued later.
+    /// The least recently kept groups are freed when the kept KV goes over
+    /// SchedulerConfig::max_session_kv_tokens.
+    pub(crate) fn keep_finished(&mut self, seq_group: SequenceGroup) {
+        assert!(seq_group.is_continuable());
+        let now = self.now();
+        self.kept.push((now, seq_group));
+        while self.num_kept_tokens() > self.config.scheduler.max_session_kv_tokens {
+            self.release_oldest_kept();
+        }
+    }
+
+    fn num_kept_tokens(&self) -> usize {
+        self.kept
+            .iter()
+            .flat_map(|(_, sg)| sg.seqs.iter())
+            .filter(|seq| seq.has_kept_kv())
+            .map(|seq| seq.num_kv_computed)
+            .sum()
+    }
+
+    /// Free the KV of the least recently kept group; false if there is none.
+    /// Kept groups are the first to go when blocks are needed.
+    fn release_oldest_kept(&mut self) -> bool {
+        if self.kept.is_empty() {
+            return false;
+        }
+        let (_, seq_group) = self.kept.remove(0);
+        log::debug!("releasing kept KV of seq_group {}", seq_group.request_id);
+        self.release_seq_group_kv(seq_group);
+        true
+    }
+
+    /// Free the KV of groups not continued within kept_kv_idle_secs.
+    fn step_release_kept(&mut self) {
+        let max_idle = match self.config.scheduler.kept_kv_idle_secs {
+            Some(secs) => secs,
+            None => return,
+        };
+        let now = self.now();
+        while let Some((kept_at, _)) = self.kept.first() {
+            if now.saturating_duration_since(*kept_at).as_secs_f32() <= max_idle {
+                break;
+            }
+            self.release_oldest_kept();
+        }
+    }
+
+    fn take_kept(&mut self, request_id: &str) -> Option<SequenceGroup> {
+        let idx = self
+            .kept
+            .iter()
+            .position(|(_, sg)| sg.request_id == request_id)?;
+        Some(self.kept.remove(idx).1)
+    }
+
+    /// Resume sequences of a kept group that reached max_tokens, allowing `extra_tokens` more.
+    pub fn continue_seq_group(&mut self, request_id: &str, extra_tokens: usize) -> bool {
+        match self.take_kept(request_id) {
+            Some(mut seq_group) => {
+                for seq in seq_group.seqs.iter_mut() {
+                    if seq.has_kept_kv() {
+                        seq.sched_phase = SchedulingPhase::Running;
+                    }
+                }
+                seq_group.sampling_params.max_tokens += extra_tokens;
+                self.q_push(Queue::OnGpu, seq_group);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Free KV cache of a kept group.
+    pub fn release_seq_group(&mut self, request_id: &str) -> bool {
+        match self.take_kept(request_id) {
+            Some(seq_group) => {
+                self.release_seq_group_kv(seq_group);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn release_seq_group_kv(&self, mut seq_group: SequenceGroup) {
+        for seq in seq_group.seqs.iter_mut() {
+            if seq.has_kept_kv() {
+                self.release_kv(seq);
+            }
+        }
+    }
+
     /// Sets the phase of all sequences in a group.
     fn set_phase(&self, seq_group: &mut SequenceGroup, status: SchedulingPhase) {
         let to_waiting = match status {
@@ -477,6 +1642,8 @@ impl<ME: ModelExec> Scheduler<ME> {
             assert!(!seq.is_finished());
             seq.sched_phase = status;
             if to_waiting {
+                // draft tokens found in the prefix cache wouldn't be computed again
+                seq.accept_draft(self.seq_mgr.deref(), &[]);
                 seq.clear_computed_kv(self.seq_mgr.deref());
             }
         }
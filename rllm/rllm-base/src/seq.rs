@@ -1,13 +1,98 @@
 use crate::{
-    config::SamplingParams, engine::ExpectedGeneration, LogitsProcessor, SeqId, SequenceManager,
+    config::SamplingParams, engine::ExpectedGeneration, grammar::GrammarMatcher, HashSet,
+    LogitsProcessor, LogitsProcessorState, SeqId, SequenceManager,
 };
 use aici_abi::{toktrie::TokTrie, Branch, TokenId};
 use aicirt::api::{AiciMidOp, SequenceResult};
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc, time::Instant};
 
 pub type Token = u32;
 
+/// Cut `buf` at the first occurrence of `needle` at or after `from`.
+fn truncate_at(buf: &mut Vec<u8>, from: usize, needle: &[u8]) {
+    if needle.is_empty() || from > buf.len() {
+        return;
+    }
+    if let Some(pos) = buf[from..].windows(needle.len()).position(|w| w == needle) {
+        buf.truncate(from + pos);
+    }
+}
+
+/// Depth in the draft tree of each draft token (1 for the children of the last
+/// verified token), given their parents (see Sequence::draft_parents).
+pub fn draft_depths(parents: &[usize]) -> Vec<usize> {
+    let mut depths: Vec<usize> = Vec::with_capacity(parents.len());
+    for &p in parents {
+        let depth = if p == 0 { 1 } else { depths[p - 1] + 1 };
+        depths.push(depth);
+    }
+    depths
+}
+
+/// The first `max_len` tokens of a draft tree, without the ones deeper than `max_depth`.
+pub(crate) fn prune_draft(
+    tokens: &[Token],
+    parents: &[usize],
+    max_depth: usize,
+    max_len: usize,
+) -> (Vec<Token>, Vec<usize>) {
+    let depths = draft_depths(parents);
+    // new index (plus one) of each kept token
+    let mut new_idx = vec![0; tokens.len()];
+    let mut r = (Vec::new(), Vec::new());
+    for (idx, &token) in tokens.iter().enumerate() {
+        if r.0.len() >= max_len {
+            break;
+        }
+        if depths[idx] > max_depth {
+            continue;
+        }
+        // parents come before their children, and are no deeper
+        let parent = if parents[idx] == 0 {
+            0
+        } else {
+            new_idx[parents[idx] - 1]
+        };
+        r.0.push(token);
+        r.1.push(parent);
+        new_idx[idx] = r.0.len();
+    }
+    r
+}
+
+/// Length of the prefix of `buf` that doesn't end with an incomplete UTF-8 sequence.
+fn utf8_complete_len(buf: &[u8]) -> usize {
+    if buf.len() == 0 {
+        return 0;
+    }
+    let mut ep = buf.len() - 1;
+    if buf[ep] >= 0x80 {
+        let mut ln = 0;
+        // skip continuation bytes (0b10xx_xxxx), but not too many
+        while ln < 4 && buf[ep] & 0b1100_0000 == 0b1000_0000 {
+            if ep == 0 {
+                break;
+            }
+            ep -= 1;
+            ln += 1;
+        }
+        // now buf[ep] is the first byte of the UTF-8 sequence
+        // make sure we have enough continuation bytes
+        if (buf[ep] & 0b1110_0000 == 0b1100_0000 && ln >= 1)
+            || (buf[ep] & 0b1111_0000 == 0b1110_0000 && ln >= 2)
+            || (ln >= 3)
+        {
+            // OK
+        } else {
+            // not enough
+            return ep;
+        }
+    }
+    buf.len()
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum FinishReason {
     /// EOS token was generated.
@@ -24,6 +109,21 @@ pub enum FinishReason {
     Failed,
     /// All sequences in the group are suspended.
     Deadlock,
+    /// SamplingParams.max_time exceeded.
+    TimedOut,
+    /// One of SamplingParams.stop strings was generated.
+    StopStringHit,
+    /// One of SamplingParams.stop_token_ids was generated.
+    StopTokenHit,
+    /// The sequence (or prompt) doesn't fit in the model context.
+    ContextLengthExceeded,
+    /// The request's stop criterion returned true.
+    CustomStop,
+    /// Hidden states of the prompt were computed (SamplingParams.hidden_states).
+    HiddenStates,
+    /// The request waited longer than SchedulerConfig::max_waiting_time to start,
+    /// and there was no room for it even after preempting others.
+    QueueTimeout,
 }
 
 impl FinishReason {
@@ -36,11 +136,70 @@ impl FinishReason {
             FinishReason::AiciStop => "aici-stop",
             FinishReason::Deadlock => "deadlock",
             FinishReason::AiciOutOfFuel => "aici-out-of-fuel",
+            FinishReason::TimedOut => "timeout",
+            FinishReason::StopStringHit => "stop",
+            FinishReason::StopTokenHit => "stop-token",
+            FinishReason::ContextLengthExceeded => "context-length",
+            FinishReason::CustomStop => "custom-stop",
+            FinishReason::HiddenStates => "hidden-states",
+            FinishReason::QueueTimeout => "queue-timeout",
         };
         r.to_string()
     }
 }
 
+/// Embeddings to use instead of token embeddings for the first `num_tokens()` prompt positions.
+#[derive(Debug, Clone)]
+pub struct PromptEmbeddings {
+    pub hidden_size: usize,
+    /// Row-major, [num_tokens, hidden_size].
+    pub data: Arc<Vec<f32>>,
+}
+
+impl PromptEmbeddings {
+    pub fn num_tokens(&self) -> usize {
+        self.data.len() / self.hidden_size
+    }
+
+    /// Embedding of the token at `position`, if overridden.
+    pub fn get(&self, position: usize) -> Option<&[f32]> {
+        if position < self.num_tokens() {
+            Some(&self.data[position * self.hidden_size..(position + 1) * self.hidden_size])
+        } else {
+            None
+        }
+    }
+}
+
+/// Placeholder tokens in the prompt (eg., for an image), starting at position `start`,
+/// whose embeddings are supplied out-of-band (eg., by a vision encoder).
+#[derive(Debug, Clone)]
+pub struct EmbeddingSpan {
+    pub start: usize,
+    pub embeddings: PromptEmbeddings,
+}
+
+impl EmbeddingSpan {
+    pub fn end(&self) -> usize {
+        self.start + self.embeddings.num_tokens()
+    }
+
+    pub fn get(&self, position: usize) -> Option<&[f32]> {
+        if position < self.start {
+            None
+        } else {
+            self.embeddings.get(position - self.start)
+        }
+    }
+}
+
+/// The stop condition matched for StopStringHit/StopTokenHit.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum StopMatch {
+    String(String),
+    Token(Token),
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum SchedulingPhase {
     Waiting,
@@ -57,11 +216,48 @@ pub struct Sequence {
     pub prompt_len: usize,
     pub(crate) output_ptr: usize,
     pub(crate) output_pending: Vec<u8>,
+    // text of the output up to output_ptr; None when it has to be decoded again
+    // (after backtracking into it, or migration)
+    pub(crate) output_text: Option<Vec<u8>>,
     pub num_kv_computed: usize,
+    // when set, compute at most this many tokens in the current step (long prompts)
+    pub(crate) prefill_chunk: Option<usize>,
+    // the last tokens were drafted by Medusa heads, and are verified in the next step;
+    // they are not part of the output until then
+    pub num_draft: usize,
+    // the draft is a tree of candidates: parent of each draft token, as an index
+    // into the draft tokens plus one (0 is the last verified token)
+    pub draft_parents: Vec<usize>,
     pub(crate) has_aici: bool,
     pub(crate) aici_sampling: Option<Branch<usize>>,
     pub aici_logs: Vec<SequenceResult>,
     pub(crate) expected: Option<ExpectedGeneration>,
+    pub(crate) grammar: Option<GrammarMatcher>,
+    // if None, the group's LogitsProcessor is used
+    pub(crate) logits_processor: Option<LogitsProcessor>,
+    // bytes of the prompt token trimmed by token healing; the first generated
+    // token starts with them, and they are left out of the output (but for echo)
+    pub(crate) healing_prefix: Option<Vec<u8>>,
+    pub(crate) stop_match: Option<StopMatch>,
+    // include prompt in outputs
+    pub(crate) echo: bool,
+    // not included in outputs (eg., FIM sentinels)
+    pub(crate) strip_tokens: Vec<Token>,
+    pub prompt_embeddings: Option<PromptEmbeddings>,
+    pub embedding_spans: Vec<EmbeddingSpan>,
+    // left out of detokenized text
+    pub(crate) skip_special: Option<Arc<HashSet<Token>>>,
+    pub(crate) include_stop_str: bool,
+    // keep KV cache on MaxTokensReached, see RllmEngine::continue_request()
+    pub(crate) keep_kv: bool,
+    // runs the negative prompt for classifier-free guidance; not sampled from
+    pub(crate) guidance: bool,
+    // hidden states of the prompt computed so far, for SamplingParams.hidden_states
+    pub(crate) hidden_states: Vec<f32>,
+    // pooled hidden states, returned in the final output
+    pub(crate) hidden_output: Option<Vec<f32>>,
+    // LoRA adapter (SamplingParams.adapter); KV computed with it only serves it
+    pub adapter: Option<String>,
 
     pub(crate) mid_op: Option<AiciMidOp>,
 
@@ -91,14 +287,33 @@ impl Sequence {
             sched_phase: SchedulingPhase::Waiting,
             tokens: tokens.to_vec(),
             num_kv_computed: 0,
+            prefill_chunk: None,
+            num_draft: 0,
+            draft_parents: Vec::new(),
             prompt_len,
             output_ptr: prompt_len,
             output_pending: Vec::new(),
+            output_text: Some(Vec::new()),
             has_aici: false,
             aici_logs: Vec::new(),
             aici_sampling: None,
             mid_op: None,
             expected: None,
+            grammar: None,
+            logits_processor: None,
+            healing_prefix: None,
+            stop_match: None,
+            echo: false,
+            strip_tokens: Vec::new(),
+            prompt_embeddings: None,
+            embedding_spans: Vec::new(),
+            skip_special: None,
+            include_stop_str: false,
+            keep_kv: false,
+            guidance: false,
+            hidden_states: Vec::new(),
+            hidden_output: None,
+            adapter: None,
         }
     }
 
@@ -106,12 +321,57 @@ impl Sequence {
         self.tokens.len()
     }
 
+    pub fn get_tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// Length without the draft tokens.
+    pub fn get_verified_len(&self) -> usize {
+        self.get_len() - self.num_draft
+    }
+
+    /// All embeddings overriding token embeddings, including prompt embeddings.
+    pub fn embedding_overrides(&self) -> Vec<EmbeddingSpan> {
+        let mut r = self.embedding_spans.clone();
+        if let Some(emb) = self.prompt_embeddings.as_ref() {
+            r.push(EmbeddingSpan {
+                start: 0,
+                embeddings: emb.clone(),
+            });
+        }
+        r
+    }
+
     /// Indicate that the generation will soon run for this sequence and thus
     /// all the tokens will have KV computed.
     pub fn sync_computed_kv(&mut self) {
         self.num_kv_computed = self.get_len();
     }
 
+    /// Positions to compute in the current step: the ones without KV computed
+    /// (at least the last one), limited to the prefill chunk for long prompts.
+    /// Use sync_computed_kv_to(step_positions().end) when scheduling them.
+    pub fn step_positions(&self) -> std::ops::Range<usize> {
+        let len = self.get_len();
+        // if everything is computed, just re-compute the last token
+        let start = std::cmp::min(self.num_kv_computed, len - 1);
+        let end = match self.prefill_chunk {
+            Some(chunk) => std::cmp::min(len, start + chunk),
+            None => len,
+        };
+        start..end
+    }
+
+    pub fn sync_computed_kv_to(&mut self, end: usize) {
+        assert!(end <= self.get_len());
+        self.num_kv_computed = end;
+    }
+
+    /// Number of tokens without KV computed.
+    pub fn num_pending_tokens(&self) -> usize {
+        self.get_len() - self.num_kv_computed
+    }
+
     fn trim_computed_kv(&mut self, v: usize, seq_mgr: &impl SequenceManager) {
         if self.num_kv_computed != v {
             assert!(self.num_kv_computed > v);
@@ -148,7 +408,10 @@ impl Sequence {
     ) {
         if backtrack > 0 {
             self.tokens.truncate(self.get_len() - backtrack);
-            self.output_ptr = std::cmp::min(self.output_ptr, self.get_len());
+            if self.get_len() < self.output_ptr {
+                self.output_ptr = self.get_len();
+                self.output_text = None;
+            }
             // backtracking can remove some tokens from the initial prompt
             self.prompt_len = std::cmp::min(self.prompt_len, self.get_len());
             self.output_pending.clear();
@@ -158,8 +421,58 @@ impl Sequence {
         self.append_tokens(tokens);
     }
 
+    /// Append a tree of tokens drafted by Medusa heads, to be verified in the next step;
+    /// see draft_parents.
+    pub(crate) fn append_draft(&mut self, tokens: &[Token], parents: &[usize]) {
+        assert!(self.num_draft == 0);
+        assert!(parents.len() == tokens.len());
+        assert!(parents.iter().enumerate().all(|(idx, &p)| p <= idx));
+        self.append_tokens(tokens);
+        self.num_draft = tokens.len();
+        self.draft_parents = parents.to_vec();
+    }
+
+    /// Keep the draft tokens at `path` (indices into the draft, each a child of the
+    /// one before) as regular tokens; the rest are dropped with their KV.
+    pub(crate) fn accept_draft(&mut self, seq_mgr: &impl SequenceManager, path: &[usize]) {
+        let verified = self.get_verified_len();
+        let draft = self.tokens.split_off(verified);
+        self.tokens.extend(path.iter().map(|&idx| draft[idx]));
+        // the KV of a draft token is at its index in the draft, which is its
+        // index in the sequence only along the first tokens of the draft;
+        // the others are computed again in the next step
+        let num_in_place = path
+            .iter()
+            .enumerate()
+            .take_while(|(i, idx)| i == *idx)
+            .count();
+        self.num_draft = 0;
+        self.draft_parents.clear();
+        let computed = std::cmp::min(self.num_kv_computed, verified + num_in_place);
+        self.trim_computed_kv(computed, seq_mgr);
+    }
+
+    /// Replace all tokens from position `at` onwards with `tokens`.
+    /// The KV cache past `at` is dropped, freeing its blocks.
+    /// Unlike splice_tokens(), the output doesn't mark the backtracking.
+    pub fn splice(&mut self, seq_mgr: &impl SequenceManager, at: usize, tokens: &[Token]) {
+        assert!(at <= self.get_len());
+        if at < self.get_len() {
+            self.tokens.truncate(at);
+            if at < self.output_ptr {
+                // the output continues from `at`
+                self.output_ptr = at;
+                self.output_pending.clear();
+                self.output_text = None;
+            }
+            self.prompt_len = std::cmp::min(self.prompt_len, at);
+            self.trim_physical_blocks(seq_mgr);
+        }
+        self.append_tokens(tokens);
+    }
+
     pub fn get_gen_len(&self) -> usize {
-        self.tokens.len() - self.prompt_len
+        self.get_verified_len() - self.prompt_len
     }
 
     pub fn get_token(&self, idx: usize) -> TokenId {
@@ -178,18 +491,56 @@ impl Sequence {
             index,
             sched_phase: self.sched_phase,
             num_kv_computed: self.num_kv_computed,
+            prefill_chunk: None,
+            num_draft: self.num_draft,
+            draft_parents: self.draft_parents.clone(),
             tokens: self.tokens.clone(),
-            output_ptr: self.prompt_len,
+            output_ptr: if self.echo { 0 } else { self.prompt_len },
             prompt_len: self.prompt_len,
             output_pending: Vec::new(),
+            output_text: Some(Vec::new()),
             has_aici: self.has_aici,
             aici_logs: Vec::new(),
             aici_sampling: None,
             expected: None,
+            grammar: self.grammar.clone(),
+            logits_processor: None,
+            healing_prefix: self.healing_prefix.clone(),
+            stop_match: None,
+            echo: self.echo,
+            strip_tokens: self.strip_tokens.clone(),
+            prompt_embeddings: self.prompt_embeddings.clone(),
+            embedding_spans: self.embedding_spans.clone(),
+            skip_special: self.skip_special.clone(),
+            include_stop_str: self.include_stop_str,
+            keep_kv: self.keep_kv,
+            guidance: self.guidance,
+            hidden_states: self.hidden_states.clone(),
+            hidden_output: None,
+            adapter: self.adapter.clone(),
             mid_op: None,
         }
     }
 
+    /// Look for any of `stop` strings in the generated text, ending within the last `num_new` tokens.
+    pub(crate) fn find_stop_string(
+        &self,
+        tok_trie: &TokTrie,
+        stop: &[String],
+        num_new: usize,
+    ) -> Option<String> {
+        let max_len = stop.iter().map(|s| s.len()).max()?;
+        // every token is at least one byte long
+        let start = self
+            .get_len()
+            .saturating_sub(num_new + max_len)
+            .max(self.prompt_len);
+        let text = tok_trie.decode(&self.tokens[start..]);
+        stop.iter()
+            .find(|s| !s.is_empty() && text.windows(s.len()).any(|w| w == s.as_bytes()))
+            .cloned()
+    }
+
     pub fn append_tokens(&mut self, tokens: &[Token]) {
         self.tokens.extend_from_slice(tokens)
     }
@@ -201,51 +552,139 @@ impl Sequence {
         }
     }
 
-    pub fn gen_output(&mut self, tok_trie: &TokTrie) -> SeqOutput {
-        let new_output_tokens = self.tokens[self.output_ptr..].to_vec();
-        let mut buf = std::mem::take(&mut self.output_pending);
-        buf.append(&mut tok_trie.decode(&new_output_tokens));
-        if buf.len() > 0 {
-            let mut ep = buf.len() - 1;
-            if buf[ep] >= 0x80 {
-                let mut ln = 0;
-                // skip continuation bytes (0b10xx_xxxx), but not too many
-                while ln < 4 && buf[ep] & 0b1100_0000 == 0b1000_0000 {
-                    if ep == 0 {
-                        break;
-                    }
-                    ep -= 1;
-                    ln += 1;
-                }
-                // now buf[ep] is the first byte of the UTF-8 sequence
-                // make sure we have enough continuation bytes
-                if (buf[ep] & 0b1110_0000 == 0b1100_0000 && ln >= 1)
-                    || (buf[ep] & 0b1111_0000 == 0b1110_0000 && ln >= 2)
-                    || (ln >= 3)
-                {
-                    // OK
+    fn decode_text(&self, tok_trie: &TokTrie, tokens: &[Token]) -> Vec<u8> {
+        match self.skip_special.as_ref() {
+            Some(skip) => tok_trie.decode(
+                &tokens
+                    .iter()
+                    .filter(|t| !skip.contains(t))
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            ),
+            None => tok_trie.decode(tokens),
+        }
+    }
+
+    fn excluded_stop(&self) -> Option<&[u8]> {
+        match &self.stop_match {
+            Some(StopMatch::String(s)) if !self.include_stop_str => Some(s.as_bytes()),
+            _ => None,
+        }
+    }
+
+    /// Length of the healing prefix at the start of the generated `text`.
+    fn healing_len(&self, text: &[u8]) -> usize {
+        match self.healing_prefix.as_ref() {
+            Some(prefix) if text.starts_with(prefix) => prefix.len(),
+            _ => 0,
+        }
+    }
+
+    /// Text of all the output so far.
+    fn decode_output(&self, tok_trie: &TokTrie) -> Vec<u8> {
+        let start = if self.echo { 0 } else { self.prompt_len };
+        let mut text = self.decode_text(tok_trie, &self.output_slice(start));
+        if !self.echo {
+            text.drain(..self.healing_len(&text));
+        }
+        text
+    }
+
+    fn output_slice(&self, start: usize) -> Vec<Token> {
+        self.strip(&self.tokens[start..self.get_verified_len()])
+    }
+
+    fn strip(&self, tokens: &[Token]) -> Vec<Token> {
+        tokens
+            .iter()
+            .filter(|t| !self.strip_tokens.contains(t))
+            .cloned()
+            .collect()
+    }
+
+    /// Produce output since the last call; `output_tokens` is only filled if `full_output`.
+    pub fn gen_output(&mut self, tok_trie: &TokTrie, full_output: bool) -> SeqOutput {
+        let new_output_tokens = self.output_slice(self.output_ptr);
+        let new_bytes = self.decode_text(tok_trie, &new_output_tokens);
+        let text = match self.output_text.take() {
+            Some(mut text) => {
+                let healing = if text.is_empty() && !self.echo {
+                    self.healing_len(&new_bytes)
                 } else {
-                    // not enough, move the whole UTF-8 sequence to output_pending
-                    self.output_pending.extend(buf.drain(ep..));
-                }
+                    0
+                };
+                text.extend_from_slice(&new_bytes[healing..]);
+                text
             }
+            None => self.decode_output(tok_trie),
+        };
+        let mut buf = std::mem::take(&mut self.output_pending);
+        buf.extend_from_slice(&new_bytes);
+        if self.output_ptr == self.prompt_len && !self.echo {
+            buf.drain(..self.healing_len(&buf));
+        }
+        // move incomplete UTF-8 sequence at the end to output_pending
+        let ep = utf8_complete_len(&buf);
+        self.output_pending.extend(buf.drain(ep..));
+        self.output_ptr = self.get_verified_len();
+        if let Some(stop) = self.excluded_stop() {
+            // only cuts the stop string if it was generated in this step
+            truncate_at(&mut buf, 0, stop);
         }
-        self.output_ptr = self.tokens.len();
         let new_text = String::from_utf8_lossy(&buf).to_string();
+        let (output_tokens, output_text) = if full_output {
+            let start = if self.echo { 0 } else { self.prompt_len };
+            let output_tokens = self.output_slice(start);
+            let mut text = text.clone();
+            if !self.is_finished() {
+                text.truncate(utf8_complete_len(&text));
+            }
+            if let Some(stop) = self.excluded_stop() {
+                let prompt_bytes = if self.echo {
+                    let prompt = self.strip(&self.tokens[..self.prompt_len]);
+                    self.decode_text(tok_trie, &prompt).len()
+                } else {
+                    0
+                };
+                truncate_at(&mut text, prompt_bytes, stop);
+            }
+            (output_tokens, String::from_utf8_lossy(&text).to_string())
+        } else {
+            (Vec::new(), String::new())
+        };
+        self.output_text = Some(text);
         SeqOutput {
             seq_id: self.seq_id.to_num(),
             index: self.index,
             new_output_tokens,
             new_text,
-            output_tokens: self.tokens[self.prompt_len..].to_vec(),
+            output_tokens,
+            output_text,
+            stop_match: self.stop_match.clone(),
             finish_reason: self.finish_reason(),
             aici_logs: std::mem::take(&mut self.aici_logs),
+            hidden_states: self.hidden_output.take(),
         }
     }
 
     pub fn is_finished(&self) -> bool {
         self.finish_reason().is_some()
     }
+
+    pub(crate) fn has_kept_kv(&self) -> bool {
+        self.keep_kv && self.finish_reason() == Some(FinishReason::MaxTokensReached)
+    }
+}
+
+/// Evaluated after every generated token; returning true finishes the sequence.
+pub type StopCriterion = Box<dyn Fn(&Sequence) -> bool + Send>;
+
+/// Sampler state of a sequence group.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SamplerState {
+    pub group: LogitsProcessorState,
+    /// Sequences with their own sampler (forked ones), by sequence index.
+    pub seqs: Vec<(usize, LogitsProcessorState)>,
 }
 
 /// A group of sequences that are generated from the same prompt.
@@ -254,16 +693,25 @@ pub struct SequenceGroup {
     pub prompt: String,
     pub seqs: Vec<Sequence>,
     pub sampling_params: SamplingParams,
-    pub arrival_time: std::time::Instant,
+    pub arrival_time: Instant,
+    /// Higher priority groups are scheduled first; ties are broken by arrival time.
+    pub priority: i32,
     pub logits_processor: LogitsProcessor,
     pub max_index: usize,
     pub usage: TokenUsage,
+    pub metadata: Option<serde_json::Value>,
+    /// Ancestor request ids, parent first.
+    pub lineage: Vec<String>,
+    pub stop_criterion: Option<StopCriterion>,
+    pub(crate) scheduled_time: Option<Instant>,
+    pub(crate) first_token_time: Option<Instant>,
 }
 
 impl Debug for SequenceGroup {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("SequenceGroup")
             .field("request_id", &self.request_id)
+            .field("priority", &self.priority)
             .field("seqs", &self.seqs)
             .finish()
     }
@@ -284,11 +732,89 @@ impl SequenceGroup {
                 // generation stage, we will have `best_of` sequences running.
                 self.sampling_params.best_of
             } else {
-                // At sampling stages, return the number of actual sequences
-                // running.
-                self.num_seqs(Some(SchedulingPhase::Running))
+                // At sampling stages, return the number of sequences that are
+                // not finished; they are scheduled together, also when they are
+                // currently swapped out or waiting to be recomputed.
+                self.seqs.iter().filter(|seq| !seq.is_finished()).count()
+            }
+        }
+    }
+
+    pub fn timing(&self) -> RequestTiming {
+        let now = Instant::now();
+        let scheduled = self.scheduled_time.unwrap_or(now);
+        let first_token = self.first_token_time.unwrap_or(now);
+        let queue_time = (scheduled - self.arrival_time).as_secs_f64();
+        let prefill_time = (first_token - scheduled).as_secs_f64();
+        let decode_time = (now - first_token).as_secs_f64();
+        let run_time = prefill_time + decode_time;
+        let gen_len = self
+            .seqs
+            .iter()
+            .filter(|seq| !seq.guidance)
+            .map(|seq| seq.get_gen_len())
+            .max()
+            .unwrap_or(0);
+        let expected_time_left = if gen_len > 1 && decode_time > 0.0 {
+            let left = self.sampling_params.max_tokens.saturating_sub(gen_len);
+            Some(left as f64 * decode_time / (gen_len - 1) as f64)
+        } else {
+            None
+        };
+        RequestTiming {
+            queue_time,
+            prefill_time,
+            decode_time,
+            tokens_per_second: if run_time > 0.0 {
+                self.usage.gen_tokens as f64 / run_time
+            } else {
+                0.0
+            },
+            expected_time_left,
+        }
+    }
+
+    pub fn get_seq_mut(&mut self, seq_id: SeqId) -> Result<&mut Sequence> {
+        match self.seqs.iter_mut().find(|s| s.seq_id == seq_id) {
+            Some(s) => Ok(s),
+            None => bail!("sequence {} not found in {}", seq_id, self.request_id),
+        }
+    }
+
+    /// Fork sequence `seq_id` into a new sequence in this group.
+    /// The full KV cache blocks are shared with the original; the tokens of the last,
+    /// partially filled one are recomputed for the new sequence.
+    pub fn fork_seq(&mut self, seq_mgr: &impl SequenceManager, seq_id: SeqId) -> Result<SeqId> {
+        let seq = match self.seqs.iter().find(|s| s.seq_id == seq_id) {
+            Some(s) => s,
+            None => bail!("sequence {} not found in {}", seq_id, self.request_id),
+        };
+        match seq.sched_phase {
+            SchedulingPhase::Running | SchedulingPhase::Suspended | SchedulingPhase::Swapped => {}
+            SchedulingPhase::Waiting => {
+                bail!(
+                    "can't fork sequence {} before its prompt is processed",
+                    seq_id
+                )
             }
+            SchedulingPhase::Finished(_) => bail!("can't fork finished sequence {}", seq_id),
+        }
+        if seq.has_aici {
+            bail!(
+                "can't fork sequence {} with a controller; only the controller forks it",
+                seq_id
+            );
+        }
+        let new_id = seq_mgr.new_sequence();
+        let mut copy = seq.fork_as(seq_mgr, new_id, self.max_index + 1);
+        let shared = seq_mgr.shareable_len(copy.num_kv_computed);
+        if shared < copy.num_kv_computed {
+            seq_mgr.trim(new_id, shared);
+            copy.num_kv_computed = shared;
         }
+        self.max_index += 1;
+        self.seqs.push(copy);
+        Ok(new_id)
     }
 
     pub fn only_seq(&self) -> &Sequence {
@@ -316,11 +842,49 @@ impl SequenceGroup {
         self.get_seqs(status).len()
     }
 
+    /// Number of tokens generated, summed over all sequences.
+    pub fn total_gen_len(&self) -> usize {
+        self.seqs
+            .iter()
+            .filter(|seq| !seq.guidance)
+            .map(|seq| seq.get_gen_len())
+            .sum()
+    }
+
+    pub fn sampler_state(&self) -> SamplerState {
+        SamplerState {
+            group: self.logits_processor.state(),
+            seqs: self
+                .seqs
+                .iter()
+                .filter_map(|seq| Some((seq.index, seq.logits_processor.as_ref()?.state())))
+                .collect(),
+        }
+    }
+
+    pub fn restore_sampler_state(&mut self, state: &SamplerState) {
+        self.logits_processor = LogitsProcessor::from_state(&state.group);
+        for (index, st) in state.seqs.iter() {
+            if let Some(seq) = self.seqs.iter_mut().find(|seq| seq.index == *index) {
+                seq.logits_processor = Some(LogitsProcessor::from_state(st));
+            }
+        }
+    }
+
+    pub fn has_guidance(&self) -> bool {
+        self.seqs.iter().any(|seq| seq.guidance)
+    }
+
     /// Checks if all sequences are finished.
     pub fn is_finished(&self) -> bool {
         self.seqs.iter().all(|seq| seq.is_finished())
     }
 
+    /// Finished, but some sequences kept their KV cache and can be continued.
+    pub fn is_continuable(&self) -> bool {
+        self.is_finished() && self.seqs.iter().any(|s| s.has_kept_kv())
+    }
+
     pub fn is_suspended(&self) -> bool {
         self.seqs
             .iter()
@@ -334,10 +898,26 @@ pub struct SeqOutput {
     pub index: usize, // within the sequence group
     pub new_output_tokens: Vec<Token>,
     pub new_text: String,
-    /// The tokens generated by the model. Doesn't include prompt tokens.
+    /// The tokens generated by the model. Doesn't include prompt tokens, unless `echo` is set.
     pub output_tokens: Vec<Token>,
+    /// Detokenized `output_tokens`; an incomplete UTF-8 sequence at the end is left out.
+    pub output_text: String,
+    pub stop_match: Option<StopMatch>,
     pub finish_reason: Option<FinishReason>,
     pub aici_logs: Vec<SequenceResult>,
+    /// Pooled final-layer hidden states of the prompt, see SamplingParams.hidden_states.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hidden_states: Option<Vec<f32>>,
+}
+
+impl SeqOutput {
+    pub fn is_empty_delta(&self) -> bool {
+        self.new_output_tokens.is_empty()
+            && self.new_text.is_empty()
+            && self.finish_reason.is_none()
+            && self.aici_logs.is_empty()
+            && self.hidden_states.is_none()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -346,6 +926,22 @@ pub struct TokenUsage {
     pub prompt_tokens: usize,
 }
 
+/// All times are in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RequestTiming {
+    /// From arrival until first scheduled.
+    pub queue_time: f64,
+    /// From first scheduled until first generated token.
+    pub prefill_time: f64,
+    /// Since first generated token.
+    pub decode_time: f64,
+    /// Generated tokens per second, not counting queue time.
+    pub tokens_per_second: f64,
+    /// Expected time until max_tokens are generated, at the decoding speed so far.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_time_left: Option<f64>,
+}
+
 impl TokenUsage {
     pub fn total_tokens(&self) -> usize {
         self.gen_tokens + self.prompt_tokens
@@ -360,6 +956,127 @@ impl TokenUsage {
 pub struct RequestOutput {
     pub request_id: String,
     pub usage: TokenUsage,
+    pub timing: RequestTiming,
     pub seq_outputs: Vec<SeqOutput>,
     pub is_final: bool,
+    /// Passed through from AddRequest.metadata.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Ancestor request ids, parent first; see SamplingParams.parent_request_id.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lineage: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aici_abi::toktrie::TokRxInfo;
+
+    /// Trie with one token per byte value.
+    fn byte_trie() -> TokTrie {
+        let words = (0..=255u8).map(|b| vec![b]).collect::<Vec<_>>();
+        let info = TokRxInfo {
+            vocab_size: words.len() as u32,
+            tok_eos: 0,
+        };
+        TokTrie::from(&info, &words)
+    }
+
+    fn tokens(s: &str) -> Vec<Token> {
+        s.bytes().map(|b| b as Token).collect()
+    }
+
+    #[test]
+    fn utf8_complete() {
+        assert_eq!(utf8_complete_len(b""), 0);
+        assert_eq!(utf8_complete_len(b"abc"), 3);
+        let s = "aé€😀".as_bytes();
+        assert_eq!(utf8_complete_len(s), s.len());
+        // cut within each of the multi-byte characters
+        assert_eq!(utf8_complete_len(&s[..2]), 1);
+        assert_eq!(utf8_complete_len(&s[..4]), 3);
+        assert_eq!(utf8_complete_len(&s[..5]), 3);
+        assert_eq!(utf8_complete_len(&s[..7]), 6);
+        assert_eq!(utf8_complete_len(&s[..9]), 6);
+    }
+
+    #[test]
+    fn draft_tree() {
+        // a chain of 3, with a sibling of each of its tokens
+        let tokens = [10, 11, 12, 20, 21, 22];
+        let parents = [0, 1, 2, 0, 1, 2];
+        assert_eq!(draft_depths(&parents), vec![1, 2, 3, 1, 2, 3]);
+        assert_eq!(
+            prune_draft(&tokens, &parents, 2, 10),
+            (vec![10, 11, 20, 21], vec![0, 1, 0, 1])
+        );
+        assert_eq!(
+            prune_draft(&tokens, &parents, 3, 5),
+            (vec![10, 11, 12, 20, 21], vec![0, 1, 2, 0, 1])
+        );
+        // parents are reindexed past the pruned tokens
+        assert_eq!(
+            prune_draft(&[10, 11, 12, 13], &[0, 1, 1, 3], 2, 10),
+            (vec![10, 11, 12], vec![0, 1, 1])
+        );
+        assert_eq!(
+            prune_draft(&[10, 11, 12, 13, 14], &[0, 1, 2, 0, 4], 2, 10),
+            (vec![10, 11, 13, 14], vec![0, 1, 0, 3])
+        );
+        assert_eq!(prune_draft(&tokens, &parents, 0, 10), (vec![], vec![]));
+    }
+
+    #[test]
+    fn truncate() {
+        let mut buf = b"foo STOP bar STOP".to_vec();
+        truncate_at(&mut buf, 5, b"STOP");
+        assert_eq!(buf, b"foo STOP bar ");
+        truncate_at(&mut buf, 0, b"");
+        assert_eq!(buf, b"foo STOP bar ");
+        truncate_at(&mut buf, 100, b"STOP");
+        assert_eq!(buf, b"foo STOP bar ");
+    }
+
+    #[test]
+    fn stop_strings() {
+        let trie = byte_trie();
+        let mut seq = Sequence::new(SeqId(1), &tokens("say END:"));
+        let stop = vec!["END".to_string(), "\n\n".to_string()];
+        // the prompt is not searched
+        assert_eq!(seq.find_stop_string(&trie, &stop, 0), None);
+
+        seq.append_tokens(&tokens("one\nEN"));
+        assert_eq!(seq.find_stop_string(&trie, &stop, 2), None);
+        seq.append_tokens(&tokens("D"));
+        assert_eq!(
+            seq.find_stop_string(&trie, &stop, 1),
+            Some("END".to_string())
+        );
+
+        assert_eq!(seq.find_stop_string(&trie, &[], 1), None);
+        assert_eq!(seq.find_stop_string(&trie, &["".to_string()], 1), None);
+    }
+
+    #[test]
+    fn gen_output() {
+        let trie = byte_trie();
+        let mut seq = Sequence::new(SeqId(1), &tokens("hi "));
+        let tail = "é!".as_bytes();
+        seq.append_tokens(&tokens("ok "));
+        seq.append_tokens(&[tail[0] as Token]);
+        let out = seq.gen_output(&trie, false);
+        // the incomplete character is held back
+        assert_eq!(out.new_text, "ok ");
+        assert_eq!(out.new_output_tokens.len(), 4);
+        seq.append_tokens(&[tail[1] as Token, tail[2] as Token]);
+        let out = seq.gen_output(&trie, true);
+        assert_eq!(out.new_text, "é!");
+        assert_eq!(out.output_text, "ok é!");
+        assert_eq!(out.output_tokens.len(), 6);
+        // the text is accumulated without full_output too
+        seq.append_tokens(&tokens("x"));
+        assert_eq!(seq.gen_output(&trie, false).new_text, "x");
+        seq.append_tokens(&tokens("y"));
+        assert_eq!(seq.gen_output(&trie, true).output_text, "ok é!xy");
+    }
 }
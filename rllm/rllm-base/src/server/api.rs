@@ -1,3 +1,4 @@
+use crate::{config::HiddenStates, seq::StopMatch};
 use aici_abi::StorageCmd;
 use serde::{Deserialize, Serialize};
 
@@ -11,6 +12,52 @@ pub struct RunRequest {
     pub top_p: Option<f32>,        // defl 1.0
     pub top_k: Option<isize>,      // defl -1
     pub max_tokens: Option<usize>, // defl context size
+    pub n: Option<usize>,          // defl 1
+    pub max_time: Option<f32>,     // seconds; defl unlimited
+    pub priority: Option<i32>,     // defl 0
+    /// Seconds from arrival; used by deadline scheduling.
+    #[serde(default)]
+    pub deadline: Option<f32>,
+    /// One of the QoS classes of the scheduler (eg., "interactive" or "batch").
+    #[serde(default)]
+    pub qos_class: Option<String>,
+    /// Limit on tokens generated over all `n` sequences; defl unlimited.
+    #[serde(default)]
+    pub max_total_tokens: Option<usize>,
+    #[serde(default)]
+    pub grammar: Option<String>,
+    #[serde(default)]
+    pub token_healing: bool,
+    /// Tokens the generation has to start with.
+    #[serde(default)]
+    pub forced_tokens: Vec<u32>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    #[serde(default)]
+    pub echo: bool,
+    #[serde(default)]
+    pub skip_special_tokens: bool,
+    #[serde(default)]
+    pub include_stop_str_in_output: bool,
+    /// If set, generate text to go between `prompt` and `suffix`.
+    #[serde(default)]
+    pub suffix: Option<String>,
+    /// Classifier-free guidance; see SamplingParams.
+    #[serde(default)]
+    pub negative_prompt: Option<String>,
+    pub guidance_scale: Option<f32>, // defl 1.0
+    /// Reuse KV cache of previous requests with the same session id.
+    #[serde(default)]
+    pub session_id: Option<String>,
+    /// Id of a previous request this one continues; inherits its session.
+    #[serde(default)]
+    pub parent_request_id: Option<String>,
+    /// Return hidden states of the prompt instead of generating.
+    #[serde(default)]
+    pub hidden_states: Option<HiddenStates>,
+    /// LoRA adapter to use; one of those the server was started with.
+    #[serde(default)]
+    pub adapter: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,9 +87,13 @@ pub struct RunForkResponse {
     pub index: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub finish_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_match: Option<StopMatch>,
     pub text: String,
     pub error: String,
     pub logs: String,
     pub storage: Vec<StorageCmd>,
     pub micros: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hidden_states: Option<Vec<f32>>,
 }
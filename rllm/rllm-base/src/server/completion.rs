@@ -1,6 +1,6 @@
 use crate::seq::{FinishReason, RequestOutput, SeqOutput};
 use crate::server::{auth_info, APIError, AiciServerData, InferenceResult};
-use crate::{config::SamplingParams, seq::Token, AddRequest};
+use crate::{config::SamplingParams, fim::FimTokens, seq::Token, AddRequest};
 use actix_web::{post, web, web::Bytes, HttpResponse};
 use aicirt::{api::InstantiateReq, get_unix_time};
 use serde_json::{json, Value};
@@ -20,12 +20,26 @@ fn check_length(
     } else {
         request.prompt.as_str()
     };
-    let token_ids = data
-        .tokenizer
-        .encode(prompt, true)
-        .map_err(APIError::from)?
-        .get_ids()
-        .to_vec();
+    let encode = |s: &str, add_special_tokens: bool| -> Result<Vec<Token>, APIError> {
+        Ok(data
+            .tokenizer
+            .encode(s, add_special_tokens)
+            .map_err(APIError::from)?
+            .get_ids()
+            .to_vec())
+    };
+    let token_ids = match request.suffix.as_ref() {
+        Some(suffix) => {
+            let fim = FimTokens::from_tokenizer(&data.tokenizer)
+                .ok_or_else(|| APIError::new_str("this model doesn't support infilling"))?;
+            fim.build_prompt(
+                &encode("", true)?,
+                &encode(prompt, false)?,
+                &encode(suffix, false)?,
+            )
+        }
+        None => encode(prompt, true)?,
+    };
 
     let max_tokens = if let Some(max_toks) = request.max_tokens {
         max_toks
@@ -82,9 +96,38 @@ async fn run_controller(
 
     let mut sampling_params = SamplingParams::default();
     sampling_params.max_tokens = max_tokens;
+    sampling_params.max_total_tokens = request.max_total_tokens;
     sampling_params.ignore_eos = true;
+    sampling_params.tenant_id = Some(auth_info(&req).user);
 
-    set_fields_if_some!(request, sampling_params, temperature, top_p, top_k);
+    set_fields_if_some!(
+        request,
+        sampling_params,
+        temperature,
+        top_p,
+        top_k,
+        priority,
+        guidance_scale
+    );
+    sampling_params.grammar = request.grammar.clone();
+    sampling_params.token_healing = request.token_healing;
+    sampling_params.forced_tokens = request.forced_tokens.clone();
+    sampling_params.max_time = request.max_time;
+    sampling_params.deadline = request.deadline;
+    sampling_params.qos_class = request.qos_class.clone();
+    sampling_params.stop = request.stop.clone();
+    sampling_params.echo = request.echo;
+    sampling_params.skip_special_tokens = request.skip_special_tokens;
+    sampling_params.include_stop_str_in_output = request.include_stop_str_in_output;
+    sampling_params.session_id = request.session_id.clone();
+    sampling_params.parent_request_id = request.parent_request_id.clone();
+    sampling_params.negative_prompt = request.negative_prompt.clone();
+    sampling_params.hidden_states = request.hidden_states;
+    sampling_params.adapter = request.adapter.clone();
+    if let Some(n) = request.n {
+        sampling_params.n = n;
+        sampling_params.best_of = n;
+    }
 
     if request.controller != NONE_CONTROLLER {
         sampling_params.controller = Some(request.controller.clone());
@@ -127,16 +170,22 @@ async fn run_controller(
             let outp = RequestOutput {
                 request_id: request_id.clone(),
                 usage: Default::default(),
+                timing: Default::default(),
                 seq_outputs: vec![SeqOutput {
                     seq_id: 0,
                     index: 0,
                     new_output_tokens: vec![],
                     new_text: String::new(),
                     output_tokens: vec![],
+                    output_text: String::new(),
+                    stop_match: None,
                     finish_reason: Some(FinishReason::Failed),
                     aici_logs: vec![r],
+                    hidden_states: None,
                 }],
                 is_final: true,
+                metadata: None,
+                lineage: Vec::new(),
             };
             let (tx, rx) = tokio::sync::mpsc::channel(1);
             tx.send(Ok(outp)).await.unwrap();
@@ -149,6 +198,10 @@ async fn run_controller(
                 sampling_params,
                 expected: None,
                 init_result,
+                prompt_embeddings: None,
+                embedding_spans: Vec::new(),
+                metadata: None,
+                stop_criterion: None,
             });
 
             bail_if_error!(rx);
@@ -209,6 +262,7 @@ impl futures::Stream for Client {
                             text: choice.new_text.clone(),
                             index: choice.index,
                             finish_reason: choice.finish_reason.map(|r| r.short_name()),
+                            stop_match: choice.stop_match.clone(),
                             micros: choice.aici_logs.iter().map(|e| e.micros).sum(),
                             logs: choice
                                 .aici_logs
@@ -227,6 +281,7 @@ impl futures::Stream for Client {
                                 .iter()
                                 .flat_map(|e| e.storage.clone())
                                 .collect::<Vec<_>>(),
+                            hidden_states: choice.hidden_states.clone(),
                         })
                         .collect(),
                 };
@@ -1,9 +1,10 @@
 use crate::{
     config::{ModelMeta, SamplingParams},
+    gguf,
     iface::{kill_self, AiciRtIface, AsyncCmdChannel},
     seq::RequestOutput,
     util::apply_settings,
-    AddRequest, HashMap, LoaderArgs, ModelExec, RllmEngine,
+    AddRequest, EngineError, HashMap, LoaderArgs, ModelExec, Repo, RllmEngine,
 };
 use actix_web::{middleware::Logger, web, App, HttpServer};
 use aici_abi::toktrie::TokTrie;
@@ -18,7 +19,7 @@ use clap::Args;
 use std::{
     fmt::Display,
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc::{channel, error::TryRecvError, Receiver, Sender};
 
@@ -70,7 +71,12 @@ impl APIError {
     }
 
     pub fn from_anyhow(value: anyhow::Error) -> Self {
-        if UserError::is_self(&value) {
+        if let Some(EngineError::QueueFull { .. }) = value.downcast_ref::<EngineError>() {
+            Self {
+                code: actix_web::http::StatusCode::TOO_MANY_REQUESTS,
+                msg: format!("{value}"),
+            }
+        } else if UserError::is_self(&value) {
             log::info!("UserError: {value}");
             Self {
                 code: actix_web::http::StatusCode::BAD_REQUEST,
@@ -133,7 +139,7 @@ pub struct RllmCliArgs {
     #[arg(long)]
     pub log: Option<String>,
 
-    /// HuggingFace model name, URL or path starting with "./"
+    /// HuggingFace model name (optionally org/model@revision), URL or path starting with "./"
     #[arg(short, long, help_heading = "Model")]
     pub model: String,
 
@@ -141,6 +147,11 @@ pub struct RllmCliArgs {
     #[arg(long, help_heading = "Model")]
     pub revision: Option<String>,
 
+    /// Don't download anything, use files already in the HuggingFace cache
+    /// (same as HF_HUB_OFFLINE=1)
+    #[arg(long, default_value_t = false, help_heading = "Model")]
+    pub offline: bool,
+
     /// The folder name that contains safetensor weights and json files
     /// (same structure as HuggingFace online)
     #[arg(long, help_heading = "Model")]
@@ -309,10 +320,58 @@ More info at: {url}"#
 
 pub enum InferenceReq {
     AddRequest(AddRequest),
+    AbortRequest(String),
 }
 
 type InferenceResult = Result<RequestOutput>;
 
+/// Aborts a request running in the inference loop.
+#[derive(Clone)]
+pub struct AbortHandle {
+    request_id: String,
+    req_sender: Sender<InferenceReq>,
+}
+
+impl AbortHandle {
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    /// The final output (with FinishReason::Aborted) is still delivered to the stream.
+    pub fn abort(&self) -> Result<()> {
+        self.req_sender
+            .try_send(InferenceReq::AbortRequest(self.request_id.clone()))?;
+        Ok(())
+    }
+}
+
+/// Incremental outputs of a request; ends after the final output.
+pub struct RequestStream {
+    rx: Receiver<InferenceResult>,
+    abort: AbortHandle,
+}
+
+impl RequestStream {
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+
+    pub async fn next_output(&mut self) -> Option<InferenceResult> {
+        self.rx.recv().await
+    }
+}
+
+impl futures::Stream for RequestStream {
+    type Item = InferenceResult;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
 pub struct InferenceWorker {
     req_sender: Sender<InferenceReq>,
     running: HashMap<String, Sender<InferenceResult>>,
@@ -334,6 +393,50 @@ impl InferenceWorker {
         self.running.insert(rid, tx);
         Ok(rx)
     }
+
+    /// Like `add_request()`, but also returns a handle to abort the request.
+    pub fn add_request_stream(&mut self, req: AddRequest) -> Result<RequestStream> {
+        let abort = AbortHandle {
+            request_id: req.request_id.clone(),
+            req_sender: self.req_sender.clone(),
+        };
+        let rx = self.add_request(req)?;
+        Ok(RequestStream { rx, abort })
+    }
+
+    /// Run the engine returned by `load` in a background thread; the engine is
+    /// driven by the requests added to the returned worker.
+    /// Waits until the engine is loaded, and returns the error if it fails to load.
+    pub fn spawn<ME: ModelExec>(
+        load: impl FnOnce() -> Result<RllmEngine<ME>> + Send + 'static,
+    ) -> Result<Arc<Mutex<InferenceWorker>>> {
+        let (handle, recv) = InferenceWorker::new();
+        let handle = Arc::new(Mutex::new(handle));
+        let handle2 = handle.clone();
+        let stats = Arc::new(Mutex::new(ServerStats {
+            num_requests: 0,
+            num_tokens: 0,
+            start_time: Instant::now(),
+        }));
+        // the engine stays on the thread it's loaded on
+        let (loaded_tx, loaded_rx) = std::sync::mpsc::sync_channel(1);
+        std::thread::spawn(move || {
+            let mut engine = match load() {
+                Ok(engine) => engine,
+                Err(e) => {
+                    let _ = loaded_tx.send(Err(e));
+                    return;
+                }
+            };
+            let _ = loaded_tx.send(Ok(()));
+            engine.set_stream_deltas(true);
+            inference_loop(handle2, engine, recv, stats, false)
+        });
+        match loaded_rx.recv() {
+            Ok(res) => res.map(|_| handle),
+            Err(_) => bail!("inference thread exited while loading the model"),
+        }
+    }
 }
 
 fn inference_loop<ME: ModelExec>(
@@ -366,12 +469,28 @@ fn inference_loop<ME: ModelExec>(
                         }
                     }
                 }
+                Ok(InferenceReq::AbortRequest(id)) => {
+                    if let Some(outp) = engine.abort_request(&id) {
+                        let tx = handle.lock().unwrap().running.remove(&id);
+                        if let Some(tx) = tx {
+                            if let Err(e) = tx.try_send(Ok(outp)) {
+                                log::warn!("failed to send output to client {id}: {e}");
+                            }
+                        }
+                    }
+                }
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => panic!(),
             }
         }
 
-        let outputs = engine.step().expect("run_model() failed");
+        let outputs = engine.step().expect("run_model() failed").outputs;
+        if outputs.is_empty() {
+            if let Some(wait) = engine.batch_window_left() {
+                // collecting a batch; don't spin
+                std::thread::sleep(std::cmp::min(wait, Duration::from_millis(1)));
+            }
+        }
         {
             let mut stats = stats.lock().unwrap();
             stats.num_tokens += 1;
@@ -472,6 +591,7 @@ fn spawn_inference_loop<ME: ModelExec>(
         let mut engine =
             ME::load_rllm_engine(loader_args, model_args).expect("failed to load model");
         engine.set_aicirt(iface);
+        engine.set_stream_deltas(true);
         let wid = "warmup".to_string();
         match warmup {
             Some(w) if w == "off" => {}
@@ -586,6 +706,8 @@ pub async fn server_main<ME: ModelExec>(
     let mut loader_args = LoaderArgs::default();
     loader_args.model_id = args.model.clone();
     loader_args.revision = args.revision.clone();
+    // HF_HUB_OFFLINE=1 is the default
+    loader_args.offline = args.offline || loader_args.offline;
     loader_args.local_weights = args.local_weights.clone();
     loader_args.file = args.file.clone();
 
@@ -594,17 +716,35 @@ pub async fn server_main<ME: ModelExec>(
             log::info!("explicit tokenizer: {}", v);
             loader_args.tokenizer = v.clone();
         }
-        None => match guess_tokenizer(&loader_args.model_id) {
-            Some(v) => {
-                log::info!("guessed tokenizer: {}", v);
+        None if gguf::is_gguf(&loader_args) => match gguf::extract_tokenizer(&loader_args) {
+            Ok(v) => {
+                log::info!("tokenizer from GGUF: {}", v);
                 loader_args.tokenizer = v;
             }
-            None => {
-                eprintln!("can't guess tokenizer from {}", loader_args.model_id);
+            Err(e) => {
+                eprintln!("can't read tokenizer from {:?}: {e}", loader_args.file);
                 eprintln!("{}", list_tokenizers());
                 std::process::exit(10);
             }
         },
+        None => match guess_tokenizer(&loader_args.model_id) {
+            Some(v) => {
+                log::info!("guessed tokenizer: {}", v);
+                loader_args.tokenizer = v;
+            }
+            // otherwise, the tokenizer of the model, from the same revision
+            None => match Repo::from(&loader_args).and_then(|r| r.get("tokenizer.json")) {
+                Ok(path) => {
+                    log::info!("tokenizer of the model: {}", path.display());
+                    loader_args.tokenizer = path.to_string_lossy().to_string();
+                }
+                Err(e) => {
+                    eprintln!("can't guess tokenizer from {}: {e}", loader_args.model_id);
+                    eprintln!("{}", list_tokenizers());
+                    std::process::exit(10);
+                }
+            },
+        },
     }
 
     if args.test.len() > 0 {
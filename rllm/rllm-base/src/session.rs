@@ -0,0 +1,554 @@
+// KV cache kept between requests of the same session.
+
+use crate::{
+    seq::{Sequence, Token},
+    HashMap, ModelExec, SeqId, SequenceManager,
+};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+// how many requests are remembered as potential parents
+const MAX_TRACKED_REQUESTS: usize = 10_000;
+
+struct Session {
+    seq_id: SeqId,
+    tokens: Vec<Token>,
+    // the KV was computed with this LoRA adapter
+    adapter: Option<String>,
+    last_used: Instant,
+    // set when the KV is on disk and its blocks are freed
+    spilled: Option<Spilled>,
+    // the KV couldn't be exported; not retried until idle again
+    spill_failed: Option<Instant>,
+}
+
+struct Spilled {
+    path: PathBuf,
+    size: usize,
+    // the KV, until the file is written
+    pending: Option<Arc<Vec<u8>>>,
+}
+
+/// Writes spilled KV on its own thread, so the engine step doesn't wait for the disk.
+struct SpillWriter {
+    jobs: mpsc::Sender<(PathBuf, Arc<Vec<u8>>)>,
+    done: mpsc::Receiver<(PathBuf, std::io::Result<()>)>,
+}
+
+impl SpillWriter {
+    fn new() -> Self {
+        let (jobs, job_rx) = mpsc::channel::<(PathBuf, Arc<Vec<u8>>)>();
+        let (done_tx, done) = mpsc::channel();
+        std::thread::spawn(move || {
+            for (path, data) in job_rx {
+                let r = match path.parent() {
+                    Some(dir) => std::fs::create_dir_all(dir),
+                    None => Ok(()),
+                }
+                .and_then(|_| std::fs::write(&path, &*data));
+                if done_tx.send((path, r)).is_err() {
+                    break;
+                }
+            }
+        });
+        SpillWriter { jobs, done }
+    }
+}
+
+struct RequestInfo {
+    session_id: Option<String>,
+    lineage: Vec<String>,
+}
+
+pub(crate) struct SessionCache {
+    sessions: HashMap<String, Session>,
+    max_tokens: usize,
+    max_spill_bytes: usize,
+    requests: HashMap<String, RequestInfo>,
+    request_order: VecDeque<String>,
+    writer: Option<SpillWriter>,
+    // makes the file names unique, even when a session is spilled again
+    num_spills: usize,
+}
+
+impl SessionCache {
+    pub fn new(max_tokens: usize, max_spill_bytes: usize) -> Self {
+        SessionCache {
+            sessions: HashMap::default(),
+            max_tokens,
+            max_spill_bytes,
+            requests: HashMap::default(),
+            request_order: VecDeque::new(),
+            writer: None,
+            num_spills: 0,
+        }
+    }
+
+    /// Remember session and ancestors (parent first) of a request, for its children.
+    pub fn add_request(
+        &mut self,
+        request_id: &str,
+        session_id: Option<String>,
+        lineage: Vec<String>,
+    ) {
+        if self.request_order.len() >= MAX_TRACKED_REQUESTS {
+            let oldest = self.request_order.pop_front().unwrap();
+            self.requests.remove(&oldest);
+        }
+        self.request_order.push_back(request_id.to_string());
+        self.requests.insert(
+            request_id.to_string(),
+            RequestInfo {
+                session_id,
+                lineage,
+            },
+        );
+    }
+
+    /// Session and lineage of a new child of `parent`; None if `parent` is unknown.
+    pub fn child_of(&self, parent: &str) -> Option<(Option<String>, Vec<String>)> {
+        let info = self.requests.get(parent)?;
+        let mut lineage = vec![parent.to_string()];
+        lineage.extend(info.lineage.iter().cloned());
+        Some((info.session_id.clone(), lineage))
+    }
+
+    fn num_tokens(&self) -> usize {
+        self.sessions
+            .values()
+            .filter(|s| s.spilled.is_none())
+            .map(|s| s.tokens.len())
+            .sum()
+    }
+
+    /// Keep KV of the computed tokens of `seq`, replacing the previous entry of the session.
+    pub fn save(&mut self, seq_mgr: &impl SequenceManager, session_id: &str, seq: &Sequence) {
+        self.remove(seq_mgr, session_id);
+        let len = seq.num_kv_computed;
+        if len == 0 || len > self.max_tokens {
+            return;
+        }
+        let seq_id = seq_mgr.new_sequence();
+        seq_mgr.copy(seq.seq_id, seq_id, len);
+        self.sessions.insert(
+            session_id.to_string(),
+            Session {
+                seq_id,
+                tokens: seq.get_tokens()[0..len].to_vec(),
+                adapter: seq.adapter.clone(),
+                last_used: Instant::now(),
+                spilled: None,
+                spill_failed: None,
+            },
+        );
+        self.evict(seq_mgr);
+    }
+
+    /// Number of leading tokens of `seq` that have KV cached in the session.
+    /// At least one token is always left to compute, to get the logits.
+    pub fn prefix_len(&self, session_id: &str, seq: &Sequence) -> usize {
+        match self.sessions.get(session_id) {
+            Some(s) if s.spilled.is_none() && s.adapter == seq.adapter => {
+                let tokens = seq.get_tokens();
+                let max_len = std::cmp::min(s.tokens.len(), tokens.len().saturating_sub(1));
+                (0..max_len)
+                    .find(|&i| s.tokens[i] != tokens[i])
+                    .unwrap_or(max_len)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Copy KV of the first `len` tokens of the session into `seq`.
+    pub fn restore(
+        &mut self,
+        seq_mgr: &impl SequenceManager,
+        session_id: &str,
+        seq: &mut Sequence,
+        len: usize,
+    ) {
+        let s = self.sessions.get_mut(session_id).unwrap();
+        assert!(len > 0 && len <= s.tokens.len() && len < seq.get_len());
+        s.last_used = Instant::now();
+        seq_mgr.copy(s.seq_id, seq.seq_id, len);
+        seq.num_kv_computed = len;
+    }
+
+    pub fn remove(&mut self, seq_mgr: &impl SequenceManager, session_id: &str) -> bool {
+        match self.sessions.remove(session_id) {
+            Some(s) => {
+                Self::drop_session(seq_mgr, s);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self, seq_mgr: &impl SequenceManager) {
+        for (_, s) in self.sessions.drain() {
+            Self::drop_session(seq_mgr, s);
+        }
+    }
+
+    fn drop_session(seq_mgr: &impl SequenceManager, s: Session) {
+        match s.spilled {
+            // if the file is still being written, it's removed by poll_writes()
+            Some(spilled) => {
+                let _ = std::fs::remove_file(spilled.path);
+            }
+            None => seq_mgr.delete(s.seq_id),
+        }
+    }
+
+    /// Move the KV of sessions not used for `idle` to files in `dir`.
+    /// The KV is copied to the host here; the files are written in the background.
+    pub fn spill_idle<ME: ModelExec>(
+        &mut self,
+        tmodel: &mut ME,
+        seq_mgr: &impl SequenceManager,
+        idle: Duration,
+        dir: &Path,
+    ) {
+        self.poll_writes();
+        let now = Instant::now();
+        let is_idle = |t: Instant| now.saturating_duration_since(t) >= idle;
+        let ids = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| {
+                s.spilled.is_none() && is_idle(s.last_used) && s.spill_failed.map_or(true, is_idle)
+            })
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in ids {
+            let s = self.sessions.get_mut(&id).unwrap();
+            match tmodel.export_kv(s.seq_id, s.tokens.len()) {
+                Ok(data) => {
+                    seq_mgr.delete(s.seq_id);
+                    self.start_spill(&id, data, dir);
+                }
+                Err(e) => {
+                    // keep it in memory; it's only evicted when over budget
+                    log::warn!("session {}: can't spill: {}", id, e);
+                    s.spill_failed = Some(now);
+                }
+            }
+        }
+        self.evict_spilled();
+    }
+
+    fn start_spill(&mut self, session_id: &str, data: Vec<u8>, dir: &Path) {
+        let s = self.sessions.get_mut(session_id).unwrap();
+        self.num_spills += 1;
+        let path = dir.join(format!("{}-{}.kv", s.seq_id.to_num(), self.num_spills));
+        let data = Arc::new(data);
+        self.writer
+            .get_or_insert_with(SpillWriter::new)
+            .jobs
+            .send((path.clone(), data.clone()))
+            .unwrap();
+        log::debug!("session {}: spilling {} tokens", session_id, s.tokens.len());
+        s.spilled = Some(Spilled {
+            path,
+            size: data.len(),
+            pending: Some(data),
+        });
+        s.spill_failed = None;
+    }
+
+    /// Handle the files written since the last call.
+    fn poll_writes(&mut self) {
+        let done = match self.writer.as_ref() {
+            Some(w) => w.done.try_iter().collect::<Vec<_>>(),
+            None => return,
+        };
+        for (path, r) in done {
+            let id = self
+                .sessions
+                .iter()
+                .find(|(_, s)| s.spilled.as_ref().map_or(false, |sp| sp.path == path))
+                .map(|(id, _)| id.clone());
+            match (id, r) {
+                (Some(id), Ok(())) => {
+                    let s = self.sessions.get_mut(&id).unwrap();
+                    s.spilled.as_mut().unwrap().pending = None;
+                }
+                (Some(id), Err(e)) => {
+                    // its blocks are already freed
+                    log::warn!("session {}: can't write {}: {}", id, path.display(), e);
+                    self.sessions.remove(&id);
+                }
+                // the session was restored or dropped while the file was written
+                (None, _) => {
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+    }
+
+    fn spilled_bytes(&self) -> usize {
+        self.sessions
+            .values()
+            .filter_map(|s| s.spilled.as_ref())
+            .map(|s| s.size)
+            .sum()
+    }
+
+    /// Drop least recently used spilled sessions until we're within the disk budget.
+    fn evict_spilled(&mut self) {
+        while self.spilled_bytes() > self.max_spill_bytes {
+            let oldest = self
+                .sessions
+                .iter()
+                .filter(|(_, s)| s.spilled.is_some())
+                .min_by_key(|(_, s)| s.last_used)
+                .map(|(id, _)| id.clone())
+                .unwrap();
+            log::debug!("evicting spilled session {}", oldest);
+            let s = self.sessions.remove(&oldest).unwrap();
+            let _ = std::fs::remove_file(s.spilled.unwrap().path);
+        }
+    }
+
+    /// Read the KV of a spilled session back into the cache; false if the session
+    /// is spilled and there is no room for it.
+    pub fn unspill<ME: ModelExec>(
+        &mut self,
+        tmodel: &mut ME,
+        seq_mgr: &impl SequenceManager,
+        session_id: &str,
+    ) -> bool {
+        self.poll_writes();
+        let s = match self.sessions.get_mut(session_id) {
+            Some(s) if s.spilled.is_some() => s,
+            _ => return true,
+        };
+        let spilled = s.spilled.as_ref().unwrap();
+        let r = match spilled.pending.as_ref() {
+            Some(data) => tmodel.import_kv(s.seq_id, s.tokens.len(), data),
+            None => std::fs::read(&spilled.path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| tmodel.import_kv(s.seq_id, s.tokens.len(), &data)),
+        };
+        match r {
+            Ok(true) => {
+                log::debug!("session {}: restored {} tokens", session_id, s.tokens.len());
+                let _ = std::fs::remove_file(&spilled.path);
+                s.spilled = None;
+                s.last_used = Instant::now();
+                self.evict(seq_mgr);
+                self.sessions.contains_key(session_id)
+            }
+            Ok(false) => false,
+            Err(e) => {
+                log::warn!("session {}: can't restore: {}", session_id, e);
+                self.remove(seq_mgr, session_id);
+                false
+            }
+        }
+    }
+
+    /// Drop least recently used sessions in memory until we're within budget.
+    fn evict(&mut self, seq_mgr: &impl SequenceManager) {
+        while self.num_tokens() > self.max_tokens {
+            let oldest = self
+                .sessions
+                .iter()
+                .filter(|(_, s)| s.spilled.is_none())
+                .min_by_key(|(_, s)| s.last_used)
+                .map(|(id, _)| id.clone())
+                .unwrap();
+            log::debug!("evicting session {}", oldest);
+            self.remove(seq_mgr, &oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Tracks the length of the KV held by each sequence.
+    #[derive(Default)]
+    struct MockSeqMgr {
+        next: Mutex<usize>,
+        kv: Mutex<HashMap<SeqId, usize>>,
+    }
+
+    impl SequenceManager for MockSeqMgr {
+        fn new_sequence(&self) -> SeqId {
+            let mut next = self.next.lock().unwrap();
+            *next += 1;
+            SeqId(*next)
+        }
+
+        fn copy(&self, src: SeqId, dst: SeqId, length: usize) {
+            let mut kv = self.kv.lock().unwrap();
+            let len = std::cmp::min(kv.get(&src).copied().unwrap_or(0), length);
+            kv.insert(dst, len);
+        }
+
+        fn trim(&self, seq: SeqId, length: usize) {
+            if let Some(len) = self.kv.lock().unwrap().get_mut(&seq) {
+                *len = std::cmp::min(*len, length);
+            }
+        }
+
+        fn delete(&self, seq: SeqId) {
+            self.kv.lock().unwrap().remove(&seq);
+        }
+    }
+
+    impl MockSeqMgr {
+        fn num_seqs(&self) -> usize {
+            self.kv.lock().unwrap().len()
+        }
+    }
+
+    fn computed_seq(seq_mgr: &MockSeqMgr, tokens: &[Token], computed: usize) -> Sequence {
+        let mut seq = Sequence::new(seq_mgr.new_sequence(), tokens);
+        seq.num_kv_computed = computed;
+        seq_mgr.kv.lock().unwrap().insert(seq.seq_id, computed);
+        seq
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let seq_mgr = MockSeqMgr::default();
+        let mut cache = SessionCache::new(100, 0);
+        let seq = computed_seq(&seq_mgr, &[1, 2, 3, 4, 5, 6], 5);
+        cache.save(&seq_mgr, "s", &seq);
+        assert_eq!(seq_mgr.num_seqs(), 2);
+
+        let mut next = Sequence::new(seq_mgr.new_sequence(), &[1, 2, 3, 9, 10]);
+        assert_eq!(cache.prefix_len("s", &next), 3);
+        assert_eq!(cache.prefix_len("other", &next), 0);
+        // the last token is always computed
+        let same = Sequence::new(seq_mgr.new_sequence(), &[1, 2, 3, 4, 5]);
+        assert_eq!(cache.prefix_len("s", &same), 4);
+        // KV computed with another adapter is not reused
+        let mut lora = Sequence::new(seq_mgr.new_sequence(), &[1, 2, 3, 4, 5]);
+        lora.adapter = Some("a".to_string());
+        assert_eq!(cache.prefix_len("s", &lora), 0);
+
+        cache.restore(&seq_mgr, "s", &mut next, 3);
+        assert_eq!(next.num_kv_computed, 3);
+        assert_eq!(seq_mgr.kv.lock().unwrap()[&next.seq_id], 3);
+
+        assert!(cache.remove(&seq_mgr, "s"));
+        assert!(!cache.remove(&seq_mgr, "s"));
+        assert_eq!(seq_mgr.num_seqs(), 2);
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let seq_mgr = MockSeqMgr::default();
+        let mut cache = SessionCache::new(10, 0);
+        let a = computed_seq(&seq_mgr, &[1, 2, 3, 4, 5], 4);
+        let b = computed_seq(&seq_mgr, &[6, 7, 8, 9, 10], 4);
+        cache.save(&seq_mgr, "a", &a);
+        std::thread::sleep(Duration::from_millis(2));
+        cache.save(&seq_mgr, "b", &b);
+        std::thread::sleep(Duration::from_millis(2));
+        let mut seq = Sequence::new(seq_mgr.new_sequence(), &[1, 2, 3, 4, 5]);
+        cache.restore(&seq_mgr, "a", &mut seq, 4);
+
+        // too long to be kept at all
+        let long = computed_seq(&seq_mgr, &[1; 12], 11);
+        cache.save(&seq_mgr, "c", &long);
+        assert_eq!(cache.prefix_len("c", &long), 0);
+
+        let c = computed_seq(&seq_mgr, &[11, 12, 13, 14], 3);
+        cache.save(&seq_mgr, "c", &c);
+        assert_eq!(cache.num_tokens(), 7);
+        assert!(cache.prefix_len("a", &a) > 0);
+        assert_eq!(cache.prefix_len("b", &b), 0);
+    }
+
+    fn spill_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rllm-test-{}-{}", std::process::id(), name))
+    }
+
+    fn wait_for_writes(cache: &mut SessionCache) {
+        for _ in 0..1000 {
+            cache.poll_writes();
+            let pending = cache
+                .sessions
+                .values()
+                .any(|s| s.spilled.as_ref().map_or(false, |sp| sp.pending.is_some()));
+            if !pending {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("spilled KV not written");
+    }
+
+    #[test]
+    fn spill_in_background() {
+        let seq_mgr = MockSeqMgr::default();
+        let mut cache = SessionCache::new(100, 15);
+        let dir = spill_dir("spill");
+        for (id, tokens) in [("a", [1, 2, 3]), ("b", [4, 5, 6])] {
+            let seq = computed_seq(&seq_mgr, &tokens, 2);
+            cache.save(&seq_mgr, id, &seq);
+            std::thread::sleep(Duration::from_millis(2));
+            seq_mgr.delete(cache.sessions[id].seq_id);
+            cache.start_spill(id, vec![7; 10], &dir);
+        }
+        // spilled sessions don't take KV, and are not reused until restored
+        assert_eq!(cache.num_tokens(), 0);
+        let next = Sequence::new(seq_mgr.new_sequence(), &[4, 5, 6]);
+        assert_eq!(cache.prefix_len("b", &next), 0);
+
+        // over the disk budget
+        cache.evict_spilled();
+        assert!(!cache.sessions.contains_key("a"));
+        wait_for_writes(&mut cache);
+        let path = cache.sessions["b"].spilled.as_ref().unwrap().path.clone();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![7; 10]);
+        // the file of the evicted session is removed once written
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        cache.clear(&seq_mgr);
+        assert!(!path.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_write() {
+        let seq_mgr = MockSeqMgr::default();
+        let mut cache = SessionCache::new(100, 1000);
+        // a file where the directory should be
+        let dir = spill_dir("not-a-dir");
+        std::fs::write(&dir, b"").unwrap();
+        let seq = computed_seq(&seq_mgr, &[1, 2, 3], 2);
+        cache.save(&seq_mgr, "a", &seq);
+        seq_mgr.delete(cache.sessions["a"].seq_id);
+        cache.start_spill("a", vec![7; 10], &dir);
+        wait_for_writes(&mut cache);
+        assert!(!cache.sessions.contains_key("a"));
+        let _ = std::fs::remove_file(&dir);
+    }
+
+    #[test]
+    fn lineage() {
+        let mut cache = SessionCache::new(100, 0);
+        cache.add_request("r1", Some("s".to_string()), vec![]);
+        let (session, lineage) = cache.child_of("r1").unwrap();
+        cache.add_request("r2", session, lineage);
+        assert_eq!(
+            cache.child_of("r2"),
+            Some((
+                Some("s".to_string()),
+                vec!["r2".to_string(), "r1".to_string()]
+            ))
+        );
+        assert_eq!(cache.child_of("r3"), None);
+    }
+}
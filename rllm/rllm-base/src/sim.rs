@@ -0,0 +1,694 @@
+//! Replay of workload traces against the scheduler, with a synthetic model
+//! in place of the GPU, to evaluate scheduling policies, token budgets and block
+//! sizes offline.
+
+use crate::{
+    config::{AiciConfig, ModelMeta, ParallelConfig, RllmConfig, SamplingParams, SchedulerConfig},
+    seq::{FinishReason, SchedulingPhase, Sequence, SequenceGroup, TokenUsage},
+    AiciBias, HashMap, LoaderArgs, LogitsProcessor, ModelExec, RllmEngine, Scheduler,
+    SchedulerOutputs, SeqId, SequenceManager, TBlockSpaceManager,
+};
+use aici_abi::SimpleVob;
+use aicirt::TimerRef;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Block layout and step latency of the synthetic model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimConfig {
+    /// Tokens per KV cache block.
+    pub block_size: usize,
+    pub num_gpu_blocks: usize,
+    pub num_cpu_blocks: usize,
+    /// Fixed cost of a step (a forward pass), in milliseconds.
+    pub step_ms: f64,
+    /// Cost of every token computed in a step.
+    pub token_ms: f64,
+    /// Cost of every sequence in a step (eg., attention over its KV cache).
+    pub seq_ms: f64,
+    /// Cost of swapping a block in or out.
+    pub swap_block_ms: f64,
+    /// Give up on requests still unfinished after this many (virtual) seconds.
+    pub max_duration_secs: f64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            block_size: 16,
+            num_gpu_blocks: 2048,
+            num_cpu_blocks: 512,
+            step_ms: 10.0,
+            token_ms: 0.05,
+            seq_ms: 0.1,
+            swap_block_ms: 0.02,
+            max_duration_secs: 3600.0,
+        }
+    }
+}
+
+/// One request of a workload trace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimRequest {
+    /// Defaults to the index in the trace.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// Seconds from the start of the trace.
+    pub arrival: f64,
+    pub prompt_tokens: usize,
+    /// Number of tokens generated before the request finishes.
+    pub gen_tokens: usize,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub deadline: Option<f32>,
+    #[serde(default)]
+    pub max_time: Option<f32>,
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    #[serde(default)]
+    pub qos_class: Option<String>,
+}
+
+/// Read a trace with one JSON-encoded SimRequest per line.
+pub fn load_trace(path: &str) -> Result<Vec<SimRequest>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut res = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(r) => res.push(r),
+            Err(e) => bail!("{path}:{}: {e}", idx + 1),
+        }
+    }
+    Ok(res)
+}
+
+/// Times (in seconds) are relative to the arrival of the request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimRequestStats {
+    pub request_id: String,
+    pub arrival: f64,
+    /// Until the first step the request was scheduled in.
+    pub queue_time: Option<f64>,
+    pub time_to_first_token: Option<f64>,
+    pub latency: f64,
+    pub gen_tokens: usize,
+    pub finish_reason: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimReport {
+    pub num_steps: usize,
+    pub num_preempted: usize,
+    pub num_swapped_blocks: usize,
+    /// Seconds from the start of the trace to the last finished request.
+    pub duration: f64,
+    pub requests: Vec<SimRequestStats>,
+}
+
+impl SimReport {
+    fn mean(v: impl Iterator<Item = f64>) -> f64 {
+        let (sum, n) = v.fold((0.0, 0), |(s, n), x| (s + x, n + 1));
+        if n == 0 {
+            0.0
+        } else {
+            sum / n as f64
+        }
+    }
+
+    pub fn mean_queue_time(&self) -> f64 {
+        Self::mean(self.requests.iter().filter_map(|r| r.queue_time))
+    }
+
+    pub fn mean_time_to_first_token(&self) -> f64 {
+        Self::mean(self.requests.iter().filter_map(|r| r.time_to_first_token))
+    }
+
+    pub fn mean_latency(&self) -> f64 {
+        Self::mean(self.requests.iter().map(|r| r.latency))
+    }
+
+    pub fn tokens_per_second(&self) -> f64 {
+        let tokens: usize = self.requests.iter().map(|r| r.gen_tokens).sum();
+        if self.duration > 0.0 {
+            tokens as f64 / self.duration
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Number of blocks held by each sequence; blocks are never shared.
+struct SimBlocks {
+    block_size: usize,
+    num_gpu: usize,
+    num_cpu: usize,
+    gpu: HashMap<SeqId, usize>,
+    cpu: HashMap<SeqId, usize>,
+    // block numbers reported in swap mappings
+    next_block: usize,
+}
+
+impl SimBlocks {
+    fn num_blocks(&self, length: usize) -> usize {
+        (length + self.block_size - 1) / self.block_size
+    }
+
+    fn free_gpu(&self) -> usize {
+        self.num_gpu - self.gpu.values().sum::<usize>()
+    }
+
+    fn free_cpu(&self) -> usize {
+        self.num_cpu - self.cpu.values().sum::<usize>()
+    }
+
+    fn held(&self, seq: SeqId) -> usize {
+        self.gpu
+            .get(&seq)
+            .or(self.cpu.get(&seq))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn move_seq(&mut self, seq: SeqId, to_gpu: bool, mapping: &mut HashMap<usize, usize>) {
+        let (from, to) = if to_gpu {
+            (&mut self.cpu, &mut self.gpu)
+        } else {
+            (&mut self.gpu, &mut self.cpu)
+        };
+        let n = from.remove(&seq).unwrap_or(0);
+        to.insert(seq, n);
+        for _ in 0..n {
+            mapping.insert(self.next_block, self.next_block + 1);
+            self.next_block += 2;
+        }
+    }
+}
+
+pub struct SimSeqMgr {
+    next: Mutex<usize>,
+    blocks: Arc<Mutex<SimBlocks>>,
+}
+
+impl SequenceManager for SimSeqMgr {
+    fn new_sequence(&self) -> SeqId {
+        let mut l = self.next.lock().unwrap();
+        let r = SeqId(*l);
+        *l = *l + 1;
+        r
+    }
+
+    fn copy(&self, src: SeqId, dst: SeqId, length: usize) {
+        let mut b = self.blocks.lock().unwrap();
+        let n = std::cmp::min(b.held(src), b.num_blocks(length));
+        b.cpu.remove(&dst);
+        b.gpu.insert(dst, n);
+    }
+
+    fn trim(&self, seq: SeqId, length: usize) {
+        let mut b = self.blocks.lock().unwrap();
+        let n = b.num_blocks(length);
+        let b = &mut *b;
+        for m in [&mut b.gpu, &mut b.cpu] {
+            if let Some(v) = m.get_mut(&seq) {
+                *v = std::cmp::min(*v, n);
+            }
+        }
+    }
+
+    fn delete(&self, seq: SeqId) {
+        let mut b = self.blocks.lock().unwrap();
+        b.gpu.remove(&seq);
+        b.cpu.remove(&seq);
+    }
+}
+
+pub struct SimBlockSpaceManager {
+    blocks: Arc<Mutex<SimBlocks>>,
+}
+
+impl SimBlockSpaceManager {
+    fn num_append_blocks(b: &SimBlocks, seq: &Sequence) -> usize {
+        b.num_blocks(seq.get_len())
+            .saturating_sub(b.held(seq.seq_id))
+    }
+}
+
+impl TBlockSpaceManager<SimModel> for SimBlockSpaceManager {
+    fn can_allocate(&self, seq_group: &SequenceGroup) -> bool {
+        self.get_num_free_gpu_blocks() >= self.get_num_required_blocks(seq_group)
+    }
+
+    fn get_num_required_blocks(&self, seq_group: &SequenceGroup) -> usize {
+        let b = self.blocks.lock().unwrap();
+        seq_group
+            .seqs
+            .iter()
+            .map(|seq| b.num_blocks(seq.get_len()))
+            .sum()
+    }
+
+    fn allocate(&mut self, seq_group: &mut SequenceGroup) {
+        let mut b = self.blocks.lock().unwrap();
+        for seq in seq_group.seqs.iter() {
+            assert!(seq.num_kv_computed == 0);
+            let n = b.num_blocks(seq.get_len());
+            b.gpu.insert(seq.seq_id, n);
+        }
+    }
+
+    fn can_append_slot(&self, seq_group: &SequenceGroup) -> bool {
+        let b = self.blocks.lock().unwrap();
+        let num_required_blocks = seq_group
+            .get_seqs(Some(SchedulingPhase::Running))
+            .iter()
+            .map(|seq| Self::num_append_blocks(&b, seq))
+            .sum::<usize>();
+        b.free_gpu() >= num_required_blocks
+    }
+
+    fn append_slots(&mut self, seq: &mut Sequence, _outputs: &mut SchedulerOutputs) {
+        let mut b = self.blocks.lock().unwrap();
+        let n = b.num_blocks(seq.get_len());
+        b.gpu.insert(seq.seq_id, n);
+    }
+
+    fn get_num_free_gpu_blocks(&self) -> usize {
+        self.blocks.lock().unwrap().free_gpu()
+    }
+
+    fn get_num_free_cpu_blocks(&self) -> usize {
+        self.blocks.lock().unwrap().free_cpu()
+    }
+
+    fn get_num_gpu_blocks(&self) -> usize {
+        self.blocks.lock().unwrap().num_gpu
+    }
+
+    fn get_num_cpu_blocks(&self) -> usize {
+        self.blocks.lock().unwrap().num_cpu
+    }
+
+    fn get_num_blocks(&self, seq_group: &SequenceGroup) -> usize {
+        let b = self.blocks.lock().unwrap();
+        seq_group.seqs.iter().map(|seq| b.held(seq.seq_id)).sum()
+    }
+
+    fn can_swap_in(&self, seq_group: &SequenceGroup) -> bool {
+        let blocks = self.get_num_blocks(seq_group);
+        let num_swapped_seqs = seq_group.num_seqs(Some(SchedulingPhase::Swapped));
+        self.get_num_free_gpu_blocks() >= blocks + num_swapped_seqs
+    }
+
+    fn swap_in(&mut self, seq_group: &mut SequenceGroup) -> HashMap<usize, usize> {
+        let mut mapping = HashMap::default();
+        let mut b = self.blocks.lock().unwrap();
+        for seq in &mut seq_group.seqs {
+            if seq.sched_phase == SchedulingPhase::Swapped {
+                b.move_seq(seq.seq_id, true, &mut mapping);
+                seq.sched_phase = SchedulingPhase::Running;
+            }
+        }
+        mapping
+    }
+
+    fn swap_out(&mut self, seq_group: &mut SequenceGroup) -> HashMap<usize, usize> {
+        let mut mapping = HashMap::default();
+        let mut b = self.blocks.lock().unwrap();
+        for seq in &mut seq_group.seqs {
+            if seq.sched_phase == SchedulingPhase::Running {
+                b.move_seq(seq.seq_id, false, &mut mapping);
+                seq.sched_phase = SchedulingPhase::Swapped;
+            }
+        }
+        mapping
+    }
+
+    fn can_swap_out(&self, seq_group: &SequenceGroup) -> bool {
+        self.get_num_blocks(seq_group) <= self.get_num_free_cpu_blocks()
+    }
+}
+
+pub struct SimBias {}
+
+impl AiciBias<Vec<f32>> for SimBias {
+    fn apply(&self, _logits: &mut Vec<f32>, _seq_id: usize) {}
+}
+
+/// Stand-in for a model, which never runs; simulate() drives the scheduler directly.
+pub struct SimModel {
+    seq_mgr: Arc<SimSeqMgr>,
+}
+
+impl ModelExec for SimModel {
+    type Tensor = Vec<f32>;
+    type BlockSpaceManager = SimBlockSpaceManager;
+    type AiciBias = SimBias;
+    type ModelConfig = ();
+    type ModelLoaderArgs = ();
+    type SequenceManager = SimSeqMgr;
+
+    fn tensor_to_vec1(tensor: &Self::Tensor) -> Vec<f32> {
+        tensor.clone()
+    }
+
+    fn load_model_config(
+        _args: &LoaderArgs,
+        _model_args: &mut Self::ModelLoaderArgs,
+    ) -> Result<(ModelMeta, Self::ModelConfig)> {
+        bail!("simulated model has no config")
+    }
+
+    fn verify_args(_args: &RllmConfig<Self>) -> Result<()> {
+        Ok(())
+    }
+
+    fn load_rllm_engine(
+        _args: LoaderArgs,
+        _model_args: Self::ModelLoaderArgs,
+    ) -> Result<RllmEngine<Self>> {
+        bail!("simulated model can't be loaded; use sim::simulate()")
+    }
+
+    fn sequence_manager(&self) -> Arc<Self::SequenceManager> {
+        self.seq_mgr.clone()
+    }
+
+    fn run(
+        &mut self,
+        _vocab_size: usize,
+        _tim: &TimerRef,
+        _step_no: usize,
+        _sched_out: &mut SchedulerOutputs,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_logits(&self, _seq_id: usize) -> Self::Tensor {
+        Vec::new()
+    }
+
+    fn finalize_run(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn empty_bias(&self, _vocab_size: usize) -> Self::AiciBias {
+        SimBias {}
+    }
+
+    fn new_bias(
+        &self,
+        _slice: &'static [f32],
+        _num_seqs: usize,
+        _vocab_size: usize,
+    ) -> Self::AiciBias {
+        SimBias {}
+    }
+
+    fn sample(&self, _processor: &mut LogitsProcessor, _logits: &Self::Tensor) -> Result<u32> {
+        bail!("simulated model doesn't sample")
+    }
+
+    fn apply_token_mask(&self, _logits: &mut Self::Tensor, _allowed: &SimpleVob) {}
+
+    fn apply_guidance(&self, _logits: &mut Self::Tensor, _negative: &Self::Tensor, _scale: f32) {}
+}
+
+fn build_seq_group(
+    seq_mgr: &SimSeqMgr,
+    idx: usize,
+    req: &SimRequest,
+    arrival_time: Instant,
+) -> SequenceGroup {
+    let mut sampling_params = SamplingParams::default();
+    sampling_params.max_tokens = req.gen_tokens;
+    sampling_params.ignore_eos = true;
+    sampling_params.priority = req.priority;
+    sampling_params.deadline = req.deadline;
+    sampling_params.max_time = req.max_time;
+    sampling_params.tenant_id = req.tenant_id.clone();
+    sampling_params.qos_class = req.qos_class.clone();
+    // the token values don't matter, only how many there are
+    let tokens = vec![1; std::cmp::max(req.prompt_tokens, 1)];
+    let seq = Sequence::new(seq_mgr.new_sequence(), &tokens);
+    SequenceGroup {
+        request_id: req.request_id.clone().unwrap_or_else(|| idx.to_string()),
+        prompt: String::new(),
+        seqs: vec![seq],
+        arrival_time,
+        priority: req.priority,
+        logits_processor: LogitsProcessor::new(&sampling_params),
+        max_index: 0,
+        usage: TokenUsage::default(),
+        metadata: None,
+        lineage: Vec::new(),
+        stop_criterion: None,
+        scheduled_time: None,
+        first_token_time: None,
+        sampling_params,
+    }
+}
+
+/// Run the requests of `trace` through a scheduler with the given config; time
+/// is virtual and advances by the modeled latency of each step.
+pub fn simulate(scheduler: SchedulerConfig, sim: &SimConfig, trace: &[SimRequest]) -> SimReport {
+    let blocks = Arc::new(Mutex::new(SimBlocks {
+        block_size: sim.block_size,
+        num_gpu: sim.num_gpu_blocks,
+        num_cpu: sim.num_cpu_blocks,
+        gpu: HashMap::default(),
+        cpu: HashMap::default(),
+        next_block: 0,
+    }));
+    let seq_mgr = Arc::new(SimSeqMgr {
+        next: Mutex::new(1),
+        blocks: blocks.clone(),
+    });
+    let config = RllmConfig::<SimModel> {
+        model: (),
+        meta: ModelMeta {
+            id: "sim".to_string(),
+            max_sequence_length: scheduler.max_model_len,
+            vocab_size: 0,
+            tok_vocab_size: 0,
+        },
+        parallel: ParallelConfig::single(),
+        scheduler,
+        aici: AiciConfig::default(),
+    };
+    let mut sched = Scheduler::new(
+        seq_mgr.clone(),
+        SimBlockSpaceManager { blocks },
+        Arc::new(config),
+    );
+    sched.stop_clock();
+    let start = sched.now();
+    let secs = |t: Instant| t.saturating_duration_since(start).as_secs_f64();
+
+    let arrivals = trace
+        .iter()
+        .map(|r| start + Duration::from_secs_f64(r.arrival.max(0.0)))
+        .collect::<Vec<_>>();
+    let mut order = (0..trace.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&idx| arrivals[idx]);
+    let mut next = 0;
+    let idle = Duration::from_secs_f64(sim.step_ms.max(1.0) / 1000.0);
+
+    let mut report = SimReport {
+        num_steps: 0,
+        num_preempted: 0,
+        num_swapped_blocks: 0,
+        duration: 0.0,
+        requests: Vec::new(),
+    };
+    let record = |sg: &SequenceGroup, now: Instant, report: &mut SimReport| {
+        let arrival = secs(sg.arrival_time);
+        let reason = sg
+            .seqs
+            .iter()
+            .find_map(|seq| seq.finish_reason())
+            .unwrap_or(FinishReason::Aborted);
+        report.duration = report.duration.max(secs(now));
+        report.requests.push(SimRequestStats {
+            request_id: sg.request_id.clone(),
+            arrival,
+            queue_time: sg.scheduled_time.map(|t| secs(t) - arrival),
+            time_to_first_token: sg.first_token_time.map(|t| secs(t) - arrival),
+            latency: secs(now) - arrival,
+            gen_tokens: sg.total_gen_len(),
+            finish_reason: reason.short_name(),
+        });
+    };
+
+    while secs(sched.now()) < sim.max_duration_secs {
+        let now = sched.now();
+        while next < order.len() && arrivals[order[next]] <= now {
+            let idx = order[next];
+            sched.add_seq_group(build_seq_group(&seq_mgr, idx, &trace[idx], arrivals[idx]));
+            next += 1;
+        }
+        let until_next = order.get(next).map(|&idx| arrivals[idx] - now);
+
+        if !sched.has_unfinished_seqs() {
+            match until_next {
+                Some(d) => {
+                    sched.advance_clock(d);
+                    continue;
+                }
+                None => break,
+            }
+        }
+
+        let mut outputs = sched.schedule();
+        for sg in outputs.dropped_seq_groups.drain(..) {
+            record(&sg, now, &mut report);
+        }
+
+        if outputs.is_empty() {
+            // nothing runnable; wait for new arrivals or time-outs
+            let mut d = sched.batch_window_left().unwrap_or(idle);
+            if let Some(n) = until_next {
+                d = std::cmp::min(d, n);
+            }
+            sched.step_finished(outputs);
+            sched.advance_clock(std::cmp::max(d, Duration::from_micros(1)));
+            continue;
+        }
+
+        report.num_steps += 1;
+        report.num_preempted += outputs.num_preempted;
+        let num_swapped_blocks = outputs.blocks_to_swap_in.len() + outputs.blocks_to_swap_out.len();
+        report.num_swapped_blocks += num_swapped_blocks;
+
+        let mut num_tokens = 0;
+        let mut num_seqs = 0;
+        for sg in outputs.next_seq_groups.iter() {
+            for seq in sg.get_seqs(Some(SchedulingPhase::Running)) {
+                num_tokens += seq.step_positions().len();
+                num_seqs += 1;
+            }
+        }
+        let ms = sim.step_ms
+            + sim.token_ms * num_tokens as f64
+            + sim.seq_ms * num_seqs as f64
+            + sim.swap_block_ms * num_swapped_blocks as f64;
+        let step_end = now + Duration::from_secs_f64(ms / 1000.0);
+
+        for sg in outputs.next_seq_groups.iter_mut() {
+            if sg.scheduled_time.is_none() {
+                sg.scheduled_time = Some(now);
+            }
+            let max_tokens = sg.sampling_params.max_tokens;
+            for seq in sg.seqs.iter_mut() {
+                if seq.sched_phase != SchedulingPhase::Running {
+                    continue;
+                }
+                let end = seq.step_positions().end;
+                seq.sync_computed_kv_to(end);
+                if end < seq.get_len() {
+                    // more prefill chunks to go
+                    continue;
+                }
+                if seq.get_gen_len() < max_tokens {
+                    seq.append_tokens(&[1]);
+                }
+                if seq.get_gen_len() >= max_tokens {
+                    sched.finish_seq(seq, FinishReason::MaxTokensReached);
+                }
+            }
+            if sg.first_token_time.is_none() && sg.seqs.iter().any(|s| s.get_gen_len() > 0) {
+                sg.first_token_time = Some(step_end);
+            }
+        }
+
+        sched.step_finished(outputs);
+        sched.advance_clock(step_end - now);
+    }
+
+    let now = sched.now();
+    for sg in sched.abort_all() {
+        record(&sg, now, &mut report);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::SchedulerPolicy, PreemptionMode};
+
+    fn scheduler_config(policy: SchedulerPolicy) -> SchedulerConfig {
+        SchedulerConfig {
+            max_num_batched_tokens: 1024,
+            max_num_kv_tokens: 10_000,
+            max_num_seqs: 10,
+            max_num_prefill_tokens: None,
+            max_num_decode_seqs: None,
+            max_model_len: 1024,
+            max_session_kv_tokens: 0,
+            kept_kv_idle_secs: None,
+            session_spill_secs: None,
+            session_spill_dir: String::new(),
+            max_session_spill_bytes: 0,
+            compact_kv_free_run: None,
+            policy,
+            prefill_chunk_size: None,
+            preemption_mode: None,
+            min_swap_tokens: 1024,
+            evict_swapped: false,
+            priority_aging_secs: None,
+            max_queue_secs: None,
+            max_waiting_time: None,
+            max_group_block_share: None,
+            max_queued_requests: None,
+            max_queued_prompt_tokens: None,
+            qos_classes: Vec::new(),
+            batch_window_ms: None,
+            batch_window_requests: 32,
+            decode_reserve: 0.0,
+            block_watermark: 0.0,
+            reserved_blocks_per_seq: 1,
+            pipeline_steps: false,
+        }
+    }
+
+    fn request(id: &str, arrival: f64, prompt_tokens: usize, gen_tokens: usize) -> SimRequest {
+        SimRequest {
+            request_id: Some(id.to_string()),
+            arrival,
+            prompt_tokens,
+            gen_tokens,
+            priority: 0,
+            deadline: None,
+            max_time: None,
+            tenant_id: Some(id.to_string()),
+            qos_class: None,
+        }
+    }
+
+    #[test]
+    fn fair_share_preempts_tenant_without_waiting_requests() {
+        let mut config = scheduler_config(SchedulerPolicy::FairShare);
+        config.max_waiting_time = Some(0.05);
+        config.preemption_mode = Some(PreemptionMode::Recompute);
+        let sim = SimConfig {
+            num_gpu_blocks: 8,
+            num_cpu_blocks: 0,
+            ..SimConfig::default()
+        };
+        // "b" can't fit next to "a", and waits too long; "a" is preempted for it
+        // while no other request of tenant "a" waits
+        let trace = [request("a", 0.0, 64, 40), request("b", 0.01, 64, 10)];
+        let report = simulate(config, &sim, &trace);
+        assert!(report.num_preempted > 0);
+        assert_eq!(report.requests.len(), 2);
+        assert!(report.requests.iter().all(|r| r.finish_reason == "length"));
+    }
+}
@@ -1,18 +1,30 @@
 use rllm::config::{ModelMeta, RllmConfig};
 use aicirt::bail_user;
 use anyhow::Result;
+use serde::Deserialize;
+use std::ops::Range;
 use tch::Device;
 
-use super::{tmodel::TModel, DType};
+use super::{lora::LoraSet, medusa::Medusa, tmodel::TModel, util::device_supports_dtype, DType};
 
 const GB: usize = 1 << 30;
 
+/// Block sizes the paged attention kernel is compiled for.
+const PAGED_ATTN_BLOCK_SIZES: [usize; 3] = [8, 16, 32];
+
 pub trait TchRllmConfig {
     fn get_hidden_size(&self) -> usize;
     fn get_head_size(&self) -> usize;
     fn get_num_heads_parallel(&self) -> usize;
     fn get_num_layers_parallel(&self) -> usize;
+    /// KV shape of the given layer of this shard.
+    fn get_layer_kv_shape_parallel(&self, layer: usize) -> KvShape;
     fn get_max_model_len(&self) -> usize;
+    /// Devices holding shards of the KV cache: for each pipeline stage, one per
+    /// tensor-parallel rank, starting with the model device.
+    fn get_kv_devices(&self) -> Vec<Device>;
+    /// Layers whose KV cache is on the `idx`-th of get_kv_devices().
+    fn get_kv_device_layers(&self, idx: usize) -> Range<usize>;
     fn verify_args(&self) -> Result<()>;
 }
 
@@ -27,6 +39,15 @@ impl TchRllmConfig for RllmConfig<TModel> {
                 parallel.pipeline_parallel_size
             );
         }
+        if model.num_key_value_heads == 0
+            || model.num_attention_heads % model.num_key_value_heads != 0
+        {
+            bail_user!(
+                "Number of attention heads ({}) must be a multiple of the number of key/value heads ({}).",
+                model.num_attention_heads,
+                model.num_key_value_heads
+            );
+        }
         if model.num_key_value_heads % parallel.tensor_parallel_size != 0 {
             bail_user!(
                 "Number of key/value heads ({}) must be divisible by the tensor parallel size ({}).",
@@ -34,6 +55,204 @@ impl TchRllmConfig for RllmConfig<TModel> {
                 parallel.tensor_parallel_size
             );
         }
+        if !device_supports_dtype(model.device, model.dtype) {
+            bail_user!(
+                "{:?} is not supported on {:?}; try --dtype auto.",
+                model.dtype,
+                model.device
+            );
+        }
+        let tp = parallel.tensor_parallel_size;
+        let pp = parallel.pipeline_parallel_size;
+        if parallel.num_micro_batches == 0 {
+            bail_user!("Number of micro-batches must be at least 1.");
+        }
+        if tp * pp > 1 {
+            match model.device {
+                Device::Cuda(n) if n + tp * pp <= tch::Cuda::device_count() as usize => {}
+                _ => bail_user!("Model parallelism needs {} CUDA devices.", tp * pp),
+            }
+            if ![ModelType::Llama, ModelType::Qwen2].contains(&model.model_type) {
+                bail_user!("Model parallelism is only supported for Llama and Qwen2 models.");
+            }
+            if model.quantization.is_some() {
+                bail_user!("Model parallelism is not supported for quantized models.");
+            }
+            if !model.lora.adapters.is_empty() {
+                bail_user!("LoRA adapters are not supported with model parallelism.");
+            }
+            if model.cache.swap_space_bytes > 0 {
+                // the host copies of blocks only cover the KV cache of the model device
+                bail_user!(
+                    "Swapping KV blocks is not supported with model parallelism; use --swap-space 0."
+                );
+            }
+        }
+        if tp > 1 {
+            if !cfg!(feature = "nccl") {
+                bail_user!("Tensor parallelism needs rllm-cuda built with the nccl feature.");
+            }
+            if model.intermediate_size % tp != 0 {
+                bail_user!(
+                    "Intermediate size ({}) must be divisible by the tensor parallel size ({}).",
+                    model.intermediate_size,
+                    tp
+                );
+            }
+            if model.moe.is_some() {
+                bail_user!("Tensor parallelism is not supported for MoE models.");
+            }
+        }
+        if pp > 1 && model.cache.kv_budget.is_some() {
+            // attention scores would have to be collected from all micro-batches
+            bail_user!("kv_budget can't be used with pipeline parallelism.");
+        }
+        if pp > 1 && model.medusa.is_some() {
+            // logits of draft tokens are not split into micro-batches
+            bail_user!("Medusa heads can't be used with pipeline parallelism.");
+        }
+        if model.medusa.as_ref().map_or(false, |m| m.top_k > 1)
+            && (model.sliding_window.is_some() || model.alibi_bias_max.is_some())
+        {
+            // the draft tree is attended to with an explicit mask, without either
+            bail_user!("medusa_top_k > 1 can't be used with sliding window or ALiBi models.");
+        }
+        if let Some(shapes) = model.layer_kv_shapes.as_ref() {
+            if shapes.len() != model.num_hidden_layers {
+                bail_user!(
+                    "Got KV shapes of {} layers, but the model has {}.",
+                    shapes.len(),
+                    model.num_hidden_layers
+                );
+            }
+            let x = 16 / model.dtype.elt_size_in_bytes();
+            for (layer, shape) in shapes.iter().enumerate() {
+                if shape.num_kv_heads % parallel.tensor_parallel_size != 0 {
+                    bail_user!(
+                        "Number of key/value heads of layer {} ({}) must be divisible by the tensor parallel size ({}).",
+                        layer,
+                        shape.num_kv_heads,
+                        parallel.tensor_parallel_size
+                    );
+                }
+                if shape.num_kv_heads == 0
+                    || shape.head_dim % x != 0
+                    || model.num_attention_heads % shape.num_kv_heads != 0
+                {
+                    bail_user!("Unsupported KV shape of layer {}: {:?}.", layer, shape);
+                }
+            }
+        }
+        let block_size = model.cache.block_size;
+        if model.cache.paged_attn_kernel_v > 0 {
+            if !PAGED_ATTN_BLOCK_SIZES.contains(&block_size) {
+                bail_user!(
+                    "Block size {} is not supported by the paged attention kernel; use one of {:?}.",
+                    block_size,
+                    PAGED_ATTN_BLOCK_SIZES
+                );
+            }
+        } else if block_size == 0 {
+            bail_user!("Block size must be positive.");
+        }
+        if let Some(budget) = model.cache.kv_budget {
+            if model.sliding_window.is_some() {
+                bail_user!("kv_budget can't be used with a sliding_window.");
+            }
+            if budget < 4 * model.cache.block_size {
+                bail_user!(
+                    "kv_budget ({}) must be at least 4 blocks ({} tokens).",
+                    budget,
+                    4 * model.cache.block_size
+                );
+            }
+        }
+        if let Some(s) = model.rope_scaling.as_ref() {
+            if !["linear", "dynamic", "yarn"].contains(&s.kind.as_str()) {
+                bail_user!(
+                    "Only linear, dynamic and yarn rope_scaling is supported, got {}.",
+                    s.kind
+                );
+            }
+            if s.factor < 1.0 {
+                bail_user!("rope_scaling factor must be at least 1, got {}.", s.factor);
+            }
+        }
+        if model.lora.runtime || model.lora.has_per_request() {
+            if ![ModelType::Llama, ModelType::Qwen2].contains(&model.model_type) {
+                bail_user!("Runtime LoRA adapters are only supported for Llama and Qwen2 models.");
+            }
+        }
+        if model.lora.has_per_request() && model.moe.is_some() {
+            // experts see a subset of the tokens of the batch
+            bail_user!("LoRA adapters selected per request are not supported with MoE models.");
+        }
+        let merged = model
+            .lora
+            .adapters
+            .iter()
+            .any(|a| !a.is_runtime(&model.lora));
+        if merged && model.quantization.is_some() {
+            bail_user!(
+                "LoRA adapters can't be merged into quantized weights; apply them at runtime."
+            );
+        }
+        if let Some(q) = model.quantization.as_ref() {
+            if !["gptq", "awq"].contains(&q.quant_method.as_str()) || q.bits != 4 {
+                bail_user!(
+                    "Only 4-bit GPTQ and AWQ quantization is supported, got {} with {} bits.",
+                    q.quant_method,
+                    q.bits
+                );
+            }
+            if model.dtype != DType::Half {
+                bail_user!("Quantized models require f16 dtype, got {:?}.", model.dtype);
+            }
+            if model.model_type != ModelType::Llama {
+                bail_user!("Quantization is only supported for Llama models.");
+            }
+            let group = q.group_size;
+            let kv_size = model.head_dim * model.num_key_value_heads;
+            for size in [model.hidden_size, model.intermediate_size, kv_size] {
+                if size % 8 != 0 || (group > 0 && size as i64 % group != 0) {
+                    bail_user!(
+                        "Layer size {} doesn't fit quantization groups of {} rows.",
+                        size,
+                        group
+                    );
+                }
+                // output channels of the AWQ kernel come in tiles of 64
+                if q.is_awq() && (group <= 0 || group % 32 != 0 || size % 64 != 0) {
+                    bail_user!(
+                        "AWQ needs group size a multiple of 32 and layer sizes of 64; got {} and {}.",
+                        group,
+                        size
+                    );
+                }
+            }
+            if q.is_awq() && q.version.as_deref().unwrap_or("gemm") != "gemm" {
+                bail_user!("AWQ version {:?} is not supported.", q.version);
+            }
+        }
+        if let Some(moe) = model.moe {
+            if moe.experts_per_tok == 0 || moe.experts_per_tok > moe.num_experts {
+                bail_user!(
+                    "Invalid number of experts per token ({}) for {} experts.",
+                    moe.experts_per_tok,
+                    moe.num_experts
+                );
+            }
+        }
+        if model.cache.cpu_block_group == 0 {
+            bail_user!("cpu_block_group must be positive.");
+        }
+        if model.cache.attention_sinks > 0 && model.sliding_window.is_none() {
+            bail_user!("attention_sinks requires a sliding_window.");
+        }
+        let watermark = self.scheduler.block_watermark;
+        if !(0.0..1.0).contains(&watermark) {
+            bail_user!("block_watermark ({}) must be in [0, 1).", watermark);
+        }
         if self.aici.max_fuel < 100 {
             bail_user!("max_fuel not configured");
         }
@@ -44,7 +263,7 @@ impl TchRllmConfig for RllmConfig<TModel> {
         self.model.hidden_size
     }
     fn get_head_size(&self) -> usize {
-        self.model.hidden_size / self.model.num_attention_heads
+        self.model.head_dim
     }
     fn get_num_heads_parallel(&self) -> usize {
         self.model.num_key_value_heads / self.parallel.tensor_parallel_size
@@ -52,15 +271,41 @@ impl TchRllmConfig for RllmConfig<TModel> {
     fn get_num_layers_parallel(&self) -> usize {
         self.model.num_hidden_layers / self.parallel.pipeline_parallel_size
     }
+    fn get_layer_kv_shape_parallel(&self, layer: usize) -> KvShape {
+        let shape = self.model.kv_shape(layer);
+        KvShape {
+            num_kv_heads: shape.num_kv_heads / self.parallel.tensor_parallel_size,
+            head_dim: shape.head_dim,
+        }
+    }
     fn get_max_model_len(&self) -> usize {
         self.meta.max_sequence_length
     }
+    fn get_kv_devices(&self) -> Vec<Device> {
+        match self.model.device {
+            Device::Cuda(n) => {
+                let num_devices =
+                    self.parallel.tensor_parallel_size * self.parallel.pipeline_parallel_size;
+                (n..n + num_devices).map(Device::Cuda).collect()
+            }
+            d => vec![d],
+        }
+    }
+    fn get_kv_device_layers(&self, idx: usize) -> Range<usize> {
+        let stage = idx / self.parallel.tensor_parallel_size;
+        let num_layers = self.get_num_layers_parallel();
+        stage * num_layers..(stage + 1) * num_layers
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ModelType {
     Llama,
     Phi,
+    Phi2,
+    Gemma,
+    Qwen2,
+    Mpt,
 }
 
 pub struct CommonModelConfig {
@@ -75,16 +320,48 @@ pub struct ModelConfig {
     pub meta: ModelMeta,
 
     pub num_attention_heads: usize,
-    pub hidden_size: usize, // head_dim * num_attention_heads
+    pub hidden_size: usize, // usually head_dim * num_attention_heads
     pub num_hidden_layers: usize,
     pub num_key_value_heads: usize,
     pub head_dim: usize,
     pub rotary_dim: usize,
+    /// KV shapes of the layers, when they differ (e.g., hybrid GQA/MQA models);
+    /// otherwise all layers use num_key_value_heads and head_dim.
+    pub layer_kv_shapes: Option<Vec<KvShape>>,
 
     pub intermediate_size: usize,
 
     pub layer_norm_eps: f64, // default 1e-5
     pub rope_theta: f32,     // default 10000
+    /// Set for models fine-tuned past the context length of their base model;
+    /// meta.max_sequence_length is then already the extended length.
+    pub rope_scaling: Option<RopeScaling>,
+    /// Set for ALiBi models, which bias attention scores by distance instead of
+    /// rotating q and k; see alibi_slopes().
+    pub alibi_bias_max: Option<f32>,
+
+    /// Tokens only attend to this many preceding tokens (including themselves),
+    /// and the attention sinks (see CacheConfig::attention_sinks).
+    /// KV blocks that fall out of the window are freed, and the attention kernels
+    /// mask the rest of the first kept block; with attention sinks, there's no mask,
+    /// so a few more tokens (up to a block) may be attended to.
+    pub sliding_window: Option<usize>,
+
+    /// Set when linear layers of the checkpoint are quantized.
+    pub quantization: Option<QuantConfig>,
+
+    /// Set when the MLP of each layer is a mixture of experts (e.g., Mixtral);
+    /// intermediate_size is then the size of one expert.
+    pub moe: Option<MoeConfig>,
+
+    /// Logits are squashed into (-cap, cap) as cap * tanh(logits / cap) (e.g., Gemma).
+    pub logit_softcap: Option<f64>,
+
+    /// LoRA adapters on top of the checkpoint.
+    pub lora: LoraSet,
+
+    /// Medusa heads drafting tokens, which are verified in the next forward pass.
+    pub medusa: Option<Medusa>,
 
     pub device: Device,
     pub dtype: DType,
@@ -93,17 +370,130 @@ pub struct ModelConfig {
     pub cache: CacheConfig,
 }
 
+/// Geometry of the KV cache of one attention layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvShape {
+    pub num_kv_heads: usize,
+    pub head_dim: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoeConfig {
+    pub num_experts: usize,
+    /// How many experts (with the highest router scores) each token is sent to.
+    pub experts_per_tok: usize,
+}
+
+/// `quantization_config` of GPTQ and AWQ checkpoints in config.json.
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuantConfig {
+    /// "gptq" or "awq"
+    pub quant_method: String,
+    pub bits: usize,
+    /// Rows of the weight sharing a scale and zero point; -1 means one group.
+    pub group_size: i64,
+    /// GPTQ: set when rows were quantized in order of activation size (g_idx isn't monotonic).
+    #[serde(default)]
+    pub desc_act: bool,
+    /// AWQ: packing of the weights; only "gemm" is supported.
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+/// `rope_scaling` in config.json.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RopeScaling {
+    /// "linear", "dynamic" (NTK-aware) or "yarn"
+    #[serde(rename = "type", alias = "rope_type")]
+    pub kind: String,
+    pub factor: f32,
+    /// Context length of the base model; defaults to max_position_embeddings.
+    #[serde(default)]
+    pub original_max_position_embeddings: Option<usize>,
+    /// YaRN: dimensions rotating fewer than beta_slow times over the original context
+    /// are interpolated, those rotating more than beta_fast times are left as is.
+    #[serde(default = "default_beta_fast")]
+    pub beta_fast: f32,
+    #[serde(default = "default_beta_slow")]
+    pub beta_slow: f32,
+}
+
+fn default_beta_fast() -> f32 {
+    32.0
+}
+
+fn default_beta_slow() -> f32 {
+    1.0
+}
+
+impl RopeScaling {
+    /// Fill in the original context length and return the extended one.
+    pub fn resolve(&mut self, max_position_embeddings: usize) -> usize {
+        let orig = *self
+            .original_max_position_embeddings
+            .get_or_insert(max_position_embeddings);
+        (orig as f32 * self.factor) as usize
+    }
+
+    pub fn original_len(&self) -> usize {
+        self.original_max_position_embeddings.unwrap()
+    }
+}
+
+impl QuantConfig {
+    pub fn is_awq(&self) -> bool {
+        self.quant_method == "awq"
+    }
+}
+
 impl ModelConfig {
-    pub fn dtype_from_str(explicit: Option<DType>, torch_dtype: &str) -> DType {
+    pub fn kv_shape(&self, layer: usize) -> KvShape {
+        match self.layer_kv_shapes.as_ref() {
+            Some(shapes) => shapes[layer],
+            None => KvShape {
+                num_kv_heads: self.num_key_value_heads,
+                head_dim: self.head_dim,
+            },
+        }
+    }
+
+    /// Config of one of `size` tensor-parallel ranks, on `device`: attention heads
+    /// and intermediate (MLP) features are split evenly between the ranks.
+    pub fn shard(&self, size: usize, device: Device) -> ModelConfig {
+        let mut cfg = self.clone();
+        cfg.num_attention_heads /= size;
+        cfg.num_key_value_heads /= size;
+        cfg.intermediate_size /= size;
+        if let Some(shapes) = cfg.layer_kv_shapes.as_mut() {
+            for shape in shapes.iter_mut() {
+                shape.num_kv_heads /= size;
+            }
+        }
+        cfg.device = device;
+        cfg
+    }
+
+    /// The `explicit` type (checked by verify_args()), or `torch_dtype` from config.json,
+    /// unless `device` doesn't support it; then f32 on the CPU and f16 elsewhere.
+    pub fn dtype_from_str(explicit: Option<DType>, device: Device, torch_dtype: &str) -> DType {
         if let Some(dtype) = explicit {
             return dtype;
         }
-        match torch_dtype {
-            "float" => DType::Float,
+        let dtype = match torch_dtype {
+            "float" | "float32" => DType::Float,
             "half" | "float16" => DType::Half,
             "bfloat16" => DType::BFloat16,
             _ => panic!("Unknown dtype {}", torch_dtype),
+        };
+        if device_supports_dtype(device, dtype) {
+            return dtype;
         }
+        let fallback = match device {
+            Device::Cpu => DType::Float,
+            _ => DType::Half,
+        };
+        log::info!("{torch_dtype} is not supported on {device:?}; using {fallback:?}");
+        fallback
     }
 }
 pub trait RllmModelConfig {
@@ -124,11 +514,38 @@ pub struct CacheConfig {
 
     // #[serde(skip)]
     pub swap_space_bytes: usize,
+
+    /// Reuse KV blocks of prompt prefixes (system prompts etc.) across requests.
+    pub enable_prefix_caching: bool,
+
+    /// With a sliding window, the KV of this many leading tokens (rounded up
+    /// to a block) is kept as well, and blocks in the middle are freed instead
+    /// (attention sinks, as in StreamingLLM). Positions are not re-assigned,
+    /// so sequences are still limited to max_model_len.
+    pub attention_sinks: usize,
+
+    /// Heavy-hitter (H2O) eviction: when a sequence holds KV of more than this
+    /// many tokens, the blocks whose tokens got the least attention so far are
+    /// freed; the most recent half of the budget is always kept. As with the
+    /// sliding window, positions are not re-assigned.
+    pub kv_budget: Option<usize>,
+
+    /// Number of CPU swap blocks allocated (and pinned) together, when first used.
+    pub cpu_block_group: usize,
+
+    /// Debugging: fill newly allocated GPU blocks with NaN, instead of leaving
+    /// whatever they held before.
+    pub poison_kv_blocks: bool,
+
+    /// Debugging: record where each reference to a block is taken, and log the
+    /// ones lost without being given back (leaking the block) when a sequence
+    /// is deleted. Slow.
+    pub track_block_refs: bool,
 }
 
 impl Default for CacheConfig {
     fn default() -> Self {
-        Self::new(16, 0.9, 4).unwrap()
+        Self::new(16, 0.9, 2).unwrap()
     }
 }
 
@@ -152,16 +569,18 @@ impl CacheConfig {
         } else if swap_space_bytes > (total_cpu_memory * 4 / 10) {
             log::warn!("Possibly too large swap space. {}", msg);
         }
-        #[cfg(feature = "cuda")]
-        let paged_attn_kernel_v = 1;
-        #[cfg(not(feature = "cuda"))]
-        let paged_attn_kernel_v = 0;
         Ok(Self {
             block_size,
             gpu_memory_utilization,
             swap_space,
             swap_space_bytes,
-            paged_attn_kernel_v,
+            paged_attn_kernel_v: 1,
+            enable_prefix_caching: true,
+            attention_sinks: 0,
+            kv_budget: None,
+            cpu_block_group: 256,
+            poison_kv_blocks: false,
+            track_block_refs: false,
         })
     }
 }
@@ -0,0 +1,236 @@
+// based on https://github.com/huggingface/transformers/blob/main/src/transformers/models/gemma/modeling_gemma.py
+
+use super::{
+    config::{CommonModelConfig, ModelConfig, ModelType, RllmModelConfig},
+    linear_no_bias,
+    paged::BatchInfo,
+    varlen_attn, RmsNorm, RotaryEmbedding,
+};
+use serde::Deserialize;
+use std::rc::Rc;
+use tch::{
+    nn::{self, Module, Path},
+    Tensor,
+};
+
+use super::tmodel::TModelInner;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum GemmaModelType {
+    Gemma,
+}
+
+/// Gemma.
+/// https://huggingface.co/google/gemma-7b
+#[derive(Deserialize)]
+pub struct GemmaConfig {
+    // Gemma configs are otherwise accepted by LlamaConfig
+    #[serde(rename = "model_type")]
+    _model_type: GemmaModelType,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    /// Not necessarily hidden_size / num_attention_heads.
+    pub head_dim: usize,
+    pub rms_norm_eps: f64,
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_rope")]
+    pub rope_theta: f32,
+    pub torch_dtype: String,
+    #[serde(default)]
+    pub final_logit_softcapping: Option<f64>,
+}
+
+fn default_rope() -> f32 {
+    10_000.0
+}
+
+impl RllmModelConfig for GemmaConfig {
+    fn into_config(self, common: CommonModelConfig) -> ModelConfig {
+        let mut meta = common.meta.clone();
+        meta.vocab_size = self.vocab_size;
+        meta.tok_vocab_size = self.vocab_size;
+        meta.max_sequence_length = self.max_position_embeddings;
+        ModelConfig {
+            model_type: ModelType::Gemma,
+            meta,
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
+            layer_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            rope_scaling: None,
+            alibi_bias_max: None,
+            head_dim: self.head_dim,
+            rotary_dim: self.head_dim,
+            layer_kv_shapes: None,
+            dtype: ModelConfig::dtype_from_str(common.dtype, common.device, &self.torch_dtype),
+            device: common.device,
+            sliding_window: None,
+            quantization: None,
+            moe: None,
+            logit_softcap: self.final_logit_softcapping,
+            lora: Default::default(),
+            medusa: None,
+            profile_step_no: 0,
+            cache: Default::default(),
+        }
+    }
+}
+
+struct Attention {
+    q_proj: nn::Linear,
+    k_proj: nn::Linear,
+    v_proj: nn::Linear,
+    o_proj: nn::Linear,
+    rotary: RotaryEmbedding,
+    config: Rc<ModelConfig>,
+}
+
+impl Attention {
+    fn forward(&self, x: &Tensor, batch_info: &mut BatchInfo, block_idx: usize) -> Tensor {
+        let (b_sz, seq_len, _hidden_size) = x.size3().unwrap();
+        assert!(b_sz == 1);
+
+        let q = self.q_proj.forward(x);
+        let k = self.k_proj.forward(x);
+        let v = self.v_proj.forward(x);
+
+        let (q, k) = self.rotary.forward(&batch_info.positions, &q, &k);
+        let v = v.reshape(&[seq_len, -1, self.config.head_dim as i64]);
+
+        let y = varlen_attn(&self.config, q, k, v, batch_info, block_idx);
+        let y = y.reshape(&[b_sz, seq_len, -1]);
+        self.o_proj.forward(&y)
+    }
+
+    fn load(vb: Path, rotary: &RotaryEmbedding, cfg: &Rc<ModelConfig>) -> Self {
+        let size_q = cfg.head_dim * cfg.num_attention_heads;
+        let size_kv = cfg.head_dim * cfg.num_key_value_heads;
+        Self {
+            q_proj: linear_no_bias(cfg.hidden_size, size_q, &vb / "q_proj"),
+            k_proj: linear_no_bias(cfg.hidden_size, size_kv, &vb / "k_proj"),
+            v_proj: linear_no_bias(cfg.hidden_size, size_kv, &vb / "v_proj"),
+            o_proj: linear_no_bias(size_q, cfg.hidden_size, &vb / "o_proj"),
+            rotary: rotary.clone(),
+            config: cfg.clone(),
+        }
+    }
+}
+
+/// GeGLU: like the Llama MLP, with the tanh approximation of GELU instead of SiLU.
+struct Mlp {
+    gate_proj: nn::Linear,
+    up_proj: nn::Linear,
+    down_proj: nn::Linear,
+}
+
+impl Mlp {
+    fn load(vb: Path, cfg: &ModelConfig) -> Self {
+        let h_size = cfg.hidden_size;
+        let i_size = cfg.intermediate_size;
+        Self {
+            gate_proj: linear_no_bias(h_size, i_size, &vb / "gate_proj"),
+            up_proj: linear_no_bias(h_size, i_size, &vb / "up_proj"),
+            down_proj: linear_no_bias(i_size, h_size, &vb / "down_proj"),
+        }
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, x: &Tensor) -> Tensor {
+        let gate = self.gate_proj.forward(x).gelu("tanh");
+        self.down_proj.forward(&(gate * self.up_proj.forward(x)))
+    }
+}
+
+struct Block {
+    input_layernorm: RmsNorm,
+    attn: Attention,
+    post_attention_layernorm: RmsNorm,
+    mlp: Mlp,
+}
+
+impl Block {
+    fn forward(&self, x: &Tensor, batch_info: &mut BatchInfo, block_idx: usize) -> Tensor {
+        let residual = x;
+        let x = self.input_layernorm.forward(x);
+        let x = self.attn.forward(&x, batch_info, block_idx) + residual;
+        let residual = &x;
+        let y = self.mlp.forward(&self.post_attention_layernorm.forward(&x));
+        y + residual
+    }
+
+    fn load(mut vb: Path, rotary: &RotaryEmbedding, cfg: &Rc<ModelConfig>) -> Self {
+        let attn = Attention::load(&vb / "self_attn", rotary, cfg);
+        let mlp = Mlp::load(&vb / "mlp", cfg);
+        let input_layernorm = RmsNorm::from_cfg(&vb / "input_layernorm", cfg).with_unit_offset();
+        let post_attention_layernorm =
+            RmsNorm::from_cfg(&vb / "post_attention_layernorm", cfg).with_unit_offset();
+        // this optimizes memory usage
+        vb.set_kind(cfg.dtype);
+        Self {
+            input_layernorm,
+            attn,
+            post_attention_layernorm,
+            mlp,
+        }
+    }
+}
+
+pub struct Gemma {
+    embed_tokens: nn::Embedding,
+    blocks: Vec<Block>,
+    norm: RmsNorm,
+    config: Rc<ModelConfig>,
+}
+
+impl TModelInner for Gemma {
+    fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
+        let x = batch_info.override_embeddings(self.embed_tokens.forward(&batch_info.tokens));
+        // embeddings are scaled, as they are shared with the output projection
+        let mut x = (x * (self.config.hidden_size as f64).sqrt()).unsqueeze(0);
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            x = block.forward(&x, batch_info, block_idx);
+        }
+        let x0 = self.norm.forward(&x).squeeze_dim(0);
+        if batch_info.wants_hidden_states() {
+            batch_info.hidden_states = Some(x0.shallow_clone());
+        }
+        let x = batch_info.extract_positions(&x0);
+        let logits = x.matmul(&self.embed_tokens.ws.tr());
+        match self.config.logit_softcap {
+            Some(cap) => (logits / cap).tanh() * cap,
+            None => logits,
+        }
+    }
+}
+
+impl Gemma {
+    pub fn load(vs: Path, cfg: &Rc<ModelConfig>) -> Self {
+        let rotary = RotaryEmbedding::new(cfg);
+        let vb = &vs / "model";
+        let embed_tokens = nn::embedding(
+            &vb / "embed_tokens",
+            cfg.meta.vocab_size as i64,
+            cfg.hidden_size as i64,
+            Default::default(),
+        );
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| Block::load(&vb / "layers" / i, &rotary, cfg))
+            .collect();
+        let norm = RmsNorm::from_cfg(&vb / "norm", cfg).with_unit_offset();
+        Self {
+            embed_tokens,
+            blocks,
+            norm,
+            config: cfg.clone(),
+        }
+    }
+}
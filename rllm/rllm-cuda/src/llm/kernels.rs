@@ -19,3 +19,24 @@ pub fn to_offsets(seqlens: impl Iterator<Item = usize>, device: Device) -> (usiz
     offsets.push(offset as i32);
     (max, Tensor::from_slice(offsets.as_slice()).to(device))
 }
+
+/// Run `f` with `device` as the current CUDA device; the vLLM kernels launch on
+/// the current device, which is the model device unless set otherwise.
+#[cfg(feature = "cuda")]
+pub fn on_device<T>(device: Device, f: impl FnOnce() -> T) -> T {
+    match device {
+        Device::Cuda(n) => {
+            let prev = tch_cuda::cuda_current_device();
+            tch_cuda::cuda_set_device(n);
+            let r = f();
+            tch_cuda::cuda_set_device(prev);
+            r
+        }
+        _ => f(),
+    }
+}
+
+#[cfg(not(feature = "cuda"))]
+pub fn on_device<T>(_device: Device, f: impl FnOnce() -> T) -> T {
+    f()
+}
@@ -1,17 +1,23 @@
 // based on https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/llama.rs
 
 use super::{
-    config::{CommonModelConfig, ModelConfig, ModelType, RllmModelConfig},
-    linear_no_bias,
+    config::{
+        CommonModelConfig, KvShape, ModelConfig, ModelType, MoeConfig, QuantConfig,
+        RllmModelConfig, RopeScaling,
+    },
+    linear, linear_no_bias,
+    lora::with_lora,
     paged::BatchInfo,
-    varlen_attn, RmsNorm, RotaryEmbedding,
+    parallel::{run_pipeline, Pipeline, Sharded, TensorParallel},
+    qlinear_no_bias, varlen_attn, Linear, RmsNorm, RotaryEmbedding,
 };
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
+use rllm::gguf::GgufFile;
 use serde::Deserialize;
-use std::rc::Rc;
+use std::{ops::Range, rc::Rc};
 use tch::{
     nn::{self, Module, Path},
-    Tensor,
+    IndexOp, Kind, Tensor,
 };
 
 use super::tmodel::TModelInner;
@@ -24,24 +30,150 @@ pub struct LlamaConfig {
     pub num_hidden_layers: usize,
     pub num_attention_heads: usize,
     pub num_key_value_heads: Option<usize>,
+    /// Set for models whose layers differ in the number of KV heads.
+    #[serde(default)]
+    pub num_key_value_heads_per_layer: Option<Vec<usize>>,
     pub rms_norm_eps: f64,
     pub max_position_embeddings: usize, // TODO - is this max seq len?
     #[serde(default = "default_rope")]
     pub rope_theta: f32,
+    /// Set for long-context fine-tunes.
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScaling>,
     pub torch_dtype: String,
+    /// Set for Mistral-style models.
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
+    /// Set for GPTQ- or AWQ-quantized checkpoints.
+    #[serde(default)]
+    pub quantization_config: Option<QuantConfig>,
+    /// Set for Mixtral-style mixture of experts models.
+    #[serde(default)]
+    pub num_local_experts: Option<usize>,
+    #[serde(default)]
+    pub num_experts_per_tok: Option<usize>,
 }
 
 fn default_rope() -> f32 {
     10_000.0
 }
 
+impl LlamaConfig {
+    /// Read hyper-parameters from the header of a llama.cpp checkpoint.
+    pub fn from_gguf(gguf: &GgufFile) -> Result<Self> {
+        let arch = gguf.architecture()?;
+        if arch != "llama" {
+            bail!("GGUF: unsupported architecture {arch}");
+        }
+        let key = |k: &str| format!("{arch}.{k}");
+
+        let hidden_size = gguf.get_usize(&key("embedding_length"))?;
+        let num_attention_heads = gguf.get_usize(&key("attention.head_count"))?;
+        let num_hidden_layers = gguf.get_usize(&key("block_count"))?;
+        let head_dim = hidden_size / num_attention_heads;
+
+        let (num_key_value_heads, num_key_value_heads_per_layer) =
+            match gguf.get(&key("attention.head_count_kv")) {
+                None => (None, None),
+                Some(v) => match v.as_array() {
+                    Some(arr) => {
+                        let heads = arr.iter().filter_map(|h| h.as_usize()).collect::<Vec<_>>();
+                        ensure!(
+                            heads.len() == num_hidden_layers,
+                            "GGUF: head_count_kv has {} entries, expected {num_hidden_layers}",
+                            heads.len()
+                        );
+                        (Some(heads[0]), Some(heads))
+                    }
+                    None => (v.as_usize(), None),
+                },
+            };
+
+        if let Some(rot) = gguf.get(&key("rope.dimension_count")) {
+            if rot.as_usize() != Some(head_dim) {
+                bail!("GGUF: partial rotary embeddings are not supported");
+            }
+        }
+        if gguf.get(&key("expert_count")).is_some() {
+            bail!("GGUF: mixture of experts models are not supported");
+        }
+        let max_position_embeddings = gguf.get_usize(&key("context_length"))?;
+        let rope_scaling = match gguf.get_str(&key("rope.scaling.type")) {
+            Ok("none") | Err(_) => None,
+            Ok(kind @ ("linear" | "yarn")) => {
+                let factor = gguf.get_f32(&key("rope.scaling.factor"))?;
+                // context_length is the extended length
+                let orig = gguf
+                    .get_usize(&key("rope.scaling.original_context_length"))
+                    .unwrap_or((max_position_embeddings as f32 / factor) as usize);
+                Some(RopeScaling {
+                    kind: kind.to_string(),
+                    factor,
+                    original_max_position_embeddings: Some(orig),
+                    beta_fast: 32.0,
+                    beta_slow: 1.0,
+                })
+            }
+            Ok(kind) => bail!("GGUF: rope scaling {kind} is not supported"),
+        };
+
+        let vocab_size = match gguf.get(&key("vocab_size")) {
+            Some(v) => v.as_usize().unwrap_or(0),
+            None => match gguf.get("tokenizer.ggml.tokens").and_then(|v| v.as_array()) {
+                Some(tokens) => tokens.len(),
+                None => 0,
+            },
+        };
+        ensure!(vocab_size > 0, "GGUF: can't determine vocab size");
+
+        Ok(LlamaConfig {
+            hidden_size,
+            intermediate_size: gguf.get_usize(&key("feed_forward_length"))?,
+            vocab_size,
+            num_hidden_layers,
+            num_attention_heads,
+            num_key_value_heads,
+            num_key_value_heads_per_layer,
+            rms_norm_eps: gguf.get_f32(&key("attention.layer_norm_rms_epsilon"))? as f64,
+            max_position_embeddings,
+            rope_theta: gguf
+                .get_f32(&key("rope.freq_base"))
+                .unwrap_or(default_rope()),
+            rope_scaling,
+            // quantized weights are dequantized to f16
+            torch_dtype: "float16".to_string(),
+            sliding_window: None,
+            quantization_config: None,
+            num_local_experts: None,
+            num_experts_per_tok: None,
+        })
+    }
+}
+
 impl RllmModelConfig for LlamaConfig {
     fn into_config(self, common: CommonModelConfig) -> ModelConfig {
         let head_dim = self.hidden_size / self.num_attention_heads;
         let mut meta = common.meta.clone();
         meta.vocab_size = self.vocab_size;
         meta.tok_vocab_size = self.vocab_size;
-        meta.max_sequence_length = self.max_position_embeddings;
+        let mut rope_scaling = self.rope_scaling;
+        meta.max_sequence_length = match rope_scaling.as_mut() {
+            Some(s) => s.resolve(self.max_position_embeddings),
+            None => self.max_position_embeddings,
+        };
+        let moe = self.num_local_experts.map(|num_experts| MoeConfig {
+            num_experts,
+            experts_per_tok: self.num_experts_per_tok.unwrap_or(2),
+        });
+        let layer_kv_shapes = self.num_key_value_heads_per_layer.map(|heads| {
+            heads
+                .into_iter()
+                .map(|num_kv_heads| KvShape {
+                    num_kv_heads,
+                    head_dim,
+                })
+                .collect()
+        });
         ModelConfig {
             model_type: ModelType::Llama,
             meta,
@@ -52,67 +184,156 @@ impl RllmModelConfig for LlamaConfig {
             num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
             layer_norm_eps: self.rms_norm_eps,
             rope_theta: self.rope_theta,
+            rope_scaling,
+            alibi_bias_max: None,
             head_dim,
             rotary_dim: head_dim,
-            dtype: ModelConfig::dtype_from_str(common.dtype, &self.torch_dtype),
+            layer_kv_shapes,
+            dtype: ModelConfig::dtype_from_str(common.dtype, common.device, &self.torch_dtype),
             device: common.device,
+            sliding_window: self.sliding_window,
+            quantization: self.quantization_config,
+            moe,
+            logit_softcap: None,
+            lora: Default::default(),
+            medusa: None,
             profile_step_no: 0,
             cache: Default::default(),
         }
     }
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Qwen2ModelType {
+    Qwen2,
+}
+
+/// Qwen2 (and Qwen1.5): Llama with biases on the query, key and value projections.
+/// https://huggingface.co/Qwen/Qwen2-7B-Instruct
+#[derive(Deserialize)]
+pub struct Qwen2Config {
+    // Qwen2 configs are otherwise accepted by LlamaConfig
+    #[serde(rename = "model_type")]
+    _model_type: Qwen2ModelType,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    pub rms_norm_eps: f64,
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_rope")]
+    pub rope_theta: f32,
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScaling>,
+    pub torch_dtype: String,
+    // sliding_window is ignored: released checkpoints have use_sliding_window=false
+}
+
+impl RllmModelConfig for Qwen2Config {
+    fn into_config(self, common: CommonModelConfig) -> ModelConfig {
+        let mut cfg = LlamaConfig {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads,
+            num_key_value_heads_per_layer: None,
+            rms_norm_eps: self.rms_norm_eps,
+            max_position_embeddings: self.max_position_embeddings,
+            rope_theta: self.rope_theta,
+            rope_scaling: self.rope_scaling,
+            torch_dtype: self.torch_dtype,
+            sliding_window: None,
+            quantization_config: None,
+            num_local_experts: None,
+            num_experts_per_tok: None,
+        }
+        .into_config(common);
+        cfg.model_type = ModelType::Qwen2;
+        cfg
+    }
+}
+
 struct CausalSelfAttention {
-    q_proj: nn::Linear,
-    k_proj: nn::Linear,
-    v_proj: nn::Linear,
-    o_proj: nn::Linear,
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_kv_heads: usize,
     config: Rc<ModelConfig>,
     rotary: RotaryEmbedding,
 }
 
 impl CausalSelfAttention {
     fn forward(&self, x: &Tensor, batch_info: &mut BatchInfo, block_idx: usize) -> Tensor {
-        let (b_sz, seq_len, hidden_size) = x.size3().unwrap();
+        let (b_sz, seq_len, _hidden_size) = x.size3().unwrap();
         assert!(b_sz == 1);
 
         batch_info.log_tensor("x", &x);
 
-        let q = self.q_proj.forward(x);
-        let k = self.k_proj.forward(x);
-        let v = self.v_proj.forward(x);
+        let q = self.q_proj.forward_batch(x, batch_info);
+        let k = self.k_proj.forward_batch(x, batch_info);
+        let v = self.v_proj.forward_batch(x, batch_info);
 
         let (q, k) = self.rotary.forward(&batch_info.positions, &q, &k);
 
         let v = v.reshape(&[
             seq_len,
-            self.config.num_key_value_heads as i64,
+            self.num_kv_heads as i64,
             self.config.head_dim as i64,
         ]);
 
         let y = varlen_attn(&self.config, q, k, v, batch_info, block_idx);
 
-        let y = y.reshape(&[b_sz, seq_len, hidden_size]);
-        let y = self.o_proj.forward(&y);
+        // with tensor parallelism, only the heads of this rank
+        let y = y.reshape(&[b_sz, seq_len, -1]);
+        let y = self.o_proj.forward_batch(&y, batch_info);
 
         batch_info.log_tensor("yp", &y);
 
         y
     }
 
-    fn load(vb: Path, rotary: &RotaryEmbedding, cfg: &Rc<ModelConfig>) -> Result<Self> {
+    fn load(
+        vb: Path,
+        layer: usize,
+        rotary: &RotaryEmbedding,
+        cfg: &Rc<ModelConfig>,
+    ) -> Result<Self> {
+        let shape = cfg.kv_shape(layer);
+        let num_kv_heads = shape.num_kv_heads;
         let size_in = cfg.hidden_size;
-        let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
-        let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
-        let q_proj = linear_no_bias(size_in, size_q, &vb / "q_proj");
-        let k_proj = linear_no_bias(size_in, size_kv, &vb / "k_proj");
-        let v_proj = linear_no_bias(size_in, size_kv, &vb / "v_proj");
-        let o_proj = linear_no_bias(size_q, size_in, &vb / "o_proj");
+        let size_q = cfg.head_dim * cfg.num_attention_heads;
+        let size_kv = shape.head_dim * num_kv_heads;
+        let (q_proj, k_proj, v_proj) = if cfg.model_type == ModelType::Qwen2 {
+            let dense = |size_out, name: &str| {
+                with_lora(size_in, size_out, &vb / name, cfg, |vb| {
+                    Linear::Dense(linear(size_in, size_out, vb))
+                })
+            };
+            (
+                dense(size_q, "q_proj"),
+                dense(size_kv, "k_proj"),
+                dense(size_kv, "v_proj"),
+            )
+        } else {
+            (
+                qlinear_no_bias(size_in, size_q, &vb / "q_proj", cfg),
+                qlinear_no_bias(size_in, size_kv, &vb / "k_proj", cfg),
+                qlinear_no_bias(size_in, size_kv, &vb / "v_proj", cfg),
+            )
+        };
+        let o_proj = qlinear_no_bias(size_q, size_in, &vb / "o_proj", cfg);
         Ok(Self {
             q_proj,
             k_proj,
             v_proj,
             o_proj,
+            num_kv_heads,
             config: cfg.clone(),
             rotary: rotary.clone(),
         })
@@ -120,30 +341,31 @@ impl CausalSelfAttention {
 }
 
 struct Mlp {
-    c_fc1: nn::Linear,
-    c_fc2: nn::Linear,
-    c_proj: nn::Linear,
+    c_fc1: Linear,
+    c_fc2: Linear,
+    c_proj: Linear,
 }
 
 impl Mlp {
     fn forward(&self, x: &Tensor, batch_info: &BatchInfo) -> Tensor {
-        let m1 = self.c_fc1.forward(x);
-        let m2 = self.c_fc2.forward(x);
-        batch_info.log_tensor("w1", &self.c_fc1.ws);
+        let m1 = self.c_fc1.forward_batch(x, batch_info);
+        let m2 = self.c_fc2.forward_batch(x, batch_info);
+        batch_info.log_tensor("w1", self.c_fc1.ws());
         batch_info.log_tensor("m1", &m1);
         batch_info.log_tensor("m2", &m2);
         let si = m1.silu();
         batch_info.log_tensor("si", &m2);
         let x = si * &m2;
-        self.c_proj.forward(&x)
+        self.c_proj.forward_batch(&x, batch_info)
     }
 
-    fn load(vb: Path, cfg: &ModelConfig) -> Result<Self> {
+    /// `names` of the gate, up and down projections
+    fn load(vb: Path, cfg: &ModelConfig, names: [&str; 3]) -> Result<Self> {
         let h_size = cfg.hidden_size;
         let i_size = cfg.intermediate_size;
-        let c_fc1 = linear_no_bias(h_size, i_size, &vb / "gate_proj");
-        let c_fc2 = linear_no_bias(h_size, i_size, &vb / "up_proj");
-        let c_proj = linear_no_bias(i_size, h_size, &vb / "down_proj");
+        let c_fc1 = qlinear_no_bias(h_size, i_size, &vb / names[0], cfg);
+        let c_fc2 = qlinear_no_bias(h_size, i_size, &vb / names[1], cfg);
+        let c_proj = qlinear_no_bias(i_size, h_size, &vb / names[2], cfg);
         Ok(Self {
             c_fc1,
             c_fc2,
@@ -152,32 +374,117 @@ impl Mlp {
     }
 }
 
+/// Mixture of experts, as in Mixtral: each token goes through the experts_per_tok
+/// experts with highest router scores, and their outputs are mixed by the scores.
+struct SparseMoe {
+    gate: nn::Linear,
+    experts: Vec<Mlp>,
+    experts_per_tok: i64,
+}
+
+impl SparseMoe {
+    fn forward(&self, x: &Tensor, batch_info: &BatchInfo) -> Tensor {
+        let (b_sz, seq_len, hidden_size) = x.size3().unwrap();
+        let x = x.reshape(&[-1, hidden_size]);
+
+        let router_logits = self.gate.forward(&x);
+        let scores = router_logits.softmax(-1, Kind::Float);
+        let (top_scores, top_experts) = scores.topk(self.experts_per_tok, -1, true, false);
+        let top_scores = &top_scores / top_scores.sum_dim_intlist(-1, true, Kind::Float);
+        batch_info.log_tensor("router", &top_experts);
+
+        let mut y = x.zeros_like();
+        for (idx, expert) in self.experts.iter().enumerate() {
+            let mask = top_experts.eq(idx as i64);
+            let rows = mask.any_dim(-1, false).nonzero().squeeze_dim(-1);
+            if rows.size()[0] == 0 {
+                continue;
+            }
+            let weight = (&top_scores * mask.to_kind(Kind::Float))
+                .sum_dim_intlist(-1, true, Kind::Float)
+                .index_select(0, &rows)
+                .to_kind(x.kind());
+            let expert_y = expert.forward(&x.index_select(0, &rows), batch_info) * weight;
+            y = y.index_add(0, &rows, &expert_y);
+        }
+
+        y.reshape(&[b_sz, seq_len, hidden_size])
+    }
+
+    fn load(vb: Path, cfg: &ModelConfig, moe: &MoeConfig) -> Result<Self> {
+        let gate = linear_no_bias(cfg.hidden_size, moe.num_experts, &vb / "gate");
+        let experts = (0..moe.num_experts)
+            .map(|i| Mlp::load(&vb / "experts" / i, cfg, ["w1", "w3", "w2"]))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            gate,
+            experts,
+            experts_per_tok: moe.experts_per_tok as i64,
+        })
+    }
+}
+
+enum FeedForward {
+    Dense(Mlp),
+    Moe(SparseMoe),
+}
+
+impl FeedForward {
+    fn forward(&self, x: &Tensor, batch_info: &BatchInfo) -> Tensor {
+        match self {
+            FeedForward::Dense(mlp) => mlp.forward(x, batch_info),
+            FeedForward::Moe(moe) => moe.forward(x, batch_info),
+        }
+    }
+}
+
 struct Block {
     rms_1: RmsNorm,
-    attn: CausalSelfAttention,
+    attn: Sharded<CausalSelfAttention>,
     rms_2: RmsNorm,
-    mlp: Mlp,
+    mlp: Sharded<FeedForward>,
 }
 
 impl Block {
     fn forward(&self, x: &Tensor, batch_info: &mut BatchInfo, block_idx: usize) -> Tensor {
         let residual = x;
         let x = self.rms_1.forward(x);
-        let x = self.attn.forward(&x, batch_info, block_idx) + residual;
+        let x = self.attn.forward(&x, batch_info, |attn, x, batch_info| {
+            attn.forward(x, batch_info, block_idx)
+        }) + residual;
         let residual = &x;
         batch_info.log_tensor("x0", &x);
         let x = self.rms_2.forward(&x);
         batch_info.log_tensor("x1", &x);
-        let x = self.mlp.forward(&x, batch_info);
+        let x = self.mlp.forward(&x, batch_info, |mlp, x, batch_info| {
+            mlp.forward(x, batch_info)
+        });
         batch_info.log_tensor("x2", &x);
         let x = x + residual;
         batch_info.log_tensor("x3", &x);
         x
     }
 
-    fn load(mut vb: Path, rotary: &RotaryEmbedding, cfg: &Rc<ModelConfig>) -> Result<Self> {
-        let attn = CausalSelfAttention::load(&vb / "self_attn", rotary, cfg)?;
-        let mlp = Mlp::load(&vb / "mlp", cfg)?;
+    /// `rotary` has the embeddings of each tensor-parallel rank.
+    fn load(
+        mut vb: Path,
+        layer: usize,
+        rotary: &[RotaryEmbedding],
+        tp: &TensorParallel,
+        cfg: &Rc<ModelConfig>,
+    ) -> Result<Self> {
+        let attn = tp.load(&vb / "self_attn", |vb, cfg, rank| {
+            CausalSelfAttention::load(vb, layer, &rotary[rank], cfg)
+        })?;
+        let mlp = match cfg.moe.as_ref() {
+            Some(moe) => tp.load(&vb / "block_sparse_moe", |vb, cfg, _| {
+                Ok(FeedForward::Moe(SparseMoe::load(vb, cfg, moe)?))
+            })?,
+            None => tp.load(&vb / "mlp", |vb, cfg, _| {
+                let names = ["gate_proj", "up_proj", "down_proj"];
+                Ok(FeedForward::Dense(Mlp::load(vb, cfg, names)?))
+            })?,
+        };
         let rms_1 = RmsNorm::from_cfg(&vb / "input_layernorm", cfg);
         let rms_2 = RmsNorm::from_cfg(&vb / "post_attention_layernorm", cfg);
         // this optimizes memory usage
@@ -194,27 +501,52 @@ impl Block {
 pub struct Llama {
     wte: nn::Embedding,
     blocks: Vec<Block>,
+    // layers of each pipeline stage
+    stages: Vec<Range<usize>>,
     ln_f: RmsNorm,
     lm_head: nn::Linear,
+    tok_vocab_size: i64,
 }
 
 impl TModelInner for Llama {
     fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
-        let mut x = self.wte.forward(&batch_info.tokens).unsqueeze(0);
-        for (block_idx, block) in self.blocks.iter().enumerate() {
-            x = block.forward(&x, batch_info, block_idx);
-        }
+        let x = batch_info
+            .override_embeddings(self.wte.forward(&batch_info.tokens))
+            .unsqueeze(0);
+        let x = run_pipeline(&x, batch_info, |stage, mut x, batch_info| {
+            for block_idx in self.stages[stage].clone() {
+                x = self.blocks[block_idx].forward(&x, batch_info, block_idx);
+            }
+            x
+        });
         let x0 = self.ln_f.forward(&x);
+        if batch_info.wants_hidden_states() {
+            batch_info.hidden_states = Some(x0.squeeze_dim(0));
+        }
         // println!("x: {}", x0);
         let x = batch_info.extract_positions(&x0.squeeze_dim(0));
         let logits = self.lm_head.forward(&x);
-        logits
+        // the output layer may be padded past the tokenizer vocabulary (e.g., Qwen2)
+        if logits.size()[1] > self.tok_vocab_size {
+            logits.i((.., 0..self.tok_vocab_size))
+        } else {
+            logits
+        }
     }
 }
 
 impl Llama {
-    pub fn load(vs: Path, cfg: &Rc<ModelConfig>) -> Result<Self> {
-        let rotary = RotaryEmbedding::new(cfg);
+    /// Layers are split between the stages of `pipeline`, and their attention and MLP
+    /// between the tensor-parallel ranks of the stage; the rest is on the model device.
+    pub fn load(vs: Path, pipeline: &Pipeline, cfg: &Rc<ModelConfig>) -> Result<Self> {
+        let rotary = (0..pipeline.num_stages())
+            .map(|stage| {
+                let tp = pipeline.stage(stage);
+                (0..tp.size())
+                    .map(|rank| RotaryEmbedding::new(tp.config(rank)))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
 
         let lm_head = linear_no_bias(cfg.hidden_size, cfg.meta.vocab_size, &vs / "lm_head");
 
@@ -228,14 +560,21 @@ impl Llama {
         let ln_f = RmsNorm::from_cfg(&vs / "model" / "norm", cfg);
 
         let blocks: Vec<_> = (0..cfg.num_hidden_layers)
-            .map(|i| Block::load(&vs / "model" / "layers" / i, &rotary, cfg).unwrap())
+            .map(|i| {
+                let stage = pipeline.stage_of_layer(i);
+                let tp = pipeline.stage(stage);
+                let vb = tp.rank0_path(&vs / "model" / "layers" / i);
+                Block::load(vb, i, &rotary[stage], tp, cfg).unwrap()
+            })
             .collect();
 
         Ok(Self {
             wte,
             blocks,
+            stages: pipeline.stage_layers(),
             ln_f,
             lm_head,
+            tok_vocab_size: cfg.meta.tok_vocab_size as i64,
         })
     }
 }
@@ -1,22 +1,30 @@
 use super::{
     config::ModelType,
-    llama,
+    gemma, llama,
+    lora::{LoraAdapter, LoraSet},
+    medusa::Medusa,
+    mpt,
     paged::{BatchInfoBuilder, BlockSpaceManager, CacheEngine},
+    parallel::Pipeline,
     phi,
     tmodel::TModel,
-    util::{gpu_memory_size, gpu_peak_allocated_bytes, log_mem_stats, reset_mem_stats},
+    util::{
+        gpu_current_allocated_bytes, gpu_free_memory, gpu_memory_size, gpu_peak_allocated_bytes,
+        log_mem_stats, reset_mem_stats,
+    },
 };
 use anyhow::{bail, Result};
 use rllm::{
     config::{ModelMeta, RllmConfig},
+    gguf::{self, GgufFile},
     CacheSize, HashSet, LoaderArgs, Repo, RllmEngine,
 };
-use safetensors::Dtype;
-use std::{path::PathBuf, rc::Rc, sync::Arc};
+use safetensors::{tensor::TensorView, Dtype};
+use std::{collections::HashMap, path::PathBuf, rc::Rc, sync::Arc};
 use tch::{nn::VarStore, Device, Kind, Tensor};
 
 use super::{
-    config::{CommonModelConfig, ModelConfig, RllmModelConfig},
+    config::{CacheConfig, CommonModelConfig, ModelConfig, RllmModelConfig, TchRllmConfig},
     tmodel::{TModelInner, TchLoaderArgs},
     DType,
 };
@@ -37,47 +45,159 @@ fn kind_from_dt(dtype: Dtype) -> Kind {
     }
 }
 
-fn read_tensor(s: &safetensors::SafeTensors, name: &str) -> Result<Tensor> {
-    let view = s.tensor(name)?;
+pub(super) fn read_tensor(s: &safetensors::SafeTensors, name: &str) -> Result<Tensor> {
+    Ok(view_tensor(&s.tensor(name)?))
+}
+
+/// The tensor of `view`, on the CPU, sharing its data (e.g., the mmapped file).
+fn view_tensor(view: &TensorView) -> Tensor {
     let size: Vec<i64> = view.shape().iter().map(|&x| x as i64).collect();
     let kind: DType = kind_from_dt(view.dtype());
     // Using from_blob here instead of from_data_size avoids some unnecessary copy.
-    let tensor = unsafe { Tensor::from_blob(view.data().as_ptr(), &size, &[], kind, Device::Cpu) };
-    Ok(tensor)
+    unsafe { Tensor::from_blob(view.data().as_ptr(), &size, &[], kind, Device::Cpu) }
 }
 
-fn load_model(
-    rllm_config: &RllmConfig<TModel>,
-    filenames: Vec<PathBuf>,
-) -> Result<Box<dyn TModelInner>> {
-    let mut vs = VarStore::new(rllm_config.model.device.clone());
+enum Weights {
+    SafeTensors(Vec<PathBuf>),
+    Gguf(Arc<GgufFile>),
+}
 
-    let rc_cfg = Rc::new(rllm_config.model.clone());
-    let mut model: Box<dyn TModelInner> = match rllm_config.model.model_type {
-        ModelType::Llama => Box::new(llama::Llama::load(vs.root(), &rc_cfg).unwrap()),
-        ModelType::Phi => Box::new(phi::MixFormerSequentialForCausalLM::new(&rc_cfg, vs.root())),
+/// Map llama.cpp tensor names to the HuggingFace ones used by our models.
+fn gguf_tensor_name(name: &str) -> Option<String> {
+    let r = match name {
+        "token_embd.weight" => "model.embed_tokens.weight".to_string(),
+        "output_norm.weight" => "model.norm.weight".to_string(),
+        "output.weight" => "lm_head.weight".to_string(),
+        _ => {
+            let (layer, suffix) = name.strip_prefix("blk.")?.split_once('.')?;
+            let hf = match suffix {
+                "attn_norm.weight" => "input_layernorm.weight",
+                "attn_q.weight" => "self_attn.q_proj.weight",
+                "attn_k.weight" => "self_attn.k_proj.weight",
+                "attn_v.weight" => "self_attn.v_proj.weight",
+                "attn_output.weight" => "self_attn.o_proj.weight",
+                "ffn_norm.weight" => "post_attention_layernorm.weight",
+                "ffn_gate.weight" => "mlp.gate_proj.weight",
+                "ffn_up.weight" => "mlp.up_proj.weight",
+                "ffn_down.weight" => "mlp.down_proj.weight",
+                _ => return None,
+            };
+            format!("model.layers.{layer}.{hf}")
+        }
     };
+    Some(r)
+}
 
-    vs.set_kind(rllm_config.model.dtype);
+/// Dequantized on the host; the caller copies it to the model variable, in the model dtype.
+fn read_gguf_tensor(gguf: &GgufFile, name: &str, head_dim: usize) -> Result<Tensor> {
+    let t = match gguf.tensor(name) {
+        Some(t) => t,
+        None => bail!("tensor {name} not found in GGUF file"),
+    };
+    let size: Vec<i64> = t.shape.iter().map(|&x| x as i64).collect();
+    let tensor = Tensor::from_slice(&gguf.read_f32(t)).reshape(&size);
+    if name.ends_with("attn_q.weight") || name.ends_with("attn_k.weight") {
+        // llama.cpp interleaves the rotary halves of each head; undo it
+        let n_head = size[0] / head_dim as i64;
+        return Ok(tensor
+            .reshape(&[n_head, head_dim as i64 / 2, 2, size[1]])
+            .transpose(1, 2)
+            .reshape(&size));
+    }
+    Ok(tensor)
+}
 
-    let mut vars = vs.variables();
+fn copy_gguf(
+    gguf: &GgufFile,
+    head_dim: usize,
+    vars: &mut HashMap<String, Tensor>,
+    bar: &indicatif::ProgressBar,
+) -> Result<()> {
+    let mut names = gguf
+        .tensors
+        .iter()
+        .map(|t| t.name.clone())
+        .collect::<Vec<_>>();
+    // embeddings are tied when there's no output layer
+    if gguf.tensor("output.weight").is_none() {
+        names.push("output.weight".to_string());
+    }
 
-    let bar = indicatif::ProgressBar::new(vars.len() as u64);
-    bar.set_style(
-        indicatif::ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:60.cyan/blue} {pos:>4}/{len:4} [{eta_precise}] {msg}",
-        )
-        .unwrap(),
-    );
+    for name in names {
+        let target_name = match gguf_tensor_name(&name) {
+            Some(n) if vars.contains_key(&n) => n,
+            _ => {
+                log::warn!("variable {} not found in the model", name);
+                continue;
+            }
+        };
+        let src_name = if gguf.tensor(&name).is_none() {
+            "token_embd.weight"
+        } else {
+            name.as_str()
+        };
+        let src_tensor = read_gguf_tensor(gguf, src_name, head_dim)?;
+        let mut var = vars.remove(&target_name).unwrap();
+        if var.size() != src_tensor.size() {
+            bail!(
+                "{name}: shape {:?} doesn't match {:?} of {target_name}",
+                src_tensor.size(),
+                var.size()
+            );
+        }
+        var.f_copy_(&src_tensor)?;
 
-    for f in &filenames {
+        bar.inc(1);
+        if bar.is_hidden() {
+            eprint!(".");
+        }
+    }
+    Ok(())
+}
+
+/// The part of `src` for tensor-parallel rank `rank`, when `var` is a shard of it
+/// (split along the one dimension where their sizes differ).
+fn shard_of(src: &Tensor, var: &Tensor, rank: usize, tp: usize) -> Result<Tensor> {
+    let (src_size, var_size) = (src.size(), var.size());
+    if src_size == var_size {
+        return Ok(src.shallow_clone());
+    }
+    if src_size.len() == var_size.len() {
+        let dims = (0..src_size.len())
+            .filter(|&d| src_size[d] != var_size[d])
+            .collect::<Vec<_>>();
+        if let [d] = dims[..] {
+            if src_size[d] == var_size[d] * tp as i64 {
+                return Ok(src.narrow(d as i64, rank as i64 * var_size[d], var_size[d]));
+            }
+        }
+    }
+    bail!("can't shard {src_size:?} into {var_size:?} for {tp} ranks")
+}
+
+/// Copy the checkpoint into `vars`, one map of variables per device;
+/// the `idx`-th is of tensor-parallel rank `idx % tp`.
+/// The files are mmapped and read in order; each tensor (or shard) is uploaded to the
+/// device as stored, and converted to the type of the model there, so the host only
+/// copies shards split by columns.
+fn copy_safetensors(
+    filenames: &[PathBuf],
+    vars: &mut [HashMap<String, Tensor>],
+    tp: usize,
+    bar: &indicatif::ProgressBar,
+) -> Result<()> {
+    for f in filenames {
         let fp = std::fs::File::open(f)?;
         let content = unsafe { memmap2::MmapOptions::new().map(&fp)? };
+        #[cfg(unix)]
+        content.advise(memmap2::Advice::Sequential)?;
         let safetensors = safetensors::SafeTensors::deserialize(&content)?;
+        let mut tensors = safetensors.tensors();
+        tensors.sort_by_key(|(_, view)| view.data().as_ptr());
 
-        for vname in safetensors.names() {
-            let target_name = vname.to_string();
-            if !vars.contains_key(&target_name) {
+        for (vname, view) in tensors {
+            let target_name = vname.clone();
+            if !vars.iter().any(|v| v.contains_key(&target_name)) {
                 if vname.ends_with(".inv_freq") {
                     // OK
                 } else {
@@ -86,12 +206,20 @@ fn load_model(
                 continue;
             }
 
-            // Using from_blob here instead of from_data_size avoids some unnecessary copy.
-            let src_tensor = read_tensor(&safetensors, vname)?;
-            let mut var = vars.remove(&target_name).unwrap();
-            assert!(var.size() == src_tensor.size());
-            // println!("copying to {var:?} from {src_tensor:?}");
-            var.f_copy_(&src_tensor)?;
+            let src_tensor = view_tensor(&view);
+            for (idx, device_vars) in vars.iter_mut().enumerate() {
+                let mut var = match device_vars.remove(&target_name) {
+                    Some(var) => var,
+                    None => continue,
+                };
+                let src_tensor = shard_of(&src_tensor, &var, idx % tp, tp)?.to_device(var.device());
+                if !src_tensor.is_floating_point() && var.kind() != src_tensor.kind() {
+                    // packed (GPTQ) weights keep their integer type
+                    var.set_data(&var.to_kind(src_tensor.kind()));
+                }
+                // println!("copying to {var:?} from {src_tensor:?}");
+                var.f_copy_(&src_tensor)?;
+            }
 
             bar.inc(1);
             if bar.is_hidden() {
@@ -99,10 +227,104 @@ fn load_model(
             }
         }
     }
+    Ok(())
+}
+
+fn load_model(rllm_config: &RllmConfig<TModel>, weights: Weights) -> Result<Box<dyn TModelInner>> {
+    let mut vs = VarStore::new(rllm_config.model.device.clone());
+    let mut pipeline = Pipeline::new(
+        &rllm_config.model,
+        &rllm_config.get_kv_devices(),
+        rllm_config.parallel.pipeline_parallel_size,
+    );
+
+    let rc_cfg = Rc::new(rllm_config.model.clone());
+    let mut model: Box<dyn TModelInner> = match rllm_config.model.model_type {
+        ModelType::Llama | ModelType::Qwen2 => {
+            Box::new(llama::Llama::load(vs.root(), &pipeline, &rc_cfg).unwrap())
+        }
+        ModelType::Phi => Box::new(phi::MixFormerSequentialForCausalLM::new(&rc_cfg, vs.root())),
+        ModelType::Phi2 => Box::new(phi::PhiForCausalLM::new(&rc_cfg, vs.root())),
+        ModelType::Gemma => Box::new(gemma::Gemma::load(vs.root(), &rc_cfg)),
+        ModelType::Mpt => Box::new(mpt::Mpt::load(vs.root(), &rc_cfg)),
+    };
+
+    vs.set_kind(rllm_config.model.dtype);
+    for store in pipeline.stores_mut() {
+        store.set_kind(rllm_config.model.dtype);
+    }
+
+    // variables on each device, starting with the model device
+    let mut device_vars = std::iter::once(&vs)
+        .chain(pipeline.stores())
+        .map(|store| store.variables())
+        .collect::<Vec<_>>();
+
+    // all ranks of a stage have the same variables; count the ones of rank 0
+    let tp = rllm_config.parallel.tensor_parallel_size;
+    let num_vars: usize = device_vars.iter().step_by(tp).map(|v| v.len()).sum();
+    let bar = indicatif::ProgressBar::new(num_vars as u64);
+    bar.set_style(
+        indicatif::ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:60.cyan/blue} {pos:>4}/{len:4} [{eta_precise}] {msg}",
+        )
+        .unwrap(),
+    );
+
+    match &weights {
+        Weights::SafeTensors(filenames) => copy_safetensors(filenames, &mut device_vars, tp, &bar)?,
+        Weights::Gguf(gguf) => {
+            copy_gguf(gguf, rllm_config.model.head_dim, &mut device_vars[0], &bar)?
+        }
+    }
+    let mut vars = device_vars.remove(0);
+
+    // older GPTQ checkpoints don't store g_idx; rows are then grouped in order
+    if let Some(q) = rllm_config.model.quantization.as_ref() {
+        let missing = vars
+            .keys()
+            .filter(|n| n.ends_with(".g_idx"))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in missing {
+            let mut var = vars.remove(&name).unwrap();
+            let rows = var.size()[0];
+            let group = if q.group_size > 0 { q.group_size } else { rows };
+            let g_idx = Tensor::arange(rows, (Kind::Int, var.device())).floor_divide_scalar(group);
+            var.set_data(&g_idx);
+        }
+    }
+
+    // embeddings are tied when there's no output layer (e.g., small Qwen2 models)
+    if vars.contains_key("lm_head.weight") && !vars.contains_key("model.embed_tokens.weight") {
+        if let Some(wte) = vs.variables().get("model.embed_tokens.weight") {
+            let mut var = vars.remove("lm_head.weight").unwrap();
+            var.f_copy_(wte)?;
+        }
+    }
+
+    let lora = &rllm_config.model.lora;
+    for (idx, adapter) in lora.adapters.iter().enumerate() {
+        log::info!("applying LoRA adapter {}", adapter.name);
+        if adapter.is_runtime(lora) {
+            adapter.copy_to(idx, &mut vars)?;
+        } else {
+            adapter.merge(&vs.variables())?;
+        }
+    }
 
     if vars.len() > 0 {
         bail!("{} variables not found in the model: {vars:?}", vars.len());
     }
+    for (idx, vars) in device_vars.iter().enumerate() {
+        if vars.len() > 0 {
+            bail!(
+                "{} variables of device {} not found in the model: {vars:?}",
+                vars.len(),
+                idx + 1
+            );
+        }
+    }
 
     if bar.is_hidden() {
         eprintln!(" done");
@@ -158,14 +380,30 @@ pub(super) fn load_rllm_engine(
 
     let rllm_config = RllmEngine::<TModel>::build_config(&args, &mut model_args)?;
 
-    let filenames = model_filenames(&repo)?;
+    let weights = if gguf::is_gguf(&args) {
+        if rllm_config.get_kv_devices().len() > 1 {
+            bail!("model parallelism is not supported for GGUF models");
+        }
+        Weights::Gguf(gguf::open_gguf(&args)?)
+    } else {
+        Weights::SafeTensors(model_filenames(&repo)?)
+    };
     log::info!("building the model");
 
     let _ = Tensor::zeros(&[1], (rllm_config.model.dtype, device));
     reset_mem_stats(device);
     log_mem_stats("initial", device);
 
-    let model = load_model(&rllm_config, filenames)?;
+    let model = load_model(&rllm_config, weights)?;
+    gguf::close_gguf();
+
+    let medusa = match rllm_config.model.medusa.as_ref() {
+        Some(m) => {
+            log::info!("loading Medusa heads from {}", m.name);
+            Some(m.load_heads(&rllm_config.model)?)
+        }
+        None => None,
+    };
 
     log_mem_stats("model fully loaded", device);
 
@@ -176,11 +414,11 @@ pub(super) fn load_rllm_engine(
     let block_mgr = BlockSpaceManager::new(
         rllm_config.model.cache.block_size,
         &cache_size,
-        0.01,
+        rllm_config.scheduler.block_watermark,
         &rllm_config,
     );
     let seq_mgr = Arc::new(block_mgr.build_seq_mgr());
-    let tmodel = TModel::new(rllm_config.clone(), cache_engine, seq_mgr, model);
+    let tmodel = TModel::new(rllm_config.clone(), cache_engine, seq_mgr, model, medusa);
 
     RllmEngine::build(args, tmodel, block_mgr, rllm_config)
 }
@@ -196,9 +434,22 @@ fn profile_model(config: Arc<RllmConfig<TModel>>, model: &Box<dyn TModelInner>)
         let _logits = model.forward(&mut info);
         log_mem_stats("after model profile", device);
 
+        // memory taken outside of torch's allocator (CUDA context, workspaces
+        // of libraries, other processes) is not available for the cache either
+        let free = gpu_free_memory(device);
+        let torch_current = gpu_current_allocated_bytes(device);
+        let other = gpu_mem.saturating_sub(free).saturating_sub(torch_current);
+        let torch_peak = gpu_peak_allocated_bytes(device);
+
         let frac = config.model.cache.gpu_memory_utilization;
-        let peak = gpu_peak_allocated_bytes(device) as isize;
+        let peak = (torch_peak + other) as isize;
         let left = (gpu_mem as f64 * frac) as isize - peak;
+        log::info!(
+            "profile: peak {}MiB (+{}MiB outside torch); {}MiB free",
+            torch_peak >> 20,
+            other >> 20,
+            free >> 20
+        );
         if left < 0 {
             panic!("not enough GPU memory for the cache: {gpu_mem} * {frac} < {peak}");
         }
@@ -207,7 +458,8 @@ fn profile_model(config: Arc<RllmConfig<TModel>>, model: &Box<dyn TModelInner>)
         512 << 20 // 512MiB
     };
 
-    let max_cpu = 2 << 30; // 2GiB
+    // host memory for swapped-out blocks is capped by the swap space
+    let max_cpu = config.model.cache.swap_space_bytes;
     let cpu_cache_size = std::cmp::min(max_cpu, gpu_cache_size);
 
     let elt_size = CacheEngine::get_cache_block_size(&config);
@@ -241,23 +493,89 @@ pub(super) fn load_model_config(
     let repo = Repo::from(args)?;
     log::info!("loading the model from {}", repo);
 
-    let bytes = repo.read("config.json")?;
     let mut err = String::new();
-
-    let cfg = load_one_config::<llama::LlamaConfig>(&mut err, args, model_args, "llama", &bytes)
-        .or_else(|| load_one_config::<phi::PhiConfig>(&mut err, args, model_args, "phi", &bytes));
+    let cfg = if gguf::is_gguf(args) {
+        let gguf = gguf::open_gguf(args)?;
+        let cfg = llama::LlamaConfig::from_gguf(&gguf)?;
+        Some(cfg.into_config(common_config(args, model_args)))
+    } else {
+        let bytes = repo.read("config.json")?;
+        load_one_config::<gemma::GemmaConfig>(&mut err, args, model_args, "gemma", &bytes)
+            .or_else(|| {
+                load_one_config::<llama::Qwen2Config>(&mut err, args, model_args, "qwen2", &bytes)
+            })
+            .or_else(|| {
+                load_one_config::<llama::LlamaConfig>(&mut err, args, model_args, "llama", &bytes)
+            })
+            .or_else(|| {
+                load_one_config::<phi::PhiConfig>(&mut err, args, model_args, "phi", &bytes)
+            })
+            .or_else(|| {
+                load_one_config::<phi::Phi2Config>(&mut err, args, model_args, "phi2", &bytes)
+            })
+            .or_else(|| {
+                load_one_config::<mpt::MptConfig>(&mut err, args, model_args, "mpt", &bytes)
+            })
+    };
 
     match cfg {
         Some(mut v) => {
             let tok = aicirt::bintokens::find_tokenizer(&args.tokenizer)?;
             v.meta.tok_vocab_size = tok.tokrx_info().vocab_size as usize;
             v.profile_step_no = model_args.profile_step_no;
+            v.lora = LoraSet {
+                adapters: model_args
+                    .lora
+                    .iter()
+                    .map(|name| LoraAdapter::load(name, None, args.offline))
+                    .chain(
+                        model_args
+                            .lora_adapters
+                            .iter()
+                            .map(|s| load_named_lora(s, args.offline)),
+                    )
+                    .collect::<Result<_>>()?,
+                runtime: model_args.lora_runtime,
+            };
+            v.medusa = model_args
+                .medusa
+                .as_deref()
+                .map(|name| Medusa::load(name, model_args.medusa_top_k, args.offline))
+                .transpose()?;
+            v.cache = CacheConfig::new(
+                model_args.block_size,
+                v.cache.gpu_memory_utilization,
+                model_args.swap_space,
+            )?;
             Ok(v)
         }
         None => bail!("failed to load model config:\n{}", err),
     }
 }
 
+/// Load a LoRA adapter requests select by name, given as NAME=PATH.
+fn load_named_lora(spec: &str, offline: bool) -> Result<LoraAdapter> {
+    match spec.split_once('=') {
+        Some((id, name)) if !id.is_empty() && !name.is_empty() => {
+            LoraAdapter::load(name, Some(id.to_string()), offline)
+        }
+        _ => bail!("invalid LoRA adapter {spec:?}; expecting NAME=PATH"),
+    }
+}
+
+fn common_config(args: &LoaderArgs, model_args: &TchLoaderArgs) -> CommonModelConfig {
+    CommonModelConfig {
+        meta: ModelMeta {
+            id: args.model_id.clone(),
+            vocab_size: 0,
+            tok_vocab_size: 0,
+            max_sequence_length: 0,
+        },
+        dtype: model_args.dtype,
+        device: model_args.device,
+    }
+}
+
 fn load_one_config<T>(
     err: &mut String,
     args: &LoaderArgs,
@@ -268,19 +586,9 @@ fn load_one_config<T>(
 where
     T: RllmModelConfig + serde::de::DeserializeOwned,
 {
-    let common = CommonModelConfig {
-        meta: ModelMeta {
-            id: args.model_id.clone(),
-            vocab_size: 0,
-            tok_vocab_size: 0,
-            max_sequence_length: 0,
-        },
-        dtype: model_args.dtype,
-        device: model_args.device,
-    };
     let json = serde_json::from_slice::<T>(bytes);
     if let Ok(json) = json {
-        Some(json.into_config(common))
+        Some(json.into_config(common_config(args, model_args)))
     } else {
         *err += &format!("{name}: {}\n", json.err().unwrap());
         None
@@ -0,0 +1,277 @@
+// LoRA adapters in the peft format: adapter_config.json and adapter_model.safetensors,
+// with weights named base_model.model.<module>.lora_{A,B}.weight
+
+use super::{config::ModelConfig, loader::read_tensor, Linear};
+use anyhow::{bail, ensure, Result};
+use rllm::{LoaderArgs, Repo};
+use serde::Deserialize;
+use std::{collections::HashMap, path::PathBuf};
+use tch::{
+    nn::{Module, Path},
+    Kind, Tensor,
+};
+
+/// `adapter_config.json` of a LoRA adapter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoraConfig {
+    pub r: usize,
+    pub lora_alpha: f64,
+    /// Names of the adapted linear layers, e.g., "q_proj".
+    pub target_modules: Vec<String>,
+    /// Scale by lora_alpha / sqrt(r) instead of lora_alpha / r.
+    #[serde(default)]
+    pub use_rslora: bool,
+    #[serde(default)]
+    pub fan_in_fan_out: bool,
+    #[serde(default)]
+    pub bias: Option<String>,
+}
+
+impl LoraConfig {
+    pub fn scale(&self) -> f64 {
+        if self.use_rslora {
+            self.lora_alpha / (self.r as f64).sqrt()
+        } else {
+            self.lora_alpha / self.r as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoraAdapter {
+    /// HuggingFace model id or local directory.
+    pub name: String,
+    /// Name requests select the adapter by (SamplingParams.adapter); None when
+    /// it applies to all of them.
+    pub id: Option<String>,
+    pub config: LoraConfig,
+    pub weights: PathBuf,
+}
+
+impl LoraAdapter {
+    pub fn load(name: &str, id: Option<String>, offline: bool) -> Result<Self> {
+        let args = LoaderArgs {
+            model_id: name.to_string(),
+            local_weights: std::path::Path::new(name)
+                .is_dir()
+                .then(|| name.to_string()),
+            offline,
+            ..LoaderArgs::default()
+        };
+        let repo = Repo::from(&args)?;
+        let config: LoraConfig = serde_json::from_slice(&repo.read("adapter_config.json")?)?;
+        if config.fan_in_fan_out {
+            bail!("LoRA {name}: fan_in_fan_out is not supported");
+        }
+        if !matches!(config.bias.as_deref(), None | Some("none")) {
+            bail!("LoRA {name}: trained biases are not supported");
+        }
+        Ok(LoraAdapter {
+            name: name.to_string(),
+            id,
+            config,
+            weights: repo.get("adapter_model.safetensors")?,
+        })
+    }
+
+    /// (module, A, B) for each adapted layer; A is [r, in_dim], B is [out_dim, r].
+    fn for_each_layer(&self, mut f: impl FnMut(&str, Tensor, Tensor) -> Result<()>) -> Result<()> {
+        let fp = std::fs::File::open(&self.weights)?;
+        let content = unsafe { memmap2::MmapOptions::new().map(&fp)? };
+        let safetensors = safetensors::SafeTensors::deserialize(&content)?;
+        for name in safetensors.names() {
+            let module = match name.strip_suffix(".lora_A.weight") {
+                Some(m) => m,
+                None => continue,
+            };
+            let b_name = format!("{module}.lora_B.weight");
+            ensure!(
+                safetensors.names().contains(&&b_name),
+                "LoRA {}: {b_name} is missing",
+                self.name
+            );
+            let a = read_tensor(&safetensors, name)?;
+            let b = read_tensor(&safetensors, &b_name)?;
+            let module = module.strip_prefix("base_model.model.").unwrap_or(module);
+            f(module, a, b)?;
+        }
+        Ok(())
+    }
+
+    /// Add B @ A * scale to the weights of the adapted layers.
+    pub fn merge(&self, vars: &HashMap<String, Tensor>) -> Result<()> {
+        let scale = self.config.scale();
+        self.for_each_layer(|module, a, b| {
+            let mut w = match vars.get(&format!("{module}.weight")) {
+                Some(w) => w.shallow_clone(),
+                None => bail!("LoRA {}: no layer {module} in the model", self.name),
+            };
+            if !w.is_floating_point() {
+                bail!("LoRA {}: can't merge into quantized {module}", self.name);
+            }
+            let delta = b
+                .to_device(w.device())
+                .to_kind(Kind::Float)
+                .matmul(&a.to_device(w.device()).to_kind(Kind::Float))
+                * scale;
+            ensure!(
+                delta.size() == w.size(),
+                "LoRA {}: {module} is {:?}, the update is {:?}",
+                self.name,
+                w.size(),
+                delta.size()
+            );
+            let _ = w.f_add_(&delta.to_kind(w.kind()))?;
+            Ok(())
+        })
+    }
+
+    /// Whether A and B are kept separate from the base weights.
+    pub fn is_runtime(&self, set: &LoraSet) -> bool {
+        set.runtime || self.id.is_some()
+    }
+
+    /// Copy A and B to the variables created by with_lora() for adapter `idx`.
+    pub fn copy_to(&self, idx: usize, vars: &mut HashMap<String, Tensor>) -> Result<()> {
+        self.for_each_layer(|module, a, b| {
+            for (part, src) in [("lora_A", a), ("lora_B", b)] {
+                let name = format!("{module}.{part}.{idx}.weight");
+                let mut var = match vars.remove(&name) {
+                    Some(v) => v,
+                    None => bail!("LoRA {}: no layer {module} in the model", self.name),
+                };
+                ensure!(
+                    var.size() == src.size(),
+                    "LoRA {}: {name} has wrong shape",
+                    self.name
+                );
+                var.f_copy_(&src)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Adapters loaded at startup.
+#[derive(Debug, Clone, Default)]
+pub struct LoraSet {
+    pub adapters: Vec<LoraAdapter>,
+    /// Keep the adapters applying to all requests separate and apply them in forward
+    /// passes (e.g., over quantized weights); otherwise they are merged into the base
+    /// weights when loading. Adapters selected per request are always kept separate.
+    pub runtime: bool,
+}
+
+impl LoraSet {
+    /// Index of the adapter requests select by `id`.
+    pub fn find(&self, id: &str) -> Option<usize> {
+        self.adapters
+            .iter()
+            .position(|a| a.id.as_deref() == Some(id))
+    }
+
+    pub fn has_per_request(&self) -> bool {
+        self.adapters.iter().any(|a| a.id.is_some())
+    }
+}
+
+#[derive(Debug)]
+struct LoraWeights {
+    a: Tensor, // [r, in_dim]
+    b: Tensor, // [out_dim, r]
+    scale: f64,
+    // index into LoraSet.adapters, for adapters selected per request
+    per_request: Option<usize>,
+}
+
+impl LoraWeights {
+    fn delta(&self, xs: &Tensor) -> Tensor {
+        xs.matmul(&self.a.tr()).matmul(&self.b.tr()) * self.scale
+    }
+}
+
+/// Linear layer with LoRA adapters applied at runtime.
+#[derive(Debug)]
+pub struct LoraLinear {
+    base: Box<Linear>,
+    adapters: Vec<LoraWeights>,
+}
+
+impl LoraLinear {
+    pub fn base(&self) -> &Linear {
+        &self.base
+    }
+
+    /// Apply the adapters selected per request only to the tokens in `lora_rows`
+    /// (adapter index -> token rows); the rows of each adapter are gathered into
+    /// one segment, multiplied, and scattered back.
+    pub fn forward_rows(&self, xs: &Tensor, lora_rows: &HashMap<usize, Tensor>) -> Tensor {
+        let ys = self.base.forward(xs);
+        let out_shape = ys.size();
+        let in_dim = *xs.size().last().unwrap();
+        let xs2 = xs.reshape(&[-1, in_dim]);
+        let mut ys2 = ys.reshape(&[-1, *out_shape.last().unwrap()]);
+        for w in self.adapters.iter() {
+            match w.per_request {
+                None => ys2 = ys2 + w.delta(&xs2),
+                Some(idx) => {
+                    if let Some(rows) = lora_rows.get(&idx) {
+                        let delta = w.delta(&xs2.index_select(0, rows));
+                        ys2 = ys2.index_add(0, rows, &delta);
+                    }
+                }
+            }
+        }
+        ys2.reshape(&out_shape)
+    }
+}
+
+impl Module for LoraLinear {
+    /// Only applies the adapters that are not selected per request.
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        let mut ys = self.base.forward(xs);
+        for w in self.adapters.iter().filter(|w| w.per_request.is_none()) {
+            ys = ys + w.delta(xs);
+        }
+        ys
+    }
+}
+
+/// Build a linear layer with `base`, adding the runtime adapters that target it.
+pub fn with_lora(
+    in_dim: usize,
+    out_dim: usize,
+    vb: Path,
+    config: &ModelConfig,
+    base: impl FnOnce(Path) -> Linear,
+) -> Linear {
+    if !config.lora.runtime && !config.lora.has_per_request() {
+        return base(vb);
+    }
+    let name = vb.components().last().unwrap_or_default().to_string();
+    let adapters = config
+        .lora
+        .adapters
+        .iter()
+        .enumerate()
+        .filter(|(_, a)| a.is_runtime(&config.lora) && a.config.target_modules.contains(&name))
+        .map(|(idx, a)| {
+            let r = a.config.r as i64;
+            LoraWeights {
+                a: (&vb / "lora_A" / idx).zeros("weight", &[r, in_dim as i64]),
+                b: (&vb / "lora_B" / idx).zeros("weight", &[out_dim as i64, r]),
+                scale: a.config.scale(),
+                per_request: a.id.as_ref().map(|_| idx),
+            }
+        })
+        .collect::<Vec<_>>();
+    let base = base(vb);
+    if adapters.is_empty() {
+        base
+    } else {
+        Linear::Lora(LoraLinear {
+            base: Box::new(base),
+            adapters,
+        })
+    }
+}
@@ -0,0 +1,179 @@
+// Medusa heads (https://github.com/FasterDecoding/Medusa): config.json with medusa_num_heads
+// and medusa_num_layers, and medusa_lm_head.safetensors with weights named
+// <head>.<layer>.linear.{weight,bias} for the residual blocks, and <head>.<num_layers>.weight
+// for the output layer (optionally prefixed with medusa_head.)
+
+use super::{config::ModelConfig, loader::read_tensor, util::to_vec1};
+use aicirt::api::Token;
+use anyhow::{bail, ensure, Result};
+use rllm::{LoaderArgs, Repo};
+use serde::Deserialize;
+use std::path::PathBuf;
+use tch::{IndexOp, Tensor};
+
+/// Medusa part of `config.json` of the heads.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MedusaConfig {
+    pub medusa_num_heads: usize,
+    #[serde(default = "default_num_layers")]
+    pub medusa_num_layers: usize,
+}
+
+fn default_num_layers() -> usize {
+    1
+}
+
+#[derive(Debug, Clone)]
+pub struct Medusa {
+    /// HuggingFace model id or local directory.
+    pub name: String,
+    pub config: MedusaConfig,
+    pub weights: PathBuf,
+    /// Candidates each head drafts.
+    pub top_k: usize,
+}
+
+impl Medusa {
+    pub fn load(name: &str, top_k: usize, offline: bool) -> Result<Self> {
+        let args = LoaderArgs {
+            model_id: name.to_string(),
+            local_weights: std::path::Path::new(name)
+                .is_dir()
+                .then(|| name.to_string()),
+            offline,
+            ..LoaderArgs::default()
+        };
+        let repo = Repo::from(&args)?;
+        let config: MedusaConfig = serde_json::from_slice(&repo.read("config.json")?)?;
+        if config.medusa_num_heads == 0 {
+            bail!("Medusa {name}: no heads");
+        }
+        if top_k == 0 {
+            bail!("Medusa {name}: top_k must be at least 1");
+        }
+        Ok(Medusa {
+            name: name.to_string(),
+            config,
+            weights: repo.get("medusa_lm_head.safetensors")?,
+            top_k,
+        })
+    }
+
+    /// Load the weights of the heads onto the model device.
+    pub fn load_heads(&self, model: &ModelConfig) -> Result<MedusaHeads> {
+        let fp = std::fs::File::open(&self.weights)?;
+        let content = unsafe { memmap2::MmapOptions::new().map(&fp)? };
+        let safetensors = safetensors::SafeTensors::deserialize(&content)?;
+        let prefix = if safetensors
+            .names()
+            .iter()
+            .any(|n| n.starts_with("medusa_head."))
+        {
+            "medusa_head."
+        } else {
+            ""
+        };
+        let hidden = model.hidden_size as i64;
+        let read = |name: String, size: &[i64]| -> Result<Tensor> {
+            let src = read_tensor(&safetensors, &format!("{prefix}{name}"))?;
+            ensure!(
+                src.size() == size,
+                "Medusa {}: {name} is {:?}, expecting {size:?}",
+                self.name,
+                src.size()
+            );
+            // the data is mmapped, so it's copied even on the CPU
+            let mut dst = Tensor::empty(size, (model.dtype, model.device));
+            dst.copy_(&src);
+            Ok(dst)
+        };
+        let num_layers = self.config.medusa_num_layers;
+        let heads = (0..self.config.medusa_num_heads)
+            .map(|i| {
+                let blocks = (0..num_layers)
+                    .map(|j| {
+                        Ok((
+                            read(format!("{i}.{j}.linear.weight"), &[hidden, hidden])?,
+                            read(format!("{i}.{j}.linear.bias"), &[hidden])?,
+                        ))
+                    })
+                    .collect::<Result<_>>()?;
+                let vocab_size = model.meta.vocab_size as i64;
+                let lm_head = read(format!("{i}.{num_layers}.weight"), &[vocab_size, hidden])?;
+                Ok(MedusaHead { blocks, lm_head })
+            })
+            .collect::<Result<_>>()?;
+        Ok(MedusaHeads {
+            heads,
+            tok_vocab_size: model.meta.tok_vocab_size as i64,
+            top_k: self.top_k,
+            tree: draft_tree(self.config.medusa_num_heads, self.top_k),
+        })
+    }
+}
+
+struct MedusaHead {
+    // (weight, bias) of the residual blocks
+    blocks: Vec<(Tensor, Tensor)>,
+    lm_head: Tensor,
+}
+
+/// Nodes of the tree of draft tokens, as (parent, head, rank of the candidate of the
+/// head): the chain of the top candidates of the heads, followed by the other
+/// candidates of each head, as siblings of its top one. Parents are indices into
+/// the nodes plus one (see Sequence::draft_parents), and come before their children.
+fn draft_tree(num_heads: usize, top_k: usize) -> Vec<(usize, usize, usize)> {
+    let mut tree = (0..num_heads)
+        .map(|head| (head, head, 0))
+        .collect::<Vec<_>>();
+    for head in 0..num_heads {
+        tree.extend((1..top_k).map(|rank| (head, head, rank)));
+    }
+    tree
+}
+
+/// Heads predicting the tokens after the next one from the final hidden state of a token.
+pub struct MedusaHeads {
+    heads: Vec<MedusaHead>,
+    tok_vocab_size: i64,
+    top_k: usize,
+    tree: Vec<(usize, usize, usize)>,
+}
+
+impl MedusaHeads {
+    /// Number of tokens drafted for a row; see tree().
+    pub fn num_draft_tokens(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// The tree of tokens drafted for a row of draft() ([num_heads, top_k]):
+    /// the tokens, and their parents.
+    pub fn tree(&self, draft: &Tensor) -> (Vec<Token>, Vec<usize>) {
+        let draft = to_vec1::<i64>(&draft.flatten(0, -1));
+        self.tree
+            .iter()
+            .map(|&(parent, head, rank)| (draft[head * self.top_k + rank] as Token, parent))
+            .unzip()
+    }
+
+    /// Draft tokens for `hidden_states` [num_rows, hidden_size]; returns
+    /// [num_rows, num_heads, top_k], the i-th head drafting candidates for the
+    /// (i+2)-th next token, the most likely first.
+    pub fn draft(&self, hidden_states: &Tensor) -> Tensor {
+        let tokens = self
+            .heads
+            .iter()
+            .map(|head| {
+                let mut x = hidden_states.shallow_clone();
+                for (weight, bias) in head.blocks.iter() {
+                    x = &x + x.linear(weight, Some(bias)).silu();
+                }
+                let logits = x.linear::<Tensor>(&head.lm_head, None);
+                // the output layer may be padded past the tokenizer vocabulary
+                let logits = logits.i((.., 0..self.tok_vocab_size));
+                logits.topk(self.top_k as i64, -1, true, true).1
+            })
+            .collect::<Vec<_>>();
+        Tensor::stack(&tokens, 1)
+    }
+}
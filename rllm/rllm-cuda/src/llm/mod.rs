@@ -1,14 +1,19 @@
 pub mod config;
+pub mod gemma;
 pub mod kernels;
 pub mod llama;
 pub mod loader;
+pub mod lora;
+pub mod medusa;
+pub mod mpt;
+pub mod parallel;
 pub mod phi;
 pub mod refkernels;
 pub mod tmodel;
 pub mod util;
 pub mod paged;
 
-use self::config::ModelConfig;
+use self::config::{ModelConfig, QuantConfig, RopeScaling};
 use paged::BatchInfo;
 use std::rc::Rc;
 use tch::{
@@ -37,21 +42,83 @@ impl Clone for RotaryEmbedding {
     }
 }
 
+/// YaRN: frequencies are interpolated (divided by the factor) for the dimensions
+/// that rotate only a few times over the original context, kept for the fast ones,
+/// and blended linearly in between.
+fn yarn_inv_freq(s: &RopeScaling, base: f32, rotary_dim: usize) -> Vec<f32> {
+    let d = rotary_dim as f32;
+    let orig = s.original_len() as f32;
+    // dimension that completes num_rotations over the original context
+    let correction_dim = |num_rotations: f32| {
+        d * (orig / (num_rotations * 2.0 * std::f32::consts::PI)).ln() / (2.0 * base.ln())
+    };
+    let low = correction_dim(s.beta_fast).floor().max(0.0);
+    let mut high = correction_dim(s.beta_slow).ceil().min(d - 1.0);
+    if low == high {
+        high += 0.001;
+    }
+    (0..rotary_dim / 2)
+        .map(|i| {
+            let extrapolation = 1f32 / base.powf(2.0 * i as f32 / d);
+            let interpolation = extrapolation / s.factor;
+            let ramp = ((i as f32 - low) / (high - low)).clamp(0.0, 1.0);
+            interpolation * ramp + extrapolation * (1.0 - ramp)
+        })
+        .collect()
+}
+
 impl RotaryEmbedding {
     pub fn new(config: &Rc<ModelConfig>) -> Self {
         // pre-compute freqs_cis
         let rotary_dim = config.rotary_dim;
-        let theta: Vec<_> = (0..rotary_dim)
-            .step_by(2)
-            .map(|i| 1f32 / config.rope_theta.powf(i as f32 / rotary_dim as f32))
-            .collect();
-        let theta = Tensor::from_slice(theta.as_slice()).to(config.device);
+        let inv_freq = |base: f32| -> Vec<f32> {
+            (0..rotary_dim)
+                .step_by(2)
+                .map(|i| 1f32 / base.powf(i as f32 / rotary_dim as f32))
+                .collect()
+        };
         let len = config.meta.max_sequence_length as i64;
-        let idx_theta = Tensor::arange(len, (DType::Float, config.device))
-            .reshape(&[len, 1])
-            .matmul(&theta.reshape(&[1, theta.numel() as i64]));
-        let cos = idx_theta.cos().to_kind(config.dtype);
-        let sin = idx_theta.sin().to_kind(config.dtype);
+        let positions = Tensor::arange(len, (DType::Float, config.device)).reshape(&[len, 1]);
+        let rotate = |theta: Vec<f32>, pos_scale: f64| {
+            let theta = Tensor::from_slice(theta.as_slice()).to(config.device);
+            (&positions / pos_scale).matmul(&theta.reshape(&[1, theta.numel() as i64]))
+        };
+        // cos and sin are multiplied by mscale
+        let (idx_theta, mscale) = match config.rope_scaling.as_ref() {
+            None => (rotate(inv_freq(config.rope_theta), 1.0), 1.0),
+            Some(s) => match s.kind.as_str() {
+                "linear" => (rotate(inv_freq(config.rope_theta), s.factor as f64), 1.0),
+                // NTK-aware: the base is scaled for the length of the sequence so far, once
+                // it's past the original context; the token at position p is rotated as if
+                // the sequence was p+1 long, so positions up to the original length use
+                // rope_theta, and keys in the KV cache never need to be rotated again
+                "dynamic" => {
+                    let d = rotary_dim as f64;
+                    let orig = s.original_len() as f64;
+                    let factor = s.factor as f64;
+                    let seq_len = (&positions + 1.0).clamp_min(orig);
+                    let log_base = (seq_len * (factor / orig) - (factor - 1.0)).log()
+                        * (d / (d - 2.0))
+                        + (config.rope_theta as f64).ln();
+                    // inv_freq[p, i] = base[p] ^ (-2i / d)
+                    let exps = Tensor::arange_start_step(
+                        0,
+                        rotary_dim as i64,
+                        2,
+                        (DType::Float, config.device),
+                    ) / -d;
+                    let inv_freq = log_base.matmul(&exps.reshape(&[1, -1])).exp();
+                    (&positions * inv_freq, 1.0)
+                }
+                "yarn" => (
+                    rotate(yarn_inv_freq(s, config.rope_theta, rotary_dim), 1.0),
+                    0.1 * (s.factor as f64).ln() + 1.0,
+                ),
+                _ => panic!("unsupported rope_scaling {}", s.kind),
+            },
+        };
+        let cos = (idx_theta.cos() * mscale).to_kind(config.dtype);
+        let sin = (idx_theta.sin() * mscale).to_kind(config.dtype);
         let cos_sin = Tensor::cat(&[&cos, &sin], -1).contiguous();
         Self {
             config: config.clone(),
@@ -71,10 +138,9 @@ impl RotaryEmbedding {
             -1,
             (self.config.num_attention_heads * self.config.head_dim) as i64,
         ]);
-        let mut k = k.reshape(&[
-            -1,
-            (self.config.num_key_value_heads * self.config.head_dim) as i64,
-        ]);
+        // the number of KV heads may differ between layers
+        let kv_size = *k.size().last().unwrap();
+        let mut k = k.reshape(&[-1, kv_size]);
         let num_tokens = q.size()[0];
         assert!(num_tokens == k.size()[0]);
 
@@ -119,11 +185,7 @@ impl RotaryEmbedding {
             self.config.num_attention_heads as i64,
             self.config.head_dim as i64,
         ]);
-        let k = k.reshape(&[
-            num_tokens,
-            self.config.num_key_value_heads as i64,
-            self.config.head_dim as i64,
-        ]);
+        let k = k.reshape(&[num_tokens, -1, self.config.head_dim as i64]);
 
         (q, k)
     }
@@ -174,15 +236,17 @@ fn compute_varlen_attn(
     let (key_cache, value_cache) = batch_info.kv_cache.get(block_idx);
 
     if q.size()[0] == 0 {
-        return Tensor::empty(&[0, config.hidden_size as i64], (q.kind(), q.device()));
+        let size = (config.num_attention_heads * config.head_dim) as i64;
+        return Tensor::empty(&[0, size], (q.kind(), q.device()));
     }
 
     // then, extend key/value and fill them from cache
+    let shape = config.kv_shape(block_idx);
     let mut k = Tensor::empty(
         &[
             batch_info.gather_mapping.size()[0],
-            config.num_key_value_heads as i64,
-            config.head_dim as i64,
+            shape.num_kv_heads as i64,
+            shape.head_dim as i64,
         ],
         (q.kind(), q.device()),
     );
@@ -211,20 +275,22 @@ fn compute_varlen_attn(
         check_all_close(&v, &vv, 1e-5);
     }
 
-    let k = repeat_kv(config, k);
-    let v = repeat_kv(config, v);
-
+    // k and v keep their num_kv_heads; the attention kernels handle GQA
     let y = {
         batch_info.log_tensor("q", &q);
         batch_info.log_tensor("k", &k);
         batch_info.log_tensor("v", &v);
 
         // flash-attn expects (seq_len, nheads, head_dim)
-        let softmax_scale = 1f32 / (config.head_dim as f32).sqrt();
+        let softmax_scale = 1f32 / (shape.head_dim as f32).sqrt();
 
         let causal = true;
 
-        let y = if config.dtype == DType::BFloat16 || config.dtype == DType::Half {
+        // flash-attn has no ALiBi support
+        let use_flash = (config.dtype == DType::BFloat16 || config.dtype == DType::Half)
+            && batch_info.alibi_slopes.is_none();
+
+        let y = if use_flash {
             let y = kernels::varlen_attn(
                 &q,
                 &k,
@@ -235,6 +301,7 @@ fn compute_varlen_attn(
                 batch_info.max_seqlen_k,
                 softmax_scale,
                 causal,
+                batch_info.sliding_window,
             );
 
             if CHECK {
@@ -248,13 +315,14 @@ fn compute_varlen_attn(
                     batch_info.max_seqlen_k,
                     softmax_scale,
                     causal,
+                    batch_info.sliding_window,
                 );
                 check_all_close_attn(&y, &y2);
             }
 
             y
         } else {
-            refkernels::varlen_attn(
+            refkernels::varlen_attn_alibi(
                 &q,
                 &k,
                 &v,
@@ -264,20 +332,33 @@ fn compute_varlen_attn(
                 batch_info.max_seqlen_k,
                 softmax_scale,
                 causal,
+                batch_info.sliding_window,
+                batch_info.alibi_slopes.as_ref(),
             )
         };
 
+        // draft tokens of a tree see only their ancestors in it
+        for (rows, keys, mask) in batch_info.draft_masks.iter() {
+            let y_tree = refkernels::masked_attn(
+                &q.i((rows.clone(), .., ..)),
+                &k.i((keys.clone(), .., ..)),
+                &v.i((keys.clone(), .., ..)),
+                mask,
+                softmax_scale,
+            );
+            y.i((rows.clone(), .., ..)).copy_(&y_tree);
+        }
+
         y
     };
 
     batch_info.log_tensor("y", &v);
 
-    let y = y.reshape(&[-1, config.hidden_size as i64]);
+    let y = y.reshape(&[-1, (config.num_attention_heads * config.head_dim) as i64]);
 
     y
 }
 
-#[cfg(feature = "cuda")]
 fn compute_paged_attn(
     config: &ModelConfig,
     q: &Tensor,
@@ -285,8 +366,6 @@ fn compute_paged_attn(
     batch_info: &mut BatchInfo,
     block_idx: usize,
 ) -> Tensor {
-    use tch_cuda::paged_attention_v1;
-
     if q.size()[0] == 0 {
         return y.shallow_clone();
     }
@@ -294,43 +373,90 @@ fn compute_paged_attn(
     let mut out = Tensor::empty_like(q);
     let (key_cache, value_cache) = batch_info.kv_cache.get(block_idx);
 
-    let softmax_scale = 1f32 / (config.head_dim as f32).sqrt();
+    let shape = config.kv_shape(block_idx);
+    let softmax_scale = 1f32 / (shape.head_dim as f32).sqrt();
 
-    paged_attention_v1(
+    kernels::paged_attention_v1(
         &mut out,
         &q,
         &key_cache,
         &value_cache,
-        config.num_key_value_heads,
+        shape.num_kv_heads,
         softmax_scale,
         &batch_info.paged_block_tables,
         &batch_info.paged_context_lens,
         batch_info.paged_block_size,
         batch_info.paged_max_context_len,
-        None,
+        batch_info.alibi_slopes.as_ref(),
+        batch_info.sliding_window,
     );
 
-    let out = out.reshape(&[-1, config.hidden_size as i64]);
+    if CHECK {
+        let mut out2 = out.empty_like();
+        refkernels::paged_attention_v1(
+            &mut out2,
+            &q,
+            &key_cache,
+            &value_cache,
+            shape.num_kv_heads,
+            softmax_scale,
+            &batch_info.paged_block_tables,
+            &batch_info.paged_context_lens,
+            batch_info.paged_block_size,
+            batch_info.paged_max_context_len,
+            batch_info.alibi_slopes.as_ref(),
+            batch_info.sliding_window,
+        );
+        check_all_close_attn(&out, &out2);
+    }
+
+    let out = out.reshape(&[-1, (config.num_attention_heads * config.head_dim) as i64]);
 
     Tensor::cat(&[y, &out], 0)
 }
 
+/// ALiBi slopes of the heads: 2^(-bias_max * i / n) for i in 1..=n, where n is the number
+/// of heads rounded up to a power of two; when rounded, every other slope is taken
+/// (even i first) until there's one per head.
+pub fn alibi_slopes(num_heads: usize, bias_max: f32) -> Vec<f32> {
+    let n = num_heads.next_power_of_two();
+    let slopes = (1..=n)
+        .map(|i| 2f32.powf(-bias_max * i as f32 / n as f32))
+        .collect::<Vec<_>>();
+    if n == num_heads {
+        return slopes;
+    }
+    slopes
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .chain(slopes.iter().step_by(2))
+        .take(num_heads)
+        .copied()
+        .collect()
+}
+
 pub fn varlen_attn(
     config: &ModelConfig,
     q: Tensor, // [num_tokens, num_heads, head_size]
-    k: Tensor, // [num_tokens, num_heads, head_size]
-    v: Tensor, // [num_tokens, num_heads, head_size]
+    k: Tensor, // [num_tokens, num_kv_heads, head_size]
+    v: Tensor, // [num_tokens, num_kv_heads, head_size]
     batch_info: &mut BatchInfo,
     block_idx: usize,
 ) -> Tensor // [num_tokens, num_heads * head_size]
 {
     // println!("varlen_attn: q: {q:?} k: {k:?} v: {v:?}");
-    // doesn't hold for GQA:
-    // assert!(q.size() == k.size());
-    // assert!(v.size() == k.size());
+    assert!(k.size() == v.size());
 
     save_attn(config, &k, &v, batch_info, block_idx);
 
+    if batch_info.kv_scores.len() > 0 {
+        let (key_cache, value_cache) = batch_info.kv_cache.get(block_idx);
+        for score in batch_info.kv_scores.iter_mut() {
+            score.add_layer(config, block_idx, &q, &key_cache, &value_cache);
+        }
+    }
+
     let y = compute_varlen_attn(
         config,
         &q.i((0..batch_info.q_multi, .., ..)),
@@ -338,10 +464,6 @@ pub fn varlen_attn(
         block_idx,
     );
 
-    #[cfg(not(feature = "cuda"))]
-    assert!(q.i((batch_info.q_multi.., .., ..)).numel() == 0);
-
-    #[cfg(feature = "cuda")]
     let y = compute_paged_attn(
         config,
         &q.i((batch_info.q_multi.., .., ..)),
@@ -353,22 +475,12 @@ pub fn varlen_attn(
     y
 }
 
-// x is [seq_len, num_heads, head_dim]
-fn repeat_kv(config: &ModelConfig, x: Tensor) -> Tensor {
-    let n_rep = config.num_attention_heads / config.num_key_value_heads;
-    if n_rep == 1 {
-        x
-    } else {
-        let dim = 1;
-        x.repeat_interleave_self_int(n_rep as i64, Some(dim), None)
-    }
-}
-
 #[derive(Debug)]
 pub struct RmsNorm {
     scale: Tensor,
     size: i64,
     eps: f64,
+    unit_offset: bool,
 }
 
 impl RmsNorm {
@@ -382,8 +494,15 @@ impl RmsNorm {
             scale,
             size: size as i64,
             eps: eps.unwrap_or(1e-5),
+            unit_offset: false,
         }
     }
+
+    /// Scale by `1 + weight` instead of `weight` (Gemma).
+    pub fn with_unit_offset(mut self) -> Self {
+        self.unit_offset = true;
+        self
+    }
 }
 
 impl Module for RmsNorm {
@@ -394,6 +513,7 @@ impl Module for RmsNorm {
         let variance = (&xs * &xs).mean_dim(-1, true, xs.kind());
         let xs_normed = xs * (variance + self.eps).rsqrt();
         let scale = self.scale.reshape([1, 1, self.size]);
+        let scale = if self.unit_offset { scale + 1.0 } else { scale };
         scale * xs_normed.to_kind(k)
     }
 }
@@ -415,6 +535,116 @@ pub fn linear(in_dim: usize, out_dim: usize, vs: Path) -> nn::Linear {
     )
 }
 
+/// 4-bit GPTQ weights of a linear layer, in the AutoGPTQ layout.
+#[derive(Debug)]
+pub struct GptqLinear {
+    qweight: Tensor, // [in_dim / 8, out_dim], int
+    qzeros: Tensor,  // [num_groups, out_dim / 8], int
+    scales: Tensor,  // [num_groups, out_dim]
+    g_idx: Tensor,   // [in_dim], int
+}
+
+impl GptqLinear {
+    pub fn new(in_dim: usize, out_dim: usize, vb: Path, q: &QuantConfig) -> Self {
+        let groups = if q.group_size > 0 {
+            in_dim as i64 / q.group_size
+        } else {
+            1
+        };
+        let (in_dim, out_dim) = (in_dim as i64, out_dim as i64);
+        // integer tensors are converted to the checkpoint's type when loading
+        Self {
+            qweight: vb.zeros_no_train("qweight", &[in_dim / 8, out_dim]),
+            qzeros: vb.zeros_no_train("qzeros", &[groups, out_dim / 8]),
+            scales: vb.zeros_no_train("scales", &[groups, out_dim]),
+            g_idx: vb.zeros_no_train("g_idx", &[in_dim]),
+        }
+    }
+}
+
+impl Module for GptqLinear {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        kernels::gptq_gemm(xs, &self.qweight, &self.qzeros, &self.scales, &self.g_idx)
+    }
+}
+
+/// 4-bit AWQ weights of a linear layer; unlike GPTQ, packed along the output dimension.
+#[derive(Debug)]
+pub struct AwqLinear {
+    qweight: Tensor, // [in_dim, out_dim / 8], int
+    qzeros: Tensor,  // [in_dim / group_size, out_dim / 8], int
+    scales: Tensor,  // [in_dim / group_size, out_dim]
+}
+
+impl AwqLinear {
+    pub fn new(in_dim: usize, out_dim: usize, vb: Path, q: &QuantConfig) -> Self {
+        let groups = in_dim as i64 / q.group_size;
+        let (in_dim, out_dim) = (in_dim as i64, out_dim as i64);
+        Self {
+            qweight: vb.zeros_no_train("qweight", &[in_dim, out_dim / 8]),
+            qzeros: vb.zeros_no_train("qzeros", &[groups, out_dim / 8]),
+            scales: vb.zeros_no_train("scales", &[groups, out_dim]),
+        }
+    }
+}
+
+impl Module for AwqLinear {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        kernels::awq_gemm(xs, &self.qweight, &self.scales, &self.qzeros)
+    }
+}
+
+/// Linear layer with either dense or quantized weights.
+#[derive(Debug)]
+pub enum Linear {
+    Dense(nn::Linear),
+    Gptq(GptqLinear),
+    Awq(AwqLinear),
+    Lora(lora::LoraLinear),
+}
+
+impl Linear {
+    /// Like forward(), but also applying the LoRA adapters the requests of the batch select.
+    pub fn forward_batch(&self, xs: &Tensor, batch_info: &BatchInfo) -> Tensor {
+        match self {
+            Linear::Lora(l) => l.forward_rows(xs, &batch_info.lora_rows),
+            _ => self.forward(xs),
+        }
+    }
+
+    /// Dense weight; for quantized layers the packed one.
+    pub fn ws(&self) -> &Tensor {
+        match self {
+            Linear::Dense(l) => &l.ws,
+            Linear::Gptq(q) => &q.qweight,
+            Linear::Awq(q) => &q.qweight,
+            Linear::Lora(l) => l.base().ws(),
+        }
+    }
+}
+
+impl Module for Linear {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        match self {
+            Linear::Dense(l) => l.forward(xs),
+            Linear::Gptq(q) => q.forward(xs),
+            Linear::Awq(q) => q.forward(xs),
+            Linear::Lora(l) => l.forward(xs),
+        }
+    }
+}
+
+/// Like linear_no_bias(), but quantized if the model is, and with runtime LoRA adapters.
+pub fn qlinear_no_bias(in_dim: usize, out_dim: usize, vb: Path, config: &ModelConfig) -> Linear {
+    lora::with_lora(in_dim, out_dim, vb, config, |vb| {
+        match config.quantization.as_ref() {
+            Some(q) if q.is_awq() => Linear::Awq(AwqLinear::new(in_dim, out_dim, vb, q)),
+            Some(q) => Linear::Gptq(GptqLinear::new(in_dim, out_dim, vb, q)),
+            None => Linear::Dense(linear_no_bias(in_dim, out_dim, vb)),
+        }
+    })
+}
+
 pub fn layer_norm(vs: nn::Path, config: &ModelConfig) -> nn::LayerNorm {
     nn::layer_norm(
         vs,
@@ -0,0 +1,271 @@
+// based on https://huggingface.co/mosaicml/mpt-7b/blob/main/modeling_mpt.py
+
+use super::{
+    config::{CommonModelConfig, ModelConfig, ModelType, RllmModelConfig},
+    linear_no_bias,
+    paged::BatchInfo,
+    varlen_attn,
+};
+use serde::Deserialize;
+use std::rc::Rc;
+use tch::{
+    nn::{self, Module, Path},
+    IndexOp, Tensor,
+};
+
+use super::tmodel::TModelInner;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum MptModelType {
+    Mpt,
+}
+
+#[derive(Deserialize)]
+pub struct MptAttnConfig {
+    #[serde(default)]
+    pub alibi: bool,
+    #[serde(default = "default_alibi_bias_max")]
+    pub alibi_bias_max: f32,
+    /// Set for grouped-query attention.
+    #[serde(default)]
+    pub kv_n_heads: Option<usize>,
+}
+
+fn default_alibi_bias_max() -> f32 {
+    8.0
+}
+
+/// MPT; positions come from ALiBi or, without it, from learned embeddings.
+/// Released checkpoints have no biases (no_bias) and no clip_qkv or qk_ln.
+/// https://huggingface.co/mosaicml/mpt-7b
+#[derive(Deserialize)]
+pub struct MptConfig {
+    #[serde(rename = "model_type")]
+    _model_type: MptModelType,
+    pub d_model: usize,
+    pub n_heads: usize,
+    pub n_layers: usize,
+    pub expansion_ratio: usize,
+    pub max_seq_len: usize,
+    pub vocab_size: usize,
+    pub attn_config: MptAttnConfig,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_epsilon: f64,
+    pub torch_dtype: String,
+}
+
+fn default_layer_norm_eps() -> f64 {
+    1e-5
+}
+
+impl RllmModelConfig for MptConfig {
+    fn into_config(self, common: CommonModelConfig) -> ModelConfig {
+        let head_dim = self.d_model / self.n_heads;
+        let mut meta = common.meta.clone();
+        meta.vocab_size = self.vocab_size;
+        meta.tok_vocab_size = self.vocab_size;
+        meta.max_sequence_length = self.max_seq_len;
+        ModelConfig {
+            model_type: ModelType::Mpt,
+            meta,
+            hidden_size: self.d_model,
+            intermediate_size: self.d_model * self.expansion_ratio,
+            num_hidden_layers: self.n_layers,
+            num_attention_heads: self.n_heads,
+            num_key_value_heads: self.attn_config.kv_n_heads.unwrap_or(self.n_heads),
+            layer_norm_eps: self.layer_norm_epsilon,
+            // not used
+            rope_theta: 10000.0,
+            rope_scaling: None,
+            alibi_bias_max: self
+                .attn_config
+                .alibi
+                .then_some(self.attn_config.alibi_bias_max),
+            head_dim,
+            rotary_dim: head_dim,
+            layer_kv_shapes: None,
+            dtype: ModelConfig::dtype_from_str(common.dtype, common.device, &self.torch_dtype),
+            device: common.device,
+            sliding_window: None,
+            quantization: None,
+            moe: None,
+            logit_softcap: None,
+            lora: Default::default(),
+            medusa: None,
+            profile_step_no: 0,
+            cache: Default::default(),
+        }
+    }
+}
+
+/// Layer norm with a weight but no bias.
+struct LayerNorm {
+    weight: Tensor,
+    size: i64,
+    eps: f64,
+}
+
+impl LayerNorm {
+    fn new(vs: Path, cfg: &ModelConfig) -> Self {
+        Self {
+            weight: vs.ones("weight", &[cfg.hidden_size as i64]),
+            size: cfg.hidden_size as i64,
+            eps: cfg.layer_norm_eps,
+        }
+    }
+}
+
+impl Module for LayerNorm {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        xs.layer_norm(
+            &[self.size],
+            Some(&self.weight),
+            None::<Tensor>,
+            self.eps,
+            false,
+        )
+    }
+}
+
+struct Attention {
+    wqkv: nn::Linear,
+    out_proj: nn::Linear,
+    config: Rc<ModelConfig>,
+    block_idx: usize,
+}
+
+impl Attention {
+    fn load(vb: Path, cfg: &Rc<ModelConfig>, block_idx: usize) -> Self {
+        let size_q = cfg.head_dim * cfg.num_attention_heads;
+        let size_kv = cfg.head_dim * cfg.num_key_value_heads;
+        Self {
+            wqkv: linear_no_bias(cfg.hidden_size, size_q + 2 * size_kv, &vb / "Wqkv"),
+            out_proj: linear_no_bias(size_q, cfg.hidden_size, &vb / "out_proj"),
+            config: cfg.clone(),
+            block_idx,
+        }
+    }
+
+    fn forward(&self, x: &Tensor, batch_info: &mut BatchInfo) -> Tensor {
+        let (seq_len, _hidden_size) = x.size2().unwrap();
+        let size_q = (self.config.head_dim * self.config.num_attention_heads) as i64;
+        let size_kv = (self.config.head_dim * self.config.num_key_value_heads) as i64;
+        let qkv = self.wqkv.forward(x);
+        let shape = [seq_len, -1, self.config.head_dim as i64];
+        let q = qkv.i((.., 0..size_q)).reshape(&shape);
+        let k = qkv.i((.., size_q..size_q + size_kv)).reshape(&shape);
+        let v = qkv.i((.., size_q + size_kv..)).reshape(&shape);
+        let y = varlen_attn(&self.config, q, k, v, batch_info, self.block_idx);
+        self.out_proj.forward(&y)
+    }
+}
+
+struct Mlp {
+    up_proj: nn::Linear,
+    down_proj: nn::Linear,
+}
+
+impl Mlp {
+    fn load(vb: Path, cfg: &ModelConfig) -> Self {
+        Self {
+            up_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, &vb / "up_proj"),
+            down_proj: linear_no_bias(cfg.intermediate_size, cfg.hidden_size, &vb / "down_proj"),
+        }
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, x: &Tensor) -> Tensor {
+        self.down_proj
+            .forward(&self.up_proj.forward(x).gelu("none"))
+    }
+}
+
+struct Block {
+    norm_1: LayerNorm,
+    attn: Attention,
+    norm_2: LayerNorm,
+    ffn: Mlp,
+}
+
+impl Block {
+    fn load(mut vb: Path, cfg: &Rc<ModelConfig>, block_idx: usize) -> Self {
+        let norm_1 = LayerNorm::new(&vb / "norm_1", cfg);
+        let attn = Attention::load(&vb / "attn", cfg, block_idx);
+        let norm_2 = LayerNorm::new(&vb / "norm_2", cfg);
+        let ffn = Mlp::load(&vb / "ffn", cfg);
+        // this optimizes memory usage
+        vb.set_kind(cfg.dtype);
+        Self {
+            norm_1,
+            attn,
+            norm_2,
+            ffn,
+        }
+    }
+
+    fn forward(&self, x: &Tensor, batch_info: &mut BatchInfo) -> Tensor {
+        let x = self.attn.forward(&self.norm_1.forward(x), batch_info) + x;
+        self.ffn.forward(&self.norm_2.forward(&x)) + x
+    }
+}
+
+pub struct Mpt {
+    wte: nn::Embedding,
+    // learned positions, without ALiBi
+    wpe: Option<nn::Embedding>,
+    blocks: Vec<Block>,
+    norm_f: LayerNorm,
+    config: Rc<ModelConfig>,
+}
+
+impl TModelInner for Mpt {
+    fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
+        let mut x = batch_info.override_embeddings(self.wte.forward(&batch_info.tokens));
+        if let Some(wpe) = &self.wpe {
+            x = x + wpe.forward(&batch_info.positions);
+        }
+        for block in self.blocks.iter() {
+            x = block.forward(&x, batch_info);
+        }
+        let x = self.norm_f.forward(&x);
+        if batch_info.wants_hidden_states() {
+            batch_info.hidden_states = Some(x.shallow_clone());
+        }
+        let x = batch_info.extract_positions(&x);
+        // the output layer is tied to the (padded) embeddings
+        let logits = x.matmul(&self.wte.ws.tr());
+        logits.i((.., 0..self.config.meta.tok_vocab_size as i64))
+    }
+}
+
+impl Mpt {
+    pub fn load(vs: Path, cfg: &Rc<ModelConfig>) -> Self {
+        let vb = &vs / "transformer";
+        let embedding = |name: &str, size: usize| {
+            nn::embedding(
+                &vb / name,
+                size as i64,
+                cfg.hidden_size as i64,
+                Default::default(),
+            )
+        };
+        let wte = embedding("wte", cfg.meta.vocab_size);
+        let wpe = match cfg.alibi_bias_max {
+            Some(_) => None,
+            None => Some(embedding("wpe", cfg.meta.max_sequence_length)),
+        };
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| Block::load(&vb / "blocks" / i, cfg, i))
+            .collect();
+        let norm_f = LayerNorm::new(&vb / "norm_f", cfg);
+        Self {
+            wte,
+            wpe,
+            blocks,
+            norm_f,
+            config: cfg.clone(),
+        }
+    }
+}
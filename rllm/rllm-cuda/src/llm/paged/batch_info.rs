@@ -1,18 +1,29 @@
-use super::super::{kernels::to_offsets, tmodel::TModel};
+use super::super::{
+    alibi_slopes,
+    config::{ModelConfig, TchRllmConfig},
+    kernels::{self, to_offsets},
+    tmodel::TModel,
+};
 use super::cache_engine::CacheEngine;
 use super::BlockAllocator;
 use rllm::{
-    config::RllmConfig, seq::SchedulingPhase, util::pad_to_multiple, HashMap, SchedulerOutputs,
+    config::RllmConfig,
+    seq::{draft_depths, EmbeddingSpan, SchedulingPhase},
+    util::pad_to_multiple,
+    HashMap, SchedulerOutputs, SeqId,
 };
 use aicirt::api::Token;
 use std::{
     fmt::Debug,
+    ops::Range,
     sync::{Arc, Mutex},
 };
-use tch::{IndexOp, Tensor};
+use tch::{Device, IndexOp, Kind, Tensor};
 
 pub trait CacheIface {
     fn get(&self, layer_no: usize) -> (Tensor, Tensor);
+    /// The cache on the `idx`-th of get_kv_devices() (0 is the model device).
+    fn shard(&self, idx: usize) -> Box<dyn CacheIface>;
 }
 
 pub struct BatchInfo {
@@ -40,6 +51,96 @@ pub struct BatchInfo {
 
     pub seqlen_multi: i64,
     pub q_multi: i64,
+
+    // keys of a sequence are the KV it holds (dropped blocks are not gathered),
+    // so the window is applied to its last keys; None with attention sinks
+    pub sliding_window: Option<usize>,
+    // f32, [num_heads]; for ALiBi models
+    pub alibi_slopes: Option<Tensor>,
+
+    // prompt embeddings overriding token embeddings: indices into tokens, and values
+    pub embedding_overrides: Option<(Tensor, Tensor)>,
+
+    // seq_id -> rows of its tokens, for sequences that want hidden states
+    pub hidden_state_ranges: HashMap<usize, Range<usize>>,
+    // final-layer hidden states, [num_tokens, hidden_size]; set by the model if wanted
+    pub hidden_states: Option<Tensor>,
+    // with Medusa heads, hidden states are kept to draft tokens from
+    pub medusa: bool,
+    // seq_id -> rows of logits (after the one of each sequence) of the tokens
+    // before its last one, one per draft token
+    pub draft_logits: HashMap<usize, Range<usize>>,
+    // (query rows, rows of their keys in gather_mapping, bool mask [query rows, keys]
+    // of the keys each query sees) of sequences with a tree of draft tokens, which
+    // see only their ancestors in the tree; their attention is computed again
+    pub draft_masks: Vec<(Range<i64>, Range<i64>, Tensor)>,
+
+    // index into LoraSet.adapters -> rows of the tokens of sequences selecting it
+    pub lora_rows: HashMap<usize, Tensor>,
+
+    // the batch on the devices of tensor-parallel ranks 1.., with their KV cache shards
+    pub shards: Vec<BatchInfo>,
+    // with pipeline parallelism, the parts of the batch run through the stages;
+    // the batch itself is only used for the embeddings and the output layer
+    pub micro_batches: Vec<MicroBatch>,
+
+    // with heavy-hitter eviction, attention to the KV of the sequences
+    pub kv_scores: Vec<KvScore>,
+}
+
+/// Attention of the last query token of a sequence to its KV from earlier steps,
+/// summed over layers and heads; it decides which blocks heavy-hitter eviction
+/// keeps (see CacheConfig::kv_budget).
+pub struct KvScore {
+    pub seq_id: usize,
+    // row of the query token in BatchInfo::tokens
+    row: i64,
+    pub slots: Vec<usize>,
+    slot_mapping: Tensor,
+    // f32, [slots.len()]
+    pub scores: Option<Tensor>,
+}
+
+impl KvScore {
+    /// Add the attention of layer `layer`, with `q` [num_tokens, num_heads, head_dim].
+    pub fn add_layer(
+        &mut self,
+        config: &ModelConfig,
+        layer: usize,
+        q: &Tensor,
+        key_cache: &Tensor,
+        value_cache: &Tensor,
+    ) {
+        let shape = config.kv_shape(layer);
+        // with tensor parallelism, this device holds the first KV heads
+        let (_, num_kv_heads, _, _, _) = key_cache.size5().unwrap();
+        let mut k = Tensor::empty(
+            &[self.slots.len() as i64, num_kv_heads, shape.head_dim as i64],
+            (q.kind(), q.device()),
+        );
+        let mut v = k.empty_like();
+        kernels::gather_cached_kv(&mut k, &mut v, key_cache, value_cache, &self.slot_mapping);
+        let n_rep = (config.num_attention_heads / shape.num_kv_heads) as i64;
+        // [num_kv_heads, n_rep, head_dim] x [num_kv_heads, head_dim, num_slots]
+        let head_dim = shape.head_dim as i64;
+        let q = q.i((self.row, 0..num_kv_heads * n_rep, ..));
+        let q = q.reshape(&[num_kv_heads, n_rep, head_dim]);
+        let att = q.matmul(&k.permute(&[1, 2, 0])) * (1.0 / (head_dim as f64).sqrt());
+        let att = att.softmax(-1, Kind::Float);
+        let att = att.sum_dim_intlist([0i64, 1].as_slice(), false, Kind::Float);
+        self.scores = Some(match self.scores.take() {
+            Some(s) => s + att,
+            None => att,
+        });
+    }
+}
+
+/// Sequences of a batch going through the pipeline stages together.
+pub struct MicroBatch {
+    /// rows of their tokens in the batch
+    pub rows: Range<i64>,
+    /// the micro-batch on the first device of each stage, with the others in `shards`
+    pub stages: Vec<BatchInfo>,
 }
 
 impl BatchInfo {
@@ -66,9 +167,26 @@ impl BatchInfo {
         Tensor::write_safetensors(&tensors, filename).unwrap();
     }
 
+    pub fn device(&self) -> Device {
+        self.positions.device()
+    }
+
     pub fn extract_positions(&self, x: &Tensor) -> Tensor {
         x.i((&self.logit_idxs, ..))
     }
+
+    /// Replace token embeddings ([num_tokens, hidden_size]) with prompt embeddings, if any.
+    pub fn override_embeddings(&self, x: Tensor) -> Tensor {
+        match &self.embedding_overrides {
+            Some((idxs, values)) => x.index_copy(0, idxs, &values.to_kind(x.kind())),
+            None => x,
+        }
+    }
+
+    /// Whether the model should save final-layer hidden states in `hidden_states`.
+    pub fn wants_hidden_states(&self) -> bool {
+        self.medusa || !self.hidden_state_ranges.is_empty()
+    }
 }
 
 impl Debug for BatchInfo {
@@ -89,33 +207,144 @@ impl Debug for BatchInfo {
             .field("paged_max_context_len", &self.paged_max_context_len)
             .field("seqlen_multi", &self.seqlen_multi)
             .field("q_multi", &self.q_multi)
+            .field("sliding_window", &self.sliding_window)
+            .field("alibi", &self.alibi_slopes.is_some())
+            .field("num_shards", &(1 + self.shards.len()))
+            .field("num_micro_batches", &self.micro_batches.len())
             .finish()
     }
 }
 
-pub struct BatchInfoBuilder {
+/// Maps positions of a sequence to KV cache slots; implemented by BlockAllocator,
+/// and can be mocked to build batches without a GPU.
+pub trait KvSlotMapper {
+    fn get_slots(&self, seq: SeqId, len: usize) -> Vec<usize>;
+}
+
+impl KvSlotMapper for BlockAllocator {
+    fn get_slots(&self, seq: SeqId, len: usize) -> Vec<usize> {
+        self.get_block_idxes(seq, len)
+    }
+}
+
+/// Device-independent layout of a batch; BatchInfo is the same data moved to the device.
+#[derive(Debug, Clone, Default)]
+pub struct BatchLayout {
+    pub tokens: Vec<i32>,
+    pub positions: Vec<i64>,
+    pub seqlens_q: Vec<usize>, // lengths, not offsets
+    pub seqlens_k: Vec<usize>,
+    pub gather_mapping: Vec<i32>,
+    pub slot_mapping: Vec<i32>,
+    pub logit_idxs: Vec<i32>,
+    pub seq_id_to_idx: HashMap<usize, usize>,
+    pub paged_block_tables: Vec<Vec<i32>>,
+    pub paged_context_lens: Vec<i32>,
+    pub num_multitoken: usize,
+    pub first_single_token: usize,
+    pub emb_idxs: Vec<i64>,
+    pub emb_values: Vec<f32>,
+    pub hidden_state_ranges: HashMap<usize, Range<usize>>,
+    /// seq_id -> indices into logit_idxs of the tokens before the last one, one per
+    /// draft token; they follow the last tokens of all the entries
+    pub draft_logits: HashMap<usize, Range<usize>>,
+    /// (query rows, rows of their keys in gather_mapping, row-major mask of the keys
+    /// each query sees) of entries with a tree of draft tokens, other than a chain
+    pub draft_masks: Vec<(Range<usize>, Range<usize>, Vec<bool>)>,
+    /// LoRA adapter name -> rows of the tokens of sequences selecting it
+    pub adapter_rows: HashMap<String, Vec<i64>>,
+    /// (seq_id, row of its last query token, KV slots before the query)
+    pub kv_score_seqs: Vec<(usize, usize, Vec<usize>)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchLayoutConfig {
+    pub block_size: usize,
+    pub max_model_len: usize,
+    /// Sort single-token entries to the back, for the paged attention kernel.
+    pub paged_attn: bool,
+    /// Collect attention scores, for heavy-hitter eviction.
+    pub kv_scores: bool,
+}
+
+pub struct BatchLayoutBuilder {
     entries: Vec<BatchEntry>,
-    config: Arc<RllmConfig<TModel>>,
+    config: BatchLayoutConfig,
 }
 
 struct BatchEntry {
     seq_id: usize,
     query_pos_token: Vec<(usize, Token)>,
     kv_slots: Vec<usize>,
+    embeddings: Vec<EmbeddingSpan>,
+    hidden_states: bool,
+    adapter: Option<String>,
+    num_draft: usize,
+    draft_parents: Vec<usize>,
 }
 
-impl BatchInfoBuilder {
-    pub fn new(config: Arc<RllmConfig<TModel>>) -> Self {
+impl BatchLayoutBuilder {
+    pub fn new(config: BatchLayoutConfig) -> Self {
         Self {
             entries: Vec::new(),
             config,
         }
     }
 
+    /// Add a sequence computing `query_pos_token` (position, token) with `kv_slots`
+    /// covering the whole context, including the query (with a sliding window,
+    /// only the part still held in the KV cache).
+    /// `embeddings` override token embeddings at their positions.
+    pub fn add_entry(
+        &mut self,
+        seq_id: usize,
+        query_pos_token: Vec<(usize, Token)>,
+        kv_slots: Vec<usize>,
+        embeddings: Vec<EmbeddingSpan>,
+    ) -> &mut Self {
+        assert!(query_pos_token.len() > 0);
+        assert!(kv_slots.len() >= query_pos_token.len());
+        self.entries.push(BatchEntry {
+            seq_id,
+            query_pos_token,
+            kv_slots,
+            embeddings,
+            hidden_states: false,
+            adapter: None,
+            num_draft: 0,
+            draft_parents: Vec::new(),
+        });
+        self
+    }
+
+    /// Keep final-layer hidden states of the last added entry.
+    pub fn keep_hidden_states(&mut self) -> &mut Self {
+        self.entries.last_mut().unwrap().hidden_states = true;
+        self
+    }
+
+    /// Apply LoRA adapter `adapter` to the last added entry.
+    pub fn with_adapter(&mut self, adapter: Option<String>) -> &mut Self {
+        self.entries.last_mut().unwrap().adapter = adapter;
+        self
+    }
+
+    /// The last `num_draft` query tokens of the last added entry are draft tokens,
+    /// which need logits of the tokens before them; `parents` is their tree
+    /// (see Sequence::draft_parents), which sets their positions and attention.
+    pub fn with_draft(&mut self, num_draft: usize, parents: &[usize]) -> &mut Self {
+        let entry = self.entries.last_mut().unwrap();
+        assert!(entry.query_pos_token.len() > num_draft);
+        assert!(parents.len() == num_draft);
+        entry.num_draft = num_draft;
+        entry.draft_parents = parents.to_vec();
+        self
+    }
+
     pub fn sched_out(
         &mut self,
         sched_out: &mut SchedulerOutputs,
-        alloc: &BlockAllocator,
+        slots: &impl KvSlotMapper,
     ) -> &mut Self {
         assert!(sched_out.next_seq_groups.len() > 0);
         for sg in sched_out.next_seq_groups.iter_mut() {
@@ -124,33 +353,222 @@ impl BatchInfoBuilder {
                     continue;
                 }
 
-                let seq_len = seq.get_len();
-                let k_len = seq_len;
                 log::trace!("seq: {seq:?}");
-                let mut q_len = seq.get_len() - seq.num_kv_computed;
-                if q_len == 0 {
-                    // just re-compute the last token
-                    q_len = 1;
+                let positions = seq.step_positions();
+                let k_len = positions.end;
+                if k_len == seq.get_len() {
+                    sg.usage.gen_tokens += 1;
                 }
-                sg.usage.gen_tokens += 1;
-                sg.usage.prompt_tokens += q_len;
+                // accepted draft tokens are counted when sampling
+                sg.usage.prompt_tokens += positions.len() - seq.num_draft;
 
-                let off = k_len - q_len;
-                self.entries.push(BatchEntry {
-                    seq_id: seq.seq_id.to_num(),
-                    query_pos_token: (off..off + q_len)
-                        .map(|idx| (idx, seq.get_token(idx)))
-                        .collect(),
-                    kv_slots: alloc.get_block_idxes(seq.seq_id, k_len),
-                });
+                self.add_entry(
+                    seq.seq_id.to_num(),
+                    positions.map(|idx| (idx, seq.get_token(idx))).collect(),
+                    slots.get_slots(seq.seq_id, k_len),
+                    seq.embedding_overrides(),
+                );
+                if sg.sampling_params.hidden_states.is_some() {
+                    self.keep_hidden_states();
+                }
+                self.with_adapter(sg.sampling_params.adapter.clone())
+                    .with_draft(seq.num_draft, &seq.draft_parents);
 
-                seq.sync_computed_kv();
+                seq.sync_computed_kv_to(k_len);
             }
         }
 
         self
     }
 
+    pub fn build(&mut self) -> BatchLayout {
+        let num_multitoken = self.sort_entries();
+        let r = Self::layout_of(&self.config, &self.entries, num_multitoken);
+        self.entries.clear();
+        r
+    }
+
+    /// Like build(), also splitting the batch into up to `num_parts` parts with about
+    /// the same number of tokens; returns their rows in the batch and layouts.
+    pub fn build_split(
+        &mut self,
+        num_parts: usize,
+    ) -> (BatchLayout, Vec<(Range<i64>, BatchLayout)>) {
+        let num_multitoken = self.sort_entries();
+        let r = Self::layout_of(&self.config, &self.entries, num_multitoken);
+        let num_tokens = r.tokens.len();
+        let mut parts = Vec::new();
+        let (mut start, mut start_row, mut row) = (0, 0, 0);
+        for (idx, e) in self.entries.iter().enumerate() {
+            row += e.query_pos_token.len();
+            let last = idx + 1 == self.entries.len();
+            // end the part once the rows so far reach its share of the batch
+            if last || row * num_parts >= num_tokens * (parts.len() + 1) {
+                let entries = &self.entries[start..idx + 1];
+                let multi = num_multitoken.saturating_sub(start).min(entries.len());
+                let layout = Self::layout_of(&self.config, entries, multi);
+                parts.push((start_row as i64..row as i64, layout));
+                (start, start_row) = (idx + 1, row);
+            }
+        }
+        self.entries.clear();
+        (r, parts)
+    }
+
+    /// With paged_attn, sort single-token entries to the back; returns the number
+    /// of the other entries.
+    fn sort_entries(&mut self) -> usize {
+        if self.config.paged_attn {
+            let (single, multi) = std::mem::take(&mut self.entries)
+                .into_iter()
+                .partition::<Vec<_>, _>(|e| e.query_pos_token.len() == 1);
+            let multi_len = multi.len();
+            self.entries = multi;
+            self.entries.extend(single);
+            multi_len
+        } else {
+            self.entries.len()
+        }
+    }
+
+    fn layout_of(
+        config: &BatchLayoutConfig,
+        entries: &[BatchEntry],
+        num_multitoken: usize,
+    ) -> BatchLayout {
+        let mut r = BatchLayout::default();
+        r.num_multitoken = num_multitoken;
+
+        let max_seq = config.max_model_len;
+        let mut idx = 0;
+        let mut draft_rows = Vec::new();
+        for e in entries {
+            r.seq_id_to_idx.insert(e.seq_id, idx);
+            let query = &e.query_pos_token;
+            let off = e.kv_slots.len() - query.len();
+            let start = r.tokens.len();
+            // draft tokens are at the position after their parent
+            let draft_start = query.len() - e.num_draft;
+            let depths = draft_depths(&e.draft_parents);
+            for (qidx, (tpos, token)) in query.iter().enumerate() {
+                assert!(*tpos < max_seq);
+                if let Some(emb) = e.embeddings.iter().find_map(|span| span.get(*tpos)) {
+                    r.emb_idxs.push(r.tokens.len() as i64);
+                    r.emb_values.extend_from_slice(emb);
+                }
+                if qidx >= draft_start {
+                    let root_pos = query[draft_start - 1].0;
+                    r.positions
+                        .push((root_pos + depths[qidx - draft_start]) as i64);
+                } else {
+                    r.positions.push(*tpos as i64);
+                }
+                r.tokens.push(*token as i32);
+                r.slot_mapping.push(e.kv_slots[off + qidx] as i32);
+            }
+            r.logit_idxs.push((r.tokens.len() - 1) as i32);
+            if e.num_draft > 0 {
+                let last = r.tokens.len() - 1;
+                draft_rows.push((e.seq_id, last - e.num_draft..last));
+            }
+            if config.kv_scores && off > 0 {
+                r.kv_score_seqs
+                    .push((e.seq_id, r.tokens.len() - 1, e.kv_slots[..off].to_vec()));
+            }
+            if e.hidden_states {
+                r.hidden_state_ranges
+                    .insert(e.seq_id, start..r.tokens.len());
+            }
+            if let Some(adapter) = &e.adapter {
+                r.adapter_rows
+                    .entry(adapter.clone())
+                    .or_default()
+                    .extend(start as i64..r.tokens.len() as i64);
+            }
+            let is_chain = e.draft_parents.iter().enumerate().all(|(i, &p)| p == i);
+            if !is_chain {
+                // the draft tokens have more than one query token
+                assert!(idx < r.num_multitoken);
+                let (len_q, len_k) = (query.len(), e.kv_slots.len());
+                let mut mask = vec![false; len_q * len_k];
+                for (qidx, keys) in mask.chunks_mut(len_k).enumerate() {
+                    if qidx < draft_start {
+                        keys[..off + qidx + 1].fill(true);
+                        continue;
+                    }
+                    keys[..off + draft_start].fill(true);
+                    let mut node = qidx - draft_start + 1;
+                    while node > 0 {
+                        keys[off + draft_start + node - 1] = true;
+                        node = e.draft_parents[node - 1];
+                    }
+                }
+                let k_start = r.gather_mapping.len();
+                r.draft_masks
+                    .push((start..r.tokens.len(), k_start..k_start + len_k, mask));
+            }
+            if idx < r.num_multitoken {
+                for slot in e.kv_slots.iter() {
+                    r.gather_mapping.push(*slot as i32);
+                }
+                r.first_single_token = r.tokens.len();
+                r.seqlens_q.push(query.len());
+                r.seqlens_k.push(e.kv_slots.len());
+            } else {
+                let ctx_size = e.kv_slots.len();
+                r.paged_context_lens.push(ctx_size as i32);
+                let bl_size = config.block_size;
+                r.paged_block_tables.push(
+                    (0..ctx_size)
+                        .step_by(bl_size)
+                        .map(|idx| {
+                            let bl = e.kv_slots[idx];
+                            assert!(bl % bl_size == 0);
+                            (bl / bl_size) as i32
+                        })
+                        .collect(),
+                );
+            }
+            idx += 1;
+        }
+
+        assert!(r.seqlens_q.len() + r.paged_context_lens.len() > 0);
+
+        for (seq_id, rows) in draft_rows {
+            let start = r.logit_idxs.len();
+            r.logit_idxs.extend(rows.map(|row| row as i32));
+            r.draft_logits.insert(seq_id, start..r.logit_idxs.len());
+        }
+
+        r
+    }
+}
+
+pub struct BatchInfoBuilder {
+    layout: BatchLayoutBuilder,
+    config: Arc<RllmConfig<TModel>>,
+}
+
+impl BatchInfoBuilder {
+    pub fn new(config: Arc<RllmConfig<TModel>>) -> Self {
+        let layout = BatchLayoutBuilder::new(BatchLayoutConfig {
+            block_size: config.model.cache.block_size,
+            max_model_len: config.scheduler.max_model_len,
+            paged_attn: config.model.cache.paged_attn_kernel_v > 0,
+            kv_scores: config.model.cache.kv_budget.is_some(),
+        });
+        Self { layout, config }
+    }
+
+    pub fn sched_out(
+        &mut self,
+        sched_out: &mut SchedulerOutputs,
+        alloc: &BlockAllocator,
+    ) -> &mut Self {
+        self.layout.sched_out(sched_out, alloc);
+        self
+    }
+
     pub fn profile_run(&mut self) -> BatchInfo {
         let sch_cfg = &self.config.clone().scheduler;
         let seq_len = sch_cfg.max_model_len;
@@ -162,22 +580,24 @@ impl BatchInfoBuilder {
         let seq_id = 424242;
 
         for idx in 0..max_num_seqs {
-            self.entries.push(BatchEntry {
+            self.layout.add_entry(
                 seq_id,
-                query_pos_token: (0..1).map(|_| (idx, fake_token)).collect(),
-                kv_slots: (0..avg_len).map(|_| fake_slot).collect(),
-            });
+                (0..1).map(|_| (idx, fake_token)).collect(),
+                (0..avg_len).map(|_| fake_slot).collect(),
+                Vec::new(),
+            );
         }
 
         let mut left = sch_cfg.max_num_batched_tokens - max_num_seqs;
         while left > 0 {
             let seq_len = std::cmp::min(seq_len, left);
             left -= seq_len;
-            self.entries.push(BatchEntry {
+            self.layout.add_entry(
                 seq_id,
-                query_pos_token: (0..seq_len).map(|idx| (idx, fake_token)).collect(),
-                kv_slots: (0..seq_len).map(|_| fake_slot).collect(),
-            });
+                (0..seq_len).map(|idx| (idx, fake_token)).collect(),
+                (0..seq_len).map(|_| fake_slot).collect(),
+                Vec::new(),
+            );
         }
 
         let res = self.fake_finish();
@@ -188,90 +608,213 @@ impl BatchInfoBuilder {
     }
 
     fn fake_finish(&mut self) -> BatchInfo {
-        let (k, v) = CacheEngine::alloc_gpu_cache_layer(&self.config, 1);
-        let kv_cache = Box::new(FakeKVCache { k, v });
+        let devices = self
+            .config
+            .get_kv_devices()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, device)| {
+                let layers = self.config.get_kv_device_layers(idx);
+                let first = layers.start;
+                let layers = layers
+                    .map(|layer| CacheEngine::alloc_gpu_cache_layer(&self.config, layer, 1, device))
+                    .collect();
+                (first, layers)
+            })
+            .collect();
+        let kv_cache = Box::new(FakeKVCache {
+            devices: Arc::new(devices),
+            idx: 0,
+        });
         self.finish(0, kv_cache)
     }
 
     pub fn finish(&mut self, step_no: usize, kv_cache: Box<dyn CacheIface>) -> BatchInfo {
-        let mut positions: Vec<i64> = Vec::new();
-        let mut tokens: Vec<i32> = Vec::new();
-        let mut logit_idxs: Vec<i32> = Vec::new();
-        let mut seqlens_q: Vec<usize> = Vec::new();
-        let mut seqlens_k: Vec<usize> = Vec::new();
-        let mut gather_mapping: Vec<i32> = Vec::new();
-        let mut slot_mapping: Vec<i32> = Vec::new();
-        let mut seq_id_to_idx: HashMap<usize, usize> = HashMap::default();
-
-        let mut paged_block_tables: Vec<Vec<i32>> = Vec::new();
-        let mut paged_context_lens: Vec<i32> = Vec::new();
-
-        let num_multitoken = if self.config.model.cache.paged_attn_kernel_v > 0 {
-            // sort single-token entries to the back
-            let (single, multi) = std::mem::take(&mut self.entries)
-                .into_iter()
-                .partition::<Vec<_>, _>(|e| e.query_pos_token.len() == 1);
-            let multi_len = multi.len();
-            self.entries = multi;
-            self.entries.extend(single);
-            multi_len
-        } else {
-            self.entries.len()
-        };
+        let parallel = &self.config.parallel;
+        if parallel.pipeline_parallel_size == 1 {
+            let layout = self.layout.build();
+            return BatchInfo::from_layout(&self.config, layout, step_no, kv_cache)
+                .with_shards(&self.config);
+        }
+        let (layout, parts) = self.layout.build_split(parallel.num_micro_batches);
+        let mut info = BatchInfo::from_layout(&self.config, layout, step_no, kv_cache);
+        info.micro_batches = parts
+            .into_iter()
+            .map(|(rows, layout)| {
+                let kv_cache = info.kv_cache.shard(0);
+                let part = BatchInfo::from_layout(&self.config, layout, step_no, kv_cache);
+                MicroBatch {
+                    rows,
+                    stages: (0..parallel.pipeline_parallel_size)
+                        .map(|stage| part.to_stage(&self.config, stage))
+                        .collect(),
+                }
+            })
+            .collect();
+        info
+    }
 
-        let mut first_single_token = 0;
+    /// Build the batch of the step after `sched_out`, assuming each of its sequences
+    /// generates one token; None unless all of them are decoding.
+    /// Tokens are only known after sampling, and are filled in by NextBatch::commit().
+    pub fn next_batch(
+        &mut self,
+        sched_out: &SchedulerOutputs,
+        alloc: &BlockAllocator,
+        kv_cache: Box<dyn CacheIface>,
+    ) -> Option<NextBatch> {
+        // the single-token entries have to go to the paged attention kernel;
+        // micro-batches are not updated by NextBatch::commit()
+        if !self.layout.config.paged_attn || self.config.parallel.pipeline_parallel_size > 1 {
+            return None;
+        }
+        let mut seqs = HashMap::default();
+        for sg in sched_out.next_seq_groups.iter() {
+            if sg.sampling_params.hidden_states.is_some() {
+                return None;
+            }
+            for seq in sg.get_seqs(Some(SchedulingPhase::Running)) {
+                let pos = seq.get_len();
+                if seq.num_kv_computed != pos || pos + 1 > self.layout.config.max_model_len {
+                    return None;
+                }
+                let mut kv_slots = alloc.get_block_idxes(seq.seq_id, pos);
+                // at a block boundary, the block is only allocated by the scheduler;
+                // slot 0 stands in for it
+                let slot = alloc.get_slot(seq.seq_id, pos).unwrap_or(0);
+                kv_slots.push(slot);
+                self.layout
+                    .add_entry(seq.seq_id.to_num(), vec![(pos, 0)], kv_slots, Vec::new())
+                    .with_adapter(sg.sampling_params.adapter.clone());
+                let dropped = alloc.num_dropped_blocks(seq.seq_id);
+                seqs.insert(seq.seq_id.to_num(), (pos, slot, dropped));
+            }
+        }
+        if seqs.is_empty() {
+            return None;
+        }
+        Some(NextBatch {
+            info: self.finish(0, kv_cache),
+            seqs,
+        })
+    }
+}
 
-        let max_seq = self.config.scheduler.max_model_len;
-        let mut idx = 0;
-        for e in &self.entries {
-            seq_id_to_idx.insert(e.seq_id, idx);
-            let query = &e.query_pos_token;
-            let off = e.kv_slots.len() - query.len();
-            for (qidx, (tpos, token)) in query.iter().enumerate() {
-                assert!(*tpos < max_seq);
-                positions.push(*tpos as i64);
-                tokens.push(*token as i32);
-                slot_mapping.push(e.kv_slots[off + qidx] as i32);
+/// Batch built by BatchInfoBuilder::next_batch() while the forward pass
+/// of the previous step was running.
+pub struct NextBatch {
+    info: BatchInfo,
+    /// seq_id -> (position of the new token, its speculated KV slot,
+    /// number of blocks dropped out of the sliding window)
+    seqs: HashMap<usize, (usize, usize, usize)>,
+}
+
+impl NextBatch {
+    /// Whether the scheduler decided to run exactly the speculated batch.
+    pub fn matches(&self, sched_out: &SchedulerOutputs, alloc: &BlockAllocator) -> bool {
+        let mut num_seqs = 0;
+        for sg in sched_out.next_seq_groups.iter() {
+            if sg.sampling_params.hidden_states.is_some() {
+                return false;
             }
-            logit_idxs.push((tokens.len() - 1) as i32);
-            if idx < num_multitoken {
-                for slot in e.kv_slots.iter() {
-                    gather_mapping.push(*slot as i32);
+            for seq in sg.get_seqs(Some(SchedulingPhase::Running)) {
+                num_seqs += 1;
+                match self.seqs.get(&seq.seq_id.to_num()) {
+                    Some((pos, _, dropped))
+                        if seq.get_len() == pos + 1
+                            && seq.step_positions() == (*pos..pos + 1)
+                            && alloc.num_dropped_blocks(seq.seq_id) == *dropped => {}
+                    _ => return false,
                 }
-                first_single_token = tokens.len();
-                seqlens_q.push(query.len());
-                seqlens_k.push(e.kv_slots.len());
-            } else {
-                let ctx_size = e.kv_slots.len();
-                paged_context_lens.push(ctx_size as i32);
-                let bl_size = self.config.model.cache.block_size;
-                paged_block_tables.push(
-                    (0..ctx_size)
-                        .step_by(bl_size)
-                        .map(|idx| {
-                            let bl = e.kv_slots[idx];
-                            assert!(bl % bl_size == 0);
-                            (bl / bl_size) as i32
-                        })
-                        .collect(),
-                );
             }
-            idx += 1;
+        }
+        num_seqs == self.seqs.len()
+    }
+
+    /// Fill in the sampled tokens, and fix the KV slots of tokens whose blocks
+    /// were allocated (or copied on write) by the scheduler.
+    pub fn commit(
+        self,
+        sched_out: &mut SchedulerOutputs,
+        alloc: &BlockAllocator,
+        step_no: usize,
+        kv_cache: Box<dyn CacheIface>,
+    ) -> BatchInfo {
+        let mut info = self.info;
+        let block_size = info.paged_block_size;
+        let mut tokens = vec![0i32; self.seqs.len()];
+        let mut rows = Vec::new();
+        let mut cols = Vec::new();
+        let mut slots = Vec::new();
+        let mut blocks = Vec::new();
+        for sg in sched_out.next_seq_groups.iter_mut() {
+            for seq in sg.seqs.iter_mut() {
+                if seq.sched_phase != SchedulingPhase::Running {
+                    continue;
+                }
+                let seq_id = seq.seq_id.to_num();
+                let (pos, slot, dropped) = self.seqs[&seq_id];
+                let idx = info.seq_id_to_idx[&seq_id];
+                tokens[idx] = seq.get_token(pos) as i32;
+                let actual = alloc.get_slot(seq.seq_id, pos).unwrap();
+                if actual != slot {
+                    rows.push(idx as i64);
+                    cols.push((pos / block_size - dropped) as i64);
+                    slots.push(actual as i32);
+                    blocks.push((actual / block_size) as i32);
+                }
+                sg.usage.gen_tokens += 1;
+                sg.usage.prompt_tokens += 1;
+                seq.sync_computed_kv_to(pos + 1);
+            }
         }
 
-        assert!(seqlens_q.len() + paged_context_lens.len() > 0);
+        let device = info.tokens.device();
+        info.tokens = Tensor::from_slice(&tokens).to(device);
+        if rows.len() > 0 {
+            let rows = Tensor::from_slice(&rows).to(device);
+            let cols = Tensor::from_slice(&cols).to(device);
+            let slots = Tensor::from_slice(&slots).to(device);
+            let blocks = Tensor::from_slice(&blocks).to(device);
+            let _ = info.slot_mapping.index_put_(&[Some(&rows)], &slots, false);
+            let _ = info
+                .paged_block_tables
+                .index_put_(&[Some(&rows), Some(&cols)], &blocks, false);
+        }
+        for (idx, shard) in info.shards.iter_mut().enumerate() {
+            let device = shard.device();
+            shard.tokens = info.tokens.to(device);
+            shard.slot_mapping = info.slot_mapping.to(device);
+            shard.paged_block_tables = info.paged_block_tables.to(device);
+            shard.step_no = step_no;
+            shard.kv_cache = kv_cache.shard(idx + 1);
+        }
+        info.step_no = step_no;
+        info.kv_cache = kv_cache;
+        info
+    }
+}
 
-        let device = self.config.model.device;
-        let (max_seqlen_q, seqlens_q) = to_offsets(seqlens_q.into_iter(), device);
-        let (max_seqlen_k, seqlens_k) = to_offsets(seqlens_k.into_iter(), device);
+impl BatchInfo {
+    pub fn from_layout(
+        config: &RllmConfig<TModel>,
+        layout: BatchLayout,
+        step_no: usize,
+        kv_cache: Box<dyn CacheIface>,
+    ) -> BatchInfo {
+        let device = config.model.device;
+        let (max_seqlen_q, seqlens_q) = to_offsets(layout.seqlens_q.into_iter(), device);
+        let (max_seqlen_k, seqlens_k) = to_offsets(layout.seqlens_k.into_iter(), device);
 
         // TODO positions, tokens should be padded to 8? see worker.py, search for multiple_of=8
-        let positions = Tensor::from_slice(positions.as_slice()).to(device);
-        let tokens = Tensor::from_slice(tokens.as_slice()).to(device);
-        let slot_mapping = Tensor::from_slice(slot_mapping.as_slice()).to(device);
-        let gather_mapping = Tensor::from_slice(gather_mapping.as_slice()).to(device);
-        let logit_idxs = Tensor::from_slice(logit_idxs.as_slice()).to(device);
+        let positions = Tensor::from_slice(layout.positions.as_slice()).to(device);
+        let tokens = Tensor::from_slice(layout.tokens.as_slice()).to(device);
+        let slot_mapping = Tensor::from_slice(layout.slot_mapping.as_slice()).to(device);
+        let gather_mapping = Tensor::from_slice(layout.gather_mapping.as_slice()).to(device);
+        let logit_idxs = Tensor::from_slice(layout.logit_idxs.as_slice()).to(device);
 
+        let paged_context_lens = layout.paged_context_lens;
+        let paged_block_tables = layout.paged_block_tables;
         let num_paged = paged_context_lens.len() as i64;
         let paged_max_context_len = *paged_context_lens.iter().max().unwrap_or(&0) as usize;
         let paged_block_tables_max_len = paged_block_tables
@@ -291,6 +834,43 @@ impl BatchInfoBuilder {
             .reshape(&[num_paged, paged_block_tables_max_len as i64]);
         let paged_context_lens = Tensor::from_slice(paged_context_lens.as_slice()).to(device);
 
+        let kv_scores = layout
+            .kv_score_seqs
+            .into_iter()
+            .map(|(seq_id, row, slots)| {
+                let mapping = slots.iter().map(|&s| s as i32).collect::<Vec<_>>();
+                KvScore {
+                    seq_id,
+                    row: row as i64,
+                    slot_mapping: Tensor::from_slice(&mapping).to(device),
+                    slots,
+                    scores: None,
+                }
+            })
+            .collect();
+
+        let lora_rows = layout
+            .adapter_rows
+            .into_iter()
+            .map(|(name, rows)| {
+                // checked against the loaded adapters when the request is added
+                let idx = config.model.lora.find(&name).unwrap();
+                (idx, Tensor::from_slice(&rows).to(device))
+            })
+            .collect();
+
+        let embedding_overrides = if layout.emb_idxs.is_empty() {
+            None
+        } else {
+            let num_emb = layout.emb_idxs.len() as i64;
+            Some((
+                Tensor::from_slice(layout.emb_idxs.as_slice()).to(device),
+                Tensor::from_slice(layout.emb_values.as_slice())
+                    .to(device)
+                    .reshape(&[num_emb, -1]),
+            ))
+        };
+
         BatchInfo {
             tokens,
             positions,
@@ -299,29 +879,287 @@ impl BatchInfoBuilder {
             logit_idxs,
             slot_mapping,
             gather_mapping,
-            seqlen_multi: num_multitoken as i64,
-            q_multi: first_single_token as i64,
+            seqlen_multi: layout.num_multitoken as i64,
+            q_multi: layout.first_single_token as i64,
             max_seqlen_q,
             max_seqlen_k,
             kv_cache,
-            seq_id_to_idx,
+            seq_id_to_idx: layout.seq_id_to_idx,
             infer_log: Mutex::new(Vec::new()),
             step_no,
-            paged_block_size: self.config.model.cache.block_size,
+            paged_block_size: config.model.cache.block_size,
             paged_max_context_len,
+            sliding_window: match config.model.cache.attention_sinks {
+                0 => config.model.sliding_window,
+                _ => None,
+            },
+            alibi_slopes: config.model.alibi_bias_max.map(|bias_max| {
+                let slopes = alibi_slopes(config.model.num_attention_heads, bias_max);
+                Tensor::from_slice(&slopes).to(device)
+            }),
             paged_block_tables,
             paged_context_lens,
+            embedding_overrides,
+            hidden_state_ranges: layout.hidden_state_ranges,
+            hidden_states: None,
+            medusa: config.model.medusa.is_some(),
+            draft_logits: layout.draft_logits,
+            draft_masks: layout
+                .draft_masks
+                .into_iter()
+                .map(|(rows, keys, mask)| {
+                    let len_k = keys.len() as i64;
+                    (
+                        rows.start as i64..rows.end as i64,
+                        keys.start as i64..keys.end as i64,
+                        Tensor::from_slice(&mask).to(device).reshape(&[-1, len_k]),
+                    )
+                })
+                .collect(),
+            lora_rows,
+            shards: Vec::new(),
+            micro_batches: Vec::new(),
+            kv_scores,
+        }
+    }
+
+    /// Copy the batch to the other KV devices, without pipeline parallelism.
+    /// Blocks have the same numbers on all devices, so only the cache differs.
+    fn with_shards(mut self, config: &RllmConfig<TModel>) -> Self {
+        let devices = config.get_kv_devices();
+        self.shards = devices[1..]
+            .iter()
+            .enumerate()
+            .map(|(idx, &device)| self.to_shard(device, self.kv_cache.shard(idx + 1)))
+            .collect();
+        self
+    }
+
+    /// Copy the batch to the devices of pipeline stage `stage`.
+    fn to_stage(&self, config: &RllmConfig<TModel>, stage: usize) -> BatchInfo {
+        let devices = config.get_kv_devices();
+        let first = stage * config.parallel.tensor_parallel_size;
+        let ranks = first..first + config.parallel.tensor_parallel_size;
+        let mut info = self.to_shard(devices[first], self.kv_cache.shard(first));
+        info.shards = ranks
+            .skip(1)
+            .map(|idx| self.to_shard(devices[idx], self.kv_cache.shard(idx)))
+            .collect();
+        info
+    }
+
+    fn to_shard(&self, device: Device, kv_cache: Box<dyn CacheIface>) -> BatchInfo {
+        BatchInfo {
+            tokens: self.tokens.to(device),
+            positions: self.positions.to(device),
+            seqlens_q: self.seqlens_q.to(device),
+            seqlens_k: self.seqlens_k.to(device),
+            logit_idxs: self.logit_idxs.to(device),
+            slot_mapping: self.slot_mapping.to(device),
+            gather_mapping: self.gather_mapping.to(device),
+            seqlen_multi: self.seqlen_multi,
+            q_multi: self.q_multi,
+            max_seqlen_q: self.max_seqlen_q,
+            max_seqlen_k: self.max_seqlen_k,
+            kv_cache,
+            seq_id_to_idx: self.seq_id_to_idx.clone(),
+            infer_log: Mutex::new(Vec::new()),
+            step_no: self.step_no,
+            paged_block_size: self.paged_block_size,
+            paged_max_context_len: self.paged_max_context_len,
+            sliding_window: self.sliding_window,
+            // tensor parallelism is only supported for models without ALiBi
+            alibi_slopes: None,
+            paged_block_tables: self.paged_block_tables.to(device),
+            paged_context_lens: self.paged_context_lens.to(device),
+            // the embeddings, hidden states and LoRA are on the model device
+            embedding_overrides: None,
+            hidden_state_ranges: HashMap::default(),
+            hidden_states: None,
+            medusa: false,
+            draft_logits: HashMap::default(),
+            // the attention heads of the shard are computed again as well
+            draft_masks: self
+                .draft_masks
+                .iter()
+                .map(|(rows, keys, mask)| (rows.clone(), keys.clone(), mask.to(device)))
+                .collect(),
+            lora_rows: HashMap::default(),
+            shards: Vec::new(),
+            micro_batches: Vec::new(),
+            kv_scores: Vec::new(),
         }
     }
 }
 
+/// A single block per layer, which all the fake slots point to.
 struct FakeKVCache {
-    k: Tensor,
-    v: Tensor,
+    // for each KV device, its first layer and blocks of its layers
+    devices: Arc<Vec<(usize, Vec<(Tensor, Tensor)>)>>,
+    idx: usize,
 }
 
 impl CacheIface for FakeKVCache {
-    fn get(&self, _layer_no: usize) -> (Tensor, Tensor) {
-        (self.k.shallow_clone(), self.v.shallow_clone())
+    fn get(&self, layer_no: usize) -> (Tensor, Tensor) {
+        let (first, layers) = &self.devices[self.idx];
+        let (k, v) = &layers[layer_no - first];
+        (k.shallow_clone(), v.shallow_clone())
+    }
+
+    fn shard(&self, idx: usize) -> Box<dyn CacheIface> {
+        Box::new(FakeKVCache {
+            devices: self.devices.clone(),
+            idx,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 4;
+
+    fn builder(paged_attn: bool) -> BatchLayoutBuilder {
+        BatchLayoutBuilder::new(BatchLayoutConfig {
+            block_size: BLOCK_SIZE,
+            max_model_len: 64,
+            paged_attn,
+            kv_scores: false,
+        })
+    }
+
+    fn query(positions: Range<usize>) -> Vec<(usize, Token)> {
+        positions.map(|p| (p, 100 + p as Token)).collect()
+    }
+
+    /// KV slots of the first `len` positions of a sequence holding `blocks`.
+    fn slots(blocks: &[usize], len: usize) -> Vec<usize> {
+        (0..len)
+            .map(|p| blocks[p / BLOCK_SIZE] * BLOCK_SIZE + p % BLOCK_SIZE)
+            .collect()
+    }
+
+    #[test]
+    fn prefill_and_decode() {
+        let mut b = builder(false);
+        b.add_entry(1, query(0..3), slots(&[2], 3), vec![]);
+        b.add_entry(2, query(5..6), slots(&[0, 5], 6), vec![]);
+        let r = b.build();
+        assert_eq!(r.tokens, vec![100, 101, 102, 105]);
+        assert_eq!(r.positions, vec![0, 1, 2, 5]);
+        assert_eq!(r.slot_mapping, vec![8, 9, 10, 21]);
+        assert_eq!(r.logit_idxs, vec![2, 3]);
+        assert_eq!(r.seqlens_q, vec![3, 1]);
+        assert_eq!(r.seqlens_k, vec![3, 6]);
+        assert_eq!(r.gather_mapping, vec![8, 9, 10, 0, 1, 2, 3, 20, 21]);
+        assert_eq!(r.seq_id_to_idx[&1], 0);
+        assert_eq!(r.seq_id_to_idx[&2], 1);
+        assert_eq!(r.num_multitoken, 2);
+        assert!(r.paged_context_lens.is_empty());
+    }
+
+    #[test]
+    fn chunked_prefill() {
+        let mut b = builder(false);
+        // second chunk of an 8-token prompt, the first 4 tokens already computed
+        b.add_entry(7, query(4..8), slots(&[3, 1], 8), vec![]);
+        let r = b.build();
+        assert_eq!(r.positions, vec![4, 5, 6, 7]);
+        assert_eq!(r.slot_mapping, vec![4, 5, 6, 7]);
+        assert_eq!(r.seqlens_q, vec![4]);
+        assert_eq!(r.seqlens_k, vec![8]);
+        assert_eq!(r.gather_mapping, vec![12, 13, 14, 15, 4, 5, 6, 7]);
+        assert_eq!(r.logit_idxs, vec![3]);
+    }
+
+    #[test]
+    fn paged_attn() {
+        let mut b = builder(true);
+        b.add_entry(1, query(5..6), slots(&[0, 5], 6), vec![]);
+        b.add_entry(2, query(0..2), slots(&[3], 2), vec![]);
+        let r = b.build();
+        // single-token entries go to the back
+        assert_eq!(r.num_multitoken, 1);
+        assert_eq!(r.seq_id_to_idx[&2], 0);
+        assert_eq!(r.seq_id_to_idx[&1], 1);
+        assert_eq!(r.positions, vec![0, 1, 5]);
+        assert_eq!(r.slot_mapping, vec![12, 13, 21]);
+        assert_eq!(r.first_single_token, 2);
+        assert_eq!(r.seqlens_q, vec![2]);
+        assert_eq!(r.gather_mapping, vec![12, 13]);
+        assert_eq!(r.paged_context_lens, vec![6]);
+        assert_eq!(r.paged_block_tables, vec![vec![0, 5]]);
+    }
+
+    #[test]
+    fn draft_rows() {
+        let mut b = builder(false);
+        // the last sampled token, followed by 2 draft tokens
+        b.add_entry(1, query(10..13), slots(&[0, 1, 2, 3], 13), vec![])
+            .with_draft(2, &[0, 1]);
+        b.add_entry(2, query(3..4), slots(&[4], 4), vec![]);
+        let r = b.build();
+        // draft rows follow the last tokens of all the entries
+        assert_eq!(r.logit_idxs, vec![2, 3, 0, 1]);
+        assert_eq!(r.draft_logits[&1], 2..4);
+        assert!(!r.draft_logits.contains_key(&2));
+        // a chain attends causally
+        assert!(r.draft_masks.is_empty());
+    }
+
+    #[test]
+    fn draft_tree() {
+        let mut b = builder(false);
+        b.add_entry(1, query(0..2), slots(&[0], 2), vec![]);
+        // a token to compute again, the last sampled token, and a draft tree
+        // of 2 candidates for the next token, the first followed by 1 token
+        b.add_entry(2, query(5..10), slots(&[1, 2, 3], 10), vec![])
+            .with_draft(3, &[0, 1, 0]);
+        let r = b.build();
+        assert_eq!(r.positions, vec![0, 1, 5, 6, 7, 8, 7]);
+        assert_eq!(r.logit_idxs, vec![1, 6, 3, 4, 5]);
+        let (rows, keys, mask) = &r.draft_masks[0];
+        assert_eq!(*rows, 2..7);
+        assert_eq!(*keys, 2..12);
+        let seen = mask
+            .chunks(10)
+            .map(|keys| keys.iter().map(|&k| k as u8).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(seen[0], vec![1, 1, 1, 1, 1, 1, 0, 0, 0, 0]);
+        assert_eq!(seen[1], vec![1, 1, 1, 1, 1, 1, 1, 0, 0, 0]);
+        // each draft token sees its ancestors, not the other candidates
+        assert_eq!(seen[2], vec![1, 1, 1, 1, 1, 1, 1, 1, 0, 0]);
+        assert_eq!(seen[3], vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+        assert_eq!(seen[4], vec![1, 1, 1, 1, 1, 1, 1, 0, 0, 1]);
+    }
+
+    #[test]
+    fn hidden_states_and_adapters() {
+        let mut b = builder(false);
+        b.add_entry(1, query(0..2), slots(&[0], 2), vec![])
+            .with_adapter(Some("a".to_string()));
+        b.add_entry(2, query(0..3), slots(&[1], 3), vec![])
+            .keep_hidden_states()
+            .with_adapter(Some("a".to_string()));
+        let r = b.build();
+        assert_eq!(r.hidden_state_ranges[&2], 2..5);
+        assert!(!r.hidden_state_ranges.contains_key(&1));
+        assert_eq!(r.adapter_rows["a"], vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn split() {
+        let mut b = builder(false);
+        for seq_id in 0..3 {
+            b.add_entry(seq_id, query(0..4), slots(&[seq_id], 4), vec![]);
+        }
+        let (r, parts) = b.build_split(2);
+        assert_eq!(r.tokens.len(), 12);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].0, 0..8);
+        assert_eq!(parts[1].0, 8..12);
+        assert_eq!(parts[1].1.slot_mapping, vec![8, 9, 10, 11]);
+        assert_eq!(parts[1].1.seq_id_to_idx[&2], 0);
     }
 }
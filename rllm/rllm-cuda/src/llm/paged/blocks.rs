@@ -1,12 +1,16 @@
-use super::super::tmodel::TModel;
+use super::super::{config::TchRllmConfig, tmodel::TModel};
 use super::cache_engine::CacheEngine;
+use super::radix::PrefixTree;
 use rllm::{
     config::RllmConfig,
-    seq::{SchedulingPhase, Sequence, SequenceGroup},
-    BlockLocation, CacheSize, HashMap, SchedulerOutputs, SeqId, SequenceManager,
-    TBlockSpaceManager,
+    seq::{SchedulingPhase, Sequence, SequenceGroup, Token},
+    BlockLocation, BlockPoolStats, CacheSize, HashMap, HashSet, SchedulerOutputs, SeqId,
+    SequenceManager, TBlockSpaceManager,
 };
 use std::{
+    backtrace::Backtrace,
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
     sync::{Arc, Mutex},
     vec::Vec,
 };
@@ -15,11 +19,41 @@ use std::{
 #[derive(Debug)]
 pub struct PhysicalTokenBlock {
     ref_count: usize,
+    /// Set for full blocks in the prefix cache; see hash_block().
+    hash: Option<u64>,
+    /// Attention its tokens got since it was allocated; see kv_budget.
+    score: f32,
 }
 
 impl PhysicalTokenBlock {
     pub fn new(_device: BlockLocation, _block_number: usize, _block_size: usize) -> Self {
-        Self { ref_count: 0 }
+        Self {
+            ref_count: 0,
+            hash: None,
+            score: 0.0,
+        }
+    }
+}
+
+/// Hash of a full block of tokens, given the hash of the preceding block
+/// (root_hash() for the first one); it thus identifies the whole prefix.
+fn hash_block(parent: u64, tokens: &[Token]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    parent.hash(&mut hasher);
+    tokens.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Parent of the first block of `seq`; KV computed with a LoRA adapter is only
+/// shared with sequences using the same one.
+fn root_hash(seq: &Sequence) -> u64 {
+    match &seq.adapter {
+        None => 0,
+        Some(adapter) => {
+            let mut hasher = DefaultHasher::new();
+            adapter.hash(&mut hasher);
+            hasher.finish()
+        }
     }
 }
 
@@ -28,16 +62,56 @@ impl PhysicalTokenBlock {
 /// The allocator maintains a list of free blocks and allocates a block when
 /// requested. When a block is freed, its reference count is decremented. If
 /// the reference count becomes zero, the block is added back to the free list.
+///
+/// With prefix caching, free blocks keep their contents (and stay in `cached`)
+/// until they are allocated again; cached blocks are reused last, least
+/// recently freed first. Cached blocks of pinned prefixes are never reused.
 
 struct Allocator {
-    free_list: Vec<usize>,
+    free_list: VecDeque<usize>,
+    // free blocks in `cached`, least recently used first
+    lru: VecDeque<usize>,
     all_blocks: Vec<PhysicalTokenBlock>,
     block_size: usize,
+    // full blocks with computed KV
+    cached: Option<PrefixTree>,
+    // hashes of blocks kept in the cache even when free; see pin_prefix()
+    pinned: HashSet<u64>,
+    // high-water mark of blocks in use
+    max_used: usize,
+    // cached blocks reused for other contents
+    num_evicted: usize,
+    // with poison_kv_blocks, blocks allocated since the last take_fresh_blocks()
+    fresh: Option<Vec<usize>>,
+    // with track_block_refs
+    refs: Option<RefTracker>,
+}
+
+/// Where the references to blocks not given back yet were taken; see
+/// CacheConfig::track_block_refs.
+#[derive(Default)]
+struct RefTracker {
+    next_id: u64,
+    // ref_id -> (block_idx, where it was taken)
+    live: HashMap<u64, (usize, Backtrace)>,
 }
 
 struct BlockAllocatorInner {
     alloc: Allocator,
     seq_blocks: HashMap<SeqId, Vec<BlockRef>>,
+    // hashes of the full blocks of the sequence registered so far
+    seq_hashes: HashMap<SeqId, Vec<u64>>,
+    // hashes of the following full blocks of the prompt, until registered
+    seq_pending: HashMap<SeqId, VecDeque<u64>>,
+    // with a sliding window, the number of blocks of the sequence already freed,
+    // after the `sink_blocks` leading ones; seq_blocks only holds the others
+    seq_dropped: HashMap<SeqId, usize>,
+    // number of tokens with slots allocated, for fragmentation stats
+    seq_lens: HashMap<SeqId, usize>,
+    window: Option<usize>,
+    sink_blocks: usize,
+    // see CacheConfig::kv_budget
+    kv_budget: Option<usize>,
 }
 
 #[derive(Clone)]
@@ -45,8 +119,12 @@ pub struct BlockAllocator {
     inner: Arc<Mutex<BlockAllocatorInner>>,
 }
 
+/// One reference to a physical block, counted in its ref_count; it's given back
+/// with Allocator::free(), and shared (between forked sequences) with fork().
 struct BlockRef {
     block_idx: usize,
+    // identifies the reference with track_block_refs; 0 otherwise
+    ref_id: u64,
 }
 
 impl Allocator {
@@ -54,32 +132,161 @@ impl Allocator {
         (length + self.block_size - 1) / self.block_size
     }
 
+    fn num_free(&self) -> usize {
+        self.free_list.len() + self.lru.len()
+    }
+
+    fn num_pinned_free(&self) -> usize {
+        self.all_blocks
+            .iter()
+            .filter(|b| b.ref_count == 0 && b.hash.map_or(false, |h| self.pinned.contains(&h)))
+            .count()
+    }
+
+    fn holds_kv(&self, block_idx: usize) -> bool {
+        let blk = &self.all_blocks[block_idx];
+        blk.ref_count > 0 || blk.hash.is_some()
+    }
+
+    /// Length of the longest run of consecutive blocks in `free_list`
+    /// (the ones not holding KV).
+    fn longest_free_run(&self) -> usize {
+        let mut longest = 0;
+        let mut run = 0;
+        for idx in 0..self.all_blocks.len() {
+            if self.holds_kv(idx) {
+                run = 0;
+            } else {
+                run += 1;
+                longest = std::cmp::max(longest, run);
+            }
+        }
+        longest
+    }
+
+    fn note_used(&mut self) {
+        let used = self.all_blocks.len() - self.num_free();
+        self.max_used = std::cmp::max(self.max_used, used);
+    }
+
+    fn new_ref(&mut self, block_idx: usize) -> BlockRef {
+        let ref_id = match self.refs.as_mut() {
+            Some(t) => {
+                t.next_id += 1;
+                t.live
+                    .insert(t.next_id, (block_idx, Backtrace::force_capture()));
+                t.next_id
+            }
+            None => 0,
+        };
+        BlockRef { block_idx, ref_id }
+    }
+
     fn free(&mut self, block: BlockRef) {
+        if let Some(t) = self.refs.as_mut() {
+            t.live.remove(&block.ref_id);
+        }
         let blk = &mut self.all_blocks[block.block_idx];
         assert!(blk.ref_count > 0);
         blk.ref_count -= 1;
         if blk.ref_count == 0 {
-            self.free_list.push(block.block_idx);
+            match blk.hash {
+                Some(h) if self.pinned.contains(&h) => {}
+                Some(_) => self.lru.push_back(block.block_idx),
+                None => self.free_list.push_back(block.block_idx),
+            }
         }
     }
 
-    fn fork(&mut self, block: &BlockRef) -> BlockRef {
-        let blk = &mut self.all_blocks[block.block_idx];
+    fn fork(&mut self, block_idx: usize) -> BlockRef {
+        let blk = &mut self.all_blocks[block_idx];
         assert!(blk.ref_count > 0);
         blk.ref_count += 1;
-        BlockRef {
-            block_idx: block.block_idx,
-        }
+        self.new_ref(block_idx)
     }
 
     fn allocate(&mut self) -> BlockRef {
         let block_idx = self
             .free_list
-            .pop()
+            .pop_back()
+            .or_else(|| self.lru.pop_front())
             .expect("Out of memory! No free blocks are available.");
-        assert!(self.all_blocks[block_idx].ref_count == 0);
+        let blk = &mut self.all_blocks[block_idx];
+        assert!(blk.ref_count == 0);
+        blk.ref_count += 1;
+        blk.score = 0.0;
+        if let Some(hash) = blk.hash.take() {
+            // evicted from the prefix cache
+            self.cached.as_mut().unwrap().remove(hash);
+            self.num_evicted += 1;
+        }
+        if let Some(fresh) = self.fresh.as_mut() {
+            fresh.push(block_idx);
+        }
+        self.note_used();
+        self.new_ref(block_idx)
+    }
+
+    /// Take a reference to the cached block with the given hash, if any.
+    fn lookup(&mut self, hash: u64) -> Option<BlockRef> {
+        let block_idx = self.cached.as_ref()?.get(hash)?;
+        Some(self.take_cached(block_idx))
+    }
+
+    /// Take a reference to a block in the prefix cache, possibly a free one.
+    fn take_cached(&mut self, block_idx: usize) -> BlockRef {
+        if self.all_blocks[block_idx].ref_count == 0 {
+            // pinned blocks are not in the LRU list
+            if let Some(pos) = self.lru.iter().position(|&b| b == block_idx) {
+                self.lru.remove(pos);
+            }
+            self.note_used();
+        }
         self.all_blocks[block_idx].ref_count += 1;
-        BlockRef { block_idx }
+        self.new_ref(block_idx)
+    }
+
+    /// Add the block with `tokens` after the prefix `parent` to the prefix cache,
+    /// unless it has the same contents as a cached one.
+    fn register(&mut self, block: &BlockRef, parent: u64, hash: u64, tokens: &[Token]) {
+        let cached = match self.cached.as_mut() {
+            Some(c) => c,
+            None => return,
+        };
+        let blk = &mut self.all_blocks[block.block_idx];
+        if blk.hash.is_none() && !cached.contains(hash) {
+            cached.insert(parent, hash, tokens, block.block_idx);
+            blk.hash = Some(hash);
+        }
+    }
+
+    /// Keep (or with `pinned == false`, stop keeping) the cached blocks with the given
+    /// hashes when they are free, including ones registered later.
+    fn pin(&mut self, hashes: &[u64], pinned: bool) {
+        for &hash in hashes {
+            let changed = if pinned {
+                self.pinned.insert(hash)
+            } else {
+                self.pinned.remove(&hash)
+            };
+            let block_idx = match self.cached.as_ref().and_then(|c| c.get(hash)) {
+                Some(b) if changed && self.all_blocks[b].ref_count == 0 => b,
+                _ => continue,
+            };
+            if pinned {
+                let pos = self.lru.iter().position(|&b| b == block_idx).unwrap();
+                self.lru.remove(pos);
+            } else {
+                self.lru.push_back(block_idx);
+            }
+        }
+    }
+
+    /// Remove the block from the prefix cache, as its contents are about to change.
+    fn unregister(&mut self, block: &BlockRef) {
+        if let Some(hash) = self.all_blocks[block.block_idx].hash.take() {
+            self.cached.as_mut().unwrap().remove(hash);
+        }
     }
 
     fn is_singular(&self, block: &BlockRef) -> bool {
@@ -87,48 +294,357 @@ impl Allocator {
         assert!(blk.ref_count > 0);
         blk.ref_count == 1
     }
+
+    /// Whether `block` can be written to in place; otherwise copy_on_write() copies it.
+    fn is_writable(&self, block: &BlockRef) -> bool {
+        let pinned = match self.all_blocks[block.block_idx].hash {
+            Some(h) => self.pinned.contains(&h),
+            None => false,
+        };
+        self.is_singular(block) && !pinned
+    }
+
+    /// Make sure `block` is not shared (or pinned) before it's written to. Such a block
+    /// is replaced by a new one, and (old, new) block numbers are returned;
+    /// the contents have to be copied. A cached block written in place
+    /// (the tail of a partially matched prefix) leaves the prefix cache.
+    fn copy_on_write(&mut self, block: &mut BlockRef) -> Option<(usize, usize)> {
+        if self.is_writable(block) {
+            self.unregister(block);
+            return None;
+        }
+        let new_block = self.allocate();
+        let old_block = std::mem::replace(block, new_block);
+        let r = (old_block.block_idx, block.block_idx);
+        self.free(old_block);
+        Some(r)
+    }
+}
+
+/// Index in seq_blocks of block `idx` of a sequence, if it's held; the first
+/// `sinks` blocks are followed by the ones after the `dropped` freed blocks.
+fn local_block_idx(idx: usize, sinks: usize, dropped: usize) -> Option<usize> {
+    if idx < sinks || dropped == 0 {
+        Some(idx)
+    } else {
+        idx.checked_sub(dropped).filter(|&l| l >= sinks)
+    }
 }
 
 impl BlockAllocatorInner {
+    fn num_dropped(&self, seq: SeqId) -> usize {
+        self.seq_dropped.get(&seq).copied().unwrap_or(0)
+    }
+
+    fn local_block(&self, seq: SeqId, idx: usize) -> Option<usize> {
+        local_block_idx(idx, self.sink_blocks, self.num_dropped(seq))
+    }
+
+    /// Number of the first `num_blocks` blocks of `seq` it holds.
+    fn num_held(&self, seq: SeqId, num_blocks: usize) -> usize {
+        let sinks = self.sink_blocks;
+        let dropped = self.num_dropped(seq);
+        if dropped == 0 || num_blocks <= sinks {
+            num_blocks
+        } else {
+            sinks + num_blocks.saturating_sub(sinks + dropped)
+        }
+    }
+
     fn copy(&mut self, src: SeqId, dst: SeqId, length: usize) {
+        let dropped = self.num_dropped(src);
+        let num_blocks = self.num_held(src, self.alloc.num_blocks(length));
         let alloc = &mut self.alloc;
         let seq_blocks = &mut self.seq_blocks;
         match seq_blocks.get(&src) {
             Some(v) => {
-                let length = alloc.num_blocks(length);
-                let mut new_v = Vec::with_capacity(std::cmp::min(length, v.len()));
-                for e in v.iter().take(length) {
-                    new_v.push(alloc.fork(e));
+                let mut new_v = Vec::with_capacity(std::cmp::min(num_blocks, v.len()));
+                for e in v.iter().take(num_blocks) {
+                    new_v.push(alloc.fork(e.block_idx));
                 }
                 seq_blocks.insert(dst, new_v);
+                if dropped > 0 {
+                    self.seq_dropped.insert(dst, dropped);
+                }
+                let src_len = self.seq_lens.get(&src).copied().unwrap_or(0);
+                self.seq_lens.insert(dst, std::cmp::min(src_len, length));
             }
             None => {}
         }
+        if let Some(hashes) = self.seq_hashes.get(&src) {
+            let n = std::cmp::min(hashes.len(), length / self.alloc.block_size);
+            let hashes = hashes[..n].to_vec();
+            self.seq_hashes.insert(dst, hashes);
+        }
+    }
+
+    /// The prompt blocks of `seq` no longer count as being computed.
+    fn clear_pending(&mut self, seq: SeqId) {
+        if let Some(pending) = self.seq_pending.remove(&seq) {
+            let tree = self.alloc.cached.as_mut().unwrap();
+            for hash in pending {
+                tree.remove_pending(hash);
+            }
+        }
     }
 
     fn trim(&mut self, seq: SeqId, length: usize) {
+        self.clear_pending(seq);
+        let dropped = self.num_dropped(seq);
+        if let Some(len) = self.seq_lens.get_mut(&seq) {
+            *len = std::cmp::min(*len, length);
+        }
+        let num_full = length / self.alloc.block_size;
+        let length = self.alloc.num_blocks(length);
+        let partial = num_full < length;
+        let keep = self.num_held(seq, length);
+        let last_held = length > 0 && self.local_block(seq, length - 1).is_some();
+        let sinks = self.sink_blocks;
         let alloc = &mut self.alloc;
-        let length = alloc.num_blocks(length);
         self.seq_blocks.get_mut(&seq).map(|v| {
-            for e in v.drain(length..) {
+            for e in v.drain(keep..) {
                 alloc.free(e)
             }
+            if partial && last_held && v.len() == keep && alloc.is_singular(&v[keep - 1]) {
+                // the tail of the last block will be overwritten in place
+                alloc.unregister(&v[keep - 1]);
+            }
         });
         if length == 0 {
             self.seq_blocks.remove(&seq);
+            self.seq_hashes.remove(&seq);
+            self.seq_dropped.remove(&seq);
+            self.seq_lens.remove(&seq);
+            self.check_leaks();
+        } else {
+            if let Some(hashes) = self.seq_hashes.get_mut(&seq) {
+                hashes.truncate(num_full);
+            }
+            if dropped > 0 && length <= sinks {
+                // back to the attention sinks only
+                self.seq_dropped.remove(&seq);
+            } else if dropped > 0 && keep == sinks && self.seq_blocks.contains_key(&seq) {
+                // trimmed back past the window; new blocks start after the kept tokens
+                log::warn!("seq {seq} trimmed to before its sliding window; context is lost");
+                self.seq_dropped.insert(seq, num_full - sinks);
+            }
+        }
+    }
+
+    /// Free the leading blocks of `seq` no query of the current step can attend to.
+    fn drop_outside_window(&mut self, seq: &Sequence) {
+        let window = match self.window {
+            Some(w) => w,
+            None => return,
+        };
+        let dropped = self.num_dropped(seq.seq_id);
+        let sinks = self.sink_blocks;
+        let first_query = seq.step_positions().start;
+        let first_needed = (first_query + 1).saturating_sub(window) / self.alloc.block_size;
+        if first_needed <= sinks + dropped {
+            return;
+        }
+        let alloc = &mut self.alloc;
+        if let Some(v) = self.seq_blocks.get_mut(&seq.seq_id) {
+            let n = std::cmp::min(
+                first_needed - sinks - dropped,
+                v.len().saturating_sub(sinks),
+            );
+            if n == 0 {
+                return;
+            }
+            for e in v.drain(sinks..sinks + n) {
+                alloc.free(e);
+            }
+            self.seq_dropped.insert(seq.seq_id, dropped + n);
+        }
+    }
+
+    /// With a kv_budget, free the computed blocks of `seq` with the lowest scores
+    /// (except for the recent half of the budget), until the blocks it needs
+    /// for this step fit the budget.
+    /// The tail of held blocks (being written to) stays aligned with the end of the
+    /// sequence, which is all that local_block() is used for after that.
+    fn evict_heavy_hitters(&mut self, seq: &Sequence) {
+        let budget = match self.kv_budget {
+            Some(b) => b,
+            None => return,
+        };
+        let block_size = self.alloc.block_size;
+        let mut dropped = self.num_dropped(seq.seq_id);
+        let needed = self.alloc.num_blocks(seq.get_len());
+        let recent = self.alloc.num_blocks(budget / 2);
+        let first_protected = std::cmp::min(
+            seq.num_kv_computed / block_size,
+            needed.saturating_sub(recent),
+        );
+        let alloc = &mut self.alloc;
+        let v = match self.seq_blocks.get_mut(&seq.seq_id) {
+            Some(v) => v,
+            None => return,
+        };
+        let initial = dropped;
+        while needed.saturating_sub(dropped) * block_size > budget {
+            // the held blocks before first_protected
+            let candidates = std::cmp::min(first_protected.saturating_sub(dropped), v.len());
+            let victim = (0..candidates).min_by(|&a, &b| {
+                let score = |i: usize| alloc.all_blocks[v[i].block_idx].score;
+                score(a).total_cmp(&score(b))
+            });
+            match victim {
+                Some(i) => {
+                    alloc.free(v.remove(i));
+                    dropped += 1;
+                }
+                None => break,
+            }
+        }
+        if dropped > initial {
+            log::trace!("seq {} evicted {} blocks", seq.seq_id, dropped - initial);
+            self.seq_dropped.insert(seq.seq_id, dropped);
+        }
+    }
+
+    /// With track_block_refs, report the references that are neither held by
+    /// a sequence nor given back; their blocks never return to the pool.
+    fn check_leaks(&mut self) {
+        if self.alloc.refs.is_none() {
+            return;
+        }
+        let held: HashSet<u64> = self
+            .seq_blocks
+            .values()
+            .flatten()
+            .map(|b| b.ref_id)
+            .collect();
+        let tracker = self.alloc.refs.as_mut().unwrap();
+        let leaked: Vec<u64> = tracker
+            .live
+            .keys()
+            .filter(|id| !held.contains(id))
+            .copied()
+            .collect();
+        for id in leaked {
+            let (block_idx, taken_at) = tracker.live.remove(&id).unwrap();
+            log::error!("lost a reference to KV block {block_idx}, taken at:\n{taken_at}");
+        }
+    }
+
+    /// Register the full blocks of `seq` with computed KV in the prefix cache.
+    fn register_seq(&mut self, seq: &Sequence) {
+        if self.alloc.cached.is_none() || !seq.embedding_overrides().is_empty() {
+            return;
+        }
+        let block_size = self.alloc.block_size;
+        let blocks = match self.seq_blocks.get(&seq.seq_id) {
+            Some(v) => v,
+            None => return,
+        };
+        let dropped = self.seq_dropped.get(&seq.seq_id).copied().unwrap_or(0);
+        let sinks = self.sink_blocks;
+        let num_full = std::cmp::min(seq.num_kv_computed / block_size, dropped + blocks.len());
+        let hashes = self.seq_hashes.entry(seq.seq_id).or_default();
+        while hashes.len() < num_full {
+            let idx = hashes.len();
+            let parent = if idx == 0 {
+                root_hash(seq)
+            } else {
+                hashes[idx - 1]
+            };
+            let tokens = &seq.get_tokens()[idx * block_size..(idx + 1) * block_size];
+            let hash = hash_block(parent, tokens);
+            hashes.push(hash);
+            if let Some(local) = local_block_idx(idx, sinks, dropped) {
+                self.alloc.register(&blocks[local], parent, hash, tokens);
+            }
+            if let Some(pending) = self.seq_pending.get_mut(&seq.seq_id) {
+                if pending.front() == Some(&hash) {
+                    pending.pop_front();
+                    self.alloc.cached.as_mut().unwrap().remove_pending(hash);
+                }
+            }
+        }
+    }
+
+    /// Fraction of the slots of blocks held by sequences that hold no token;
+    /// a shared block counts as filled as far as its longest user got.
+    fn fragmentation(&self) -> f32 {
+        let block_size = self.alloc.block_size;
+        let mut fill: HashMap<usize, usize> = HashMap::default();
+        for (seq, blocks) in self.seq_blocks.iter() {
+            let len = self.seq_lens.get(seq).copied().unwrap_or(0);
+            let dropped = self.num_dropped(*seq);
+            for (i, b) in blocks.iter().enumerate() {
+                let idx = if i < self.sink_blocks { i } else { i + dropped };
+                let n = std::cmp::min(len.saturating_sub(idx * block_size), block_size);
+                let e = fill.entry(b.block_idx).or_insert(0);
+                *e = std::cmp::max(*e, n);
+            }
         }
+        if fill.is_empty() {
+            return 0.0;
+        }
+        let used = fill.values().sum::<usize>();
+        1.0 - used as f32 / (fill.len() * block_size) as f32
+    }
+
+    /// Move the blocks holding KV (used, or free but cached) to the start of the pool,
+    /// so that the free ones follow consecutively; returns (old, new) block numbers,
+    /// for the contents to be copied. The moves only go to blocks that were free.
+    fn compact(&mut self) -> Vec<(usize, usize)> {
+        let alloc = &mut self.alloc;
+        let num_blocks = alloc.all_blocks.len();
+        let num_kv = (0..num_blocks).filter(|&i| alloc.holds_kv(i)).count();
+        let moves: Vec<(usize, usize)> = (num_kv..num_blocks)
+            .filter(|&i| alloc.holds_kv(i))
+            .zip((0..num_kv).filter(|&i| !alloc.holds_kv(i)))
+            .collect();
+        if moves.is_empty() {
+            return moves;
+        }
+
+        for &(src, dst) in moves.iter() {
+            alloc.all_blocks.swap(src, dst);
+            if let Some(hash) = alloc.all_blocks[dst].hash {
+                alloc.cached.as_mut().unwrap().relocate(hash, dst);
+            }
+        }
+        let new_idx: HashMap<usize, usize> = moves.iter().copied().collect();
+        let relocate = |b: &mut usize| {
+            if let Some(&dst) = new_idx.get(b) {
+                *b = dst;
+            }
+        };
+        for blocks in self.seq_blocks.values_mut() {
+            blocks.iter_mut().for_each(|b| relocate(&mut b.block_idx));
+        }
+        alloc.lru.iter_mut().for_each(relocate);
+        if let Some(t) = alloc.refs.as_mut() {
+            t.live.values_mut().for_each(|(b, _)| relocate(b));
+        }
+        alloc.free_list = (num_kv..num_blocks).rev().collect();
+        moves
     }
 
     fn get_block_idx(&self, seq: SeqId, position: usize) -> usize {
         let blocks = self.seq_blocks.get(&seq).unwrap();
         let block_size = self.alloc.block_size;
         let block_offset = position % block_size;
-        blocks[position / block_size].block_idx * block_size + block_offset
+        let idx = self.local_block(seq, position / block_size).unwrap();
+        blocks[idx].block_idx * block_size + block_offset
     }
 }
 
 impl BlockAllocator {
-    fn new(device: BlockLocation, block_size: usize, num_blocks: usize) -> Self {
+    fn new(
+        device: BlockLocation,
+        block_size: usize,
+        num_blocks: usize,
+        prefix_caching: bool,
+        window: Option<usize>,
+        sink_blocks: usize,
+        poison: bool,
+    ) -> Self {
         let all_blocks = (0..num_blocks)
             .map(|i| PhysicalTokenBlock::new(device, i, block_size))
             .collect();
@@ -136,9 +652,27 @@ impl BlockAllocator {
             alloc: Allocator {
                 all_blocks,
                 free_list: (0..num_blocks).rev().collect(),
+                lru: VecDeque::new(),
                 block_size,
+                cached: if prefix_caching {
+                    Some(PrefixTree::default())
+                } else {
+                    None
+                },
+                pinned: HashSet::default(),
+                max_used: 0,
+                num_evicted: 0,
+                fresh: if poison { Some(Vec::new()) } else { None },
+                refs: None,
             },
             seq_blocks: HashMap::default(),
+            seq_hashes: HashMap::default(),
+            seq_pending: HashMap::default(),
+            seq_dropped: HashMap::default(),
+            seq_lens: HashMap::default(),
+            window,
+            sink_blocks,
+            kv_budget: None,
         };
         Self {
             inner: Arc::new(Mutex::new(inner)),
@@ -146,12 +680,145 @@ impl BlockAllocator {
     }
 
     fn get_num_free_blocks(&self) -> usize {
-        self.inner.lock().unwrap().alloc.free_list.len()
+        self.inner.lock().unwrap().alloc.num_free()
+    }
+
+    fn get_num_total_blocks(&self) -> usize {
+        self.inner.lock().unwrap().alloc.all_blocks.len()
+    }
+
+    fn get_max_used_blocks(&self) -> usize {
+        self.inner.lock().unwrap().alloc.max_used
+    }
+
+    fn get_fragmentation(&self) -> f32 {
+        self.inner.lock().unwrap().fragmentation()
+    }
+
+    /// (evictable free blocks in the prefix cache, free pinned ones, blocks evicted so far)
+    fn get_cache_counters(&self) -> (usize, usize, usize) {
+        let l = self.inner.lock().unwrap();
+        (
+            l.alloc.lru.len(),
+            l.alloc.num_pinned_free(),
+            l.alloc.num_evicted,
+        )
+    }
+
+    /// Pin (or unpin) the full blocks of the prefix `tokens` in the prefix cache;
+    /// returns the number of blocks affected.
+    fn pin_prefix(&self, tokens: &[Token], pinned: bool) -> usize {
+        let mut l = self.inner.lock().unwrap();
+        if l.alloc.cached.is_none() {
+            return 0;
+        }
+        let mut hashes: Vec<u64> = Vec::new();
+        for chunk in tokens.chunks_exact(l.alloc.block_size) {
+            let parent = hashes.last().copied().unwrap_or(0);
+            hashes.push(hash_block(parent, chunk));
+        }
+        l.alloc.pin(&hashes, pinned);
+        hashes.len()
     }
 
+    /// KV cache slots of positions up to `len` of `seq`; with a sliding window,
+    /// the ones of freed blocks are skipped (see num_dropped_blocks()).
     pub fn get_block_idxes(&self, seq: SeqId, len: usize) -> Vec<usize> {
         let l = self.inner.lock().unwrap();
-        (0..len).map(|k| l.get_block_idx(seq, k)).collect()
+        let block_size = l.alloc.block_size;
+        let sinks = l.sink_blocks * block_size;
+        let start = sinks + l.num_dropped(seq) * block_size;
+        (0..std::cmp::min(sinks, len))
+            .chain(start..len)
+            .map(|k| l.get_block_idx(seq, k))
+            .collect()
+    }
+
+    /// Physical blocks held by `seq`, in order; None if it has dropped blocks.
+    pub fn get_seq_blocks(&self, seq: SeqId) -> Option<Vec<usize>> {
+        let l = self.inner.lock().unwrap();
+        if l.num_dropped(seq) > 0 {
+            return None;
+        }
+        let blocks = l.seq_blocks.get(&seq)?;
+        Some(blocks.iter().map(|b| b.block_idx).collect())
+    }
+
+    /// Allocate blocks for `len` tokens of `seq`, which holds none; their KV
+    /// is filled in by the caller. None if there are not enough free blocks.
+    pub fn alloc_blocks(&self, seq: SeqId, len: usize) -> Option<Vec<usize>> {
+        let mut l = self.inner.lock().unwrap();
+        assert!(!l.seq_blocks.contains_key(&seq));
+        let num_bl = l.alloc.num_blocks(len);
+        if num_bl > l.alloc.num_free() {
+            return None;
+        }
+        let v: Vec<BlockRef> = (0..num_bl).map(|_| l.alloc.allocate()).collect();
+        let idxs = v.iter().map(|b| b.block_idx).collect();
+        l.seq_blocks.insert(seq, v);
+        l.seq_lens.insert(seq, len);
+        Some(idxs)
+    }
+
+    /// Add attention scores of blocks (block number -> score), which decide
+    /// the blocks heavy-hitter eviction keeps.
+    pub fn add_attention(&self, scores: &HashMap<usize, f32>) {
+        let mut l = self.inner.lock().unwrap();
+        for (&block_idx, &score) in scores.iter() {
+            l.alloc.all_blocks[block_idx].score += score;
+        }
+    }
+
+    fn with_kv_budget(self, kv_budget: Option<usize>) -> Self {
+        self.inner.lock().unwrap().kv_budget = kv_budget;
+        self
+    }
+
+    fn with_ref_tracking(self, enabled: bool) -> Self {
+        if enabled {
+            self.inner.lock().unwrap().alloc.refs = Some(RefTracker::default());
+        }
+        self
+    }
+
+    /// With poison_kv_blocks, the blocks allocated since the last call, which
+    /// are to be poisoned before their KV is written; otherwise empty.
+    pub fn take_fresh_blocks(&self) -> Vec<usize> {
+        let mut l = self.inner.lock().unwrap();
+        l.alloc
+            .fresh
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Compact the pool (see BlockAllocatorInner::compact()) if the longest run of
+    /// consecutive free blocks is less than `min_free_run` of the free blocks.
+    pub fn compact(&self, min_free_run: f32) -> Vec<(usize, usize)> {
+        let mut l = self.inner.lock().unwrap();
+        let num_free = l.alloc.free_list.len();
+        if num_free == 0 || l.alloc.longest_free_run() as f32 >= min_free_run * num_free as f32 {
+            return Vec::new();
+        }
+        l.compact()
+    }
+
+    /// Number of blocks of `seq` freed as they fell out of the sliding window
+    /// (after the attention sinks).
+    pub fn num_dropped_blocks(&self, seq: SeqId) -> usize {
+        self.inner.lock().unwrap().num_dropped(seq)
+    }
+
+    /// KV cache slot of `position` of `seq`, if its block is already allocated.
+    pub fn get_slot(&self, seq: SeqId, position: usize) -> Option<usize> {
+        let l = self.inner.lock().unwrap();
+        let num_blocks = l.seq_blocks.get(&seq)?.len();
+        let block_idx = l.local_block(seq, position / l.alloc.block_size)?;
+        if block_idx < num_blocks {
+            Some(l.get_block_idx(seq, position))
+        } else {
+            None
+        }
     }
 
     fn num_needed_blocks(&self, seq: &Sequence) -> usize {
@@ -164,41 +831,147 @@ impl BlockAllocator {
         l.seq_blocks.get(&seq.seq_id).map(|v| v.len()).unwrap_or(0)
     }
 
-    fn alloc_seq(&self, seq: &Sequence) {
+    /// Number of new blocks append_slots() needs for `seq`, including copies of shared blocks.
+    fn num_append_blocks(&self, seq: &Sequence) -> usize {
+        let l = self.inner.lock().unwrap();
+        let block_size = l.alloc.block_size;
+        let block_table = match l.seq_blocks.get(&seq.seq_id) {
+            Some(v) => v,
+            None => return l.alloc.num_blocks(seq.get_len()),
+        };
+        let mut num_blocks = 0;
+        let mut ptr = seq.num_kv_computed;
+        while ptr < seq.get_len() {
+            let block_idx = ptr / block_size;
+            let local_idx = l.local_block(seq.seq_id, block_idx).unwrap();
+            if local_idx >= block_table.len() || !l.alloc.is_writable(&block_table[local_idx]) {
+                num_blocks += 1;
+            }
+            ptr = (block_idx + 1) * block_size;
+        }
+        num_blocks
+    }
+
+    /// Allocate blocks for the prompt of `seq`, reusing cached blocks of its prefix
+    /// if `use_cache`; returns the number of tokens with KV already computed.
+    fn alloc_seq(&self, seq: &Sequence, use_cache: bool) -> usize {
         assert!(self.num_allocated_blocks(seq) == 0);
         let mut l = self.inner.lock().unwrap();
+        let block_size = l.alloc.block_size;
         let num_bl = l.alloc.num_blocks(seq.get_len());
         let mut v = Vec::with_capacity(num_bl);
-        for _ in 0..num_bl {
+        let mut hashes = Vec::new();
+        let mut num_cached = 0;
+        if use_cache && l.alloc.cached.is_some() {
+            // the last token is always computed, to get its logits
+            let max_cached = (seq.get_len() - 1) / block_size;
+            let tokens = seq.get_tokens();
+            let root = root_hash(seq);
+            for idx in 0..max_cached {
+                let parent = hashes.last().copied().unwrap_or(root);
+                let hash = hash_block(parent, &tokens[idx * block_size..(idx + 1) * block_size]);
+                match l.alloc.lookup(hash) {
+                    Some(b) => {
+                        v.push(b);
+                        hashes.push(hash);
+                    }
+                    None => break,
+                }
+            }
+            num_cached = v.len() * block_size;
+
+            // the next block may start like a cached one; it's copied (or taken
+            // over) before the rest of it is written
+            let parent = hashes.last().copied().unwrap_or(root);
+            let end = std::cmp::min(seq.get_len() - 1, num_cached + block_size);
+            let partial = l
+                .alloc
+                .cached
+                .as_ref()
+                .unwrap()
+                .longest_match(parent, &tokens[num_cached..end]);
+            if let Some((block_idx, len)) = partial {
+                v.push(l.alloc.take_cached(block_idx));
+                num_cached += len;
+            }
+
+            // the rest of the full blocks is computed by this sequence; others
+            // with the same prefix can wait for it (see is_prefix_pending())
+            let mut parent = parent;
+            let mut pending = VecDeque::new();
+            for chunk in tokens[hashes.len() * block_size..].chunks_exact(block_size) {
+                parent = hash_block(parent, chunk);
+                l.alloc.cached.as_mut().unwrap().add_pending(parent);
+                pending.push_back(parent);
+            }
+            if pending.len() > 0 {
+                l.seq_pending.insert(seq.seq_id, pending);
+            }
+        }
+        while v.len() < num_bl {
             v.push(l.alloc.allocate())
         }
         l.seq_blocks.insert(seq.seq_id, v);
+        l.seq_lens.insert(seq.seq_id, seq.get_len());
+        if hashes.len() > 0 {
+            l.seq_hashes.insert(seq.seq_id, hashes);
+        }
+        num_cached
+    }
+
+    /// Whether a full block of the prompt of `seq`, after the ones in the prefix cache,
+    /// is being computed by another sequence.
+    fn is_prefix_pending(&self, seq: &Sequence) -> bool {
+        let l = self.inner.lock().unwrap();
+        let tree = match l.alloc.cached.as_ref() {
+            Some(t) => t,
+            None => return false,
+        };
+        let mut parent = root_hash(seq);
+        for chunk in seq.get_tokens().chunks_exact(l.alloc.block_size) {
+            let hash = hash_block(parent, chunk);
+            if !tree.contains(hash) {
+                return tree.is_pending(hash);
+            }
+            parent = hash;
+        }
+        false
     }
 
-    fn swap_out(&self, seq: &Sequence) -> Vec<usize> {
+    /// Free the blocks of `seq`; returns the number of dropped leading blocks,
+    /// and the indices of the others.
+    fn swap_out(&self, seq: &Sequence) -> (usize, Vec<usize>) {
         let r = {
             let l = self.inner.lock().unwrap();
-            l.seq_blocks
+            let idxs = l
+                .seq_blocks
                 .get(&seq.seq_id)
                 .unwrap_or(&Vec::new())
                 .iter()
                 .map(|b| b.block_idx)
-                .collect()
+                .collect();
+            (l.num_dropped(seq.seq_id), idxs)
         };
         self.trim(seq.seq_id, 0);
         r
     }
 
-    fn swap_in(&self, seq: &Sequence, block_idxs: Vec<usize>, mapping: &mut HashMap<usize, usize>) {
+    fn swap_in(
+        &self,
+        seq: &Sequence,
+        (dropped, block_idxs): (usize, Vec<usize>),
+        mapping: &mut HashMap<usize, usize>,
+    ) {
         assert!(self.num_allocated_blocks(seq) == 0);
         let mut l = self.inner.lock().unwrap();
+        if dropped > 0 {
+            l.seq_dropped.insert(seq.seq_id, dropped);
+        }
         let mut v = Vec::with_capacity(block_idxs.len());
         for bidx in block_idxs {
             match mapping.get(&bidx) {
                 Some(&new_bidx) => {
-                    v.push(l.alloc.fork(&BlockRef {
-                        block_idx: new_bidx,
-                    }));
+                    v.push(l.alloc.fork(new_bidx));
                 }
                 None => {
                     let b2 = l.alloc.allocate();
@@ -208,38 +981,39 @@ impl BlockAllocator {
             }
         }
         l.seq_blocks.insert(seq.seq_id, v);
+        l.seq_lens.insert(seq.seq_id, seq.get_len());
     }
 
     fn append_slots(&self, seq: &Sequence, outputs: &mut SchedulerOutputs) {
         let mut l = self.inner.lock().unwrap();
+        l.register_seq(seq);
+        l.drop_outside_window(seq);
+        l.evict_heavy_hitters(seq);
         let block_size = l.alloc.block_size;
+        let dropped = l.num_dropped(seq.seq_id);
         let mut block_table = l.seq_blocks.remove(&seq.seq_id).unwrap();
 
-        assert!(block_table.len() > 0);
-        assert!(block_table.len() * block_size >= seq.num_kv_computed);
+        assert!(block_table.len() > 0 || dropped > 0);
+        assert!((dropped + block_table.len()) * block_size >= seq.num_kv_computed);
 
         let mut ptr = seq.num_kv_computed;
         while ptr < seq.get_len() {
-            let block_idx = ptr / block_size;
+            let block_idx = l.local_block(seq.seq_id, ptr / block_size).unwrap();
             if block_idx < block_table.len() {
-                let curr_block = &mut block_table[block_idx];
-                if !l.alloc.is_singular(curr_block) {
-                    let new_block = l.alloc.allocate();
-                    let old_block_number = curr_block.block_idx;
-                    let new_block_number = new_block.block_idx;
-                    let old_block = std::mem::replace(curr_block, new_block);
-                    l.alloc.free(old_block);
-                    outputs.copy_block(old_block_number, new_block_number);
+                // only the block being filled is written to; full blocks stay shared
+                if let Some((src, dst)) = l.alloc.copy_on_write(&mut block_table[block_idx]) {
+                    outputs.copy_block(src, dst);
                 }
             } else {
                 assert!(block_table.len() == block_idx);
                 block_table.push(l.alloc.allocate());
             }
-            ptr = (block_idx + 1) * block_size;
+            ptr = (ptr / block_size + 1) * block_size;
         }
 
-        assert!(block_table.len() == l.alloc.num_blocks(seq.get_len()));
+        assert!(dropped + block_table.len() == l.alloc.num_blocks(seq.get_len()));
         l.seq_blocks.insert(seq.seq_id, block_table);
+        l.seq_lens.insert(seq.seq_id, seq.get_len());
     }
 
     fn copy(&self, src: SeqId, dst: SeqId, length: usize) {
@@ -257,6 +1031,11 @@ impl BlockAllocator {
 }
 
 /// Manages the mapping between logical and physical token blocks.
+///
+/// With tensor parallelism, each of the KV devices has a pool of the same number
+/// of GPU blocks, holding its shard of the KV heads. A block number refers to
+/// the same block in all pools, so the pools are managed by one allocator and
+/// batches carry a copy of the mappings per device (see BatchInfo::shards).
 pub struct BlockSpaceManager {
     watermark_blocks: usize,
     gpu_allocator: BlockAllocator,
@@ -265,20 +1044,53 @@ pub struct BlockSpaceManager {
 
 impl TBlockSpaceManager<TModel> for BlockSpaceManager {
     fn can_allocate(&self, seq_group: &SequenceGroup) -> bool {
-        let num_required_blocks = self.gpu_allocator.num_needed_blocks(seq_group.only_seq());
-        self.can_alloc_gpu(num_required_blocks + self.watermark_blocks)
+        self.can_alloc_gpu(self.get_num_required_blocks(seq_group) + self.watermark_blocks)
+    }
+
+    fn get_num_required_blocks(&self, seq_group: &SequenceGroup) -> usize {
+        seq_group
+            .seqs
+            .iter()
+            .map(|seq| {
+                if seq.num_kv_computed > 0 {
+                    // restored from a session; only the rest of the prompt needs blocks
+                    self.gpu_allocator.num_append_blocks(seq)
+                } else {
+                    self.gpu_allocator.num_needed_blocks(seq)
+                }
+            })
+            .sum()
+    }
+
+    fn is_prefix_pending(&self, seq_group: &SequenceGroup) -> bool {
+        seq_group.sampling_params.hidden_states.is_none()
+            && seq_group.seqs.iter().any(|seq| {
+                seq.embedding_overrides().is_empty() && self.gpu_allocator.is_prefix_pending(seq)
+            })
     }
 
     fn allocate(&mut self, seq_group: &mut SequenceGroup) {
-        let seq = seq_group.only_seq();
-        assert!(seq.num_kv_computed == 0);
-        self.gpu_allocator.alloc_seq(seq);
+        // hidden states are returned for the whole prompt, so it has to be computed
+        let use_cache = seq_group.sampling_params.hidden_states.is_none();
+        // more than one sequence only with classifier-free guidance
+        for seq in seq_group.seqs.iter_mut() {
+            assert!(seq.num_kv_computed == 0);
+            let use_cache = use_cache && seq.embedding_overrides().is_empty();
+            let num_cached = self.gpu_allocator.alloc_seq(seq, use_cache);
+            if num_cached > 0 {
+                seq.sync_computed_kv_to(num_cached);
+            }
+        }
     }
 
     fn can_append_slot(&self, seq_group: &SequenceGroup) -> bool {
-        let num_seqs = seq_group.num_seqs(Some(SchedulingPhase::Running));
-        // TODO this is not correct - more than one token can be appended
-        self.can_alloc_gpu(num_seqs)
+        // all running sequences of the group get their slots, or none of them do
+        let num_required_blocks = seq_group
+            .get_seqs(Some(SchedulingPhase::Running))
+            .iter()
+            .map(|seq| self.gpu_allocator.num_append_blocks(seq))
+            .sum::<usize>();
+        self.can_alloc_gpu(num_required_blocks)
     }
 
     fn append_slots(&mut self, seq: &mut Sequence, outputs: &mut SchedulerOutputs) {
@@ -316,6 +1128,10 @@ impl TBlockSpaceManager<TModel> for BlockSpaceManager {
         mapping
     }
 
+    fn get_num_blocks(&self, seq_group: &SequenceGroup) -> usize {
+        self.num_phys_blocks(seq_group)
+    }
+
     fn can_swap_out(&self, seq_group: &SequenceGroup) -> bool {
         let blocks = self.num_phys_blocks(seq_group);
         blocks <= self.get_num_free_cpu_blocks()
@@ -328,6 +1144,35 @@ impl TBlockSpaceManager<TModel> for BlockSpaceManager {
     fn get_num_free_cpu_blocks(&self) -> usize {
         self.cpu_allocator.get_num_free_blocks()
     }
+
+    fn get_num_gpu_blocks(&self) -> usize {
+        self.gpu_allocator.get_num_total_blocks()
+    }
+
+    fn get_num_cpu_blocks(&self) -> usize {
+        self.cpu_allocator.get_num_total_blocks()
+    }
+
+    fn get_pool_stats(&self) -> BlockPoolStats {
+        let (num_cached, num_pinned, num_evicted_blocks) = self.gpu_allocator.get_cache_counters();
+        BlockPoolStats {
+            num_gpu_blocks: self.get_num_gpu_blocks(),
+            num_free_gpu_blocks: self.get_num_free_gpu_blocks(),
+            num_cpu_blocks: self.get_num_cpu_blocks(),
+            num_free_cpu_blocks: self.get_num_free_cpu_blocks(),
+            max_used_gpu_blocks: self.gpu_allocator.get_max_used_blocks(),
+            max_used_cpu_blocks: self.cpu_allocator.get_max_used_blocks(),
+            gpu_fragmentation: self.gpu_allocator.get_fragmentation(),
+            num_cached_blocks: num_cached + num_pinned,
+            num_pinned_blocks: num_pinned,
+            num_evicted_blocks,
+            ..BlockPoolStats::default()
+        }
+    }
+
+    fn pin_prefix(&mut self, tokens: &[Token], pinned: bool) -> usize {
+        self.gpu_allocator.pin_prefix(tokens, pinned)
+    }
 }
 
 impl BlockSpaceManager {
@@ -339,8 +1184,17 @@ impl BlockSpaceManager {
     ) -> Self {
         assert!(watermark >= 0.0);
         let watermark_blocks = (watermark * cache_size.gpu as f32) as usize;
+        let prefix_caching = config.model.cache.enable_prefix_caching;
+        let window = config.model.sliding_window;
 
-        log::info!("BlockSpaceManager: block_size: {} tokens", block_size);
+        log::info!(
+            "BlockSpaceManager: block_size: {} tokens; prefix caching: {}; window: {:?}; sinks: {}; devices: {:?}",
+            block_size,
+            prefix_caching,
+            window,
+            config.model.cache.attention_sinks,
+            config.get_kv_devices()
+        );
 
         Self {
             watermark_blocks,
@@ -348,12 +1202,16 @@ impl BlockSpaceManager {
                 BlockLocation::GPU,
                 block_size,
                 cache_size.gpu,
+                prefix_caching,
+                window,
                 config,
             ),
             cpu_allocator: Self::new_allocator(
                 BlockLocation::CPU,
                 block_size,
                 cache_size.cpu,
+                false,
+                None,
                 config,
             ),
         }
@@ -367,6 +1225,8 @@ impl BlockSpaceManager {
         location: BlockLocation,
         block_size: usize,
         num_blocks: usize,
+        prefix_caching: bool,
+        window: Option<usize>,
         config: &RllmConfig<TModel>,
     ) -> BlockAllocator {
         log::info!(
@@ -375,7 +1235,30 @@ impl BlockSpaceManager {
             num_blocks,
             (num_blocks * CacheEngine::get_cache_block_size(config)) >> 20
         );
-        BlockAllocator::new(location, block_size, num_blocks)
+        // the CPU allocator holds swapped-out sequences with the same layout of blocks
+        let sink_blocks = match config.model.sliding_window {
+            Some(_) => (config.model.cache.attention_sinks + block_size - 1) / block_size,
+            None => 0,
+        };
+        let (poison, kv_budget) = match location {
+            BlockLocation::GPU => (
+                config.model.cache.poison_kv_blocks,
+                config.model.cache.kv_budget,
+            ),
+            // swapped-out sequences are not extended
+            BlockLocation::CPU => (false, None),
+        };
+        BlockAllocator::new(
+            location,
+            block_size,
+            num_blocks,
+            prefix_caching,
+            window,
+            sink_blocks,
+            poison,
+        )
+        .with_kv_budget(kv_budget)
+        .with_ref_tracking(config.model.cache.track_block_refs)
     }
 
     fn can_alloc_gpu(&self, num_required_blocks: usize) -> bool {
@@ -440,4 +1323,26 @@ impl SequenceManager for TchSeqMgr {
         self.cpu_allocator.delete(seq);
         self.gpu_allocator.delete(seq);
     }
+
+    /// Only full blocks; the last one is still being written to by the original.
+    fn shareable_len(&self, length: usize) -> usize {
+        let block_size = self.gpu_allocator.inner.lock().unwrap().alloc.block_size;
+        length / block_size * block_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_hashes() {
+        let h1 = hash_block(0, &[1, 2, 3, 4]);
+        assert_eq!(h1, hash_block(0, &[1, 2, 3, 4]));
+        assert_ne!(h1, hash_block(0, &[1, 2, 3, 5]));
+        // the hash identifies the whole prefix, not just the block
+        let h2 = hash_block(h1, &[5, 6, 7, 8]);
+        assert_ne!(h2, hash_block(0, &[5, 6, 7, 8]));
+        assert_ne!(h2, hash_block(hash_block(0, &[1, 2, 3, 5]), &[5, 6, 7, 8]));
+    }
 }
@@ -14,73 +14,185 @@ use tch_cuda::{CudaEvent, CudaStream};
 type KVCache = (Tensor, Tensor);
 
 pub struct CacheEngine {
+    config: Arc<RllmConfig<TModel>>,
     gpu_cache: Arc<Vec<KVCache>>,
-    cpu_cache: Vec<KVCache>,
+    // with tensor parallelism, the caches on the other KV devices; swapping is
+    // not supported then, so these are only used by the forward pass and copies
+    shard_caches: Vec<Arc<Vec<KVCache>>>,
+    // groups of cpu_block_group swap blocks, allocated on first use
+    cpu_cache: Vec<Option<Vec<KVCache>>>,
+    num_cpu_blocks: usize,
     cache_stream: CudaStream,
+    // recorded on the compute stream; swaps wait for it, so that they don't
+    // overlap with earlier use of the blocks
+    compute_event: CudaEvent,
+    // per layer, after swap-out copies of this round
     events: Arc<Vec<CudaEvent>>,
     used_events: bool,
+    // per layer, after swap-in copies, which run during the forward pass of the
+    // step that scheduled them; each layer of the next step waits for its copy
+    prefetch_events: Arc<Vec<CudaEvent>>,
+    pending_prefetch: bool,
+    // the prefetch_events of the previous round, if it swapped in
+    ready_events: Arc<Vec<CudaEvent>>,
+    wait_ready: bool,
 }
 
 struct MyCacheAwaiter {
-    gpu_cache: Arc<Vec<KVCache>>,
-    events: Option<Arc<Vec<CudaEvent>>>,
+    config: Arc<RllmConfig<TModel>>,
+    // caches of all KV devices, and the index of the one used here
+    caches: Vec<Arc<Vec<KVCache>>>,
+    idx: usize,
+    // per-layer events of copies each layer has to wait for
+    events: Vec<Arc<Vec<CudaEvent>>>,
     stream: CudaStream,
 }
 
 impl CacheIface for MyCacheAwaiter {
     fn get(&self, layer_no: usize) -> (Tensor, Tensor) {
-        let (key, value) = &self.gpu_cache[layer_no];
-        if let Some(events) = &self.events {
+        let layer_no = layer_no - self.config.get_kv_device_layers(self.idx).start;
+        let (key, value) = &self.caches[self.idx][layer_no];
+        for events in self.events.iter() {
             events[layer_no].wait(&self.stream);
         }
         (key.shallow_clone(), value.shallow_clone())
     }
+
+    fn shard(&self, idx: usize) -> Box<dyn CacheIface> {
+        Box::new(MyCacheAwaiter {
+            config: self.config.clone(),
+            caches: self.caches.clone(),
+            idx,
+            events: self.events.clone(),
+            stream: CudaStream::current(self.caches[idx][0].0.device()),
+        })
+    }
 }
 
 impl CacheEngine {
     pub fn new(config: Arc<RllmConfig<TModel>>, num_blocks: &CacheSize) -> Self {
         let num_layers = config.get_num_layers_parallel();
-        let (gpu_cache, cpu_cache) = Self::allocate_caches(&config, num_blocks);
+        let mut gpu_caches = (0..config.get_kv_devices().len())
+            .map(|idx| Arc::new(Self::allocate_gpu_cache(&config, num_blocks.gpu, idx)))
+            .collect::<Vec<_>>();
+        let gpu_cache = gpu_caches.remove(0);
+        let group = config.model.cache.cpu_block_group;
+        let num_groups = (num_blocks.cpu + group - 1) / group;
+        let layer_events = || Arc::new((0..num_layers).map(|_| CudaEvent::new()).collect());
         Self {
-            gpu_cache: Arc::new(gpu_cache),
-            cpu_cache,
+            config: config.clone(),
+            gpu_cache,
+            shard_caches: gpu_caches,
+            cpu_cache: (0..num_groups).map(|_| None).collect(),
+            num_cpu_blocks: num_blocks.cpu,
             cache_stream: CudaStream::new(config.model.device),
-            events: Arc::new((0..num_layers).map(|_| CudaEvent::new()).collect()),
+            compute_event: CudaEvent::new(),
+            events: layer_events(),
             used_events: false,
+            prefetch_events: layer_events(),
+            pending_prefetch: false,
+            ready_events: layer_events(),
+            wait_ready: false,
+        }
+    }
+
+    /// Per-layer events the current round has to wait for.
+    fn round_events(&self) -> Vec<Arc<Vec<CudaEvent>>> {
+        let mut r = Vec::new();
+        if self.used_events {
+            r.push(self.events.clone());
+        }
+        if self.wait_ready {
+            r.push(self.ready_events.clone());
         }
+        r
     }
 
     pub fn get_cache_iface(&mut self) -> Box<dyn CacheIface> {
         let d = self.gpu_cache[0].0.device();
-        let events = if self.used_events {
-            Some(self.events.clone())
-        } else {
-            None
-        };
         Box::new(MyCacheAwaiter {
-            events,
+            config: self.config.clone(),
+            caches: std::iter::once(&self.gpu_cache)
+                .chain(self.shard_caches.iter())
+                .cloned()
+                .collect(),
+            idx: 0,
+            events: self.round_events(),
             stream: CudaStream::current(d),
-            gpu_cache: self.gpu_cache.clone(),
         })
     }
 
     pub fn new_round(&mut self) {
         self.used_events = false;
+        self.wait_ready = std::mem::take(&mut self.pending_prefetch);
+        if self.wait_ready {
+            // swap-ins of this round record into the other set
+            std::mem::swap(&mut self.prefetch_events, &mut self.ready_events);
+        }
     }
 
+    /// Start copying blocks to GPU for sequences resumed in the next step.
+    /// The current forward pass doesn't use these blocks, so it doesn't wait
+    /// for the copy; in the next round, each layer waits for its part.
     pub fn swap_in(&mut self, src_to_dst: &HashMap<usize, usize>) {
-        self.swap(&self.cpu_cache, &self.gpu_cache, src_to_dst);
-        self.used_events = true;
+        for (group, mapping) in self.split_by_group(src_to_dst.iter().map(|(&s, &d)| (s, d))) {
+            self.swap(
+                self.cpu_cache[group].as_ref().unwrap(),
+                &self.gpu_cache,
+                &mapping,
+                &self.prefetch_events,
+            );
+        }
+        self.pending_prefetch = true;
     }
 
     pub fn swap_out(&mut self, src_to_dst: &HashMap<usize, usize>) {
-        self.swap(&self.gpu_cache, &self.cpu_cache, src_to_dst);
+        // the freed GPU blocks may be reused in the current forward pass
+        for (group, mapping) in self.split_by_group(src_to_dst.iter().map(|(&s, &d)| (d, s))) {
+            let mapping = mapping.into_iter().map(|(d, s)| (s, d)).collect();
+            self.swap(
+                &self.gpu_cache,
+                self.cpu_cache[group].as_ref().unwrap(),
+                &mapping,
+                &self.events,
+            );
+        }
         self.used_events = true;
     }
 
-    fn alloc_key_block(config: &RllmConfig<TModel>, num_bl: i64, device: Device) -> Tensor {
-        let head_size = config.get_head_size() as i64;
-        let num_heads = config.get_num_heads_parallel() as i64;
+    /// Group (CPU block, other block) pairs by the group of the CPU block, which is
+    /// replaced with its index within the group; the groups are allocated if needed.
+    fn split_by_group(
+        &mut self,
+        pairs: impl Iterator<Item = (usize, usize)>,
+    ) -> Vec<(usize, HashMap<usize, usize>)> {
+        let group_size = self.config.model.cache.cpu_block_group;
+        let mut groups: HashMap<usize, HashMap<usize, usize>> = HashMap::default();
+        for (cpu, other) in pairs {
+            groups
+                .entry(cpu / group_size)
+                .or_default()
+                .insert(cpu % group_size, other);
+        }
+        for &group in groups.keys() {
+            if self.cpu_cache[group].is_none() {
+                let num_blocks =
+                    std::cmp::min(group_size, self.num_cpu_blocks - group * group_size);
+                self.cpu_cache[group] = Some(Self::allocate_cpu_group(&self.config, num_blocks));
+            }
+        }
+        groups.into_iter().collect()
+    }
+
+    fn alloc_key_block(
+        config: &RllmConfig<TModel>,
+        layer: usize,
+        num_bl: i64,
+        device: Device,
+    ) -> Tensor {
+        let shape = config.get_layer_kv_shape_parallel(layer);
+        let head_size = shape.head_dim as i64;
+        let num_heads = shape.num_kv_heads as i64;
         let block_size = config.model.cache.block_size as i64;
         let x = 16 / (config.model.dtype.elt_size_in_bytes() as i64);
         Tensor::empty(
@@ -89,9 +201,15 @@ impl CacheEngine {
         )
     }
 
-    fn alloc_value_block(config: &RllmConfig<TModel>, num_bl: i64, device: Device) -> Tensor {
-        let head_size = config.get_head_size() as i64;
-        let num_heads = config.get_num_heads_parallel() as i64;
+    fn alloc_value_block(
+        config: &RllmConfig<TModel>,
+        layer: usize,
+        num_bl: i64,
+        device: Device,
+    ) -> Tensor {
+        let shape = config.get_layer_kv_shape_parallel(layer);
+        let head_size = shape.head_dim as i64;
+        let num_heads = shape.num_kv_heads as i64;
         let block_size = config.model.cache.block_size as i64;
         Tensor::empty(
             &[num_bl, num_heads, head_size, block_size],
@@ -99,78 +217,177 @@ impl CacheEngine {
         )
     }
 
-    pub fn alloc_gpu_cache_layer(config: &RllmConfig<TModel>, num_bl: i64) -> (Tensor, Tensor) {
-        let device = config.model.device;
+    pub fn alloc_gpu_cache_layer(
+        config: &RllmConfig<TModel>,
+        layer: usize,
+        num_bl: i64,
+        device: Device,
+    ) -> (Tensor, Tensor) {
         (
-            Self::alloc_key_block(config, num_bl, device),
-            Self::alloc_value_block(config, num_bl, device),
+            Self::alloc_key_block(config, layer, num_bl, device),
+            Self::alloc_value_block(config, layer, num_bl, device),
         )
     }
 
-    fn allocate_caches(
+    // not initialized; with poison_kv_blocks, blocks are filled with NaN when allocated
+    // of the layers on the `idx`-th KV device
+    fn allocate_gpu_cache(
         config: &RllmConfig<TModel>,
-        num_blocks: &CacheSize,
-    ) -> (Vec<KVCache>, Vec<KVCache>) {
-        let num_layers = config.get_num_layers_parallel() as i64;
-
-        let gpu_cache = (0..num_layers)
-            .map(|_| Self::alloc_gpu_cache_layer(config, num_blocks.gpu as i64))
-            .collect();
+        num_blocks: usize,
+        idx: usize,
+    ) -> Vec<KVCache> {
+        let device = config.get_kv_devices()[idx];
+        config
+            .get_kv_device_layers(idx)
+            .map(|layer| Self::alloc_gpu_cache_layer(config, layer, num_blocks as i64, device))
+            .collect()
+    }
 
-        let cpu_cache = (0..num_layers)
-            .map(|_| {
-                // TODO: vllm sets pin_memory=True here
+    fn allocate_cpu_group(config: &RllmConfig<TModel>, num_blocks: usize) -> Vec<KVCache> {
+        log::debug!("allocating {num_blocks} CPU KV blocks");
+        // pinned, so that swap copies run asynchronously on cache_stream;
+        // pinning one layer at a time keeps the temporary copy small
+        (0..config.get_num_layers_parallel())
+            .map(|layer| {
                 let device = Device::Cpu;
-                (
-                    Self::alloc_key_block(config, num_blocks.cpu as i64, device),
-                    Self::alloc_value_block(config, num_blocks.cpu as i64, device),
-                )
+                let key = Self::alloc_key_block(config, layer, num_blocks as i64, device);
+                let value = Self::alloc_value_block(config, layer, num_blocks as i64, device);
+                #[cfg(feature = "cuda")]
+                let (key, value) = (kernels::pin_memory(&key), kernels::pin_memory(&value));
+                (key, value)
             })
-            .collect();
-
-        (gpu_cache, cpu_cache)
+            .collect()
     }
 
+    /// Copy the blocks right away; there are no streams to wait for on the CPU.
     #[cfg(not(feature = "cuda"))]
-    fn swap(&self, _src: &[KVCache], _dst: &[KVCache], _src_to_dst: &HashMap<usize, usize>) {
-        let _ = self.cache_stream;
-        panic!("swap not implemented for CPU");
+    fn swap(
+        &self,
+        src: &[KVCache],
+        dst: &[KVCache],
+        src_to_dst: &HashMap<usize, usize>,
+        _events: &[CudaEvent],
+    ) {
+        let _ = (&self.cache_stream, &self.compute_event);
+        for ((src_k_cache, src_v_cache), (dst_k_cache, dst_v_cache)) in src.iter().zip(dst) {
+            kernels::swap_blocks(src_k_cache, dst_k_cache, src_to_dst);
+            kernels::swap_blocks(src_v_cache, dst_v_cache, src_to_dst);
+        }
     }
 
+    /// Copy the blocks on cache_stream, recording `events` after each layer;
+    /// the copies start after the work already submitted to the compute stream.
     #[cfg(feature = "cuda")]
-    fn swap(&self, src: &[KVCache], dst: &[KVCache], src_to_dst: &HashMap<usize, usize>) {
+    fn swap(
+        &self,
+        src: &[KVCache],
+        dst: &[KVCache],
+        src_to_dst: &HashMap<usize, usize>,
+        events: &[CudaEvent],
+    ) {
         let stream = &self.cache_stream;
+        let d = self.gpu_cache[0].0.device();
+        self.compute_event.record(&CudaStream::current(d));
+        self.compute_event.wait(stream);
         for (i, (src_k_cache, src_v_cache)) in src.iter().enumerate() {
             let (dst_k_cache, dst_v_cache) = &dst[i];
-            kernels::swap_blocks(src_k_cache, dst_k_cache, src_to_dst, &self.cache_stream);
-            kernels::swap_blocks(src_v_cache, dst_v_cache, src_to_dst, &self.cache_stream);
-            self.events[i].record(stream);
+            kernels::swap_blocks(src_k_cache, dst_k_cache, src_to_dst, stream);
+            kernels::swap_blocks(src_v_cache, dst_v_cache, src_to_dst, stream);
+            events[i].record(stream);
+        }
+    }
+
+    /// Key and value caches of all layers, on all KV devices.
+    fn all_gpu_tensors(&self) -> impl Iterator<Item = &Tensor> {
+        std::iter::once(&self.gpu_cache)
+            .chain(self.shard_caches.iter())
+            .flat_map(|cache| cache.iter().flat_map(|(k, v)| [k, v]))
+    }
+
+    fn num_gpu_tensors(&self) -> usize {
+        2 * self.gpu_cache.len() * (1 + self.shard_caches.len())
+    }
+
+    /// Keys and values of the given GPU blocks of all layers (and devices), on the CPU.
+    pub fn read_blocks(&self, blocks: &[usize]) -> Vec<Tensor> {
+        let idx = Tensor::from_slice(&Self::to_i64(blocks));
+        self.all_gpu_tensors()
+            .map(|t| t.index_select(0, &idx.to(t.device())).to(Device::Cpu))
+            .collect()
+    }
+
+    /// Whether `data` has the layers and shape of blocks of read_blocks().
+    pub fn matches_blocks(&self, data: &[Tensor]) -> bool {
+        data.len() == self.num_gpu_tensors()
+            && self
+                .all_gpu_tensors()
+                .zip(data)
+                .all(|(t, d)| t.kind() == d.kind() && t.size()[1..] == d.size()[1..])
+    }
+
+    /// Fill the given GPU blocks with NaN, so that reading KV that was never
+    /// written shows up in the outputs.
+    pub fn poison_blocks(&self, blocks: &[usize]) {
+        let idx = Tensor::from_slice(&Self::to_i64(blocks));
+        for t in self.all_gpu_tensors() {
+            let _ = t
+                .shallow_clone()
+                .index_fill_(0, &idx.to(t.device()), f64::NAN);
         }
     }
 
+    /// Inverse of read_blocks().
+    pub fn write_blocks(&self, blocks: &[usize], data: &[Tensor]) {
+        let idx = Tensor::from_slice(&Self::to_i64(blocks));
+        assert!(data.len() == self.num_gpu_tensors());
+        for (t, src) in self.all_gpu_tensors().zip(data) {
+            let device = t.device();
+            let _ = t
+                .shallow_clone()
+                .index_copy_(0, &idx.to(device), &src.to(device));
+        }
+    }
+
+    fn to_i64(blocks: &[usize]) -> Vec<i64> {
+        blocks.iter().map(|&b| b as i64).collect()
+    }
+
     pub fn copy(&mut self, src_to_dsts: &HashMap<usize, Vec<usize>>) {
-        let mut key_caches: Vec<_> = self
-            .gpu_cache
-            .iter()
-            .map(|(key, _)| key.shallow_clone())
-            .collect();
-        let mut value_caches: Vec<_> = self
-            .gpu_cache
-            .iter()
-            .map(|(_, value)| value.shallow_clone())
-            .collect();
-        kernels::copy_blocks(&mut key_caches, &mut value_caches, &src_to_dsts);
+        // copies may read swapped-in blocks, or write to swapped-out ones;
+        // the events of the last layer cover all of them
+        let d = self.gpu_cache[0].0.device();
+        for events in self.round_events() {
+            events.last().unwrap().wait(&CudaStream::current(d));
+        }
+        for cache in std::iter::once(&self.gpu_cache).chain(self.shard_caches.iter()) {
+            let mut key_caches: Vec<_> = cache.iter().map(|(key, _)| key.shallow_clone()).collect();
+            let mut value_caches: Vec<_> = cache
+                .iter()
+                .map(|(_, value)| value.shallow_clone())
+                .collect();
+            kernels::on_device(cache[0].0.device(), || {
+                kernels::copy_blocks(&mut key_caches, &mut value_caches, &src_to_dsts)
+            });
+        }
     }
 
+    /// Bytes of a block on the KV device holding the most.
     pub fn get_cache_block_size(config: &RllmConfig<TModel>) -> usize {
         let block_size = config.model.cache.block_size;
-        let head_size = config.get_head_size();
-        let num_heads = config.get_num_heads_parallel();
-        let num_layers = config.get_num_layers_parallel();
-
-        let key_cache_block = block_size * num_heads * head_size;
-        let value_cache_block = key_cache_block;
-        let total = num_layers * (key_cache_block + value_cache_block);
+        let total: usize = (0..config.get_kv_devices().len())
+            .map(|idx| {
+                config
+                    .get_kv_device_layers(idx)
+                    .map(|layer| {
+                        let shape = config.get_layer_kv_shape_parallel(layer);
+                        let key_cache_block = block_size * shape.num_kv_heads * shape.head_dim;
+                        let value_cache_block = key_cache_block;
+                        key_cache_block + value_cache_block
+                    })
+                    .sum()
+            })
+            .max()
+            .unwrap();
         config.model.dtype.elt_size_in_bytes() * total
     }
 }
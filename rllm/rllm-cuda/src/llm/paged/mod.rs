@@ -4,6 +4,7 @@ mod cuda_stub;
 mod batch_info;
 mod blocks;
 mod cache_engine;
+mod radix;
 
 pub use batch_info::*;
 pub use blocks::*;
@@ -0,0 +1,153 @@
+use rllm::{seq::Token, HashMap};
+
+/// Radix tree over the token prefixes with KV in the cache (as in RadixAttention),
+/// with one edge per full block of tokens.
+///
+/// A node is identified by the hash_block() of its path, so exact lookups are
+/// a hash-map access; the children of a node are only scanned to find the
+/// cached block sharing the longest prefix with a partial block.
+/// Children are kept by the hash of their parent, so removing (evicting) a
+/// node only makes them unreachable until it's cached again.
+#[derive(Default)]
+pub(super) struct PrefixTree {
+    nodes: HashMap<u64, Node>,
+    children: HashMap<u64, Vec<u64>>,
+    // blocks allocated for prompts but not computed yet -> number of such sequences
+    pending: HashMap<u64, usize>,
+}
+
+struct Node {
+    parent: u64,
+    block_idx: usize,
+    tokens: Vec<Token>,
+}
+
+impl PrefixTree {
+    pub fn get(&self, hash: u64) -> Option<usize> {
+        self.nodes.get(&hash).map(|n| n.block_idx)
+    }
+
+    pub fn contains(&self, hash: u64) -> bool {
+        self.nodes.contains_key(&hash)
+    }
+
+    /// Add the node for the full block `tokens` after the prefix with hash `parent` (0 for root).
+    pub fn insert(&mut self, parent: u64, hash: u64, tokens: &[Token], block_idx: usize) {
+        assert!(!self.nodes.contains_key(&hash));
+        self.nodes.insert(
+            hash,
+            Node {
+                parent,
+                block_idx,
+                tokens: tokens.to_vec(),
+            },
+        );
+        self.children.entry(parent).or_default().push(hash);
+    }
+
+    /// The block of node `hash` was moved to `block_idx`.
+    pub fn relocate(&mut self, hash: u64, block_idx: usize) {
+        self.nodes.get_mut(&hash).unwrap().block_idx = block_idx;
+    }
+
+    pub fn remove(&mut self, hash: u64) {
+        if let Some(node) = self.nodes.remove(&hash) {
+            let siblings = self.children.get_mut(&node.parent).unwrap();
+            siblings.retain(|&h| h != hash);
+            if siblings.is_empty() {
+                self.children.remove(&node.parent);
+            }
+        }
+    }
+
+    /// The cached block after prefix `parent` whose tokens share the longest
+    /// prefix with `tokens`, as (block index, length of the shared prefix).
+    pub fn longest_match(&self, parent: u64, tokens: &[Token]) -> Option<(usize, usize)> {
+        self.children
+            .get(&parent)?
+            .iter()
+            .map(|h| {
+                let node = &self.nodes[h];
+                let len = node
+                    .tokens
+                    .iter()
+                    .zip(tokens)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (node.block_idx, len)
+            })
+            .filter(|&(_, len)| len > 0)
+            .max_by_key(|&(_, len)| len)
+    }
+
+    pub fn add_pending(&mut self, hash: u64) {
+        *self.pending.entry(hash).or_insert(0) += 1;
+    }
+
+    pub fn remove_pending(&mut self, hash: u64) {
+        if let Some(n) = self.pending.get_mut(&hash) {
+            *n -= 1;
+            if *n == 0 {
+                self.pending.remove(&hash);
+            }
+        }
+    }
+
+    pub fn is_pending(&self, hash: u64) -> bool {
+        self.pending.contains_key(&hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_match() {
+        let mut tree = PrefixTree::default();
+        tree.insert(0, 1, &[1, 2, 3, 4], 10);
+        tree.insert(0, 2, &[1, 2, 5, 6], 11);
+        tree.insert(1, 3, &[7, 8, 9, 10], 12);
+        assert_eq!(tree.get(1), Some(10));
+        assert_eq!(tree.get(3), Some(12));
+        assert!(tree.contains(2));
+        assert!(!tree.contains(4));
+
+        assert_eq!(tree.longest_match(0, &[1, 2, 5]), Some((11, 3)));
+        assert_eq!(tree.longest_match(0, &[1, 7]).map(|m| m.1), Some(1));
+        assert_eq!(tree.longest_match(1, &[7, 8]), Some((12, 2)));
+        // children of other prefixes are not looked at
+        assert_eq!(tree.longest_match(2, &[7, 8]), None);
+        assert_eq!(tree.longest_match(0, &[2]), None);
+    }
+
+    #[test]
+    fn remove_and_relocate() {
+        let mut tree = PrefixTree::default();
+        tree.insert(0, 1, &[1, 2], 10);
+        tree.insert(1, 2, &[3, 4], 11);
+        tree.relocate(2, 20);
+        assert_eq!(tree.get(2), Some(20));
+        assert_eq!(tree.longest_match(1, &[3]), Some((20, 1)));
+
+        tree.remove(1);
+        assert!(!tree.contains(1));
+        assert_eq!(tree.longest_match(0, &[1, 2]), None);
+        // the child is unreachable from the root, but still there
+        assert_eq!(tree.get(2), Some(20));
+        tree.remove(1);
+    }
+
+    #[test]
+    fn pending() {
+        let mut tree = PrefixTree::default();
+        tree.add_pending(5);
+        tree.add_pending(5);
+        tree.remove_pending(5);
+        assert!(tree.is_pending(5));
+        tree.remove_pending(5);
+        assert!(!tree.is_pending(5));
+        tree.remove_pending(5);
+        assert!(!tree.is_pending(5));
+    }
+}
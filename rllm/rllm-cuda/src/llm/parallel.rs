@@ -0,0 +1,221 @@
+// Tensor parallelism, as in Megatron-LM: the attention heads and MLP features of each
+// layer are split between devices driven from this process, one rank per device.
+// The query/key/value and gate/up projections are split by output features, and
+// the output and down projections by input features, so that each rank computes
+// a partial sum of the output of the layer; these are all-reduced with NCCL.
+// Each rank keeps the KV of its heads (see BatchInfo::shards).
+//
+// With pipeline parallelism, the layers are also split into stages of consecutive
+// layers, each on its own group of tensor-parallel devices. A batch is split into
+// micro-batches (see BatchInfo::micro_batches); all kernels are launched from this
+// thread without waiting, so a stage works on one micro-batch while the next stage
+// works on the previous one.
+
+use super::{config::ModelConfig, kernels::on_device, paged::BatchInfo};
+use anyhow::Result;
+use std::{ops::Range, rc::Rc};
+use tch::{
+    nn::{Path, VarStore},
+    Device, Tensor,
+};
+
+#[cfg(feature = "nccl")]
+use tch_cuda::NcclComm;
+
+#[cfg(not(feature = "nccl"))]
+struct NcclComm {}
+
+#[cfg(not(feature = "nccl"))]
+impl NcclComm {
+    fn new(_devices: &[Device]) -> Self {
+        panic!("tensor parallelism needs the nccl feature")
+    }
+
+    fn all_reduce(&self, _tensors: &mut [Tensor]) {}
+}
+
+/// Stages of the model, each split between tensor-parallel ranks.
+pub struct Pipeline {
+    stages: Vec<TensorParallel>,
+    stage_layers: usize,
+}
+
+impl Pipeline {
+    /// `devices` are the devices of the ranks of each stage in turn (see get_kv_devices()).
+    pub fn new(cfg: &ModelConfig, devices: &[Device], num_stages: usize) -> Self {
+        let size = devices.len() / num_stages;
+        Self {
+            stages: devices
+                .chunks(size)
+                .map(|devices| TensorParallel::new(cfg, devices))
+                .collect(),
+            stage_layers: cfg.num_hidden_layers / num_stages,
+        }
+    }
+
+    pub fn num_stages(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn stage(&self, stage: usize) -> &TensorParallel {
+        &self.stages[stage]
+    }
+
+    /// Layers of each stage, in order.
+    pub fn stage_layers(&self) -> Vec<Range<usize>> {
+        (0..self.num_stages())
+            .map(|s| s * self.stage_layers..(s + 1) * self.stage_layers)
+            .collect()
+    }
+
+    pub fn stage_of_layer(&self, layer: usize) -> usize {
+        layer / self.stage_layers
+    }
+
+    /// Variables not in the VarStore of the model, in the order of the devices.
+    pub fn stores(&self) -> impl Iterator<Item = &VarStore> {
+        self.stages.iter().flat_map(|s| s.stores.iter())
+    }
+
+    pub fn stores_mut(&mut self) -> impl Iterator<Item = &mut VarStore> {
+        self.stages.iter_mut().flat_map(|s| s.stores.iter_mut())
+    }
+}
+
+/// Ranks of a stage of the model; on the model device, rank 0 keeps its shards
+/// in the VarStore of the model.
+pub struct TensorParallel {
+    // see ModelConfig::shard()
+    configs: Vec<Rc<ModelConfig>>,
+    // variables of the ranks not on the model device, i.e., of the last stores.len() ranks
+    stores: Vec<VarStore>,
+    comm: Option<Rc<NcclComm>>,
+}
+
+impl TensorParallel {
+    pub fn new(cfg: &ModelConfig, devices: &[Device]) -> Self {
+        let size = devices.len();
+        Self {
+            configs: devices
+                .iter()
+                .map(|&d| Rc::new(cfg.shard(size, d)))
+                .collect(),
+            stores: devices
+                .iter()
+                .filter(|&&d| d != cfg.device)
+                .map(|&d| VarStore::new(d))
+                .collect(),
+            comm: (size > 1).then(|| Rc::new(NcclComm::new(devices))),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.configs.len()
+    }
+
+    pub fn config(&self, rank: usize) -> &Rc<ModelConfig> {
+        &self.configs[rank]
+    }
+
+    // ranks before this one are in the VarStore of the model
+    fn first_stored(&self) -> usize {
+        self.size() - self.stores.len()
+    }
+
+    fn store_path<'a>(store: &'a VarStore, vb: &Path) -> Path<'a> {
+        vb.components().fold(store.root(), |p, c| &p / c)
+    }
+
+    /// The path `vb` of the model in the VarStore of rank 0.
+    pub fn rank0_path<'a>(&'a self, vb: Path<'a>) -> Path<'a> {
+        if self.first_stored() == 0 {
+            Self::store_path(&self.stores[0], &vb)
+        } else {
+            vb
+        }
+    }
+
+    /// Build a part of the model on each rank with `load(vb, config, rank)`, where
+    /// `vb` is the given path for rank 0 on the model device, and the same path
+    /// in the VarStore of the rank for the others.
+    pub fn load<T>(
+        &self,
+        vb: Path,
+        load: impl Fn(Path, &Rc<ModelConfig>, usize) -> Result<T>,
+    ) -> Result<Sharded<T>> {
+        let first = self.first_stored();
+        let mut ranks = Vec::with_capacity(self.size());
+        for (idx, store) in self.stores.iter().enumerate() {
+            let rank = first + idx;
+            let path = Self::store_path(store, &vb);
+            ranks.push(load(path, &self.configs[rank], rank)?);
+        }
+        if first > 0 {
+            ranks.insert(0, load(vb, &self.configs[0], 0)?);
+        }
+        Ok(Sharded {
+            ranks,
+            comm: self.comm.clone(),
+        })
+    }
+}
+
+/// Run the model from the first to the last layer on `x` (the embeddings
+/// of the tokens of the batch), with `stage(stage, x, batch_info)` running
+/// the layers of a stage, on its first device. Returns the output on the device of `x`.
+pub fn run_pipeline(
+    x: &Tensor,
+    batch_info: &mut BatchInfo,
+    stage: impl Fn(usize, Tensor, &mut BatchInfo) -> Tensor,
+) -> Tensor {
+    if batch_info.micro_batches.is_empty() {
+        return stage(0, x.shallow_clone(), batch_info);
+    }
+    let device = x.device();
+    let mut xs = batch_info
+        .micro_batches
+        .iter()
+        .map(|mb| x.narrow(1, mb.rows.start, mb.rows.end - mb.rows.start))
+        .collect::<Vec<_>>();
+    let num_stages = batch_info.micro_batches[0].stages.len();
+    for idx in 0..num_stages {
+        for (mb, x) in batch_info.micro_batches.iter_mut().zip(xs.iter_mut()) {
+            let info = &mut mb.stages[idx];
+            let stage_device = info.device();
+            *x = on_device(stage_device, || stage(idx, x.to_device(stage_device), info));
+        }
+    }
+    let xs = xs.iter().map(|x| x.to_device(device)).collect::<Vec<_>>();
+    Tensor::cat(&xs, 1)
+}
+
+/// A part of the model split between the tensor-parallel ranks.
+pub struct Sharded<T> {
+    ranks: Vec<T>,
+    comm: Option<Rc<NcclComm>>,
+}
+
+impl<T> Sharded<T> {
+    /// Run `f` on each rank, with `x` and the batch on the device of the rank,
+    /// and sum the results; returns the sum on the model device.
+    pub fn forward(
+        &self,
+        x: &Tensor,
+        batch_info: &mut BatchInfo,
+        f: impl Fn(&T, &Tensor, &mut BatchInfo) -> Tensor,
+    ) -> Tensor {
+        let y = f(&self.ranks[0], x, batch_info);
+        let comm = match self.comm.as_ref() {
+            Some(comm) => comm,
+            None => return y,
+        };
+        let mut ys = vec![y.contiguous()];
+        for (rank, shard) in self.ranks[1..].iter().zip(batch_info.shards.iter_mut()) {
+            let device = shard.device();
+            let x = x.to_device(device);
+            ys.push(on_device(device, || f(rank, &x, shard).contiguous()));
+        }
+        comm.all_reduce(&mut ys);
+        ys.swap_remove(0)
+    }
+}
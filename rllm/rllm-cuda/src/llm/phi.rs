@@ -47,10 +47,78 @@ impl RllmModelConfig for PhiConfig {
             num_key_value_heads: self.n_head,
             layer_norm_eps: self.layer_norm_epsilon,
             rope_theta: 10000.0,
+            rope_scaling: None,
+            alibi_bias_max: None,
             head_dim: self.n_embd / self.n_head,
             rotary_dim: self.rotary_dim,
-            dtype: ModelConfig::dtype_from_str(common.dtype, &self.torch_dtype),
+            layer_kv_shapes: None,
+            dtype: ModelConfig::dtype_from_str(common.dtype, common.device, &self.torch_dtype),
             device: common.device,
+            sliding_window: None,
+            quantization: None,
+            moe: None,
+            logit_softcap: None,
+            lora: Default::default(),
+            medusa: None,
+            profile_step_no: 0,
+            cache: Default::default(),
+        }
+    }
+}
+
+/// Phi-2, in the transformers layout (PhiForCausalLM).
+/// https://huggingface.co/microsoft/phi-2
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Phi2Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    pub(crate) num_key_value_heads: Option<usize>,
+    pub(crate) max_position_embeddings: usize,
+    pub(crate) partial_rotary_factor: f64,
+    pub(crate) layer_norm_eps: f64,
+    #[serde(default = "default_rope_theta")]
+    pub(crate) rope_theta: f32,
+    pub(crate) torch_dtype: String,
+}
+
+fn default_rope_theta() -> f32 {
+    10000.0
+}
+
+impl RllmModelConfig for Phi2Config {
+    fn into_config(self, common: CommonModelConfig) -> ModelConfig {
+        let mut meta = common.meta.clone();
+        meta.vocab_size = self.vocab_size;
+        meta.tok_vocab_size = self.vocab_size;
+        meta.max_sequence_length = self.max_position_embeddings;
+        let head_dim = self.hidden_size / self.num_attention_heads;
+        ModelConfig {
+            model_type: ModelType::Phi2,
+            meta,
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
+            layer_norm_eps: self.layer_norm_eps,
+            rope_theta: self.rope_theta,
+            rope_scaling: None,
+            alibi_bias_max: None,
+            head_dim,
+            // only the leading part of each head is rotated
+            rotary_dim: (head_dim as f64 * self.partial_rotary_factor) as usize,
+            layer_kv_shapes: None,
+            dtype: ModelConfig::dtype_from_str(common.dtype, common.device, &self.torch_dtype),
+            device: common.device,
+            sliding_window: None,
+            quantization: None,
+            moe: None,
+            logit_softcap: None,
+            lora: Default::default(),
+            medusa: None,
             profile_step_no: 0,
             cache: Default::default(),
         }
@@ -210,10 +278,13 @@ impl MixFormerSequentialForCausalLM {
 
 impl TModelInner for MixFormerSequentialForCausalLM {
     fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
-        let mut xs = self.embedding.forward(&batch_info.tokens);
+        let mut xs = batch_info.override_embeddings(self.embedding.forward(&batch_info.tokens));
         for block in self.blocks.iter() {
             xs = block.forward(&xs, batch_info);
         }
+        if batch_info.wants_hidden_states() {
+            batch_info.hidden_states = Some(self.head.ln.forward(&xs));
+        }
         let r = self.head.forward(&xs);
 
         // it should approximately match...
@@ -231,3 +302,133 @@ impl TModelInner for MixFormerSequentialForCausalLM {
         batch_info.extract_positions(&r)
     }
 }
+
+#[derive(Debug)]
+struct PhiAttention {
+    q_proj: nn::Linear,
+    k_proj: nn::Linear,
+    v_proj: nn::Linear,
+    dense: nn::Linear,
+    rotary_emb: RotaryEmbedding,
+    config: Rc<ModelConfig>,
+    block_idx: usize,
+}
+
+impl PhiAttention {
+    fn new(cfg: &Rc<ModelConfig>, block_idx: usize, vb: Path) -> Self {
+        let kv_size = cfg.num_key_value_heads * cfg.head_dim;
+        Self {
+            q_proj: linear(cfg.hidden_size, cfg.hidden_size, &vb / "q_proj"),
+            k_proj: linear(cfg.hidden_size, kv_size, &vb / "k_proj"),
+            v_proj: linear(cfg.hidden_size, kv_size, &vb / "v_proj"),
+            dense: linear(cfg.hidden_size, cfg.hidden_size, &vb / "dense"),
+            rotary_emb: RotaryEmbedding::new(cfg),
+            config: cfg.clone(),
+            block_idx,
+        }
+    }
+
+    fn forward(&self, xs: &Tensor, batch_info: &mut BatchInfo) -> Tensor {
+        let (seq_len, _hidden_size) = xs.size2().unwrap();
+        let q = self.q_proj.forward(xs);
+        let k = self.k_proj.forward(xs);
+        let v = self
+            .v_proj
+            .forward(xs)
+            .reshape(&[seq_len, -1, self.config.head_dim as i64]);
+        let (q, k) = self.rotary_emb.forward(&batch_info.positions, &q, &k);
+        let y = varlen_attn(&self.config, q, k, v, batch_info, self.block_idx);
+        self.dense.forward(&y)
+    }
+}
+
+#[derive(Debug)]
+struct PhiDecoderLayer {
+    input_layernorm: nn::LayerNorm,
+    self_attn: PhiAttention,
+    mlp: MLP,
+}
+
+impl PhiDecoderLayer {
+    fn new(cfg: &Rc<ModelConfig>, mut vb: Path, block_idx: usize) -> Self {
+        let input_layernorm = layer_norm(&vb / "input_layernorm", cfg);
+        let self_attn = PhiAttention::new(cfg, block_idx, &vb / "self_attn");
+        let mlp = MLP::new(cfg, &vb / "mlp");
+        // this optimizes memory usage
+        vb.set_kind(cfg.dtype);
+        Self {
+            input_layernorm,
+            self_attn,
+            mlp,
+        }
+    }
+
+    fn forward(&self, xs: &Tensor, batch_info: &mut BatchInfo) -> Tensor {
+        // attention and MLP run in parallel off the same normalized input
+        let residual = xs;
+        let xs = xs.apply(&self.input_layernorm);
+        let attn_outputs = self.self_attn.forward(&xs, batch_info);
+        let feed_forward_hidden_states = self.mlp.forward(&xs);
+        attn_outputs + feed_forward_hidden_states + residual
+    }
+}
+
+#[derive(Debug)]
+pub struct PhiForCausalLM {
+    embed_tokens: nn::Embedding,
+    layers: Vec<PhiDecoderLayer>,
+    final_layernorm: nn::LayerNorm,
+    lm_head: nn::Linear,
+    config: Rc<ModelConfig>,
+}
+
+impl PhiForCausalLM {
+    pub fn new(cfg: &Rc<ModelConfig>, vb0: Path) -> Self {
+        let vb = &vb0 / "model";
+        let embed_tokens = nn::embedding(
+            &vb / "embed_tokens",
+            cfg.meta.vocab_size as i64,
+            cfg.hidden_size as i64,
+            Default::default(),
+        );
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| PhiDecoderLayer::new(cfg, &vb / "layers" / i, i))
+            .collect();
+        let final_layernorm = layer_norm(&vb / "final_layernorm", cfg);
+        let lm_head = linear(cfg.hidden_size, cfg.meta.vocab_size, &vb0 / "lm_head");
+        Self {
+            embed_tokens,
+            layers,
+            final_layernorm,
+            lm_head,
+            config: cfg.clone(),
+        }
+    }
+}
+
+impl TModelInner for PhiForCausalLM {
+    fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
+        let mut xs = batch_info.override_embeddings(self.embed_tokens.forward(&batch_info.tokens));
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs, batch_info);
+        }
+        let xs = self.final_layernorm.forward(&xs);
+        if batch_info.wants_hidden_states() {
+            batch_info.hidden_states = Some(xs.shallow_clone());
+        }
+        let xs = batch_info.extract_positions(&xs);
+        let r = self.lm_head.forward(&xs);
+
+        // the embedding matrix is padded past the tokenizer vocabulary
+        let tok_size = self.config.meta.tok_vocab_size as i64;
+        if r.size()[1] < tok_size {
+            panic!(
+                "unexpected logits size: {:?} ({}/{})",
+                r.size(),
+                tok_size,
+                self.config.meta.vocab_size
+            );
+        }
+        r.i((.., 0..tok_size))
+    }
+}
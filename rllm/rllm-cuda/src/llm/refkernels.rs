@@ -9,51 +9,52 @@ pub fn reshape_and_cache(
     value_cache: &mut Tensor, // [num_blocks, num_heads, head_size, block_size]
     slot_mapping: &Tensor,    // [num_tokens], int
 ) {
-    let (_num_blocks, num_heads, head_size_x, block_size, x) = key_cache.size5().unwrap();
+    let (_num_blocks, num_heads, head_size_x, _block_size, x) = key_cache.size5().unwrap();
 
-    let slot_mapping = to_vec1::<i32>(slot_mapping);
+    let (blocks, offsets) = split_slots(key_cache, slot_mapping);
     let key_reshaped = key.reshape(&[-1, num_heads, head_size_x, x]);
 
-    for (idx, slot) in slot_mapping.iter().enumerate() {
-        let slot = *slot as i64;
-        let idx = idx as i64;
-        let block_idx = slot / block_size;
-        let block_off = slot % block_size;
-
-        key_cache
-            .i((block_idx, .., .., block_off, ..))
-            .copy_(&key_reshaped.i(idx));
-        value_cache
-            .i((block_idx, .., .., block_off))
-            .copy_(&value.i(idx));
-    }
+    let _ = key_cache.index_put_(
+        &[Some(&blocks), None, None, Some(&offsets)],
+        &key_reshaped,
+        false,
+    );
+    let _ = value_cache.index_put_(&[Some(&blocks), None, None, Some(&offsets)], value, false);
+}
+
+/// Block indices and offsets in the block of `slot_mapping`; indexing the cache with
+/// them, rather than slot by slot, avoids copying the slots to the host (eg., from MPS).
+fn split_slots(key_cache: &Tensor, slot_mapping: &Tensor) -> (Tensor, Tensor) {
+    let block_size = key_cache.size()[3];
+    let slots = slot_mapping.to_kind(Kind::Int64);
+    (
+        slots.divide_scalar_mode(block_size, "floor"),
+        slots.remainder(block_size),
+    )
 }
 
 pub fn gather_cached_kv(
-    key: &mut Tensor,      // [num_tokens, num_heads, head_size]
-    value: &mut Tensor,    // [num_tokens, num_heads, head_size]
-    key_cache: &Tensor,    // [num_blocks, num_heads, head_size/x, block_size, x]
-    value_cache: &Tensor,  // [num_blocks, num_heads, head_size, block_size]
+    key: &mut Tensor,      // [num_tokens, num_kv_heads, head_size]
+    value: &mut Tensor,    // [num_tokens, num_kv_heads, head_size]
+    key_cache: &Tensor,    // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+    value_cache: &Tensor,  // [num_blocks, num_kv_heads, head_size, block_size]
     slot_mapping: &Tensor, // [num_tokens], int
 ) {
-    let (_num_blocks, num_heads, head_size_x, block_size, x) = key_cache.size5().unwrap();
-
-    let slot_mapping = to_vec1::<i32>(slot_mapping);
-    let key_reshaped = key.view([-1, num_heads, head_size_x, x]);
-
-    for (idx, slot) in slot_mapping.iter().enumerate() {
-        let slot = *slot as i64;
-        let idx = idx as i64;
-        let block_idx = slot / block_size;
-        let block_off = slot % block_size;
-
-        key_reshaped
-            .i(idx)
-            .copy_(&key_cache.i((block_idx, .., .., block_off, ..)));
-        value
-            .i(idx)
-            .copy_(&value_cache.i((block_idx, .., .., block_off)));
-    }
+    let (_num_blocks, num_heads, head_size_x, _block_size, x) = key_cache.size5().unwrap();
+
+    let (blocks, offsets) = split_slots(key_cache, slot_mapping);
+    let mut key_reshaped = key.view([-1, num_heads, head_size_x, x]);
+
+    key_reshaped.copy_(&key_cache.index(&[Some(&blocks), None, None, Some(&offsets)]));
+    value.copy_(&value_cache.index(&[Some(&blocks), None, None, Some(&offsets)]));
+}
+
+/// Repeat each KV head of x ([num_tokens, num_kv_heads, head_size]), so there's
+/// one per query head, as flash-attn does implicitly for grouped-query attention.
+fn repeat_kv(x: &Tensor, num_heads: i64) -> Tensor {
+    let num_kv_heads = x.size()[1];
+    assert!(num_heads % num_kv_heads == 0);
+    x.repeat_interleave_self_int(num_heads / num_kv_heads, Some(1), None)
 }
 
 pub fn varlen_attn(
@@ -66,6 +67,36 @@ pub fn varlen_attn(
     max_seqlen_k: usize,
     softmax_scale: f32,
     causal: bool,
+    window_size: Option<usize>,
+) -> Tensor {
+    varlen_attn_alibi(
+        q,
+        k,
+        v,
+        seqlens_q,
+        seqlens_k,
+        max_seqlen_q,
+        max_seqlen_k,
+        softmax_scale,
+        causal,
+        window_size,
+        None,
+    )
+}
+
+/// Like varlen_attn(), with scores of head h biased by slope[h] * (key pos - query pos).
+pub fn varlen_attn_alibi(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    seqlens_q: &Tensor,
+    seqlens_k: &Tensor,
+    max_seqlen_q: usize,
+    max_seqlen_k: usize,
+    softmax_scale: f32,
+    causal: bool,
+    window_size: Option<usize>,
+    alibi_slopes: Option<&Tensor>, // f32, [num_heads]
 ) -> Tensor {
     let seqlens_q = to_vec1::<i32>(seqlens_q);
     let seqlens_k = to_vec1::<i32>(seqlens_k);
@@ -92,8 +123,8 @@ pub fn varlen_attn(
         assert!(len_k <= max_seqlen_k as i64);
 
         let q = q.i((ptr_q..ptr_q + len_q, .., ..)).transpose(0, 1);
-        let k = k.i((ptr_k..ptr_k + len_k, .., ..)).transpose(0, 1);
-        let v = v.i((ptr_k..ptr_k + len_k, .., ..)).transpose(0, 1);
+        let k = repeat_kv(&k.i((ptr_k..ptr_k + len_k, .., ..)), num_heads).transpose(0, 1);
+        let v = repeat_kv(&v.i((ptr_k..ptr_k + len_k, .., ..)), num_heads).transpose(0, 1);
 
         assert!(q.size() == [num_heads, len_q, head_dim]);
         assert!(k.size() == [num_heads, len_k, head_dim]);
@@ -106,6 +137,24 @@ pub fn varlen_attn(
         let _ = attn_bias
             .i((.., len_k - len_q..))
             .masked_fill_(&mask, f64::NEG_INFINITY);
+        if let Some(w) = window_size {
+            // query i is at key position len_k - len_q + i, and sees the w keys ending there
+            let q_pos = Tensor::arange(len_q, (Kind::Int64, q.device())).unsqueeze(1);
+            let k_pos = Tensor::arange(len_k, (Kind::Int64, q.device())).unsqueeze(0);
+            let outside = k_pos.le_tensor(&(q_pos + (len_k - len_q - w as i64)));
+            let _ = attn_bias.masked_fill_(&outside, f64::NEG_INFINITY);
+        }
+        let attn_bias = match alibi_slopes {
+            // slope * (key position - query position), per head
+            Some(slopes) => {
+                let q_pos = Tensor::arange(len_q, (Kind::Float, q.device())).unsqueeze(1);
+                let k_pos = Tensor::arange(len_k, (Kind::Float, q.device())).unsqueeze(0);
+                let dist = k_pos - (q_pos + (len_k - len_q) as f64);
+                let alibi = slopes.reshape(&[num_heads, 1, 1]) * dist.unsqueeze(0);
+                attn_bias.unsqueeze(0) + alibi.to_kind(q.kind())
+            }
+            None => attn_bias,
+        };
 
         let attn0 = Tensor::scaled_dot_product_attention(
             &q,
@@ -147,6 +196,103 @@ pub fn varlen_attn(
     attn
 }
 
+/// Attention of `q` [len_q, num_heads, head_dim] over `k` and `v`
+/// [len_k, num_kv_heads, head_dim], each query seeing the keys set in `mask`
+/// (bool, [len_q, len_k]).
+pub fn masked_attn(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: &Tensor,
+    softmax_scale: f32,
+) -> Tensor {
+    let (_len_q, num_heads, _head_dim) = q.size3().unwrap();
+    let (len_q, len_k) = mask.size2().unwrap();
+    let q = q.transpose(0, 1);
+    let k = repeat_kv(k, num_heads).transpose(0, 1);
+    let v = repeat_kv(v, num_heads).transpose(0, 1);
+    let attn_bias = Tensor::zeros(&[len_q, len_k], (q.kind(), q.device()))
+        .masked_fill(&mask.logical_not(), f64::NEG_INFINITY);
+    Tensor::scaled_dot_product_attention(
+        &q,
+        &k,
+        &v,
+        Some(&attn_bias),
+        0.0,
+        false,
+        softmax_scale as f64,
+    )
+    .transpose(0, 1)
+}
+
+/// Attention of the single query of each sequence over its cached keys and values,
+/// like the paged attention kernel, but gathering the blocks of all sequences and
+/// masking the positions past the context length of each.
+pub fn paged_attention_v1(
+    out: &mut Tensor,     // [num_seqs, num_heads, head_size]
+    query: &Tensor,       // [num_seqs, num_heads, head_size]
+    key_cache: &Tensor,   // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+    value_cache: &Tensor, // [num_blocks, num_kv_heads, head_size, block_size]
+    num_kv_heads: usize,
+    scale: f32,
+    block_tables: &Tensor, // [num_seqs, max_num_blocks_per_seq], int
+    context_lens: &Tensor, // [num_seqs], int
+    block_size: usize,
+    max_context_len: usize,
+    alibi_slopes: Option<&Tensor>, // f32, [num_heads]
+    sliding_window: Option<usize>,
+) {
+    let (num_seqs, num_heads, head_size) = query.size3().unwrap();
+    let num_kv_heads = num_kv_heads as i64;
+    let max_len = block_tables.size()[1] * block_size as i64;
+    let len = max_context_len as i64;
+    let device = query.device();
+
+    // tables are padded with block 0, masked below like the rest of the last block
+    let blocks = block_tables.reshape(&[-1]).to_kind(Kind::Int64);
+    let gather = |cache: &Tensor, perm: &[i64]| {
+        let kv = cache
+            .index_select(0, &blocks)
+            .permute(perm)
+            .reshape(&[num_seqs, max_len, num_kv_heads, head_size])
+            .narrow(1, 0, len);
+        // [num_seqs, num_heads, len, head_size]
+        kv.repeat_interleave_self_int(num_heads / num_kv_heads, Some(2), None)
+            .transpose(1, 2)
+    };
+    let k = gather(key_cache, &[0, 3, 1, 2, 4]);
+    let v = gather(value_cache, &[0, 3, 1, 2]);
+
+    let pos = Tensor::arange(len, (Kind::Int64, device)).unsqueeze(0);
+    let ctx = context_lens.to_kind(Kind::Int64).unsqueeze(1);
+    let mut outside = pos.ge_tensor(&ctx);
+    if let Some(w) = sliding_window {
+        outside = outside.logical_or(&pos.lt_tensor(&(&ctx - w as i64)));
+    }
+    let attn_bias = Tensor::zeros(&[num_seqs, len], (Kind::Float, device))
+        .masked_fill(&outside, f64::NEG_INFINITY)
+        .view([num_seqs, 1, 1, len]);
+    let attn_bias = match alibi_slopes {
+        // slope * (key position - query position), per head
+        Some(slopes) => {
+            let dist = (pos - (ctx - 1)).to_kind(Kind::Float);
+            attn_bias + slopes.reshape(&[1, num_heads, 1, 1]) * dist.view([num_seqs, 1, 1, len])
+        }
+        None => attn_bias,
+    };
+
+    let y = Tensor::scaled_dot_product_attention(
+        &query.unsqueeze(2),
+        &k,
+        &v,
+        Some(attn_bias.to_kind(query.kind())),
+        0.0,
+        false,
+        scale as f64,
+    );
+    out.copy_(&y.reshape(&[num_seqs, num_heads, head_size]));
+}
+
 pub fn rotary_embedding(
     positions: &Tensor,  // [num_tokens]
     query0: &mut Tensor, // [num_tokens, num_heads * head_size]
@@ -209,21 +355,313 @@ pub fn rotary_embedding(
     key0.copy_(&key_rot.reshape(key0.size()));
 }
 
+/// Copy whole blocks (eg., shared blocks about to be written by one of their owners).
 #[allow(dead_code)]
 pub fn copy_blocks(
-    _key_caches: &mut Vec<Tensor>,
-    _value_caches: &mut Vec<Tensor>,
-    _block_mapping: &HashMap<usize, Vec<usize>>,
+    key_caches: &mut Vec<Tensor>,
+    value_caches: &mut Vec<Tensor>,
+    block_mapping: &HashMap<usize, Vec<usize>>,
 ) {
-    todo!()
+    let (srcs, dsts): (Vec<i64>, Vec<i64>) = block_mapping
+        .iter()
+        .flat_map(|(&src, dsts)| dsts.iter().map(move |&dst| (src as i64, dst as i64)))
+        .unzip();
+    let srcs = Tensor::from_slice(&srcs);
+    let dsts = Tensor::from_slice(&dsts);
+    for cache in key_caches.iter_mut().chain(value_caches.iter_mut()) {
+        let device = cache.device();
+        let blocks = cache.index_select(0, &srcs.to(device));
+        let _ = cache.index_copy_(0, &dsts.to(device), &blocks);
+    }
 }
 
-#[allow(dead_code)]
-pub fn swap_blocks(
-    _src: &Tensor,
-    _dst: &Tensor,
-    _block_mapping: &HashMap<usize, usize>,
-    // _stream: &CudaStream,
-) {
-    todo!()
+/// Copy blocks between caches (eg., the GPU and CPU ones); synchronous, unlike the kernel.
+pub fn swap_blocks(src: &Tensor, dst: &Tensor, block_mapping: &HashMap<usize, usize>) {
+    assert!(src.size()[1..] == dst.size()[1..]);
+    let (srcs, dsts): (Vec<i64>, Vec<i64>) = block_mapping
+        .iter()
+        .map(|(&s, &d)| (s as i64, d as i64))
+        .unzip();
+    let blocks = src.index_select(0, &Tensor::from_slice(&srcs).to(src.device()));
+    let mut dst = dst.shallow_clone();
+    let _ = dst.index_copy_(
+        0,
+        &Tensor::from_slice(&dsts).to(dst.device()),
+        &blocks.to(dst.device()),
+    );
+}
+
+pub fn gptq_gemm(
+    a: &Tensor,       // [..., in_features], half
+    qweight: &Tensor, // [in_features / 8, out_features], int
+    qzeros: &Tensor,  // [num_groups, out_features / 8], int
+    scales: &Tensor,  // [num_groups, out_features], half
+    g_idx: &Tensor,   // [in_features], int
+) -> Tensor {
+    // 8 nibbles per int, lowest first
+    let shifts = Tensor::arange_start_step(0, 32, 4, (Kind::Int, a.device()));
+    let (k8, n) = qweight.size2().unwrap();
+    // [in_features, out_features]
+    let q = qweight
+        .unsqueeze(1)
+        .bitwise_right_shift(&shifts.view([1, 8, 1]))
+        .bitwise_and(0xf)
+        .reshape(&[k8 * 8, n]);
+    // [num_groups, out_features]; zero points are stored minus one
+    let zeros = qzeros
+        .unsqueeze(2)
+        .bitwise_right_shift(&shifts.view([1, 1, 8]))
+        .bitwise_and(0xf)
+        .reshape(&[qzeros.size()[0], n])
+        + 1;
+    let g_idx = g_idx.to_kind(Kind::Int64);
+    let q = q - zeros.index_select(0, &g_idx);
+    let w = q.to_kind(scales.kind()) * scales.index_select(0, &g_idx);
+    a.matmul(&w)
+}
+
+pub fn awq_gemm(
+    a: &Tensor,       // [..., in_features], half
+    qweight: &Tensor, // [in_features, out_features / 8], int
+    scales: &Tensor,  // [num_groups, out_features], half
+    qzeros: &Tensor,  // [num_groups, out_features / 8], int
+) -> Tensor {
+    // nibble holding each of 8 consecutive output columns
+    let order = Tensor::from_slice(&[0i64, 4, 1, 5, 2, 6, 3, 7]).to(a.device());
+    let shifts = (order * 4).to_kind(Kind::Int);
+    let unpack = |t: &Tensor| {
+        let (rows, n8) = t.size2().unwrap();
+        t.unsqueeze(2)
+            .bitwise_right_shift(&shifts.view([1, 1, 8]))
+            .bitwise_and(0xf)
+            .reshape(&[rows, n8 * 8])
+    };
+    let q = unpack(qweight);
+    let zeros = unpack(qzeros);
+    let group_size = q.size()[0] / zeros.size()[0];
+    let zeros = zeros.repeat_interleave_self_int(group_size, Some(0), None);
+    let scales = scales.repeat_interleave_self_int(group_size, Some(0), None);
+    let w = (q - zeros).to_kind(scales.kind()) * scales;
+    a.matmul(&w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::util::check_all_close;
+
+    fn randn(size: &[i64]) -> Tensor {
+        Tensor::randn(size, (Kind::Float, tch::Device::Cpu))
+    }
+
+    fn seqlens(lens: &[i32]) -> Tensor {
+        let mut ptrs = vec![0];
+        for l in lens {
+            ptrs.push(ptrs.last().unwrap() + l);
+        }
+        Tensor::from_slice(&ptrs)
+    }
+
+    // softmax(q k^T * scale) v, with query i seeing the keys up to len_k - len_q + i
+    fn naive_attn(q: &Tensor, k: &Tensor, v: &Tensor, scale: f64) -> Tensor {
+        let (len_q, num_heads, _) = q.size3().unwrap();
+        let len_k = k.size()[0];
+        let k = repeat_kv(k, num_heads).transpose(0, 1);
+        let v = repeat_kv(v, num_heads).transpose(0, 1);
+        let scores = q.transpose(0, 1).matmul(&k.transpose(1, 2)) * scale;
+        let q_pos = Tensor::arange(len_q, (Kind::Int64, tch::Device::Cpu)).unsqueeze(1);
+        let k_pos = Tensor::arange(len_k, (Kind::Int64, tch::Device::Cpu)).unsqueeze(0);
+        let future = k_pos.gt_tensor(&(q_pos + (len_k - len_q)));
+        scores
+            .masked_fill(&future, f64::NEG_INFINITY)
+            .softmax(-1, Kind::Float)
+            .matmul(&v)
+            .transpose(0, 1)
+    }
+
+    #[test]
+    fn cache_roundtrip() {
+        tch::manual_seed(1);
+        let (num_heads, head_size, x, block_size) = (2, 8, 4, 4);
+        let key = randn(&[3, num_heads, head_size]);
+        let value = randn(&[3, num_heads, head_size]);
+        let mut key_cache = Tensor::zeros(
+            &[3, num_heads, head_size / x, block_size, x],
+            (Kind::Float, tch::Device::Cpu),
+        );
+        let mut value_cache = Tensor::zeros(
+            &[3, num_heads, head_size, block_size],
+            (Kind::Float, tch::Device::Cpu),
+        );
+        let slots = Tensor::from_slice(&[5i32, 0, 11]);
+        reshape_and_cache(&key, &value, &mut key_cache, &mut value_cache, &slots);
+
+        // slot 5 is block 1, offset 1
+        check_all_close(
+            &key_cache
+                .i((1, .., .., 1, ..))
+                .reshape(&[num_heads, head_size]),
+            &key.i(0),
+            0.0,
+        );
+        check_all_close(&value_cache.i((1, .., .., 1)), &value.i(0), 0.0);
+
+        let mut key2 = key.zeros_like();
+        let mut value2 = value.zeros_like();
+        gather_cached_kv(&mut key2, &mut value2, &key_cache, &value_cache, &slots);
+        check_all_close(&key2, &key, 0.0);
+        check_all_close(&value2, &value, 0.0);
+    }
+
+    #[test]
+    fn swap_and_copy() {
+        tch::manual_seed(2);
+        let src = randn(&[3, 2, 4]);
+        let dst = Tensor::zeros(&[2, 2, 4], (Kind::Float, tch::Device::Cpu));
+        let mapping: HashMap<usize, usize> = [(2, 0), (0, 1)].into_iter().collect();
+        swap_blocks(&src, &dst, &mapping);
+        check_all_close(&dst.i(0), &src.i(2), 0.0);
+        check_all_close(&dst.i(1), &src.i(0), 0.0);
+
+        let mut keys = vec![src.copy()];
+        let mut values = vec![src.copy()];
+        let mapping: HashMap<usize, Vec<usize>> = [(0, vec![1, 2])].into_iter().collect();
+        copy_blocks(&mut keys, &mut values, &mapping);
+        for cache in keys.iter().chain(values.iter()) {
+            check_all_close(&cache.i(1), &src.i(0), 0.0);
+            check_all_close(&cache.i(2), &src.i(0), 0.0);
+        }
+    }
+
+    #[test]
+    fn varlen_and_paged_attn() {
+        tch::manual_seed(3);
+        let (num_heads, num_kv_heads, head_size, x, block_size) = (4, 2, 8, 4, 4);
+        let scale = 1.0 / (head_size as f64).sqrt();
+        // the second sequence has 3 cached tokens before its 2 new ones
+        let q = randn(&[5, num_heads, head_size]);
+        let k = randn(&[8, num_kv_heads, head_size]);
+        let v = randn(&[8, num_kv_heads, head_size]);
+
+        let y = varlen_attn(
+            &q,
+            &k,
+            &v,
+            &seqlens(&[3, 2]),
+            &seqlens(&[3, 5]),
+            3,
+            5,
+            scale as f32,
+            true,
+            None,
+        );
+        let expected = Tensor::cat(
+            &[
+                naive_attn(&q.i(0..3), &k.i(0..3), &v.i(0..3), scale),
+                naive_attn(&q.i(3..5), &k.i(3..8), &v.i(3..8), scale),
+            ],
+            0,
+        );
+        check_all_close(&y, &expected, 1e-5);
+
+        // the same keys and values in blocks 2 and then 0, 1 of the cache
+        let mut key_cache = Tensor::zeros(
+            &[3, num_kv_heads, head_size / x, block_size, x],
+            (Kind::Float, tch::Device::Cpu),
+        );
+        let mut value_cache = Tensor::zeros(
+            &[3, num_kv_heads, head_size, block_size],
+            (Kind::Float, tch::Device::Cpu),
+        );
+        let slots = Tensor::from_slice(&[8i32, 9, 10, 0, 1, 2, 3, 4]);
+        reshape_and_cache(&k, &v, &mut key_cache, &mut value_cache, &slots);
+
+        // the last query of each sequence
+        let query = Tensor::stack(&[q.i(2), q.i(4)], 0);
+        let mut out = query.zeros_like();
+        paged_attention_v1(
+            &mut out,
+            &query,
+            &key_cache,
+            &value_cache,
+            num_kv_heads as usize,
+            scale as f32,
+            &Tensor::from_slice2(&[[2i32, 0], [0, 1]]),
+            &Tensor::from_slice(&[3i32, 5]),
+            block_size as usize,
+            5,
+            None,
+            None,
+        );
+        check_all_close(&out, &Tensor::stack(&[y.i(2), y.i(4)], 0), 1e-5);
+    }
+
+    #[test]
+    fn rotary_neox() {
+        let freqs = [1.0f32, 0.1];
+        let cache = (0..3)
+            .flat_map(|p| {
+                let cos = freqs.map(|f| (p as f32 * f).cos());
+                let sin = freqs.map(|f| (p as f32 * f).sin());
+                [cos, sin].concat()
+            })
+            .collect::<Vec<_>>();
+        let cos_sin_cache = Tensor::from_slice(&cache).view([3, 4]);
+
+        let x = [0.5f32, -1.0, 2.0, 0.25];
+        let mut query = Tensor::from_slice(&[x, x].concat()).view([2, 4]);
+        let mut key = query.copy();
+        let positions = Tensor::from_slice(&[0i64, 2]);
+        rotary_embedding(&positions, &mut query, &mut key, 4, &cos_sin_cache, true);
+
+        // position 0 is unchanged, position 2 rotates the halves (x[i], x[i + 2])
+        let (cos, sin) = (&cache[8..10], &cache[10..12]);
+        let rotated = [
+            x[0] * cos[0] - x[2] * sin[0],
+            x[1] * cos[1] - x[3] * sin[1],
+            x[2] * cos[0] + x[0] * sin[0],
+            x[3] * cos[1] + x[1] * sin[1],
+        ];
+        let expected = Tensor::from_slice(&[x, rotated].concat()).view([2, 4]);
+        check_all_close(&query, &expected, 1e-6);
+        check_all_close(&key, &expected, 1e-6);
+    }
+
+    #[test]
+    fn gptq() {
+        tch::manual_seed(4);
+        let (k, n, group_size) = (16usize, 8usize, 8usize);
+        let q = |r: usize, c: usize| ((r * 7 + c * 3) % 16) as i64;
+        let z = |g: usize, c: usize| ((g * 5 + c) % 15 + 1) as i64;
+        let pack = |nibbles: &dyn Fn(usize) -> i64| {
+            (0..8).fold(0i64, |acc, i| acc | (nibbles(i) << (4 * i))) as u32 as i32
+        };
+
+        let qweight = (0..k / 8)
+            .flat_map(|r| (0..n).map(move |c| pack(&|i| q(r * 8 + i, c))))
+            .collect::<Vec<_>>();
+        // zero points are stored minus one
+        let qzeros = (0..k / group_size)
+            .flat_map(|g| (0..n / 8).map(move |c| pack(&|i| z(g, c * 8 + i) - 1)))
+            .collect::<Vec<_>>();
+        let g_idx = (0..k).map(|i| (i / group_size) as i32).collect::<Vec<_>>();
+        let scales = randn(&[(k / group_size) as i64, n as i64]);
+        let a = randn(&[3, k as i64]);
+
+        let w = (0..k)
+            .flat_map(|r| (0..n).map(move |c| (q(r, c) - z(r / group_size, c)) as f32))
+            .collect::<Vec<_>>();
+        let g_idx_t = Tensor::from_slice(&g_idx);
+        let w = Tensor::from_slice(&w).view([k as i64, n as i64])
+            * scales.index_select(0, &g_idx_t.to_kind(Kind::Int64));
+
+        let y = gptq_gemm(
+            &a,
+            &Tensor::from_slice(&qweight).view([(k / 8) as i64, n as i64]),
+            &Tensor::from_slice(&qzeros).view([(k / group_size) as i64, (n / 8) as i64]),
+            &scales,
+            &g_idx_t,
+        );
+        check_all_close(&y, &a.matmul(&w), 1e-4);
+    }
 }
@@ -1,16 +1,23 @@
 use super::{
     config::{self, TchRllmConfig},
     loader::{load_model_config, load_rllm_engine},
-    paged::{BatchInfo, BatchInfoBuilder, BlockSpaceManager, CacheEngine, CacheIface, TchSeqMgr},
+    medusa::MedusaHeads,
+    paged::{
+        BatchInfo, BatchInfoBuilder, BlockSpaceManager, CacheEngine, CacheIface, NextBatch,
+        TchSeqMgr,
+    },
     util::{synchronize, to_vec1},
     DType,
 };
-use aicirt::{with_timer, TimerRef};
-use anyhow::Result;
+use aicirt::{api::Token, with_timer, TimerRef};
+use anyhow::{bail, ensure, Result};
 use rand::distributions::Distribution as _;
-use rllm::{config::RllmConfig, AiciBias, LogitsProcessor, ModelExec, SchedulerOutputs};
+use rllm::{
+    config::{ParallelConfig, RllmConfig},
+    AiciBias, HashMap, LogitsProcessor, ModelExec, SchedulerOutputs, SeqId, SimpleVob,
+};
 use std::{sync::Arc, time::Instant};
-use tch::{Device, IndexOp, Tensor};
+use tch::{Device, IndexOp, Kind, Tensor};
 
 pub trait TModelInner {
     fn forward(&self, batch_info: &mut BatchInfo) -> Tensor;
@@ -22,7 +29,11 @@ pub struct TModel {
     model: Box<dyn TModelInner>,
     cache_engine: CacheEngine,
     batch_info: Option<BatchInfo>,
+    next_batch: Option<NextBatch>,
     logits: Option<Tensor>,
+    medusa: Option<MedusaHeads>,
+    // draft tokens of the rows of the logits, [num_logits, num_heads, top_k], on the CPU
+    drafts: Option<Tensor>,
     t0: Instant,
     seq_mgr: Arc<TchSeqMgr>,
     pub nv_profile: bool,
@@ -32,6 +43,26 @@ pub struct TchLoaderArgs {
     pub profile_step_no: usize,
     pub device: Device,
     pub dtype: Option<DType>,
+    /// Tokens per KV cache block; see CacheConfig::block_size.
+    pub block_size: usize,
+    /// Host memory for swapped-out KV blocks, in GiB.
+    pub swap_space: usize,
+    /// LoRA adapters (HuggingFace ids or local directories) to load on top of the model.
+    pub lora: Vec<String>,
+    /// Apply the adapters in forward passes instead of merging them into the weights.
+    pub lora_runtime: bool,
+    /// NAME=PATH adapters applied only to requests selecting NAME.
+    pub lora_adapters: Vec<String>,
+    /// Number of GPUs (starting with `device`) to split attention heads and MLP between.
+    pub tensor_parallel_size: usize,
+    /// Number of groups of tensor_parallel_size GPUs to split the layers between.
+    pub pipeline_parallel_size: usize,
+    /// Parts of a batch going through the pipeline at once; pipeline_parallel_size by default.
+    pub num_micro_batches: Option<usize>,
+    /// Medusa heads (HuggingFace id or local directory) drafting tokens to speculate on.
+    pub medusa: Option<String>,
+    /// Candidates each Medusa head drafts; see MedusaHeads::draft().
+    pub medusa_top_k: usize,
 }
 
 impl ModelExec for TModel {
@@ -54,6 +85,15 @@ impl ModelExec for TModel {
         args.verify_args()
     }
 
+    fn parallel_config(model_args: &Self::ModelLoaderArgs) -> ParallelConfig {
+        let pp = model_args.pipeline_parallel_size;
+        ParallelConfig {
+            pipeline_parallel_size: pp,
+            tensor_parallel_size: model_args.tensor_parallel_size,
+            num_micro_batches: model_args.num_micro_batches.unwrap_or(pp),
+        }
+    }
+
     fn load_rllm_engine(
         args: rllm::LoaderArgs,
         model_args: Self::ModelLoaderArgs,
@@ -78,9 +118,17 @@ impl ModelExec for TModel {
             self.nv_profile = true;
         }
 
-        let mut info = BatchInfoBuilder::new(self.config.clone())
-            .sched_out(sched_out, self.seq_mgr.get_gpu_allocator())
-            .finish(step_no, self.cache_iface(sched_out));
+        let kv_cache = self.cache_iface(sched_out);
+        let alloc = self.seq_mgr.get_gpu_allocator();
+        let mut info = match self.next_batch.take() {
+            Some(next) if next.matches(sched_out, alloc) => {
+                log::trace!("using batch prepared in previous step");
+                next.commit(sched_out, alloc, step_no, kv_cache)
+            }
+            _ => BatchInfoBuilder::new(self.config.clone())
+                .sched_out(sched_out, alloc)
+                .finish(step_no, kv_cache),
+        };
         log::trace!("batch_info #{}: {:?}", info.step_no, info);
 
         #[cfg(feature = "cuda")]
@@ -105,7 +153,18 @@ impl ModelExec for TModel {
             if logit_vocab_size != t_vocab {
                 panic!("vocab size mismatch: model {logit_vocab_size} != tokenizer {t_vocab}");
             }
-            assert!(num_seq == info.seq_id_to_idx.len() as i64);
+            let num_draft_logits = info.draft_logits.values().map(|r| r.len()).sum::<usize>();
+            assert!(num_seq == (info.seq_id_to_idx.len() + num_draft_logits) as i64);
+        }
+
+        if info.kv_scores.len() > 0 {
+            self.add_kv_scores(&mut info);
+        }
+
+        if let Some(medusa) = self.medusa.as_ref() {
+            let hidden_states = info.hidden_states.as_ref().unwrap();
+            let rows = hidden_states.i((&info.logit_idxs, ..));
+            self.drafts = Some(medusa.draft(&rows).to_device(Device::Cpu));
         }
 
         self.batch_info = Some(info);
@@ -120,6 +179,110 @@ impl ModelExec for TModel {
         self.logits.as_ref().unwrap().i((idx as i64, ..))
     }
 
+    fn num_draft_tokens(&self) -> usize {
+        self.medusa.as_ref().map_or(0, |m| m.num_draft_tokens())
+    }
+
+    fn get_draft_logits(&self, seq_id: usize) -> Vec<Tensor> {
+        let _no_grad = tch::no_grad_guard();
+        let info = self.batch_info.as_ref().unwrap();
+        let logits = self.logits.as_ref().unwrap();
+        let mut r = match info.draft_logits.get(&seq_id) {
+            Some(rows) => rows.clone().map(|idx| logits.i((idx as i64, ..))).collect(),
+            None => Vec::new(),
+        };
+        r.push(self.get_logits(seq_id));
+        r
+    }
+
+    fn get_draft(&self, seq_id: usize, row: usize) -> (Vec<Token>, Vec<usize>) {
+        let info = self.batch_info.as_ref().unwrap();
+        let idx = match info.draft_logits.get(&seq_id) {
+            Some(rows) if row < rows.len() => rows.start + row,
+            _ => info.seq_id_to_idx[&seq_id],
+        };
+        match (self.medusa.as_ref(), self.drafts.as_ref()) {
+            (Some(medusa), Some(drafts)) => medusa.tree(&drafts.i(idx as i64)),
+            _ => (Vec::new(), Vec::new()),
+        }
+    }
+
+    fn export_kv(&mut self, seq_id: SeqId, len: usize) -> Result<Vec<u8>> {
+        let _no_grad = tch::no_grad_guard();
+        let alloc = self.seq_mgr.get_gpu_allocator();
+        let blocks = match alloc.get_seq_blocks(seq_id) {
+            Some(b) => b,
+            None => bail!("seq {seq_id:?} has no blocks, or only a sliding window of them"),
+        };
+        ensure!(
+            blocks.len() == self.num_kv_blocks(len),
+            "seq {seq_id:?} has {} blocks",
+            blocks.len()
+        );
+        let data = self.cache_engine.read_blocks(&blocks);
+        let named = data
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (format!("kv.{i}"), t))
+            .collect::<Vec<_>>();
+        let mut buf = Vec::new();
+        Tensor::save_multi_to_stream(&named, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn import_kv(&mut self, seq_id: SeqId, len: usize, data: &[u8]) -> Result<bool> {
+        let _no_grad = tch::no_grad_guard();
+        let mut data = Tensor::load_multi_from_stream(std::io::Cursor::new(data))?;
+        data.sort_by_key(|(name, _)| {
+            name.strip_prefix("kv.")
+                .and_then(|n| n.parse::<usize>().ok())
+                .unwrap_or(usize::MAX)
+        });
+        let data = data.into_iter().map(|(_, t)| t).collect::<Vec<_>>();
+        ensure!(
+            self.cache_engine.matches_blocks(&data),
+            "KV blocks don't match the cache of this model"
+        );
+        ensure!(
+            data[0].size()[0] == self.num_kv_blocks(len) as i64,
+            "wrong number of KV blocks"
+        );
+        let blocks = match self.seq_mgr.get_gpu_allocator().alloc_blocks(seq_id, len) {
+            Some(b) => b,
+            None => return Ok(false),
+        };
+        self.poison_fresh_blocks();
+        self.cache_engine.write_blocks(&blocks, &data);
+        Ok(true)
+    }
+
+    fn compact_kv(&mut self, min_free_run: f32) -> usize {
+        let _no_grad = tch::no_grad_guard();
+        let moves = self.seq_mgr.get_gpu_allocator().compact(min_free_run);
+        if moves.len() > 0 {
+            let src_to_dsts: HashMap<usize, Vec<usize>> =
+                moves.iter().map(|&(src, dst)| (src, vec![dst])).collect();
+            self.cache_engine.copy(&src_to_dsts);
+            // it would use the old block numbers
+            self.next_batch = None;
+        }
+        moves.len()
+    }
+
+    fn prepare_next_run(&mut self, sched_out: &SchedulerOutputs) {
+        if self.medusa.is_some() {
+            // sequences get draft tokens, so they don't generate one token each
+            return;
+        }
+        let _no_grad = tch::no_grad_guard();
+        let kv_cache = self.cache_engine.get_cache_iface();
+        self.next_batch = BatchInfoBuilder::new(self.config.clone()).next_batch(
+            sched_out,
+            self.seq_mgr.get_gpu_allocator(),
+            kv_cache,
+        );
+    }
+
     fn finalize_run(&mut self) -> Result<()> {
         let _no_grad = tch::no_grad_guard();
 
@@ -193,6 +356,53 @@ impl ModelExec for TModel {
         Ok(next_token)
     }
 
+    fn prompt_embedding_size(&self) -> Option<usize> {
+        Some(self.config.model.hidden_size)
+    }
+
+    fn lora_adapters(&self) -> Vec<String> {
+        let lora = &self.config.model.lora;
+        lora.adapters.iter().filter_map(|a| a.id.clone()).collect()
+    }
+
+    fn get_hidden_states(&self, seq_id: usize) -> Option<(usize, Vec<f32>)> {
+        let _no_grad = tch::no_grad_guard();
+        let info = self.batch_info.as_ref()?;
+        let range = info.hidden_state_ranges.get(&seq_id)?;
+        let states = info.hidden_states.as_ref()?;
+        let rows = states.i((range.start as i64..range.end as i64, ..));
+        Some((self.config.model.hidden_size, to_vec1(&rows.flatten(0, -1))))
+    }
+
+    fn apply_token_mask(&self, logits: &mut Tensor, allowed: &SimpleVob) {
+        let _no_grad = tch::no_grad_guard();
+
+        // upload the bitmask (32 tokens per word) as is, and expand it on the device
+        let device = logits.device();
+        let words = unsafe {
+            std::slice::from_raw_parts(allowed.as_ptr() as *const i32, (allowed.len() + 31) / 32)
+        };
+        let bits = Tensor::from_slice(words)
+            .to(device)
+            .unsqueeze(1)
+            .bitwise_right_shift(&Tensor::arange(32, (Kind::Int, device)))
+            .bitwise_and(1)
+            .flatten(0, -1);
+        // tokens past the end of the bitmask (padding of the vocab) are not allowed
+        let vocab_size = logits.size()[0];
+        let len = std::cmp::min(vocab_size, bits.size()[0]);
+        let disallowed = Tensor::ones(&[vocab_size], (Kind::Bool, device));
+        disallowed
+            .narrow(0, 0, len)
+            .copy_(&bits.narrow(0, 0, len).eq(0));
+        let _ = logits.masked_fill_(&disallowed, f64::NEG_INFINITY);
+    }
+
+    fn apply_guidance(&self, logits: &mut Tensor, negative: &Tensor, scale: f32) {
+        let _no_grad = tch::no_grad_guard();
+        *logits = &*logits + (&*logits - negative) * (scale as f64);
+    }
+
     fn tensor_to_vec1(tensor: &Self::Tensor) -> Vec<f32> {
         to_vec1(tensor)
     }
@@ -204,6 +414,7 @@ impl TModel {
         cache_engine: CacheEngine,
         seq_mgr: Arc<TchSeqMgr>,
         model: Box<dyn TModelInner>,
+        medusa: Option<MedusaHeads>,
     ) -> Self {
         Self {
             config,
@@ -211,13 +422,48 @@ impl TModel {
             nv_profile: false,
             model,
             batch_info: None,
+            next_batch: None,
             logits: None,
+            medusa,
+            drafts: None,
             seq_mgr,
             t0: Instant::now(),
         }
     }
 
+    fn num_kv_blocks(&self, len: usize) -> usize {
+        let block_size = self.config.model.cache.block_size;
+        (len + block_size - 1) / block_size
+    }
+
+    /// Credit the blocks with the attention their KV got in this step
+    /// (for heavy-hitter eviction).
+    fn add_kv_scores(&self, info: &mut BatchInfo) {
+        let block_size = self.config.model.cache.block_size;
+        let mut blocks: HashMap<usize, f32> = HashMap::default();
+        for score in std::mem::take(&mut info.kv_scores) {
+            let scores: Vec<f32> = match score.scores {
+                Some(s) => to_vec1(&s),
+                None => continue,
+            };
+            for (slot, s) in score.slots.iter().zip(scores) {
+                *blocks.entry(slot / block_size).or_insert(0.0) += s;
+            }
+        }
+        self.seq_mgr.get_gpu_allocator().add_attention(&blocks);
+    }
+
+    /// With poison_kv_blocks, fill newly allocated blocks with NaN; this is done
+    /// before the swaps and copies that write to them.
+    fn poison_fresh_blocks(&mut self) {
+        let fresh = self.seq_mgr.get_gpu_allocator().take_fresh_blocks();
+        if fresh.len() > 0 {
+            self.cache_engine.poison_blocks(&fresh);
+        }
+    }
+
     fn cache_iface(&mut self, sched_out: &mut SchedulerOutputs) -> Box<dyn CacheIface> {
+        self.poison_fresh_blocks();
         self.cache_engine.new_round();
         if sched_out.blocks_to_swap_in.len() > 0 {
             self.cache_engine.swap_in(&sched_out.blocks_to_swap_in);
@@ -237,7 +483,7 @@ impl TModel {
 
     fn sample_multinomial(&self, state: &mut LogitsProcessor, prs: &Vec<f32>) -> Result<u32> {
         let distr = rand::distributions::WeightedIndex::new(prs)?;
-        let next_token = distr.sample(&mut state.rng) as u32;
+        let next_token = distr.sample(state.sample_rng()) as u32;
         Ok(next_token)
     }
 
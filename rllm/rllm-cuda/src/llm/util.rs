@@ -5,7 +5,7 @@ use tch::{kind::Element, Device, IndexOp as _, Tensor};
 #[cfg(feature = "cuda")]
 use tch_cuda::{
     cuda_empty_cache, cuda_get_device_properties, cuda_get_stats_allocated_bytes,
-    cuda_reset_peak_memory_stats,
+    cuda_mem_get_info, cuda_reset_peak_memory_stats,
 };
 
 pub fn check_all_close_attn(t1: &Tensor, t2: &Tensor) {
@@ -111,6 +111,30 @@ pub fn gpu_peak_allocated_bytes(device: Device) -> usize {
     }
 }
 
+/// Free GPU memory, after releasing memory cached by torch.
+pub fn gpu_free_memory(device: Device) -> usize {
+    match device {
+        #[cfg(feature = "cuda")]
+        Device::Cuda(n) => {
+            synchronize(device);
+            cuda_empty_cache();
+            cuda_mem_get_info(n).0
+        }
+        _ => 0,
+    }
+}
+
+pub fn gpu_current_allocated_bytes(device: Device) -> usize {
+    match device {
+        #[cfg(feature = "cuda")]
+        Device::Cuda(n) => {
+            let stats = cuda_get_stats_allocated_bytes(n);
+            stats.current as usize
+        }
+        _ => 0,
+    }
+}
+
 pub fn gpu_memory_size(device: Device) -> usize {
     match device {
         #[cfg(feature = "cuda")]
@@ -122,6 +146,20 @@ pub fn gpu_memory_size(device: Device) -> usize {
     }
 }
 
+/// Whether the model can run in `dtype` on `device`: bf16 needs Ampere or newer GPUs,
+/// and isn't supported with MPS; f16 is mostly unsupported on the CPU.
+pub fn device_supports_dtype(device: Device, dtype: DType) -> bool {
+    match (device, dtype) {
+        (_, DType::Float) => true,
+        #[cfg(feature = "cuda")]
+        (Device::Cuda(n), DType::BFloat16) => cuda_get_device_properties(n).major >= 8,
+        (Device::Cuda(_), DType::Half | DType::BFloat16) => true,
+        (Device::Mps, DType::Half) => true,
+        (Device::Cpu, DType::BFloat16) => true,
+        _ => false,
+    }
+}
+
 pub fn synchronize(device: Device) {
     match device {
         #[cfg(feature = "cuda")]
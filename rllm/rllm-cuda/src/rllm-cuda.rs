@@ -15,10 +15,64 @@ pub struct DriverArgs {
     #[clap(flatten)]
     pub args: rllm::server::RllmCliArgs,
 
-    /// Specify which type to use in the model (bf16, f16, f32)
+    /// Specify which device to run the model on (cuda, mps, cpu); by default
+    /// the first one available
     #[arg(long, default_value = "", help_heading = "Model")]
+    pub device: String,
+
+    /// Specify which type to use for the weights, activations and KV cache (auto, bf16,
+    /// f16, f32); auto is torch_dtype of config.json, when supported by the device
+    #[arg(long, default_value = "auto", help_heading = "Model")]
     pub dtype: String,
 
+    /// Number of tokens per KV cache block (8, 16 or 32 with the paged attention kernel)
+    #[arg(long, default_value_t = 16, help_heading = "Model")]
+    pub block_size: usize,
+
+    /// Host memory (GiB) for KV blocks of preempted sequences; when it's full,
+    /// sequences are recomputed instead; must be 0 with tensor parallelism
+    #[arg(long, default_value_t = 2, help_heading = "Model")]
+    pub swap_space: usize,
+
+    /// LoRA adapter (peft format) to load on top of the model: HuggingFace id or local
+    /// directory; can be repeated
+    #[arg(long, help_heading = "Model")]
+    pub lora: Vec<String>,
+
+    /// Keep LoRA adapters separate and apply them in each forward pass (needed for
+    /// quantized models); by default they are merged into the weights at startup
+    #[arg(long, default_value_t = false, help_heading = "Model")]
+    pub lora_runtime: bool,
+
+    /// LoRA adapter applied only to requests with `"adapter": NAME`, given as NAME=PATH;
+    /// can be repeated, and requests with different adapters are batched together
+    #[arg(long, help_heading = "Model")]
+    pub lora_adapter: Vec<String>,
+
+    /// Number of GPUs to split the model between (tensor parallelism, over NCCL)
+    #[arg(long, default_value_t = 1, help_heading = "Model")]
+    pub tensor_parallel_size: usize,
+
+    /// Number of pipeline stages to split the layers between, each on
+    /// tensor-parallel-size GPUs (for GPUs without a fast interconnect)
+    #[arg(long, default_value_t = 1, help_heading = "Model")]
+    pub pipeline_parallel_size: usize,
+
+    /// Number of parts batches are split into to keep the pipeline stages busy
+    /// [default: pipeline-parallel-size]
+    #[arg(long, help_heading = "Model")]
+    pub micro_batches: Option<usize>,
+
+    /// Medusa heads (HuggingFace id or local directory with medusa_lm_head.safetensors)
+    /// trained for the model; they draft tokens, which are verified in the next step
+    #[arg(long, help_heading = "Model")]
+    pub medusa: Option<String>,
+
+    /// Candidates Medusa heads draft for each token after the next one; with more
+    /// than 1, a tree of them is verified in the next step
+    #[arg(long, default_value_t = 1, help_heading = "Model")]
+    pub medusa_top_k: usize,
+
     /// Enable nvprof profiling for given engine step (if available)
     #[arg(long, default_value_t = 0, help_heading = "Development")]
     pub profile_step: usize,
@@ -29,28 +83,40 @@ async fn main() -> () {
     let args = parse_with_settings::<DriverArgs>();
     let _ = args;
 
-    let (device, dtype) = if tch::Cuda::is_available() {
-        (Device::Cuda(0), None)
-    } else {
-        // At least on AMD 5500m MPS is 3x slower than CPU
-        // #[cfg(target_os = "macos")]
-        // let r = (Device::Mps, DType::Half);
-        // #[cfg(not(target_os = "macos"))]
-        let r = (Device::Cpu, Some(DType::Float));
-        r
+    let device = match args.device.as_str() {
+        "cuda" => Device::Cuda(0),
+        "mps" => Device::Mps,
+        "cpu" => Device::Cpu,
+        "" if tch::Cuda::is_available() => Device::Cuda(0),
+        // Metal on Apple Silicon; on Intel Macs (eg. AMD 5500m) MPS is 3x slower than CPU
+        "" if cfg!(all(target_os = "macos", target_arch = "aarch64")) && tch::utils::has_mps() => {
+            Device::Mps
+        }
+        "" => Device::Cpu,
+        _ => panic!("invalid device; try one of cuda, mps, cpu"),
     };
 
     let dtype = match args.dtype.as_str() {
         "bf16" => Some(DType::BFloat16),
         "f16" => Some(DType::Half),
         "f32" => Some(DType::Float),
-        "" => dtype,
-        _ => panic!("invalid dtype; try one of bf16, f16, f32"),
+        "auto" => None,
+        _ => panic!("invalid dtype; try one of auto, bf16, f16, f32"),
     };
 
     let model_args = TchLoaderArgs {
         device,
         dtype,
+        block_size: args.block_size,
+        swap_space: args.swap_space,
+        lora: args.lora,
+        lora_runtime: args.lora_runtime,
+        lora_adapters: args.lora_adapter,
+        tensor_parallel_size: args.tensor_parallel_size,
+        pipeline_parallel_size: args.pipeline_parallel_size,
+        num_micro_batches: args.micro_batches,
+        medusa: args.medusa,
+        medusa_top_k: args.medusa_top_k,
         profile_step_no: args.profile_step,
     };
     rllm::server::server_main::<TModel>(args.args, model_args).await;
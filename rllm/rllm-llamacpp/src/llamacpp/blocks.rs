@@ -14,8 +14,9 @@ impl TBlockSpaceManager<TModel> for CppBlockSpaceManager {
     }
 
     fn allocate(&mut self, seq_group: &mut SequenceGroup) {
-        let seq = seq_group.only_seq();
-        assert!(seq.num_kv_computed == 0);
+        for seq in seq_group.seqs.iter() {
+            assert!(seq.num_kv_computed == 0);
+        }
     }
 
     fn can_append_slot(&self, _seq_group: &SequenceGroup) -> bool {
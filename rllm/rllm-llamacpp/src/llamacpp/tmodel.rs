@@ -5,7 +5,7 @@ use rand::distributions::Distribution as _;
 use rllm::{
     config::{ModelMeta, RllmConfig},
     seq::SchedulingPhase,
-    AiciBias, HashMap, LoaderArgs, LogitsProcessor, ModelExec, SchedulerOutputs,
+    AiciBias, HashMap, LoaderArgs, LogitsProcessor, ModelExec, SchedulerOutputs, SimpleVob,
 };
 use std::{sync::Arc, time::Instant};
 
@@ -64,20 +64,16 @@ impl ModelExec for TModel {
                     continue;
                 }
 
-                let seq_len = seq.get_len();
-                let k_len = seq_len;
                 log::trace!("fwd seq: {seq:?}");
-                let mut q_len = seq.get_len() - seq.num_kv_computed;
-                if q_len == 0 {
-                    // just re-compute the last token
-                    q_len = 1;
+                let positions = seq.step_positions();
+                let k_len = positions.end;
+                if k_len == seq.get_len() {
+                    sg.usage.gen_tokens += 1;
                 }
-                sg.usage.gen_tokens += 1;
-                sg.usage.prompt_tokens += q_len;
+                sg.usage.prompt_tokens += positions.len();
 
-                let off = k_len - q_len;
-                for idx in off..off + q_len {
-                    let logits = idx + 1 == off + q_len;
+                for idx in positions {
+                    let logits = idx + 1 == k_len;
                     if logits {
                         self.seq_id_to_idx
                             .insert(seq.seq_id.to_num(), self.batch.len());
@@ -88,7 +84,7 @@ impl ModelExec for TModel {
                     });
                 }
 
-                seq.sync_computed_kv();
+                seq.sync_computed_kv_to(k_len);
             }
         }
 
@@ -194,6 +190,23 @@ impl ModelExec for TModel {
         self.seq_mgr.clone()
     }
 
+    fn apply_token_mask(&self, logits: &mut Tensor, allowed: &SimpleVob) {
+        let logits = logits.as_mut_slice();
+        for i in 0..logits.len() {
+            if !allowed.is_allowed(i as u32) {
+                logits[i] = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    fn apply_guidance(&self, logits: &mut Tensor, negative: &Tensor, scale: f32) {
+        let negative = negative.as_slice();
+        let logits = logits.as_mut_slice();
+        for i in 0..logits.len() {
+            logits[i] += scale * (logits[i] - negative[i]);
+        }
+    }
+
     fn tensor_to_vec1(tensor: &Self::Tensor) -> Vec<f32> {
         tensor.to_vec1()
     }
@@ -228,7 +241,7 @@ impl TModel {
 
     fn sample_multinomial(&self, state: &mut LogitsProcessor, prs: &Vec<f32>) -> Result<u32> {
         let distr = rand::distributions::WeightedIndex::new(prs)?;
-        let next_token = distr.sample(&mut state.rng) as u32;
+        let next_token = distr.sample(state.sample_rng()) as u32;
         Ok(next_token)
     }
 
@@ -133,7 +133,7 @@ impl SystemInfo {
     }
 }
 
-const KERNEL_FILES: [&str; 25] = [
+const KERNEL_FILES: [&str; 27] = [
     "flash_attn/flash_api.cpp",
     "flash_attn/flash_fwd_split_hdim128_bf16_sm80.cu",
     "flash_attn/flash_fwd_split_hdim160_bf16_sm80.cu",
@@ -157,10 +157,15 @@ const KERNEL_FILES: [&str; 25] = [
     "vllm/layernorm_kernels.cu",
     "vllm/pos_encoding_kernels.cu",
     "vllm/attention/attention_kernels.cu",
+    "vllm/quantization/gptq/q_gemm.cu",
+    "vllm/quantization/awq/gemm_kernels.cu",
     "vllm_bindings.cpp",
     "cuda.cpp",
 ];
 
+// with the nccl feature
+const NCCL_FILES: [&str; 1] = ["nccl.cpp"];
+
 fn main() -> Result<()> {
     if std::env::var("VSCODE_CWD").is_ok() {
         // do not compile from rust-analyzer
@@ -216,9 +221,11 @@ fn main() -> Result<()> {
 
     let out_file = build_dir.join("libflashattention.a");
 
+    let nccl = std::env::var("CARGO_FEATURE_NCCL").is_ok();
     let kernel_dir = PathBuf::from("kernels");
     let cu_files: Vec<_> = KERNEL_FILES
         .iter()
+        .chain(NCCL_FILES.iter().filter(|_| nccl))
         .map(|f| {
             let mut obj_file = out_dir.join(f);
             obj_file.set_extension("o");
@@ -277,6 +284,10 @@ fn main() -> Result<()> {
                     .args(["-o", obj_file.to_str().unwrap()])
                     .args(["--default-stream", "per-thread"])
                     .arg("-Icutlass/include")
+                    .arg(format!(
+                        "-I{}",
+                        sysinfo.libtorch_lib_dir.join("../../nvidia/nccl/include").display()
+                    ))
                     .arg(format!("-D_GLIBCXX_USE_CXX11_ABI={}", sysinfo.cxx11_abi))
                     .args(sysinfo.libtorch_include_dirs.iter().map(|p| {
                         format!(
@@ -338,6 +349,16 @@ fn main() -> Result<()> {
     println!("cargo:rustc-link-search={cudart_path}");
     println!("cargo:rustc-link-lib=flashattention");
     println!("cargo:rustc-link-lib=cudart");
+    if nccl {
+        let nccl_path = sysinfo
+            .libtorch_lib_dir
+            .join("../../nvidia/nccl/lib")
+            .display()
+            .to_string();
+        println!("cargo:rustc-link-search={nccl_path}");
+        // the nvidia-nccl wheel only has libnccl.so.2, without the libnccl.so symlink
+        println!("cargo:rustc-link-lib=dylib:+verbatim=libnccl.so.2");
+    }
     println!("cargo:rustc-link-lib=c10_cuda");
     println!("cargo:rustc-link-lib=dylib=stdc++");
 
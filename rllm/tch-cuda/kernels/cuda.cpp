@@ -1,7 +1,9 @@
 #include <c10/cuda/CUDACachingAllocator.h>
 #include <c10/cuda/CUDAStream.h>
+#include <c10/cuda/CUDAGuard.h>
 #include <ATen/cuda/CUDAContext.h>
 #include <ATen/cuda/CUDAEvent.h>
+#include <ATen/ATen.h>
 
 using namespace c10::cuda;
 using namespace at::cuda;
@@ -37,6 +39,12 @@ char *cuda_reset_peak_memory_stats_C(int device) {
 
 char *cuda_empty_cache_C() { PROTECT(CUDACachingAllocator::emptyCache()); }
 
+char *cuda_set_device_C(int device) { PROTECT(c10::cuda::set_device(device)); }
+
+char *cuda_current_device_C(int *outp) {
+  PROTECT(*outp = c10::cuda::current_device());
+}
+
 char *cuda_get_stats_allocated_bytes_C(int device, Stats *outp) {
   PROTECT({
     auto stats = CUDACachingAllocator::getDeviceStats(device);
@@ -47,6 +55,16 @@ char *cuda_get_stats_allocated_bytes_C(int device, Stats *outp) {
   });
 }
 
+char *cuda_mem_get_info_C(int device, int64_t *free, int64_t *total) {
+  PROTECT({
+    c10::cuda::CUDAGuard guard(device);
+    size_t f, t;
+    C10_CUDA_CHECK(cudaMemGetInfo(&f, &t));
+    *free = f;
+    *total = t;
+  });
+}
+
 char *cuda_get_device_properties_C(int64_t device, CudaProps *outp) {
   PROTECT({
     auto p = at::cuda::getDeviceProperties(device);
@@ -146,4 +164,51 @@ char *cuda_event_free_C(CUDAEvent *cuEv) {
   PROTECT({ delete cuEv; });
 }
 
+//
+// Host <-> device block copies
+//
+
+char *pin_memory_C(at::Tensor *t, at::Tensor **outp) {
+  PROTECT({ *outp = new at::Tensor(t->pin_memory()); });
+}
+
+// Copies blocks (slices along dimension 0) between a CUDA tensor and a CPU
+// tensor. The copies are only asynchronous with respect to the host when
+// the CPU tensor is in pinned memory.
+char *swap_blocks_C(at::Tensor *src, at::Tensor *dst,
+                    const int64_t *block_mapping, int num_pairs,
+                    CUDAStream *cuStr) {
+  PROTECT({
+    auto src_device = src->device();
+    auto dst_device = dst->device();
+    cudaMemcpyKind memcpy_type;
+    if (src_device.is_cuda() && dst_device.is_cuda()) {
+      TORCH_CHECK(src_device.index() == dst_device.index(),
+                  "src and dst must be on the same GPU");
+      memcpy_type = cudaMemcpyDeviceToDevice;
+    } else if (src_device.is_cuda() && dst_device.is_cpu()) {
+      memcpy_type = cudaMemcpyDeviceToHost;
+    } else if (src_device.is_cpu() && dst_device.is_cuda()) {
+      memcpy_type = cudaMemcpyHostToDevice;
+    } else {
+      TORCH_CHECK(false, "Invalid device combination");
+    }
+    TORCH_CHECK(src->is_contiguous() && dst->is_contiguous(),
+                "src and dst must be contiguous");
+
+    char *src_ptr = static_cast<char *>(src->data_ptr());
+    char *dst_ptr = static_cast<char *>(dst->data_ptr());
+    const int64_t block_size_in_bytes = src->element_size() * src->stride(0);
+    auto stream = cuStr->stream();
+    for (int i = 0; i < num_pairs; i++) {
+      int64_t src_block_number = block_mapping[2 * i];
+      int64_t dst_block_number = block_mapping[2 * i + 1];
+      C10_CUDA_CHECK(cudaMemcpyAsync(
+          dst_ptr + dst_block_number * block_size_in_bytes,
+          src_ptr + src_block_number * block_size_in_bytes,
+          block_size_in_bytes, memcpy_type, stream));
+    }
+  });
+}
+
 } // extern "C"
\ No newline at end of file
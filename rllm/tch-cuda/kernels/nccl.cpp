@@ -0,0 +1,68 @@
+#include <c10/cuda/CUDAStream.h>
+#include <c10/cuda/CUDAGuard.h>
+#include <ATen/ATen.h>
+#include <nccl.h>
+
+#include <stdexcept>
+#include <string>
+
+using namespace c10::cuda;
+
+#define PROTECT(call)                                                          \
+  try {                                                                        \
+    call;                                                                      \
+    return nullptr;                                                            \
+  } catch (const std::exception &e) {                                          \
+    return strdup(e.what());                                                   \
+  }
+
+#define NCCL_CHECK(call)                                                       \
+  do {                                                                         \
+    ncclResult_t r = call;                                                     \
+    if (r != ncclSuccess)                                                      \
+      throw std::runtime_error(std::string("NCCL error: ") +                   \
+                               ncclGetErrorString(r));                         \
+  } while (0)
+
+static ncclDataType_t nccl_type(at::ScalarType t) {
+  switch (t) {
+  case at::ScalarType::Half:
+    return ncclHalf;
+  case at::ScalarType::BFloat16:
+    return ncclBfloat16;
+  case at::ScalarType::Float:
+    return ncclFloat;
+  default:
+    throw std::runtime_error("all-reduce: unsupported tensor type");
+  }
+}
+
+extern "C" {
+
+// One communicator per device, all in this process.
+char *nccl_comm_init_all_C(int ndev, const int *devices, ncclComm_t *outp) {
+  PROTECT(NCCL_CHECK(ncclCommInitAll(outp, ndev, devices)));
+}
+
+char *nccl_comm_destroy_C(ncclComm_t comm) {
+  PROTECT(NCCL_CHECK(ncclCommDestroy(comm)));
+}
+
+// Sum tensors[i] (on the device of comms[i]) in place, on the current stream
+// of each device.
+char *nccl_all_reduce_C(int ndev, at::Tensor **tensors, ncclComm_t *comms) {
+  PROTECT({
+    NCCL_CHECK(ncclGroupStart());
+    for (int i = 0; i < ndev; i++) {
+      auto &t = *tensors[i];
+      TORCH_CHECK(t.is_cuda() && t.is_contiguous());
+      CUDAGuard guard(t.device());
+      auto stream = getCurrentCUDAStream(t.device().index());
+      NCCL_CHECK(ncclAllReduce(t.data_ptr(), t.data_ptr(), t.numel(),
+                               nccl_type(t.scalar_type()), ncclSum, comms[i],
+                               stream.stream()));
+    }
+    NCCL_CHECK(ncclGroupEnd());
+  });
+}
+}
@@ -11,7 +11,8 @@ void paged_attention_v1(
   torch::Tensor& context_lens,
   int block_size,
   int max_context_len,
-  const c10::optional<torch::Tensor>& alibi_slopes);
+  const c10::optional<torch::Tensor>& alibi_slopes,
+  int sliding_window);
 
 void paged_attention_v2(
   torch::Tensor& out,
@@ -27,7 +28,8 @@ void paged_attention_v2(
   torch::Tensor& context_lens,
   int block_size,
   int max_context_len,
-  const c10::optional<torch::Tensor>& alibi_slopes);
+  const c10::optional<torch::Tensor>& alibi_slopes,
+  int sliding_window);
 
 void rms_norm(
   torch::Tensor& out,
@@ -70,6 +72,14 @@ torch::Tensor awq_gemm(
   int split_k_iters);
 #endif
 
+void gptq_gemm(
+  torch::Tensor& out,
+  const torch::Tensor& a,
+  const torch::Tensor& b_q_weight,
+  const torch::Tensor& b_gptq_qzeros,
+  const torch::Tensor& b_gptq_scales,
+  const torch::Tensor& b_g_idx);
+
 void squeezellm_gemm(
   torch::Tensor vec,
   torch::Tensor mat,
@@ -0,0 +1,164 @@
+/*
+GEMM with 4-bit GPTQ weights (AutoGPTQ layout):
+  qweight: [K / 8, N] int32, 8 consecutive rows of K packed per int
+  qzeros:  [G, N / 8] int32, zero points minus one, packed along N
+  scales:  [G, N] half
+  g_idx:   [K] int32, group of each row of K (groups are permuted with act-order)
+W[k, n] = scales[g_idx[k], n] * (q[k, n] - zero[g_idx[k], n])
+*/
+
+#include <torch/all.h>
+#include <c10/cuda/CUDAGuard.h>
+#include <ATen/cuda/CUDAContext.h>
+
+#include <cuda_fp16.h>
+
+namespace vllm {
+namespace gptq {
+
+// rows of K handled by one thread block of the GEMV kernel
+#define GEMV_BLOCK_K 128
+#define GEMV_THREADS 256
+// larger batches dequantize the weights and use cuBLAS
+#define GEMV_MAX_M 8
+
+__device__ __forceinline__ float dequant(const int* __restrict__ qzeros,
+                                         const half* __restrict__ scales,
+                                         int g, int n, int N, int q) {
+  int z = ((qzeros[g * (N / 8) + n / 8] >> (4 * (n % 8))) & 0xf) + 1;
+  return __half2float(scales[g * N + n]) * (float)(q - z);
+}
+
+// out_f32[m, n] += sum over GEMV_BLOCK_K rows of K of a[m, k] * W[k, n]
+template <int M>
+__global__ void gemv_kernel(const half* __restrict__ a,
+                            const int* __restrict__ qweight,
+                            const int* __restrict__ qzeros,
+                            const half* __restrict__ scales,
+                            const int* __restrict__ g_idx,
+                            float* __restrict__ out, int K, int N) {
+  __shared__ float a_sh[M][GEMV_BLOCK_K];
+  __shared__ int g_sh[GEMV_BLOCK_K];
+
+  int n = blockIdx.x * GEMV_THREADS + threadIdx.x;
+  int k0 = blockIdx.y * GEMV_BLOCK_K;
+  int k_len = min(GEMV_BLOCK_K, K - k0);
+
+  for (int i = threadIdx.x; i < k_len; i += GEMV_THREADS) {
+    g_sh[i] = g_idx[k0 + i];
+    for (int m = 0; m < M; m++) {
+      a_sh[m][i] = __half2float(a[m * K + k0 + i]);
+    }
+  }
+  __syncthreads();
+
+  if (n >= N) return;
+
+  float acc[M];
+  for (int m = 0; m < M; m++) acc[m] = 0.0f;
+
+  int prev_g = -1;
+  float scale = 0.0f, zero = 0.0f;
+  for (int i = 0; i < k_len; i += 8) {
+    int packed = qweight[((k0 + i) / 8) * N + n];
+    for (int j = 0; j < 8; j++) {
+      int g = g_sh[i + j];
+      if (g != prev_g) {
+        prev_g = g;
+        scale = __half2float(scales[g * N + n]);
+        zero = (float)(((qzeros[g * (N / 8) + n / 8] >> (4 * (n % 8))) & 0xf) + 1);
+      }
+      float w = scale * ((float)((packed >> (4 * j)) & 0xf) - zero);
+      for (int m = 0; m < M; m++) acc[m] += a_sh[m][i + j] * w;
+    }
+  }
+
+  for (int m = 0; m < M; m++) atomicAdd(&out[m * N + n], acc[m]);
+}
+
+// w[k, n] = W[k, n] (as half)
+__global__ void dequant_kernel(const int* __restrict__ qweight,
+                               const int* __restrict__ qzeros,
+                               const half* __restrict__ scales,
+                               const int* __restrict__ g_idx,
+                               half* __restrict__ w, int K, int N) {
+  int n = blockIdx.x * blockDim.x + threadIdx.x;
+  int k8 = blockIdx.y;
+  if (n >= N) return;
+  int packed = qweight[k8 * N + n];
+  for (int j = 0; j < 8; j++) {
+    int k = k8 * 8 + j;
+    int q = (packed >> (4 * j)) & 0xf;
+    w[k * N + n] = __float2half(dequant(qzeros, scales, g_idx[k], n, N, q));
+  }
+}
+
+template <int M>
+void launch_gemv(const torch::Tensor& a, const torch::Tensor& qweight,
+                 const torch::Tensor& qzeros, const torch::Tensor& scales,
+                 const torch::Tensor& g_idx, torch::Tensor& out_f32, int K,
+                 int N, cudaStream_t stream) {
+  dim3 grid((N + GEMV_THREADS - 1) / GEMV_THREADS,
+            (K + GEMV_BLOCK_K - 1) / GEMV_BLOCK_K);
+  gemv_kernel<M><<<grid, GEMV_THREADS, 0, stream>>>(
+      (const half*)a.data_ptr(), qweight.data_ptr<int>(),
+      qzeros.data_ptr<int>(), (const half*)scales.data_ptr(),
+      g_idx.data_ptr<int>(), out_f32.data_ptr<float>(), K, N);
+}
+
+}  // namespace gptq
+}  // namespace vllm
+
+void gptq_gemm(torch::Tensor& out, const torch::Tensor& a,
+               const torch::Tensor& b_q_weight,
+               const torch::Tensor& b_gptq_qzeros,
+               const torch::Tensor& b_gptq_scales,
+               const torch::Tensor& b_g_idx) {
+  const at::cuda::OptionalCUDAGuard device_guard(device_of(a));
+  cudaStream_t stream = at::cuda::getCurrentCUDAStream();
+
+  TORCH_CHECK(a.dim() == 2 && a.is_contiguous(), "a must be contiguous 2D");
+  TORCH_CHECK(a.scalar_type() == at::ScalarType::Half, "a must be half");
+  TORCH_CHECK(b_q_weight.scalar_type() == at::ScalarType::Int,
+              "qweight must be int32");
+  TORCH_CHECK(b_gptq_scales.scalar_type() == at::ScalarType::Half,
+              "scales must be half");
+
+  int M = a.size(0);
+  int K = a.size(1);
+  int N = b_q_weight.size(1);
+  TORCH_CHECK(K % 8 == 0 && b_q_weight.size(0) == K / 8, "qweight shape");
+  TORCH_CHECK(N % 8 == 0 && b_gptq_qzeros.size(1) == N / 8, "qzeros shape");
+  TORCH_CHECK(b_g_idx.size(0) == K, "g_idx shape");
+  TORCH_CHECK(out.size(0) == M && out.size(1) == N, "out shape");
+
+  if (M > GEMV_MAX_M) {
+    auto w = torch::empty({K, N}, a.options());
+    dim3 grid((N + 255) / 256, K / 8);
+    vllm::gptq::dequant_kernel<<<grid, 256, 0, stream>>>(
+        b_q_weight.data_ptr<int>(), b_gptq_qzeros.data_ptr<int>(),
+        (const half*)b_gptq_scales.data_ptr(), b_g_idx.data_ptr<int>(),
+        (half*)w.data_ptr(), K, N);
+    torch::mm_out(out, a, w);
+    return;
+  }
+
+  auto out_f32 = torch::zeros({M, N}, a.options().dtype(at::ScalarType::Float));
+  switch (M) {
+#define GEMV_CASE(m)                                                      \
+  case m:                                                                 \
+    vllm::gptq::launch_gemv<m>(a, b_q_weight, b_gptq_qzeros, b_gptq_scales, \
+                               b_g_idx, out_f32, K, N, stream);           \
+    break;
+    GEMV_CASE(1)
+    GEMV_CASE(2)
+    GEMV_CASE(3)
+    GEMV_CASE(4)
+    GEMV_CASE(5)
+    GEMV_CASE(6)
+    GEMV_CASE(7)
+    GEMV_CASE(8)
+#undef GEMV_CASE
+  }
+  out.copy_(out_f32);
+}
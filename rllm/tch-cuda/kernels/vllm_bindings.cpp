@@ -18,14 +18,15 @@ char *paged_attention_v1_C(tensor out, tensor query, tensor key_cache,
                            tensor value_cache, int num_kv_heads, float scale,
                            tensor block_tables, tensor context_lens,
                            int block_size, int max_context_len,
-                           tensor alibi_slopes) {
+                           tensor alibi_slopes, int sliding_window) {
   c10::optional<torch::Tensor> alibi;
   if (alibi_slopes != nullptr) {
     alibi = *alibi_slopes;
   }
   PROTECT(paged_attention_v1(*out, *query, *key_cache, *value_cache,
                              num_kv_heads, scale, *block_tables, *context_lens,
-                             block_size, max_context_len, alibi));
+                             block_size, max_context_len, alibi,
+                             sliding_window));
 }
 
 char *paged_attention_v2_C(tensor out, tensor exp_sums, tensor max_logits,
@@ -33,7 +34,7 @@ char *paged_attention_v2_C(tensor out, tensor exp_sums, tensor max_logits,
                            tensor value_cache, int num_kv_heads, float scale,
                            tensor block_tables, tensor context_lens,
                            int block_size, int max_context_len,
-                           tensor alibi_slopes) {
+                           tensor alibi_slopes, int sliding_window) {
   c10::optional<torch::Tensor> alibi;
   if (alibi_slopes != nullptr) {
     alibi = *alibi_slopes;
@@ -41,7 +42,7 @@ char *paged_attention_v2_C(tensor out, tensor exp_sums, tensor max_logits,
   PROTECT(paged_attention_v2(*out, *exp_sums, *max_logits, *tmp_out, *query,
                              *key_cache, *value_cache, num_kv_heads, scale,
                              *block_tables, *context_lens, block_size,
-                             max_context_len, alibi));
+                             max_context_len, alibi, sliding_window));
 }
 
 char *rms_norm_C(tensor out, tensor input, tensor weight, float epsilon) {
@@ -69,6 +70,12 @@ char *gelu_fast_C(tensor out, tensor input) {
   PROTECT(gelu_fast(*out, *input));
 }
 
+char *gptq_gemm_C(tensor out, tensor a, tensor b_q_weight,
+                  tensor b_gptq_qzeros, tensor b_gptq_scales, tensor b_g_idx) {
+  PROTECT(gptq_gemm(*out, *a, *b_q_weight, *b_gptq_qzeros, *b_gptq_scales,
+                    *b_g_idx));
+}
+
 char *reshape_and_cache_C(tensor key, tensor value, tensor key_cache,
                           tensor value_cache, tensor slot_mapping) {
   PROTECT(
@@ -88,4 +95,14 @@ char *copy_blocks_2_C(tensor key_cache_ptrs_tensor,
                         *block_mapping_tensor, *key0));
 }
 }
-// END GENERATED SECTION
\ No newline at end of file
+// END GENERATED SECTION
+extern "C" {
+
+char *awq_gemm_C(tensor in_feats, tensor kernel, tensor scaling_factors,
+                 tensor zeros, int split_k_iters, tensor *outp) {
+  PROTECT({
+    *outp = new torch::Tensor(
+        awq_gemm(*in_feats, *kernel, *scaling_factors, *zeros, split_k_iters));
+  });
+}
+}
@@ -4,9 +4,13 @@ use torch_sys::C_tensor;
 use rustc_hash::FxHashMap as HashMap;
 
 mod event;
+#[cfg(feature = "nccl")]
+mod nccl;
 mod stream;
 
 pub use event::*;
+#[cfg(feature = "nccl")]
+pub use nccl::*;
 pub use stream::*;
 
 unsafe fn ptr_to_string(ptr: *mut libc::c_char) -> Option<String> {
@@ -61,6 +65,7 @@ extern "C" {
 /// * `seqlens_k` - The cumulative lengths of the sequences in the batch, used to index in k and v.
 /// * `max_seqlen_q` - The maximum query sequence length for q in the batch.
 /// * `max_seqlen_k` - The maximum query sequence length for k and v in the batch.
+/// * `window_size` - If set, each query only attends to this many keys, ending at its own position.
 ///
 /// `seqlens_q` and `seqlens_k` contain `batch_size + 1` elements, typically `0`, `seqlen_1`,
 /// `seqlen_1 + seqlen_2`, etc.
@@ -76,7 +81,9 @@ pub fn flash_attn_varlen(
     max_seqlen_k: usize,
     softmax_scale: f32,
     causal: bool,
+    window_size: Option<usize>,
 ) -> Tensor {
+    let window_size_left = window_size.map_or(-1, |w| w as i32 - 1);
     let mut outputs = vec![std::ptr::null_mut(); 1];
     let err = unsafe {
         ptr_to_string(mha_varlen_fwd_C(
@@ -92,7 +99,7 @@ pub fn flash_attn_varlen(
             softmax_scale,
             false,
             causal,
-            -1,
+            window_size_left,
             -1,
             outputs.as_mut_ptr(),
         ))
@@ -117,6 +124,7 @@ extern "C" {
         block_size: i32,
         max_context_len: i32,
         alibi_slopes: *const C_tensor,
+        sliding_window: i32,
     ) -> *mut libc::c_char;
 
     fn paged_attention_v2_C(
@@ -134,6 +142,7 @@ extern "C" {
         block_size: i32,
         max_context_len: i32,
         alibi_slopes: *const C_tensor,
+        sliding_window: i32,
     ) -> *mut libc::c_char;
 
     fn rms_norm_C(
@@ -165,6 +174,15 @@ extern "C" {
 
     fn gelu_fast_C(out: *mut C_tensor, input: *mut C_tensor) -> *mut libc::c_char;
 
+    fn gptq_gemm_C(
+        out: *mut C_tensor,
+        a: *const C_tensor,
+        b_q_weight: *const C_tensor,
+        b_gptq_qzeros: *const C_tensor,
+        b_gptq_scales: *const C_tensor,
+        b_g_idx: *const C_tensor,
+    ) -> *mut libc::c_char;
+
     fn reshape_and_cache_C(
         key: *const C_tensor,
         value: *const C_tensor,
@@ -189,6 +207,77 @@ extern "C" {
     ) -> *mut libc::c_char;
 }
 
+/// Multiply `a` by 4-bit GPTQ-quantized weights; returns `[..., out_features]`.
+pub fn gptq_gemm(
+    a: &Tensor,       // [..., in_features], half
+    qweight: &Tensor, // [in_features / 8, out_features], int
+    qzeros: &Tensor,  // [num_groups, out_features / 8], int
+    scales: &Tensor,  // [num_groups, out_features], half
+    g_idx: &Tensor,   // [in_features], int
+) -> Tensor {
+    let in_features = *a.size().last().unwrap();
+    let out_features = qweight.size()[1];
+    let a2 = a.reshape(&[-1, in_features]).contiguous();
+    let mut out = Tensor::empty(&[a2.size()[0], out_features], (a.kind(), a.device()));
+    unsafe {
+        check_res(
+            "gptq_gemm_C",
+            gptq_gemm_C(
+                out.as_mut_ptr(),
+                a2.as_ptr(),
+                qweight.as_ptr(),
+                qzeros.as_ptr(),
+                scales.as_ptr(),
+                g_idx.as_ptr(),
+            ),
+        );
+    }
+    let mut size = a.size();
+    *size.last_mut().unwrap() = out_features;
+    out.reshape(&size)
+}
+
+extern "C" {
+    fn awq_gemm_C(
+        in_feats: *const C_tensor,
+        kernel: *const C_tensor,
+        scaling_factors: *const C_tensor,
+        zeros: *const C_tensor,
+        split_k_iters: i32,
+        outp: *mut *mut C_tensor,
+    ) -> *mut libc::c_char;
+}
+
+/// Multiply `a` by 4-bit AWQ-quantized weights; returns `[..., out_features]`.
+/// Requires compute capability 7.5 or newer.
+pub fn awq_gemm(
+    a: &Tensor,       // [..., in_features], half
+    qweight: &Tensor, // [in_features, out_features / 8], int
+    scales: &Tensor,  // [num_groups, out_features], half
+    qzeros: &Tensor,  // [num_groups, out_features / 8], int
+) -> Tensor {
+    let in_features = *a.size().last().unwrap();
+    let a2 = a.reshape(&[-1, in_features]).contiguous();
+    let mut outp: *mut C_tensor = std::ptr::null_mut();
+    let out = unsafe {
+        check_res(
+            "awq_gemm_C",
+            awq_gemm_C(
+                a2.as_ptr(),
+                qweight.as_ptr(),
+                scales.as_ptr(),
+                qzeros.as_ptr(),
+                8, // split_k_iters; same as vLLM
+                &mut outp,
+            ),
+        );
+        Tensor::from_ptr(outp)
+    };
+    let mut size = a.size();
+    *size.last_mut().unwrap() = scales.size()[1];
+    out.reshape(&size)
+}
+
 pub fn reshape_and_cache(
     key: &Tensor,             // [num_tokens, num_heads, head_size]
     value: &Tensor,           // [num_tokens, num_heads, head_size]
@@ -233,13 +322,56 @@ pub fn gather_cached_kv(
     }
 }
 
+extern "C" {
+    fn pin_memory_C(t: *const C_tensor, outp: *mut *mut C_tensor) -> *mut libc::c_char;
+
+    fn swap_blocks_C(
+        src: *const C_tensor,
+        dst: *const C_tensor,
+        block_mapping: *const i64,
+        num_pairs: i32,
+        stream: *mut stream::CUDAStream,
+    ) -> *mut libc::c_char;
+}
+
+/// Copy CPU tensor `t` into page-locked memory, so that transfers to and from
+/// the GPU can be asynchronous.
+pub fn pin_memory(t: &Tensor) -> Tensor {
+    let mut outp: *mut C_tensor = std::ptr::null_mut();
+    unsafe {
+        check_res("pin_memory_C", pin_memory_C(t.as_ptr(), &mut outp));
+        Tensor::from_ptr(outp)
+    }
+}
+
+/// Copy blocks (slices along the first dimension) from `src` to `dst` on `stream`.
+/// One of `src` and `dst` is typically on the CPU; unless it's pinned (see `pin_memory()`),
+/// the copies will block the host.
 pub fn swap_blocks(
-    _src: &Tensor,
-    _dst: &Tensor,
-    _block_mapping: &HashMap<usize, usize>,
-    _stream: &CudaStream,
+    src: &Tensor,
+    dst: &Tensor,
+    block_mapping: &HashMap<usize, usize>,
+    stream: &CudaStream,
 ) {
-    todo!()
+    assert!(src.size()[1..] == dst.size()[1..]);
+    assert!(src.kind() == dst.kind());
+    let mut block_mapping_vec = Vec::with_capacity(block_mapping.len() * 2);
+    for (&src_block_number, &dst_block_number) in block_mapping {
+        block_mapping_vec.push(src_block_number as i64);
+        block_mapping_vec.push(dst_block_number as i64);
+    }
+    unsafe {
+        check_res(
+            "swap_blocks_C",
+            swap_blocks_C(
+                src.as_ptr(),
+                dst.as_ptr(),
+                block_mapping_vec.as_ptr(),
+                block_mapping.len() as i32,
+                stream.cu_str,
+            ),
+        );
+    }
 }
 
 fn to_cuda_ptr(t: &Tensor) -> i64 {
@@ -365,6 +497,7 @@ pub fn paged_attention_v1(
     block_size: usize,
     max_context_len: usize,
     alibi_slopes: Option<&Tensor>,
+    sliding_window: Option<usize>,
 ) {
     let alibi_slopes = match alibi_slopes {
         None => std::ptr::null(),
@@ -385,6 +518,7 @@ pub fn paged_attention_v1(
                 block_size as i32,
                 max_context_len as i32,
                 alibi_slopes,
+                sliding_window.unwrap_or(0) as i32,
             ),
         );
     }
@@ -426,8 +560,11 @@ impl Display for Stats {
 extern "C" {
     fn cuda_reset_peak_memory_stats_C(device: i32) -> *mut libc::c_char;
     fn cuda_empty_cache_C() -> *mut libc::c_char;
+    fn cuda_set_device_C(device: i32) -> *mut libc::c_char;
+    fn cuda_current_device_C(outp: *mut i32) -> *mut libc::c_char;
     fn cuda_get_stats_allocated_bytes_C(device: i32, outp: *mut Stats) -> *mut libc::c_char;
     fn cuda_get_device_properties_C(device: i32, outp: *mut CudaProps) -> *mut libc::c_char;
+    fn cuda_mem_get_info_C(device: i32, free: *mut i64, total: *mut i64) -> *mut libc::c_char;
 }
 
 pub fn cuda_reset_peak_memory_stats(device: usize) {
@@ -445,6 +582,22 @@ pub fn cuda_empty_cache() {
     }
 }
 
+/// Make `device` the current CUDA device of this thread; kernels without
+/// a device guard run on it, on its current stream.
+pub fn cuda_set_device(device: usize) {
+    unsafe {
+        check_res("cuda_set_device", cuda_set_device_C(device as i32));
+    }
+}
+
+pub fn cuda_current_device() -> usize {
+    let mut device = 0;
+    unsafe {
+        check_res("cuda_current_device", cuda_current_device_C(&mut device));
+    }
+    device as usize
+}
+
 pub fn cuda_get_stats_allocated_bytes(device: usize) -> Stats {
     let mut stats = Stats::default();
     unsafe {
@@ -456,6 +609,20 @@ pub fn cuda_get_stats_allocated_bytes(device: usize) -> Stats {
     stats
 }
 
+/// Free and total memory of the device in bytes, as seen by the driver
+/// (so including memory used by other processes).
+pub fn cuda_mem_get_info(device: usize) -> (usize, usize) {
+    let mut free = 0;
+    let mut total = 0;
+    unsafe {
+        check_res(
+            "cuda_mem_get_info",
+            cuda_mem_get_info_C(device as i32, &mut free, &mut total),
+        );
+    }
+    (free as usize, total as usize)
+}
+
 pub fn cuda_get_device_properties(device: usize) -> CudaProps {
     let mut props = CudaProps::default();
     unsafe {
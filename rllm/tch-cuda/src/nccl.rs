@@ -0,0 +1,84 @@
+use crate::check_res;
+use std::os::raw::{c_char, c_int};
+use tch::{Device, Tensor};
+use torch_sys::C_tensor;
+
+#[repr(C)]
+struct NcclCommInner {
+    _private: [u8; 0],
+}
+
+extern "C" {
+    fn nccl_comm_init_all_C(
+        ndev: c_int,
+        devices: *const c_int,
+        outp: *mut *mut NcclCommInner,
+    ) -> *mut c_char;
+    fn nccl_comm_destroy_C(comm: *mut NcclCommInner) -> *mut c_char;
+    fn nccl_all_reduce_C(
+        ndev: c_int,
+        tensors: *const *mut C_tensor,
+        comms: *const *mut NcclCommInner,
+    ) -> *mut c_char;
+}
+
+/// NCCL communicators of a group of devices driven from this process
+/// (one rank per device).
+pub struct NcclComm {
+    devices: Vec<Device>,
+    comms: Vec<*mut NcclCommInner>,
+}
+
+impl NcclComm {
+    pub fn new(devices: &[Device]) -> Self {
+        let ids = devices
+            .iter()
+            .map(|d| match d {
+                Device::Cuda(i) => *i as c_int,
+                _ => panic!("NCCL needs CUDA devices, got {d:?}"),
+            })
+            .collect::<Vec<_>>();
+        let mut comms = vec![std::ptr::null_mut(); ids.len()];
+        unsafe {
+            check_res(
+                "nccl_comm_init_all_C",
+                nccl_comm_init_all_C(ids.len() as c_int, ids.as_ptr(), comms.as_mut_ptr()),
+            );
+        }
+        Self {
+            devices: devices.to_vec(),
+            comms,
+        }
+    }
+
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// Replace each of `tensors` (one per device, in order, contiguous) with the
+    /// sum of all of them. Runs on the current stream of each device.
+    pub fn all_reduce(&self, tensors: &mut [Tensor]) {
+        assert!(tensors.len() == self.comms.len());
+        for (t, d) in tensors.iter().zip(self.devices.iter()) {
+            assert!(t.device() == *d);
+            assert!(t.is_contiguous());
+        }
+        let ptrs = tensors.iter().map(|t| t.as_mut_ptr()).collect::<Vec<_>>();
+        unsafe {
+            check_res(
+                "nccl_all_reduce_C",
+                nccl_all_reduce_C(ptrs.len() as c_int, ptrs.as_ptr(), self.comms.as_ptr()),
+            );
+        }
+    }
+}
+
+impl Drop for NcclComm {
+    fn drop(&mut self) {
+        for &comm in self.comms.iter() {
+            unsafe {
+                check_res("nccl_comm_destroy_C", nccl_comm_destroy_C(comm));
+            }
+        }
+    }
+}
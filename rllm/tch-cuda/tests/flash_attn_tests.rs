@@ -47,7 +47,7 @@ fn flash_attn_varlen() -> Result<()> {
         let q = q.transpose(0, 1);
         let k = k.transpose(0, 1);
         let v = v.transpose(0, 1);
-        tch_cuda::flash_attn_varlen(&q, &k, &v, &seqlens_q, &seqlens_k, 32, 32, 0.5, false)
+        tch_cuda::flash_attn_varlen(&q, &k, &v, &seqlens_q, &seqlens_k, 32, 32, 0.5, false, None)
             .transpose(0, 1)
     };
     let ys = ys.to_kind(Kind::Float);
@@ -72,3 +72,24 @@ fn flash_attn_varlen() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn flash_attn_varlen_window() -> Result<()> {
+    let device = Device::Cuda(0);
+    let q = Tensor::randn(&[4, 2, 8], (Kind::BFloat16, device));
+    let k = Tensor::randn(&[4, 2, 8], (Kind::BFloat16, device));
+    let v = Tensor::randn(&[4, 2, 8], (Kind::BFloat16, device));
+    let seqlens = Tensor::from_slice(&[0i32, 4i32]).to_device(device);
+    let attn = |window_size| {
+        tch_cuda::flash_attn_varlen(&q, &k, &v, &seqlens, &seqlens, 4, 4, 0.5, true, window_size)
+            .to_kind(Kind::Float)
+    };
+
+    // with a window of one, each query only attends to its own key
+    let ys = attn(Some(1));
+    assert!(ys.allclose(&v.to_kind(Kind::Float), 1e-3, 1e-3, false));
+
+    // a window covering the whole sequence is the same as plain causal attention
+    assert!(attn(Some(4)).allclose(&attn(None), 1e-3, 1e-3, false));
+    Ok(())
+}